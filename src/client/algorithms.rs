@@ -0,0 +1,431 @@
+use {
+    std::{collections::{BinaryHeap, HashMap, VecDeque, hash_map::RandomState}, cmp::Ordering, hash::{Hash, BuildHasher}, ops::Range},
+    tracing::{span, Level},
+    super::{
+        client::FarMemoryClient,
+        buffered_vec::FarMemoryBufferedVec,
+        serialized_object_vec::FarMemorySerializedObjectVec,
+        codec::SerializationCodec,
+    },
+};
+
+const DEFAULT_MEMORY_BUDGET_SHARE: u64 = 4; // quarter of client local memory limit
+const MIN_RUN_BUFFER_SIZE: usize = 64 * 1024;
+
+/**
+ * far memory collection that external algorithms read input from and write sorted runs and partitions to.
+ */
+pub trait FarMemoryExternalStorage<T>: Sized {
+    fn external_new(client: FarMemoryClient) -> Self;
+    fn external_len(&self) -> usize;
+    fn external_read(&self, range: Range<usize>) -> Vec<T>;
+    fn external_write(&mut self, items: Vec<T>);
+    // estimate of local memory that item takes once it is read.
+    fn external_item_size(&self, index: usize) -> usize;
+    fn external_free(self);
+}
+
+impl<T: Copy> FarMemoryExternalStorage<T> for FarMemoryBufferedVec<T> {
+    fn external_new(client: FarMemoryClient) -> Self {
+        Self::new(client)
+    }
+
+    fn external_len(&self) -> usize {
+        self.len()
+    }
+
+    fn external_read(&self, range: Range<usize>) -> Vec<T> {
+        self.read_range(range)
+    }
+
+    fn external_write(&mut self, items: Vec<T>) {
+        self.append(items);
+    }
+
+    fn external_item_size(&self, _index: usize) -> usize {
+        std::mem::size_of::<T>()
+    }
+
+    fn external_free(self) {
+        drop(self);
+    }
+}
+
+impl<T, C: SerializationCodec<T>> FarMemoryExternalStorage<T> for FarMemorySerializedObjectVec<T, C> {
+    fn external_new(client: FarMemoryClient) -> Self {
+        Self::new(client)
+    }
+
+    fn external_len(&self) -> usize {
+        self.len()
+    }
+
+    fn external_read(&self, range: Range<usize>) -> Vec<T> {
+        range.map(|index| self.get(index).unwrap()).collect()
+    }
+
+    fn external_write(&mut self, items: Vec<T>) {
+        for item in items {
+            self.push(item);
+        }
+    }
+
+    // deserialized object is assumed to take about as much memory as its serialized form.
+    fn external_item_size(&self, index: usize) -> usize {
+        std::mem::size_of::<T>() + self.object_size(index)
+    }
+
+    fn external_free(mut self) {
+        self.clear();
+    }
+}
+
+/**
+ * sorting and grouping of far memory collections that can be larger than local memory. At most `memory_budget` bytes of
+ * items are kept in local memory at a time, by default it is a quarter of client local memory limit.
+ *
+ * sort is a merge sort: input is split into sorted runs that fit into memory budget, which are then merged (possibly in
+ * several passes) with a buffer per run. Group by partitions input by hash of the key so that every partition can be
+ * aggregated in memory.
+ */
+pub struct FarMemoryExternalAlgorithms {
+    client: FarMemoryClient,
+    memory_budget: usize,
+}
+
+struct Run<S> {
+    storage: S,
+    bytes: usize,
+}
+
+struct RunCursor<T, S> {
+    run: Run<S>,
+    position: usize,
+    items_per_read: usize,
+    buffer: VecDeque<T>,
+}
+
+struct HeapEntry<'a, T, F> {
+    item: T,
+    run: usize,
+    compare: &'a F,
+}
+
+impl FarMemoryExternalAlgorithms {
+    pub fn new(client: FarMemoryClient) -> Self {
+        // budget is a divisor in partitioning, so it can't be zero even for tiny local memory limits.
+        let memory_budget = ((client.local_memory_limit() / DEFAULT_MEMORY_BUDGET_SHARE) as usize).max(1);
+        Self {
+            client,
+            memory_budget,
+        }
+    }
+
+    pub fn with_memory_budget(mut self, memory_budget: usize) -> Self {
+        self.memory_budget = memory_budget.max(1);
+        self
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    pub fn sort<T: Ord, S: FarMemoryExternalStorage<T>>(&self, input: &S) -> S {
+        self.sort_by(input, |a: &T, b: &T| a.cmp(b))
+    }
+
+    pub fn sort_by_key<T, K: Ord, S: FarMemoryExternalStorage<T>, F: Fn(&T) -> K>(&self, input: &S, key: F) -> S {
+        self.sort_by(input, |a: &T, b: &T| key(a).cmp(&key(b)))
+    }
+
+    // stable sort. Returns a new sorted collection, input is not modified.
+    pub fn sort_by<T, S: FarMemoryExternalStorage<T>, F: Fn(&T, &T) -> Ordering>(&self, input: &S, compare: F) -> S {
+        let mut runs = span!(Level::DEBUG, "external sort - creating runs").in_scope(|| {
+            self.batches(input, self.memory_budget)
+                .into_iter()
+                .map(|(range, bytes)| {
+                    let mut items = input.external_read(range);
+                    items.sort_by(&compare);
+
+                    let mut storage = S::external_new(self.client.clone());
+                    storage.external_write(items);
+                    Run {
+                        storage,
+                        bytes,
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let max_fan_in = (self.memory_budget / MIN_RUN_BUFFER_SIZE).max(2);
+        while runs.len() > 1 {
+            runs = span!(Level::DEBUG, "external sort - merge pass").in_scope(|| {
+                let mut merged = Vec::new();
+                let mut runs = runs.into_iter().peekable();
+                while runs.peek().is_some() {
+                    let group: Vec<_> = runs.by_ref().take(max_fan_in).collect();
+                    merged.push(self.merge_runs(group, &compare));
+                }
+                merged
+            });
+        }
+
+        match runs.pop() {
+            Some(run) => run.storage,
+            None => S::external_new(self.client.clone()),
+        }
+    }
+
+    // calls `aggregate` for every item with aggregate value of its group, and then `emit` once for every group.
+    pub fn group_by<T, S, K, A, KF, AF, EF>(&self, input: &S, key: KF, mut aggregate: AF, mut emit: EF)
+    where
+        S: FarMemoryExternalStorage<T>,
+        K: Hash + Eq,
+        A: Default,
+        KF: Fn(&T) -> K,
+        AF: FnMut(&mut A, T),
+        EF: FnMut(K, A),
+    {
+        let total_bytes: usize = (0..input.external_len()).map(|index| input.external_item_size(index)).sum();
+        let total_partitions = total_bytes.div_ceil(self.memory_budget).max(1);
+
+        if total_partitions == 1 {
+            let mut groups: HashMap<K, A> = HashMap::new();
+            for item in input.external_read(0..input.external_len()) {
+                aggregate(groups.entry(key(&item)).or_default(), item);
+            }
+            groups.into_iter().for_each(|(key, value)| emit(key, value));
+            return;
+        }
+
+        // half of the budget is for input batch, and another half is for partition buffers.
+        let partitions = span!(Level::DEBUG, "group by - partitioning").in_scope(|| {
+            let hasher = RandomState::new();
+            let partition_buffer_size = (self.memory_budget / 2 / total_partitions).max(1);
+
+            let mut partitions: Vec<S> = (0..total_partitions).map(|_| S::external_new(self.client.clone())).collect();
+            let mut buffers: Vec<(Vec<T>, usize)> = (0..total_partitions).map(|_| (Vec::new(), 0)).collect();
+
+            for (range, _) in self.batches(input, self.memory_budget / 2) {
+                let sizes: Vec<_> = range.clone().map(|index| input.external_item_size(index)).collect();
+                for (item, size) in input.external_read(range).into_iter().zip(sizes.into_iter()) {
+                    let partition = (hasher.hash_one(key(&item)) % total_partitions as u64) as usize;
+                    let buffer = &mut buffers[partition];
+                    buffer.0.push(item);
+                    buffer.1 += size;
+
+                    if buffer.1 >= partition_buffer_size {
+                        partitions[partition].external_write(std::mem::take(&mut buffer.0));
+                        buffer.1 = 0;
+                    }
+                }
+            }
+
+            for (partition, buffer) in partitions.iter_mut().zip(buffers.into_iter()) {
+                partition.external_write(buffer.0);
+            }
+
+            partitions
+        });
+
+        for partition in partitions {
+            span!(Level::DEBUG, "group by - aggregating partition").in_scope(|| {
+                let mut groups: HashMap<K, A> = HashMap::new();
+                for (range, _) in self.batches(&partition, self.memory_budget / 2) {
+                    for item in partition.external_read(range) {
+                        aggregate(groups.entry(key(&item)).or_default(), item);
+                    }
+                }
+                partition.external_free();
+                groups.into_iter().for_each(|(key, value)| emit(key, value));
+            });
+        }
+    }
+
+    // splits storage into ranges of items which fit into given size (but at least one item each), with their sizes.
+    fn batches<T, S: FarMemoryExternalStorage<T>>(&self, storage: &S, max_bytes: usize) -> Vec<(Range<usize>, usize)> {
+        let mut batches = Vec::new();
+        let len = storage.external_len();
+
+        let mut start = 0;
+        while start < len {
+            let mut end = start;
+            let mut bytes = 0;
+            while end < len {
+                let item_size = storage.external_item_size(end);
+                if end > start && bytes + item_size > max_bytes {
+                    break;
+                }
+                bytes += item_size;
+                end += 1;
+            }
+
+            batches.push((start..end, bytes));
+            start = end;
+        }
+
+        batches
+    }
+
+    fn merge_runs<T, S: FarMemoryExternalStorage<T>, F: Fn(&T, &T) -> Ordering>(&self, runs: Vec<Run<S>>, compare: &F) -> Run<S> {
+        if runs.len() == 1 {
+            return runs.into_iter().next().unwrap();
+        }
+
+        // every run and output get an equal share of memory budget.
+        let buffer_size = (self.memory_budget / (runs.len() + 1)).max(1);
+        let total_bytes: usize = runs.iter().map(|v| v.bytes).sum();
+        let total_items: usize = runs.iter().map(|v| v.storage.external_len()).sum();
+        let output_batch_len = (buffer_size / (total_bytes / total_items.max(1)).max(1)).max(1);
+
+        let mut cursors: Vec<_> = runs.into_iter()
+            .map(|run| {
+                let item_size = (run.bytes / run.storage.external_len().max(1)).max(1);
+                RunCursor {
+                    run,
+                    position: 0,
+                    items_per_read: (buffer_size / item_size).max(1),
+                    buffer: VecDeque::new(),
+                }
+            })
+            .collect();
+
+        let mut heap = BinaryHeap::new();
+        for (run, cursor) in cursors.iter_mut().enumerate() {
+            if let Some(item) = cursor.next() {
+                heap.push(HeapEntry { item, run, compare });
+            }
+        }
+
+        let mut output = S::external_new(self.client.clone());
+        let mut output_batch = Vec::with_capacity(output_batch_len);
+        while let Some(entry) = heap.pop() {
+            output_batch.push(entry.item);
+            if output_batch.len() >= output_batch_len {
+                output.external_write(std::mem::replace(&mut output_batch, Vec::with_capacity(output_batch_len)));
+            }
+
+            if let Some(item) = cursors[entry.run].next() {
+                heap.push(HeapEntry { item, run: entry.run, compare });
+            }
+        }
+        output.external_write(output_batch);
+
+        for cursor in cursors {
+            cursor.run.storage.external_free();
+        }
+
+        Run {
+            storage: output,
+            bytes: total_bytes,
+        }
+    }
+}
+
+impl<T, S: FarMemoryExternalStorage<T>> RunCursor<T, S> {
+    fn next(&mut self) -> Option<T> {
+        if self.buffer.is_empty() {
+            let len = self.run.storage.external_len();
+            if self.position == len {
+                return None;
+            }
+
+            let end = (self.position + self.items_per_read).min(len);
+            self.buffer.extend(self.run.storage.external_read(self.position..end));
+            self.position = end;
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+// reversed, so that max heap pops the smallest item first. Items from earlier runs go first when equal, which keeps sort stable.
+impl<'a, T, F: Fn(&T, &T) -> Ordering> Ord for HeapEntry<'a, T, F> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.compare)(&other.item, &self.item).then_with(|| other.run.cmp(&self.run))
+    }
+}
+
+impl<'a, T, F: Fn(&T, &T) -> Ordering> PartialOrd for HeapEntry<'a, T, F> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'a, T, F: Fn(&T, &T) -> Ordering> PartialEq for HeapEntry<'a, T, F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<'a, T, F: Fn(&T, &T) -> Ordering> Eq for HeapEntry<'a, T, F> {}
+
+#[cfg(test)]
+mod tests {
+    use {
+        rand::{Rng, SeedableRng, rngs::SmallRng},
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn sort_buffered_vec() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut rng = SmallRng::seed_from_u64(42);
+        let items: Vec<(u32, u32)> = (0..20_000).map(|i| (rng.gen_range(0..1000), i)).collect();
+        let vec = FarMemoryBufferedVec::from_vec(client.clone(), items.clone());
+
+        // 8 runs, merged in 3 passes
+        let algorithms = FarMemoryExternalAlgorithms::new(client).with_memory_budget(20_000);
+        let sorted = algorithms.sort_by_key(&vec, |v: &(u32, u32)| v.0);
+
+        let mut expected = items;
+        expected.sort_by_key(|v| v.0);
+        assert_eq!(expected, sorted.to_local_vec());
+    }
+
+    #[test]
+    fn sort_serialized_object_vec() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut rng = SmallRng::seed_from_u64(42);
+        let items: Vec<String> = (0..2000).map(|_| format!("item-{}", rng.gen_range(0..1_000_000))).collect();
+
+        let mut vec: FarMemorySerializedObjectVec<String> = FarMemorySerializedObjectVec::new(client.clone());
+        for item in &items {
+            vec.push(item.clone());
+        }
+
+        let algorithms = FarMemoryExternalAlgorithms::new(client).with_memory_budget(8 * 1024);
+        let sorted = algorithms.sort(&vec);
+
+        let mut expected = items;
+        expected.sort();
+        assert_eq!(expected, (0..sorted.len()).map(|i| sorted.get(i).unwrap()).collect::<Vec<_>>());
+        assert_eq!(2000, vec.len());
+    }
+
+    #[test]
+    fn group_by_partitions() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let items: Vec<u64> = (0..50_000).collect();
+        let vec = FarMemoryBufferedVec::from_vec(client.clone(), items);
+
+        let algorithms = FarMemoryExternalAlgorithms::new(client).with_memory_budget(64 * 1024);
+        let mut groups = HashMap::new();
+        algorithms.group_by(&vec, |v: &u64| v % 37, |sum: &mut u64, v| *sum += v, |key, sum| {
+            assert!(groups.insert(key, sum).is_none());
+        });
+
+        assert_eq!(37, groups.len());
+        for key in 0..37 {
+            assert_eq!((0..50_000u64).filter(|v| v % 37 == key).sum::<u64>(), groups[&key]);
+        }
+    }
+
+    #[test]
+    fn memory_budget_is_never_zero() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 2);
+        assert_eq!(1, FarMemoryExternalAlgorithms::new(client.clone()).memory_budget());
+        assert_eq!(1, FarMemoryExternalAlgorithms::new(client).with_memory_budget(0).memory_budget());
+    }
+}
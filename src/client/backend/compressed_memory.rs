@@ -0,0 +1,464 @@
+use {
+    std::{sync::{Mutex, MutexGuard, Condvar}, collections::{BTreeMap, HashMap, HashSet}},
+    tracing::{span, Level},
+    prometheus::{Registry, IntCounter, IntGauge, Gauge, register_int_counter_with_registry, register_int_gauge_with_registry, register_gauge_with_registry},
+    crate::client::span::SpanId,
+    super::{
+        FarMemoryBackend,
+        SwapOutOperation,
+        SwapOutOperationData,
+        compression::{CompressionCodec, SpanCompressor},
+    },
+};
+
+/**
+ * Keeps swapped out spans compressed in a bounded local memory pool, similar to zswap. When the pool is full, least
+ * recently swapped out spans are spilled to inner backend still compressed, so that prepends to spilled spans are
+ * passed to inner backend as compressed frames without reading span back.
+ */
+pub struct CompressedMemoryBackend {
+    inner: Box<dyn FarMemoryBackend>,
+    compressor: SpanCompressor,
+    pool_capacity: usize,
+
+    state: Mutex<PoolState>,
+    spill_finished: Condvar,
+    metrics: Option<CompressedMemoryMetrics>,
+}
+
+struct PoolState {
+    spans: HashMap<SpanId, PoolEntry>,
+    // insertion tick to span, coldest span first.
+    lru: BTreeMap<u64, SpanId>,
+    next_tick: u64,
+
+    pool_bytes: usize,
+    original_bytes: usize,
+
+    spilled: HashSet<SpanId>,
+    // spans that are being written to inner backend. Pool is not locked while they are written, so operations on these
+    // spans wait for the write to finish.
+    spilling: HashSet<SpanId>,
+
+    pool_hits: u64,
+    inner_hits: u64,
+}
+
+// marks spans as written to inner backend when dropped, including when write fails.
+struct SpillGuard<'a> {
+    backend: &'a CompressedMemoryBackend,
+    spans: Vec<SpanId>,
+}
+
+struct PoolEntry {
+    // compressed frames, as produced by `SpanCompressor`.
+    frames: Vec<u8>,
+    original_len: usize,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressedMemoryStats {
+    pub pool_spans: usize,
+    pub pool_bytes: usize,
+    pub spilled_spans: usize,
+    // share of swap ins served from the pool.
+    pub hit_rate: f64,
+    // compressed size of spans in the pool divided by their original size.
+    pub compression_ratio: f64,
+}
+
+struct CompressedMemoryMetrics {
+    registry: Registry,
+
+    pool_hits: IntCounter,
+    inner_hits: IntCounter,
+    spills: IntCounter,
+
+    pool_bytes: IntGauge,
+    compression_ratio: Gauge,
+}
+
+impl CompressedMemoryBackend {
+    pub fn new(inner: Box<dyn FarMemoryBackend>, pool_capacity: usize) -> Self {
+        Self {
+            inner,
+            compressor: SpanCompressor::new(CompressionCodec::Lz4),
+            pool_capacity,
+
+            state: Mutex::new(PoolState {
+                spans: HashMap::new(),
+                lru: BTreeMap::new(),
+                next_tick: 0,
+
+                pool_bytes: 0,
+                original_bytes: 0,
+
+                spilled: HashSet::new(),
+                spilling: HashSet::new(),
+
+                pool_hits: 0,
+                inner_hits: 0,
+            }),
+            spill_finished: Condvar::new(),
+            metrics: None,
+        }
+    }
+
+    pub fn with_codec(mut self, codec: CompressionCodec) -> Self {
+        self.compressor = SpanCompressor::new(codec);
+        self
+    }
+
+    pub fn with_metrics(mut self, registry: Registry) -> Self {
+        self.metrics = Some(CompressedMemoryMetrics::new(registry));
+        self
+    }
+
+    pub fn stats(&self) -> CompressedMemoryStats {
+        let state = self.state.lock().unwrap();
+        let swap_ins = state.pool_hits + state.inner_hits;
+
+        CompressedMemoryStats {
+            pool_spans: state.spans.len(),
+            pool_bytes: state.pool_bytes,
+            spilled_spans: state.spilled.len(),
+            hit_rate: if swap_ins > 0 { state.pool_hits as f64 / swap_ins as f64 } else { 0.0 },
+            compression_ratio: state.compression_ratio(),
+        }
+    }
+
+    // removes coldest spans from the pool, they are written to inner backend by `spill` after the pool is unlocked.
+    fn take_over_capacity(&self, state: &mut PoolState) -> Vec<SwapOutOperation> {
+        let mut spilled = Vec::new();
+        while state.pool_bytes > self.pool_capacity {
+            let span_id = match state.lru.values().next() {
+                Some(v) => v.clone(),
+                None => break,
+            };
+
+            let entry = state.remove(&span_id).unwrap();
+            state.spilled.insert(span_id.clone());
+            state.spilling.insert(span_id.clone());
+            spilled.push(SwapOutOperation::new(span_id, SwapOutOperationData::Owned(entry.frames), false));
+        }
+
+        if !spilled.is_empty() {
+            if let Some(metrics) = &self.metrics {
+                metrics.spills.inc_by(spilled.len() as u64);
+            }
+        }
+
+        spilled
+    }
+
+    fn spill(&self, spilled: Vec<SwapOutOperation>) {
+        if spilled.is_empty() {
+            return;
+        }
+
+        let _guard = SpillGuard {
+            backend: self,
+            spans: spilled.iter().map(|v| v.id.clone()).collect(),
+        };
+        span!(Level::DEBUG, "spill to inner backend").in_scope(|| self.inner.batch_swap_out(spilled));
+    }
+
+    fn wait_for_spill<'a>(&self, mut state: MutexGuard<'a, PoolState>, id: &SpanId) -> MutexGuard<'a, PoolState> {
+        while state.spilling.contains(id) {
+            state = self.spill_finished.wait(state).unwrap();
+        }
+        state
+    }
+
+    fn update_gauges(&self, state: &PoolState) {
+        if let Some(metrics) = &self.metrics {
+            metrics.pool_bytes.set(state.pool_bytes as i64);
+            metrics.compression_ratio.set(state.compression_ratio());
+        }
+    }
+}
+
+impl PoolState {
+    fn insert(&mut self, id: SpanId, frames: Vec<u8>, original_len: usize) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        self.pool_bytes += frames.len();
+        self.original_bytes += original_len;
+        self.lru.insert(tick, id.clone());
+        self.spans.insert(id, PoolEntry {
+            frames,
+            original_len,
+            tick,
+        });
+    }
+
+    fn remove(&mut self, id: &SpanId) -> Option<PoolEntry> {
+        let entry = self.spans.remove(id)?;
+        self.pool_bytes -= entry.frames.len();
+        self.original_bytes -= entry.original_len;
+        self.lru.remove(&entry.tick);
+        Some(entry)
+    }
+
+    fn compression_ratio(&self) -> f64 {
+        if self.original_bytes > 0 {
+            self.pool_bytes as f64 / self.original_bytes as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Drop for SpillGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.backend.state.lock().unwrap();
+        for span_id in &self.spans {
+            state.spilling.remove(span_id);
+        }
+        self.backend.spill_finished.notify_all();
+    }
+}
+
+impl CompressedMemoryMetrics {
+    fn new(registry: Registry) -> Self {
+        Self {
+            registry: registry.clone(),
+
+            pool_hits: register_int_counter_with_registry!(
+                "client_compressed_memory_pool_hits",
+                "swap ins served from compressed memory pool",
+                registry
+            ).unwrap(),
+            inner_hits: register_int_counter_with_registry!(
+                "client_compressed_memory_inner_hits",
+                "swap ins of spans spilled to inner backend",
+                registry
+            ).unwrap(),
+            spills: register_int_counter_with_registry!(
+                "client_compressed_memory_spills",
+                "spans spilled from compressed memory pool to inner backend",
+                registry
+            ).unwrap(),
+
+            pool_bytes: register_int_gauge_with_registry!(
+                "client_compressed_memory_pool_bytes",
+                "compressed bytes in memory pool",
+                registry
+            ).unwrap(),
+            compression_ratio: register_gauge_with_registry!(
+                "client_compressed_memory_compression_ratio",
+                "compressed size of spans in memory pool divided by their original size",
+                registry
+            ).unwrap(),
+        }
+    }
+
+    fn unregister(&self) {
+        self.registry.unregister(Box::new(self.pool_hits.clone())).unwrap();
+        self.registry.unregister(Box::new(self.inner_hits.clone())).unwrap();
+        self.registry.unregister(Box::new(self.spills.clone())).unwrap();
+
+        self.registry.unregister(Box::new(self.pool_bytes.clone())).unwrap();
+        self.registry.unregister(Box::new(self.compression_ratio.clone())).unwrap();
+    }
+}
+
+impl FarMemoryBackend for CompressedMemoryBackend {
+    fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+        let frame = self.compressor.compress(span);
+
+        let mut state = self.wait_for_spill(self.state.lock().unwrap(), &id);
+        if prepend && state.spilled.contains(&id) {
+            self.inner.swap_out(id, &frame, true);
+            return;
+        }
+
+        if prepend {
+            let previous = state.remove(&id).unwrap();
+            let mut frames = frame;
+            frames.extend_from_slice(&previous.frames);
+            state.insert(id, frames, span.len() + previous.original_len);
+        } else {
+            state.remove(&id);
+            if state.spilled.remove(&id) {
+                self.inner.remove(&id);
+            }
+            state.insert(id, frame, span.len());
+        }
+
+        let spilled = self.take_over_capacity(&mut state);
+        self.update_gauges(&state);
+        drop(state);
+
+        self.spill(spilled);
+    }
+
+    fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+        let mut state = self.wait_for_spill(self.state.lock().unwrap(), id);
+
+        let frames = match state.remove(id) {
+            Some(entry) => {
+                state.pool_hits += 1;
+                if let Some(metrics) = &self.metrics {
+                    metrics.pool_hits.inc();
+                }
+                self.update_gauges(&state);
+                drop(state);
+
+                entry.frames
+            },
+            None => {
+                assert!(state.spilled.remove(id), "span {} is not swapped out", id.id());
+                state.inner_hits += 1;
+                if let Some(metrics) = &self.metrics {
+                    metrics.inner_hits.inc();
+                }
+                drop(state);
+
+                self.inner.swap_in(id)
+            }
+        };
+
+        self.compressor.decompress(&frames)
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let mut state = self.wait_for_spill(self.state.lock().unwrap(), id);
+        if state.remove(id).is_none() && state.spilled.remove(id) {
+            self.inner.remove(id);
+        }
+        self.update_gauges(&state);
+    }
+
+    fn on_stop(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.unregister();
+        }
+
+        self.inner.on_stop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::{thread, sync::{Arc, mpsc::{channel, Sender, Receiver}}},
+        rand::Rng,
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    // signals when write starts and blocks it until test releases it.
+    struct SlowBackend {
+        inner: InMemoryBackend,
+        started: Mutex<Sender<()>>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl FarMemoryBackend for SlowBackend {
+        fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            self.inner.swap_out(id, span, prepend);
+        }
+
+        fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+            self.inner.swap_in(id)
+        }
+    }
+
+    #[test]
+    fn swap_out_swap_in_from_pool() {
+        let backend = CompressedMemoryBackend::new(Box::new(InMemoryBackend::new()), 1024 * 1024);
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 17) as u8).collect();
+
+        backend.swap_out(SpanId::from_id(1), &data, false);
+        backend.swap_out(SpanId::from_id(1), &[1, 2, 3], true);
+
+        let stats = backend.stats();
+        assert_eq!(1, stats.pool_spans);
+        assert!(stats.compression_ratio < 0.1);
+
+        assert_eq!([vec![1, 2, 3], data].concat(), backend.swap_in(&SpanId::from_id(1)));
+        assert_eq!(1.0, backend.stats().hit_rate);
+    }
+
+    #[test]
+    fn coldest_spans_are_spilled() {
+        let backend = CompressedMemoryBackend::new(Box::new(InMemoryBackend::new()), 10 * 1024);
+        let spans: Vec<Vec<u8>> = (0..4).map(|_| (0..4096).map(|_| rand::thread_rng().gen()).collect()).collect();
+
+        for (i, span) in spans.iter().enumerate() {
+            backend.swap_out(SpanId::from_id(i as u64), span, false);
+        }
+
+        // random data does not compress, so only two spans fit into the pool.
+        let stats = backend.stats();
+        assert_eq!(2, stats.pool_spans);
+        assert_eq!(2, stats.spilled_spans);
+
+        // prepend to spilled span goes to inner backend.
+        backend.swap_out(SpanId::from_id(0), &[1, 2, 3], true);
+        assert_eq!([vec![1, 2, 3], spans[0].clone()].concat(), backend.swap_in(&SpanId::from_id(0)));
+        for i in 1..4 {
+            assert_eq!(spans[i], backend.swap_in(&SpanId::from_id(i as u64)));
+        }
+
+        assert_eq!(0.5, backend.stats().hit_rate);
+    }
+
+    #[test]
+    fn pool_is_not_locked_while_spilling() {
+        let (started_sender, started) = channel();
+        let (release, release_receiver) = channel();
+        let backend = Arc::new(CompressedMemoryBackend::new(Box::new(SlowBackend {
+            inner: InMemoryBackend::new(),
+            started: Mutex::new(started_sender),
+            release: Mutex::new(release_receiver),
+        }), 6 * 1024));
+        let spans: Vec<Vec<u8>> = (0..2).map(|_| (0..4096).map(|_| rand::thread_rng().gen()).collect()).collect();
+
+        backend.swap_out(SpanId::from_id(0), &spans[0], false);
+        let spilling = {
+            let (backend, span) = (backend.clone(), spans[1].clone());
+            thread::spawn(move || backend.swap_out(SpanId::from_id(1), &span, false))
+        };
+        started.recv().unwrap();
+
+        // span 0 is being spilled, while span 1 can still be used.
+        assert_eq!(1, backend.stats().spilled_spans);
+        assert_eq!(spans[1], backend.swap_in(&SpanId::from_id(1)));
+
+        let swap_in = {
+            let backend = backend.clone();
+            thread::spawn(move || backend.swap_in(&SpanId::from_id(0)))
+        };
+        release.send(()).unwrap();
+        spilling.join().unwrap();
+        assert_eq!(spans[0], swap_in.join().unwrap());
+    }
+
+    #[test]
+    fn metrics() {
+        let registry = Registry::new();
+        let backend = CompressedMemoryBackend::new(Box::new(InMemoryBackend::new()), 16 * 1024)
+            .with_codec(CompressionCodec::Zstd)
+            .with_metrics(registry.clone());
+
+        backend.swap_out(SpanId::from_id(1), &(0..16 * 1024).map(|_| rand::thread_rng().gen()).collect::<Vec<u8>>(), false);
+        backend.swap_out(SpanId::from_id(2), &vec![0; 64 * 1024], false);
+        backend.swap_in(&SpanId::from_id(1));
+        backend.swap_in(&SpanId::from_id(2));
+
+        let metrics = backend.metrics.as_ref().unwrap();
+        assert_eq!(1, metrics.spills.get());
+        assert_eq!(1, metrics.pool_hits.get());
+        assert_eq!(1, metrics.inner_hits.get());
+        assert_eq!(0, metrics.pool_bytes.get());
+
+        backend.on_stop();
+        assert!(registry.gather().is_empty());
+    }
+}
@@ -1,69 +1,299 @@
 use {
-    std::io::{Write, Read},
-    lz4::{EncoderBuilder, Decoder},
+    std::time::Instant,
+    tracing::{span, Level},
+    lz4::block::CompressionMode,
+    prometheus::{
+        Registry,
+        IntCounterVec,
+        CounterVec,
+        GaugeVec,
+        register_int_counter_vec_with_registry,
+        register_counter_vec_with_registry,
+        register_gauge_vec_with_registry,
+    },
     crate::client::span::SpanId,
     super::{FarMemoryBackend, SwapOutOperation, SwapOutOperationData},
 };
 
+const FRAME_HEADER_SIZE: usize = 17;
+const DEFAULT_COMPRESSION_THRESHOLD: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionCodec {
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+/**
+ * Compresses spans before passing them to inner backend. Every swap out is stored as a frame:
+ * codec (u8, 0 for raw data), original length (u64), stored length (u64), followed by stored data. Frames are
+ * self-describing, so prepend is passed to inner backend as is, and swap in decompresses frames one by one.
+ *
+ * Data that does not shrink to `compression_threshold` of its size (for example, encrypted or already compressed
+ * data) is stored raw, so that it is not decompressed for nothing when swapped in.
+ */
 pub struct CompressionBackend {
     inner: Box<dyn FarMemoryBackend>,
+    compressor: SpanCompressor,
+}
+
+// encodes and decodes frames described above, shared with backends that store compressed spans themselves.
+pub(super) struct SpanCompressor {
+    codec: CompressionCodec,
+    level: Option<i32>,
+    compression_threshold: f64,
+
+    metrics: Option<CompressionMetrics>,
+}
+
+struct CompressionMetrics {
+    registry: Registry,
+
+    input_bytes: IntCounterVec,
+    output_bytes: IntCounterVec,
+    ratio: GaugeVec,
+    raw_spans: IntCounterVec,
+    time_ms: CounterVec,
+}
+
+impl CompressionCodec {
+    fn id(&self) -> u8 {
+        match self {
+            Self::Lz4 => 1,
+            Self::Snappy => 2,
+            Self::Zstd => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Self::Lz4),
+            2 => Some(Self::Snappy),
+            3 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Lz4 => "lz4",
+            Self::Snappy => "snappy",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn default_level(&self) -> i32 {
+        match self {
+            Self::Lz4 => 4,
+            Self::Snappy => 0, // snappy has no levels
+            Self::Zstd => 3,
+        }
+    }
+
+    fn compress(&self, data: &[u8], level: i32) -> Vec<u8> {
+        match self {
+            Self::Lz4 => lz4::block::compress(data, Some(CompressionMode::HIGHCOMPRESSION(level)), false).unwrap(),
+            Self::Snappy => snap::raw::Encoder::new().compress_vec(data).unwrap(),
+            Self::Zstd => zstd::bulk::compress(data, level).unwrap(),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], original_len: usize) -> Vec<u8> {
+        match self {
+            Self::Lz4 => lz4::block::decompress(data, Some(original_len as i32)).unwrap(),
+            Self::Snappy => snap::raw::Decoder::new().decompress_vec(data).unwrap(),
+            Self::Zstd => zstd::bulk::decompress(data, original_len).unwrap(),
+        }
+    }
 }
 
 impl CompressionBackend {
     pub fn new(inner: Box<dyn FarMemoryBackend>) -> Self {
         Self {
             inner,
+            compressor: SpanCompressor::new(CompressionCodec::Lz4),
         }
     }
 
-    fn compress(&self, data: &[u8]) -> Vec<u8> {
-        let mut output = Vec::new();
-
-        let mut encoder = EncoderBuilder::new()
-            .level(4)
-            .build(&mut output)
-            .unwrap();
+    pub fn with_codec(mut self, codec: CompressionCodec) -> Self {
+        self.compressor.codec = codec;
+        self
+    }
 
-        encoder.write(&data).unwrap();
+    // compression level for lz4 and zstd, default level of the codec is used otherwise.
+    pub fn with_level(mut self, level: i32) -> Self {
+        self.compressor.level = Some(level);
+        self
+    }
 
-        output
+    // spans that are compressed to more than this fraction of original size are stored raw.
+    pub fn with_compression_threshold(mut self, compression_threshold: f64) -> Self {
+        self.compressor.compression_threshold = compression_threshold;
+        self
     }
 
-    fn decompress(&self, mut data: &[u8]) -> Vec<u8> {
-        let mut output = Vec::new();
-        let mut decoder = Decoder::new(&mut data).unwrap();
-        decoder.read_to_end(&mut output).unwrap();
-        output
+    pub fn with_metrics(mut self, registry: Registry) -> Self {
+        self.compressor.metrics = Some(CompressionMetrics::new(registry));
+        self
     }
 
     fn compress_batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) -> Vec<SwapOutOperation> {
         swap_out_operations.into_iter()
             .map(|v| SwapOutOperation {
                 id: v.id,
-                data: SwapOutOperationData::Owned(self.compress(v.data.as_slice())),
+                data: SwapOutOperationData::Owned(self.compressor.compress(v.data.as_slice())),
                 prepend: v.prepend,
             })
             .collect()
     }
 }
 
+impl SpanCompressor {
+    pub(super) fn new(codec: CompressionCodec) -> Self {
+        Self {
+            codec,
+            level: None,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+
+            metrics: None,
+        }
+    }
+
+    pub(super) fn compress(&self, data: &[u8]) -> Vec<u8> {
+        span!(Level::DEBUG, "compress").in_scope(|| {
+            let started_at = Instant::now();
+            let compressed = self.codec.compress(data, self.level.unwrap_or(self.codec.default_level()));
+            let is_raw = compressed.len() as f64 > data.len() as f64 * self.compression_threshold;
+
+            if let Some(metrics) = &self.metrics {
+                metrics.on_compress(self.codec, data.len(), if is_raw { data.len() } else { compressed.len() }, is_raw, started_at);
+            }
+
+            let (codec_id, stored) = if is_raw {
+                (0, data)
+            } else {
+                (self.codec.id(), compressed.as_slice())
+            };
+
+            let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + stored.len());
+            frame.push(codec_id);
+            frame.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            frame.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+            frame.extend_from_slice(stored);
+            frame
+        })
+    }
+
+    pub(super) fn decompress(&self, data: &[u8]) -> Vec<u8> {
+        span!(Level::DEBUG, "decompress").in_scope(|| {
+            let mut output = Vec::new();
+
+            let mut offset = 0;
+            while offset < data.len() {
+                let codec_id = data[offset];
+                let original_len = u64::from_le_bytes(data[offset + 1..offset + 9].try_into().unwrap()) as usize;
+                let stored_len = u64::from_le_bytes(data[offset + 9..offset + FRAME_HEADER_SIZE].try_into().unwrap()) as usize;
+                let stored = &data[offset + FRAME_HEADER_SIZE..offset + FRAME_HEADER_SIZE + stored_len];
+
+                if codec_id == 0 {
+                    output.extend_from_slice(stored);
+                } else {
+                    let codec = CompressionCodec::from_id(codec_id).expect("unknown compression codec");
+                    let started_at = Instant::now();
+                    output.append(&mut codec.decompress(stored, original_len));
+                    if let Some(metrics) = &self.metrics {
+                        metrics.on_decompress(codec, started_at);
+                    }
+                }
+
+                offset += FRAME_HEADER_SIZE + stored_len;
+            }
+
+            output
+        })
+    }
+}
+
+impl CompressionMetrics {
+    fn new(registry: Registry) -> Self {
+        Self {
+            registry: registry.clone(),
+
+            input_bytes: register_int_counter_vec_with_registry!(
+                "client_compression_input_bytes",
+                "total bytes passed to compression",
+                &["codec"],
+                registry
+            ).unwrap(),
+            output_bytes: register_int_counter_vec_with_registry!(
+                "client_compression_output_bytes",
+                "total bytes stored after compression, including spans stored raw",
+                &["codec"],
+                registry
+            ).unwrap(),
+            ratio: register_gauge_vec_with_registry!(
+                "client_compression_ratio",
+                "ratio of stored bytes to input bytes",
+                &["codec"],
+                registry
+            ).unwrap(),
+            raw_spans: register_int_counter_vec_with_registry!(
+                "client_compression_raw_spans",
+                "spans stored raw because they did not compress well enough",
+                &["codec"],
+                registry
+            ).unwrap(),
+            time_ms: register_counter_vec_with_registry!(
+                "client_compression_time",
+                "total time spent compressing and decompressing",
+                &["codec", "operation"],
+                registry
+            ).unwrap(),
+        }
+    }
+
+    fn on_compress(&self, codec: CompressionCodec, input_len: usize, output_len: usize, is_raw: bool, started_at: Instant) {
+        let codec = codec.name();
+
+        self.input_bytes.with_label_values(&[codec]).inc_by(input_len as u64);
+        self.output_bytes.with_label_values(&[codec]).inc_by(output_len as u64);
+        if is_raw {
+            self.raw_spans.with_label_values(&[codec]).inc();
+        }
+
+        let total_input = self.input_bytes.with_label_values(&[codec]).get();
+        if total_input > 0 {
+            self.ratio.with_label_values(&[codec]).set(self.output_bytes.with_label_values(&[codec]).get() as f64 / total_input as f64);
+        }
+
+        self.time_ms.with_label_values(&[codec, "compress"]).inc_by((Instant::now() - started_at).as_micros() as f64 / 1000.0);
+    }
+
+    fn on_decompress(&self, codec: CompressionCodec, started_at: Instant) {
+        self.time_ms.with_label_values(&[codec.name(), "decompress"]).inc_by((Instant::now() - started_at).as_micros() as f64 / 1000.0);
+    }
+
+    fn unregister(&self) {
+        self.registry.unregister(Box::new(self.input_bytes.clone())).unwrap();
+        self.registry.unregister(Box::new(self.output_bytes.clone())).unwrap();
+        self.registry.unregister(Box::new(self.ratio.clone())).unwrap();
+        self.registry.unregister(Box::new(self.raw_spans.clone())).unwrap();
+        self.registry.unregister(Box::new(self.time_ms.clone())).unwrap();
+    }
+}
+
 impl FarMemoryBackend for CompressionBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
-        if prepend {
-            // not optimal, but good enough for now
-            let mut span_data = span.to_vec();
-            let mut data = self.decompress(&self.inner.swap_in(&id));
-            span_data.append(&mut data);
-
-            let compressed = self.compress(&data);
-            self.inner.swap_out(id, &compressed, false)
-        } else {
-            self.inner.swap_out(id, &self.compress(span), prepend)
-        }
+        self.inner.swap_out(id, &self.compressor.compress(span), prepend)
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        self.decompress(&self.inner.swap_in(id))
+        self.compressor.decompress(&self.inner.swap_in(id))
+    }
+
+    fn remove(&self, id: &SpanId) {
+        self.inner.remove(id)
     }
 
     fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
@@ -71,12 +301,73 @@ impl FarMemoryBackend for CompressionBackend {
     }
 
     fn batch(&self, swap_out_operations: Vec<SwapOutOperation>, swap_in: Option<&SpanId>) -> Option<Vec<u8>> {
-        self.inner.batch(self.compress_batch_swap_out(swap_out_operations), swap_in).map(|v| self.decompress(&v))
+        self.inner.batch(self.compress_batch_swap_out(swap_out_operations), swap_in).map(|v| self.compressor.decompress(&v))
     }
 
     fn on_stop(&self) {
+        if let Some(metrics) = &self.compressor.metrics {
+            metrics.unregister();
+        }
+
         self.inner.on_stop()
     }
 }
 
-// TODO: add tests
+#[cfg(test)]
+mod tests {
+    use {
+        rand::Rng,
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn swap_out_swap_in_with_every_codec() {
+        let first: Vec<u8> = (0..10000).map(|i| (i % 7) as u8).collect();
+        let second: Vec<u8> = (0..3000).map(|i| (i % 13) as u8).collect();
+
+        for codec in [CompressionCodec::Lz4, CompressionCodec::Snappy, CompressionCodec::Zstd] {
+            let backend = CompressionBackend::new(Box::new(InMemoryBackend::new())).with_codec(codec);
+
+            assert!(backend.compressor.compress(&first).len() < first.len() / 4);
+
+            backend.swap_out(SpanId::from_id(42), &first, false);
+            backend.swap_out(SpanId::from_id(42), &second, true);
+            assert_eq!([second.clone(), first.clone()].concat(), backend.swap_in(&SpanId::from_id(42)));
+        }
+    }
+
+    #[test]
+    fn incompressible_data_is_stored_raw() {
+        let data: Vec<u8> = (0..4096).map(|_| rand::thread_rng().gen()).collect();
+        let backend = CompressionBackend::new(Box::new(InMemoryBackend::new())).with_codec(CompressionCodec::Zstd);
+
+        let frame = backend.compressor.compress(&data);
+        assert_eq!(0, frame[0]);
+        assert_eq!(data.len() + FRAME_HEADER_SIZE, frame.len());
+
+        backend.swap_out(SpanId::from_id(42), &data, false);
+        assert_eq!(data, backend.swap_in(&SpanId::from_id(42)));
+    }
+
+    #[test]
+    fn metrics() {
+        let registry = Registry::new();
+        let backend = CompressionBackend::new(Box::new(InMemoryBackend::new()))
+            .with_codec(CompressionCodec::Snappy)
+            .with_metrics(registry.clone());
+
+        backend.swap_out(SpanId::from_id(1), &vec![0; 4096], false);
+        backend.swap_out(SpanId::from_id(2), &(0..4096).map(|_| rand::thread_rng().gen()).collect::<Vec<u8>>(), false);
+        backend.swap_in(&SpanId::from_id(1));
+
+        let metrics = backend.compressor.metrics.as_ref().unwrap();
+        assert_eq!(8192, metrics.input_bytes.with_label_values(&["snappy"]).get());
+        assert_eq!(1, metrics.raw_spans.with_label_values(&["snappy"]).get());
+        let ratio = metrics.ratio.with_label_values(&["snappy"]).get();
+        assert!(ratio > 0.5 && ratio < 0.6);
+
+        backend.on_stop();
+        assert!(registry.gather().is_empty());
+    }
+}
@@ -1,44 +1,619 @@
 use {
-    std::fs,
+    std::{
+        fs::{self, File, OpenOptions},
+        os::unix::fs::{FileExt, OpenOptionsExt},
+        path::{Path, PathBuf},
+        collections::HashMap,
+        process,
+        sync::{Arc, Mutex, OnceLock, atomic::{AtomicBool, AtomicUsize, Ordering}},
+        thread::{self, JoinHandle},
+        panic::{self, AssertUnwindSafe},
+        time::Duration,
+    },
+    tracing::{span, Level, warn},
     crate::client::span::SpanId,
-    super::FarMemoryBackend,
+    super::{FarMemoryBackend, SwapOutOperation},
 };
 
-const PATH: &str = "./data/spans";
+const DEFAULT_PATH: &str = "./data/spans";
+const DEFAULT_SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+const BLOCK_SIZE: u64 = 4096;
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(1);
+// segments with less live data than this fraction of their written data are compacted.
+const COMPACTION_THRESHOLD: f64 = 0.5;
 
+static NEXT_INSTANCE: AtomicUsize = AtomicUsize::new(0);
+
+/**
+ * Stores spans in preallocated segment files. Every swap out is appended as a chunk to the active segment, and an
+ * in-memory index maps spans to their chunks, so prepend only writes a new chunk and links it in front of existing
+ * ones. Chunks are block-aligned, so segments can be accessed with O_DIRECT. Only the index is locked: space for a
+ * chunk is reserved under the lock, and data is read and written with positioned I/O outside of it.
+ *
+ * Space of removed chunks is tracked per segment. Empty segments are reused right away, and segments that are
+ * mostly garbage are compacted in background by moving their live chunks to the active segment. Every segment keeps
+ * spans that have chunks in it, so compaction does not scan the whole index.
+ *
+ * The index lives in memory only, so spans of a previous run cannot be read back. Segment files are created in a
+ * subdirectory of `path` unique to this backend, which is removed on drop, so that backends sharing the same path do
+ * not overwrite each other's segments.
+ */
 pub struct LocalDiskBackend {
+    state: Arc<DiskState>,
+    // started on first write, so that backend can be configured before that.
+    compaction_thread: OnceLock<JoinHandle<()>>,
+}
+
+struct DiskState {
+    path: PathBuf,
+    instance: String,
+    segment_size: u64,
+    direct_io: bool,
+    fsync: bool,
+    background_compaction: bool,
+    is_running: AtomicBool,
+
+    inner: Mutex<DiskStateInner>,
+}
+
+struct DiskStateInner {
+    // chunks of every span, in order of span data.
+    index: HashMap<SpanId, Vec<Chunk>>,
+    segments: Vec<Segment>,
+    active_segment: Option<usize>,
+    next_chunk_id: u64,
+}
+
+#[derive(Clone)]
+struct Chunk {
+    // unique, so that compaction can detect that chunk was freed while it was being moved.
+    id: u64,
+    segment: usize,
+    offset: u64,
+    len: u64,
+}
+
+struct Segment {
+    file: Arc<File>,
+    size: u64,
+    write_offset: u64,
+    live_bytes: u64,
+    // number of chunks every span has in this segment.
+    spans: HashMap<SpanId, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDiskBackendStats {
+    pub segments: usize,
+    pub total_bytes: u64,
+    pub live_bytes: u64,
+    pub garbage_bytes: u64,
+}
+
+// buffer with block-aligned start address, as required by O_DIRECT.
+struct AlignedBuffer {
+    data: Vec<u8>,
+    offset: usize,
+    len: usize,
 }
 
 impl LocalDiskBackend {
     pub fn new() -> Self {
-        fs::create_dir_all(PATH).unwrap();
-
         Self {
+            state: Arc::new(DiskState {
+                path: PathBuf::from(DEFAULT_PATH),
+                instance: format!("{}-{}", process::id(), NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed)),
+                segment_size: DEFAULT_SEGMENT_SIZE,
+                direct_io: false,
+                fsync: false,
+                background_compaction: true,
+                is_running: AtomicBool::new(true),
+
+                inner: Mutex::new(DiskStateInner {
+                    index: HashMap::new(),
+                    segments: Vec::new(),
+                    active_segment: None,
+                    next_chunk_id: 0,
+                }),
+            }),
+            compaction_thread: OnceLock::new(),
         }
     }
 
-    fn path_for_span(&self, id: &SpanId) -> String {
-        format!("{}/{}", PATH, id.id())
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.state_mut().path = path.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn with_segment_size(mut self, segment_size: u64) -> Self {
+        self.state_mut().segment_size = block_aligned(segment_size);
+        self
+    }
+
+    // bypasses page cache. Falls back to buffered I/O if file system does not support it.
+    pub fn with_direct_io(mut self, direct_io: bool) -> Self {
+        self.state_mut().direct_io = direct_io;
+        self
+    }
+
+    // syncs segment data to disk after every write.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.state_mut().fsync = fsync;
+        self
+    }
+
+    // segments are then only compacted by calling `compact`.
+    pub fn with_background_compaction(mut self, background_compaction: bool) -> Self {
+        self.state_mut().background_compaction = background_compaction;
+        self
+    }
+
+    fn state_mut(&mut self) -> &mut DiskState {
+        Arc::get_mut(&mut self.state).expect("disk backend can only be configured before use")
+    }
+
+    fn start_compaction_thread(&self) {
+        if !self.state.background_compaction {
+            return;
+        }
+
+        self.compaction_thread.get_or_init(|| {
+            let state = self.state.clone();
+            thread::Builder::new()
+                .name("disk-compaction".to_owned())
+                .spawn(move || {
+                    while state.is_running.load(Ordering::Relaxed) {
+                        thread::park_timeout(COMPACTION_INTERVAL);
+                        if panic::catch_unwind(AssertUnwindSafe(|| state.compact())).is_err() {
+                            warn!("failed to compact segments");
+                        }
+                    }
+                })
+                .unwrap()
+        });
+    }
+
+    // compacts segments now, without waiting for background compaction.
+    pub fn compact(&self) {
+        self.state.compact();
+    }
+
+    pub fn stats(&self) -> LocalDiskBackendStats {
+        let inner = self.state.inner.lock().unwrap();
+        let total_bytes = inner.segments.iter().map(|v| v.size).sum();
+        let live_bytes = inner.segments.iter().map(|v| v.live_bytes).sum();
+        let written_bytes: u64 = inner.segments.iter().map(|v| v.write_offset).sum();
+
+        LocalDiskBackendStats {
+            segments: inner.segments.len(),
+            total_bytes,
+            live_bytes,
+            garbage_bytes: written_bytes - live_bytes,
+        }
     }
 }
 
-impl FarMemoryBackend for LocalDiskBackend {
+impl DiskState {
+    fn directory(&self) -> PathBuf {
+        self.path.join(&self.instance)
+    }
+
+    fn open_segment(&self, index: usize, size: u64) -> Segment {
+        if index == 0 {
+            fs::create_dir_all(self.directory()).unwrap();
+        }
+        let path = self.directory().join(format!("segment-{}", index));
+
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        let file = if self.direct_io {
+            match options.clone().custom_flags(libc::O_DIRECT).open(&path) {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("failed to open segment with O_DIRECT, falling back to buffered I/O: {:?}", err);
+                    options.open(&path).unwrap()
+                }
+            }
+        } else {
+            options.open(&path).unwrap()
+        };
+
+        file.set_len(size).unwrap();
+
+        Segment {
+            file: Arc::new(file),
+            size,
+            write_offset: 0,
+            live_bytes: 0,
+            spans: HashMap::new(),
+        }
+    }
+
+    // reserves space for a chunk, so that it can be written without holding the lock.
+    fn reserve_chunk(&self, inner: &mut DiskStateInner, len: u64, excluded_segment: Option<usize>) -> (Chunk, Arc<File>) {
+        let size = block_aligned(len);
+
+        let fits = |segment: &Segment| segment.size - segment.write_offset >= size;
+        let segment_index = match inner.active_segment.filter(|v| fits(&inner.segments[*v]) && Some(*v) != excluded_segment) {
+            Some(v) => v,
+            None => {
+                let empty_segment = (0..inner.segments.len())
+                    .find(|v| inner.segments[*v].write_offset == 0 && fits(&inner.segments[*v]) && Some(*v) != excluded_segment);
+
+                let segment_index = match empty_segment {
+                    Some(v) => v,
+                    None => {
+                        let segment_index = inner.segments.len();
+                        inner.segments.push(self.open_segment(segment_index, self.segment_size.max(size)));
+                        segment_index
+                    }
+                };
+
+                inner.active_segment = Some(segment_index);
+                segment_index
+            }
+        };
+
+        let id = inner.next_chunk_id;
+        inner.next_chunk_id += 1;
+
+        let segment = &mut inner.segments[segment_index];
+        let offset = segment.write_offset;
+        segment.write_offset += size;
+        segment.live_bytes += size;
+
+        (Chunk { id, segment: segment_index, offset, len }, segment.file.clone())
+    }
+
+    fn write_chunk(&self, file: &File, chunk: &Chunk, data: &[u8]) {
+        let mut buffer = AlignedBuffer::new(block_aligned(chunk.len) as usize);
+        buffer.as_mut_slice()[0..data.len()].copy_from_slice(data);
+        file.write_all_at(buffer.as_slice(), chunk.offset).unwrap();
+        if self.fsync {
+            file.sync_data().unwrap();
+        }
+    }
+
+    fn read_chunk(&self, file: &File, chunk: &Chunk) -> Vec<u8> {
+        let mut buffer = AlignedBuffer::new(block_aligned(chunk.len) as usize);
+        file.read_exact_at(buffer.as_mut_slice(), chunk.offset).unwrap();
+        buffer.as_slice()[0..chunk.len as usize].to_vec()
+    }
+
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
-        let path: String = self.path_for_span(&id);
-        if prepend {
-            let mut data = fs::read(&path).unwrap();
-            let mut new_data = span.to_vec();
-            new_data.append(&mut data);
-            fs::write(path, new_data).unwrap();
+        let (chunk, file) = self.reserve_chunk(&mut self.inner.lock().unwrap(), span.len() as u64, None);
+        self.write_chunk(&file, &chunk, span);
+
+        let mut inner = self.inner.lock().unwrap();
+        let previous_chunks = inner.index.remove(&id).unwrap_or_default();
+        let previous_chunks = if prepend {
+            previous_chunks
         } else {
-            fs::write(path, span.to_vec()).unwrap();
+            previous_chunks.iter().for_each(|chunk| inner.free_chunk(&id, chunk));
+            Vec::new()
+        };
+
+        inner.link_chunk(&id, &chunk);
+        let mut chunks = vec![chunk];
+        chunks.extend(previous_chunks);
+        inner.index.insert(id, chunks);
+    }
+
+    fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+        // chunks are unlinked from segments right away, so that compaction does not move them, but their space is freed
+        // only after they are read, so that it is not reused before that.
+        let chunks: Vec<(Chunk, Arc<File>)> = {
+            let mut inner = self.inner.lock().unwrap();
+            let chunks = inner.index.remove(id).unwrap();
+            chunks.into_iter().map(|chunk| {
+                inner.unlink_chunk(id, &chunk);
+                let file = inner.segments[chunk.segment].file.clone();
+                (chunk, file)
+            }).collect()
+        };
+
+        let mut result = Vec::with_capacity(chunks.iter().map(|(chunk, _)| chunk.len as usize).sum());
+        for (chunk, file) in &chunks {
+            result.append(&mut self.read_chunk(file, chunk));
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        chunks.iter().for_each(|(chunk, _)| inner.free_space(chunk));
+
+        result
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(chunks) = inner.index.remove(id) {
+            chunks.iter().for_each(|chunk| inner.free_chunk(id, chunk));
+        }
+    }
+
+    fn compact(&self) {
+        let segments_to_compact: Vec<usize> = {
+            let inner = self.inner.lock().unwrap();
+            (0..inner.segments.len())
+                .filter(|v| inner.active_segment != Some(*v))
+                .filter(|v| {
+                    let segment = &inner.segments[*v];
+                    segment.write_offset > 0 && (segment.live_bytes as f64) < segment.write_offset as f64 * COMPACTION_THRESHOLD
+                })
+                .collect()
+        };
+
+        for segment in segments_to_compact {
+            span!(Level::DEBUG, "compact segment").in_scope(|| self.compact_segment(segment));
         }
     }
 
+    // moves live chunks one by one, so that swap in and swap out are not blocked for the whole compaction.
+    fn compact_segment(&self, segment: usize) {
+        loop {
+            if !self.is_running.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let (span_id, chunk, file, new_chunk, new_file) = {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.active_segment == Some(segment) {
+                    return;
+                }
+
+                let live_chunk = inner.segments[segment].spans.keys().find_map(|span_id| {
+                    let chunk = inner.index.get(span_id)?.iter().find(|chunk| chunk.segment == segment)?;
+                    Some((span_id.clone(), chunk.clone()))
+                });
+                let (span_id, chunk) = match live_chunk {
+                    Some(v) => v,
+                    None => {
+                        let segment = &mut inner.segments[segment];
+                        if segment.live_bytes == 0 {
+                            segment.write_offset = 0;
+                        }
+                        return;
+                    }
+                };
+                let file = inner.segments[segment].file.clone();
+                let (new_chunk, new_file) = self.reserve_chunk(&mut inner, chunk.len, Some(segment));
+
+                (span_id, chunk, file, new_chunk, new_file)
+            };
+
+            self.write_chunk(&new_file, &new_chunk, &self.read_chunk(&file, &chunk));
+
+            // span could be swapped in or overwritten while chunk was copied.
+            let mut inner = self.inner.lock().unwrap();
+            let position = inner.index.get(&span_id).and_then(|chunks| chunks.iter().position(|v| v.id == chunk.id));
+            match position {
+                Some(position) => {
+                    inner.free_chunk(&span_id, &chunk);
+                    inner.link_chunk(&span_id, &new_chunk);
+                    inner.index.get_mut(&span_id).unwrap()[position] = new_chunk;
+                },
+                None => inner.free_space(&new_chunk),
+            }
+        }
+    }
+}
+
+impl DiskStateInner {
+    fn link_chunk(&mut self, span_id: &SpanId, chunk: &Chunk) {
+        *self.segments[chunk.segment].spans.entry(span_id.clone()).or_default() += 1;
+    }
+
+    fn unlink_chunk(&mut self, span_id: &SpanId, chunk: &Chunk) {
+        let spans = &mut self.segments[chunk.segment].spans;
+        let chunks = spans.get_mut(span_id).unwrap();
+        *chunks -= 1;
+        if *chunks == 0 {
+            spans.remove(span_id);
+        }
+    }
+
+    fn free_chunk(&mut self, span_id: &SpanId, chunk: &Chunk) {
+        self.unlink_chunk(span_id, chunk);
+        self.free_space(chunk);
+    }
+
+    fn free_space(&mut self, chunk: &Chunk) {
+        let segment = &mut self.segments[chunk.segment];
+        segment.live_bytes -= block_aligned(chunk.len);
+
+        if segment.live_bytes == 0 {
+            segment.write_offset = 0;
+        }
+    }
+}
+
+impl FarMemoryBackend for LocalDiskBackend {
+    fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+        self.start_compaction_thread();
+        span!(Level::DEBUG, "disk write").in_scope(|| self.state.swap_out(id, span, prepend))
+    }
+
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        let path: String = self.path_for_span(&id);
-        let res = fs::read(&path).unwrap();
-        fs::remove_file(path).unwrap();
-        res
+        span!(Level::DEBUG, "disk read").in_scope(|| self.state.swap_in(id))
     }
-}
\ No newline at end of file
+
+    fn remove(&self, id: &SpanId) {
+        self.state.remove(id)
+    }
+
+    fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
+        self.start_compaction_thread();
+        for operation in swap_out_operations {
+            self.state.swap_out(operation.id.clone(), operation.data.as_slice(), operation.prepend);
+        }
+    }
+}
+
+impl Drop for LocalDiskBackend {
+    fn drop(&mut self) {
+        self.state.is_running.store(false, Ordering::Relaxed);
+        if let Some(compaction_thread) = self.compaction_thread.take() {
+            compaction_thread.thread().unpark();
+            compaction_thread.join().unwrap();
+        }
+
+        if !self.state.inner.lock().unwrap().segments.is_empty() {
+            if let Err(err) = fs::remove_dir_all(self.state.directory()) {
+                warn!("failed to remove segments directory: {:?}", err);
+            }
+        }
+    }
+}
+
+impl AlignedBuffer {
+    fn new(len: usize) -> Self {
+        let data = vec![0; len + BLOCK_SIZE as usize];
+        let offset = data.as_ptr().align_offset(BLOCK_SIZE as usize);
+
+        Self {
+            data,
+            offset,
+            len,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+fn block_aligned(len: u64) -> u64 {
+    // empty spans still take one block, so that every chunk has a distinct location.
+    len.div_ceil(BLOCK_SIZE).max(1) * BLOCK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        rand::Rng,
+        super::*,
+    };
+
+    fn test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("far-memory-disk-test-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn swap_out_prepend_swap_in() {
+        let path = test_path("prepend");
+        let backend = LocalDiskBackend::new().with_path(&path).with_segment_size(64 * 1024).with_direct_io(true);
+
+        let first: Vec<u8> = (0..10000).map(|_| rand::thread_rng().gen()).collect();
+        let second: Vec<u8> = (0..100).map(|_| rand::thread_rng().gen()).collect();
+
+        backend.swap_out(SpanId::from_id(1), &first, false);
+        backend.swap_out(SpanId::from_id(1), &second, true);
+        backend.swap_out(SpanId::from_id(2), &[], false);
+
+        assert_eq!([second, first].concat(), backend.swap_in(&SpanId::from_id(1)));
+        assert_eq!(Vec::<u8>::new(), backend.swap_in(&SpanId::from_id(2)));
+
+        drop(backend);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn empty_segments_are_reused() {
+        let path = test_path("reuse");
+        let backend = LocalDiskBackend::new().with_path(&path).with_segment_size(16 * 1024);
+
+        for i in 0..8 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 4096], false);
+        }
+        assert_eq!(2, backend.stats().segments);
+
+        for i in 0..8 {
+            assert_eq!(vec![i as u8; 4096], backend.swap_in(&SpanId::from_id(i)));
+        }
+        for i in 0..8 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 4096], false);
+        }
+        assert_eq!(2, backend.stats().segments);
+
+        drop(backend);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_moves_live_chunks() {
+        let path = test_path("compaction");
+        let backend = LocalDiskBackend::new().with_path(&path).with_segment_size(16 * 1024).with_background_compaction(false);
+
+        for i in 0..8 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 4096], false);
+        }
+        // leaves one live chunk in the first segment.
+        for i in 1..4 {
+            backend.remove(&SpanId::from_id(i));
+        }
+        assert_eq!(3 * BLOCK_SIZE, backend.stats().garbage_bytes);
+
+        backend.compact();
+        let stats = backend.stats();
+        assert_eq!(0, stats.garbage_bytes);
+        assert_eq!(5 * BLOCK_SIZE, stats.live_bytes);
+
+        assert_eq!(vec![0; 4096], backend.swap_in(&SpanId::from_id(0)));
+        for i in 4..8 {
+            assert_eq!(vec![i as u8; 4096], backend.swap_in(&SpanId::from_id(i)));
+        }
+
+        drop(backend);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_during_swap_in_and_swap_out() {
+        let path = test_path("concurrent-compaction");
+        let backend = Arc::new(LocalDiskBackend::new().with_path(&path).with_segment_size(16 * 1024).with_background_compaction(false));
+        let is_running = Arc::new(AtomicBool::new(true));
+
+        let compaction = {
+            let (backend, is_running) = (backend.clone(), is_running.clone());
+            thread::spawn(move || while is_running.load(Ordering::Relaxed) {
+                backend.compact();
+            })
+        };
+
+        for round in 0..200u64 {
+            for i in 0..8 {
+                backend.swap_out(SpanId::from_id(i), &vec![(round + i) as u8; 4096], false);
+            }
+            for i in 0..8 {
+                assert_eq!(vec![(round + i) as u8; 4096], backend.swap_in(&SpanId::from_id(i)));
+            }
+        }
+
+        is_running.store(false, Ordering::Relaxed);
+        compaction.join().unwrap();
+
+        drop(backend);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn backends_sharing_path_do_not_interfere() {
+        let path = test_path("shared");
+        let first = LocalDiskBackend::new().with_path(&path).with_segment_size(16 * 1024);
+        let second = LocalDiskBackend::new().with_path(&path).with_segment_size(16 * 1024);
+
+        first.swap_out(SpanId::from_id(1), &[1; 4096], false);
+        second.swap_out(SpanId::from_id(1), &[2; 4096], false);
+
+        assert_eq!(vec![1; 4096], first.swap_in(&SpanId::from_id(1)));
+        assert_eq!(vec![2; 4096], second.swap_in(&SpanId::from_id(1)));
+
+        drop(first);
+        drop(second);
+        assert_eq!(0, fs::read_dir(&path).unwrap().count());
+        fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -1,46 +1,230 @@
 use {
-    aes_gcm::{aead::{KeyInit, Aead, AeadCore}, Aes256Gcm, Key},
+    std::{
+        collections::HashMap,
+        env,
+        fs,
+        path::Path,
+        sync::{RwLock, atomic::{AtomicU64, Ordering}},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    aes_gcm::{aead::{KeyInit, Aead, AeadCore, Payload}, Aes256Gcm, Key},
     rand::rngs::OsRng,
     crate::client::span::SpanId,
     super::{FarMemoryBackend, SwapOutOperation, SwapOutOperationData},
 };
 
+const FRAME_HEADER_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+
+/**
+ * Encrypts spans with AES-256-GCM before passing them to inner backend. Every swap out is stored as a frame:
+ * key id (u32), version (u64), nonce (12 bytes), ciphertext length (u64), followed by ciphertext.
+ *
+ * Span id, version, number of frames after this one and their total length are used as associated data, so a storage
+ * node cannot return ciphertext of another span or drop trailing frames, and returning an older version of a span is
+ * detected as long as the client knows which version it wrote last.
+ * Key id is stored in every frame, so that keys can be rotated: new spans are encrypted with the active key, while
+ * spans written before the rotation can still be decrypted with older keys.
+ */
 pub struct EncryptionBackend {
     inner: Box<dyn FarMemoryBackend>,
-    cipher: Aes256Gcm,
+    keys: EncryptionKeys,
+
+    next_version: AtomicU64,
+    // frames written for every span, used to detect rollbacks and to authenticate prepended frames.
+    spans: RwLock<HashMap<SpanId, StoredFrames>>,
+}
+
+struct StoredFrames {
+    // version of the first frame, which is the latest write.
+    version: u64,
+    frames: u64,
+    len: u64,
+}
+
+struct FrameHeader {
+    key_id: u32,
+    version: u64,
+    offset: usize,
+    len: usize,
+}
+
+/**
+ * Set of encryption keys identified by key id. Key with the highest id is the active one.
+ * Keys are loaded from text with one key per line in `<key id>:<key as 64 hex characters>` format (lines can also be
+ * separated by `,`, which is more convenient for environment variables). Empty lines and lines starting with `#` are
+ * ignored.
+ */
+pub struct EncryptionKeys {
+    keys: HashMap<u32, Aes256Gcm>,
+    active_key_id: u32,
+}
+
+impl EncryptionKeys {
+    // random key, which is lost when process exits.
+    pub fn generate() -> Self {
+        Self {
+            keys: vec![(0, Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng)))].into_iter().collect(),
+            active_key_id: 0,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Self {
+        Self::parse(&fs::read_to_string(path).unwrap())
+    }
+
+    pub fn from_env(var_name: &str) -> Self {
+        Self::parse(&env::var(var_name).unwrap_or_else(|_| panic!("environment variable {} with encryption keys is not set", var_name)))
+    }
+
+    pub fn parse(keys: &str) -> Self {
+        let keys: HashMap<u32, Aes256Gcm> = keys.split(|c| c == '\n' || c == ',')
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let (key_id, key) = line.split_once(':').expect("expected encryption key in <key id>:<hex key> format");
+                let key = parse_hex(key.trim());
+                assert_eq!(32, key.len(), "encryption key should be 32 bytes long");
+
+                (key_id.trim().parse().unwrap(), Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+            })
+            .collect();
+
+        let active_key_id = *keys.keys().max().expect("no encryption keys provided");
+
+        Self {
+            keys,
+            active_key_id,
+        }
+    }
+
+    pub fn active_key_id(&self) -> u32 {
+        self.active_key_id
+    }
 }
 
 impl EncryptionBackend {
+    // uses a random key, so data cannot be decrypted after restart. Use `with_keys` to provide persistent keys.
     pub fn new(inner: Box<dyn FarMemoryBackend>) -> Self {
         Self {
             inner,
-            cipher: Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng)),
+            keys: EncryptionKeys::generate(),
+
+            // versions are based on time, so that they keep increasing after restart.
+            next_version: AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64),
+            spans: RwLock::new(HashMap::new()),
         }
     }
 
-    fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+    pub fn with_keys(mut self, keys: EncryptionKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    // span should be known to this backend for prepend (see `resolve_prepend`).
+    fn encrypt(&self, id: &SpanId, data: &[u8], prepend: bool) -> Vec<u8> {
+        let version = self.next_version.fetch_add(1, Ordering::Relaxed);
+        let key_id = self.keys.active_key_id;
         let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
-        assert_eq!(12, nonce.len());
-
-        let encrypted = self.cipher.encrypt(&nonce, data).unwrap();
-        {
-            let mut encrypted = encrypted;
-            let mut result = nonce.to_vec();
-            result.append(&mut encrypted);
-            result
+        assert_eq!(NONCE_SIZE, nonce.len());
+
+        let (frames_after, len_after) = match (prepend, self.spans.read().unwrap().get(id)) {
+            (false, _) => (0, 0),
+            (true, Some(stored)) => (stored.frames, stored.len),
+            (true, None) => panic!("cannot prepend to span {} with unknown frames", id.id()),
+        };
+
+        // encrypting without holding the lock, so that operations on other spans are not blocked. Operations on the same
+        // span are not concurrent, so stored frames do not change in the meantime.
+        let encrypted = self.keys.keys[&key_id].encrypt(&nonce, Payload {
+            msg: data,
+            aad: &associated_data(id, version, frames_after, len_after),
+        }).unwrap();
+
+        self.spans.write().unwrap().insert(id.clone(), StoredFrames {
+            version,
+            frames: frames_after + 1,
+            len: len_after + (FRAME_HEADER_SIZE + encrypted.len()) as u64,
+        });
+
+        let mut result = Vec::with_capacity(FRAME_HEADER_SIZE + encrypted.len());
+        result.extend_from_slice(&key_id.to_le_bytes());
+        result.extend_from_slice(&version.to_le_bytes());
+        result.extend_from_slice(&nonce);
+        result.extend_from_slice(&(encrypted.len() as u64).to_le_bytes());
+        result.extend_from_slice(&encrypted);
+        result
+    }
+
+    // returns None if any frame fails authentication, or if span data is older than the version written last.
+    fn decrypt(&self, id: &SpanId, data: &[u8]) -> Option<Vec<u8>> {
+        let expected_version = self.spans.write().unwrap().remove(id).map(|stored| stored.version);
+        let frames = parse_frames(data)?;
+
+        let mut result = Vec::new();
+        let mut previous_version: Option<u64> = None;
+
+        for (i, frame) in frames.iter().enumerate() {
+            // first frame is the most recent write, prepended frames are always newer than frames after them.
+            match previous_version {
+                None => if expected_version.map(|expected| expected != frame.version).unwrap_or(false) {
+                    return None;
+                },
+                Some(previous_version) => if frame.version >= previous_version {
+                    return None;
+                },
+            }
+            previous_version = Some(frame.version);
+
+            let frame_end = frame.offset + frame.len;
+            let nonce = &data[frame.offset + 12..frame.offset + 12 + NONCE_SIZE];
+            let decrypted = self.keys.keys.get(&frame.key_id)?.decrypt(nonce.into(), Payload {
+                msg: &data[frame.offset + FRAME_HEADER_SIZE..frame_end],
+                aad: &associated_data(id, frame.version, (frames.len() - i - 1) as u64, (data.len() - frame_end) as u64),
+            }).ok()?;
+            result.extend_from_slice(&decrypted);
         }
+
+        Some(result)
     }
 
-    fn decrypt(&self, data: &[u8]) -> Vec<u8> {
-        self.cipher.decrypt(data[0..12].into(), &data[12..]).unwrap()
+    // frames of spans written before restart are unknown, so span is read back and written again with the new frame.
+    fn resolve_prepend(&self, id: &SpanId, prepend: bool) -> Option<Vec<u8>> {
+        if !prepend || self.spans.read().unwrap().contains_key(id) {
+            return None;
+        }
+
+        let existing = self.inner.swap_in(id);
+        let frames = parse_frames(&existing).unwrap_or_else(|| panic!("failed to parse frames of span {}", id.id()));
+        self.spans.write().unwrap().insert(id.clone(), StoredFrames {
+            version: frames.first().map(|frame| frame.version).unwrap_or(0),
+            frames: frames.len() as u64,
+            len: existing.len() as u64,
+        });
+
+        Some(existing)
+    }
+
+    fn encrypt_prepend(&self, id: &SpanId, data: &[u8], prepend: bool) -> (Vec<u8>, bool) {
+        match self.resolve_prepend(id, prepend) {
+            Some(existing) => ([self.encrypt(id, data, true), existing].concat(), false),
+            None => (self.encrypt(id, data, prepend), prepend),
+        }
+    }
+
+    fn decrypt_span(&self, id: &SpanId, data: &[u8]) -> Vec<u8> {
+        self.decrypt(id, data).unwrap_or_else(|| panic!("failed to decrypt span {}: data was modified, replaced or rolled back", id.id()))
     }
 
     fn encrypt_batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) -> Vec<SwapOutOperation> {
         swap_out_operations.into_iter()
-            .map(|v| SwapOutOperation {
-                id: v.id,
-                data: SwapOutOperationData::Owned(self.encrypt(v.data.as_slice())),
-                prepend: v.prepend,
+            .map(|v| {
+                let (data, prepend) = self.encrypt_prepend(&v.id, v.data.as_slice(), v.prepend);
+                SwapOutOperation {
+                    data: SwapOutOperationData::Owned(data),
+                    id: v.id,
+                    prepend,
+                }
             })
             .collect()
     }
@@ -48,21 +232,18 @@ impl EncryptionBackend {
 
 impl FarMemoryBackend for EncryptionBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
-        if prepend {
-            // not optimal, but good enough for now
-            let mut span_data = span.to_vec();
-            let mut data = self.decrypt(&self.inner.swap_in(&id));
-            span_data.append(&mut data);
-
-            let encrypted = self.encrypt(&data);
-            self.inner.swap_out(id, &encrypted, false);
-        } else {
-            self.inner.swap_out(id, & self.encrypt(span), prepend)
-        }
+        // frames are self-contained, so prepend does not need to read existing data.
+        let (encrypted, prepend) = self.encrypt_prepend(&id, span, prepend);
+        self.inner.swap_out(id, &encrypted, prepend)
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        self.decrypt(&self.inner.swap_in(id))
+        self.decrypt_span(id, &self.inner.swap_in(id))
+    }
+
+    fn remove(&self, id: &SpanId) {
+        self.spans.write().unwrap().remove(id);
+        self.inner.remove(id)
     }
 
     fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
@@ -70,7 +251,7 @@ impl FarMemoryBackend for EncryptionBackend {
     }
 
     fn batch(&self, swap_out_operations: Vec<SwapOutOperation>, swap_in: Option<&SpanId>) -> Option<Vec<u8>> {
-        self.inner.batch(self.encrypt_batch_swap_out(swap_out_operations), swap_in).map(|v| self.decrypt(&v))
+        self.inner.batch(self.encrypt_batch_swap_out(swap_out_operations), swap_in).map(|v| self.decrypt_span(swap_in.unwrap(), &v))
     }
 
     fn on_stop(&self) {
@@ -78,14 +259,72 @@ impl FarMemoryBackend for EncryptionBackend {
     }
 }
 
+fn associated_data(id: &SpanId, version: u64, frames_after: u64, len_after: u64) -> [u8; 32] {
+    let mut result = [0; 32];
+    result[0..8].copy_from_slice(&id.id().to_le_bytes());
+    result[8..16].copy_from_slice(&version.to_le_bytes());
+    result[16..24].copy_from_slice(&frames_after.to_le_bytes());
+    result[24..32].copy_from_slice(&len_after.to_le_bytes());
+    result
+}
+
+// returns None if data is not a sequence of complete frames.
+fn parse_frames(data: &[u8]) -> Option<Vec<FrameHeader>> {
+    let mut frames = Vec::new();
+
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data.get(offset..offset + FRAME_HEADER_SIZE)?;
+        let encrypted_len = u64::from_le_bytes(header[24..32].try_into().unwrap()) as usize;
+        let len = FRAME_HEADER_SIZE.checked_add(encrypted_len)?;
+        if data.len() - offset < len {
+            return None;
+        }
+
+        frames.push(FrameHeader {
+            key_id: u32::from_le_bytes(header[0..4].try_into().unwrap()),
+            version: u64::from_le_bytes(header[4..12].try_into().unwrap()),
+            offset,
+            len,
+        });
+        offset += len;
+    }
+
+    Some(frames)
+}
+
+fn parse_hex(hex: &str) -> Vec<u8> {
+    assert!(hex.bytes().all(|c| c.is_ascii_hexdigit()), "invalid hex string");
+    assert!(hex.len() % 2 == 0, "hex string should have even length");
+    hex.as_bytes().chunks(2)
+        .map(|v| u8::from_str_radix(std::str::from_utf8(v).unwrap(), 16).unwrap())
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use {
+        std::sync::Arc,
         rand::Rng,
         crate::client::InMemoryBackend,
         super::*,
     };
 
+    const FIRST_KEY: &str = "1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const SECOND_KEY: &str = "2:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    struct SharedBackend(Arc<InMemoryBackend>);
+
+    impl FarMemoryBackend for SharedBackend {
+        fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+            self.0.swap_out(id, span, prepend)
+        }
+
+        fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+            self.0.swap_in(id)
+        }
+    }
+
     #[test]
     fn simple() {
         let span_id = SpanId::from_id(42);
@@ -94,8 +333,64 @@ mod tests {
         let backend = EncryptionBackend::new(Box::new(InMemoryBackend::new()));
 
         backend.swap_out(span_id.clone(), &data, false);
+        backend.swap_out(span_id.clone(), &[1, 2, 3], true);
 
         let swapped_in_data = backend.swap_in(&span_id);
-        assert_eq!(swapped_in_data, data);
+        assert_eq!(swapped_in_data, [vec![1, 2, 3], data].concat());
+    }
+
+    #[test]
+    fn keys_survive_restart_and_rotation() {
+        let storage = Arc::new(InMemoryBackend::new());
+        let data: Vec<u8> = (0..1024).map(|_| rand::thread_rng().gen()).collect();
+
+        env::set_var("FAR_MEMORY_TEST_ENCRYPTION_KEYS", FIRST_KEY);
+        let backend = EncryptionBackend::new(Box::new(SharedBackend(storage.clone())))
+            .with_keys(EncryptionKeys::from_env("FAR_MEMORY_TEST_ENCRYPTION_KEYS"));
+        backend.swap_out(SpanId::from_id(1), &data, false);
+        drop(backend);
+
+        // after restart, second key is added and used for new writes.
+        let backend = EncryptionBackend::new(Box::new(SharedBackend(storage.clone())))
+            .with_keys(EncryptionKeys::parse(&format!("# rotated keys\n{}\n{}\n", FIRST_KEY, SECOND_KEY)));
+        assert_eq!(2, backend.keys.active_key_id());
+        backend.swap_out(SpanId::from_id(1), &[1, 2, 3], true);
+
+        assert_eq!([vec![1, 2, 3], data].concat(), backend.swap_in(&SpanId::from_id(1)));
+    }
+
+    #[test]
+    fn ciphertext_is_bound_to_span_and_version() {
+        let backend = EncryptionBackend::new(Box::new(InMemoryBackend::new()))
+            .with_keys(EncryptionKeys::parse(FIRST_KEY));
+
+        let first = backend.encrypt(&SpanId::from_id(1), &[1, 2, 3], false);
+        let second = backend.encrypt(&SpanId::from_id(2), &[4, 5, 6], false);
+        assert_eq!(None, backend.decrypt(&SpanId::from_id(1), &second));
+
+        // older version of the same span is rejected too.
+        let old = backend.encrypt(&SpanId::from_id(3), &[7, 8, 9], false);
+        backend.encrypt(&SpanId::from_id(3), &[10, 11, 12], false);
+        assert_eq!(None, backend.decrypt(&SpanId::from_id(3), &old));
+
+        assert_eq!(Some(vec![1, 2, 3]), backend.decrypt(&SpanId::from_id(1), &first));
+    }
+
+    #[test]
+    fn dropped_trailing_frames_are_detected() {
+        let backend = EncryptionBackend::new(Box::new(InMemoryBackend::new()));
+
+        let first = backend.encrypt(&SpanId::from_id(1), &[1, 2, 3], false);
+        let second = backend.encrypt(&SpanId::from_id(1), &[4, 5, 6], true);
+        assert_eq!(Some(vec![4, 5, 6, 1, 2, 3]), backend.decrypt(&SpanId::from_id(1), &[second.clone(), first].concat()));
+
+        // latest frame alone fails authentication, because frames after it are part of its associated data.
+        assert_eq!(None, backend.decrypt(&SpanId::from_id(1), &second));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid hex string")]
+    fn non_ascii_key() {
+        EncryptionKeys::parse("1:0\u{e9}0");
     }
 }
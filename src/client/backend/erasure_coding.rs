@@ -1,25 +1,102 @@
 use {
-    std::{sync::RwLock, collections::HashMap},
+    std::{
+        sync::{atomic::{AtomicU64, Ordering}, mpsc::{channel, Sender}},
+        collections::HashMap,
+        time::{SystemTime, UNIX_EPOCH},
+    },
     tracing::{span, Level},
     reed_solomon_erasure::galois_8::ReedSolomon,
     crate::client::span::SpanId,
-    super::FarMemoryBackend,
+    super::{FarMemoryBackend, worker::BackendWorker},
 };
 
+const DEFAULT_DATA_SHARDS: usize = 3;
+const DEFAULT_PARITY_SHARDS: usize = 2;
+
+const SHARD_HEADER_MAGIC: u32 = 0x4345_4d46; // "FMEC"
+const SHARD_HEADER_VERSION: u8 = 2;
+const SHARD_HEADER_SIZE: usize = 40;
+
+/**
+ * Splits every span into `data_shards` shards and adds `parity_shards` parity shards, each stored on its own target.
+ * Span can be restored from any `data_shards` shards, so up to `parity_shards` targets can fail.
+ *
+ * Every target has its own worker (see `BackendWorker`), so that shards are written and read in parallel, while operations on the same
+ * target are still applied in order. Swap out completes as soon as `data_shards` shards are written, and swap in as soon
+ * as enough shards arrive; remaining writes and reads are finished in background.
+ *
+ * Span is stored as a sequence of independently encoded parts: prepend encodes only the new data and prepends its
+ * shards on every target, without reading span back.
+ *
+ * Every part of every shard starts with a header (see `ShardHeader`), so shards are self-describing: swap in does not
+ * need any client-side metadata, and span can be reconstructed from shards read directly from storage nodes
+ * (see `ErasureCodingBackend::reconstruct`).
+ */
 pub struct ErasureCodingBackend {
-    targets: Vec<Box<dyn FarMemoryBackend>>,
+    targets: Vec<BackendWorker>,
+    data_shards: usize,
+    parity_shards: usize,
     reed_solomon: ReedSolomon,
+    // starts from current time, so that parts written before restart do not get the same generation as new ones.
+    next_generation: AtomicU64,
+}
 
-    span_length: RwLock<HashMap<SpanId, usize>>,
+/**
+ * Header written before every part of a shard:
+ * magic (u32), version (u8), shard index (u8), data shards (u8), parity shards (u8), span id (u64), generation (u64),
+ * part length (u64), checksum (u64).
+ * All values are little endian. Checksum covers the header fields before it and shard data of this part.
+ *
+ * Generation is assigned to every write, so that shards of the same span which were written by different writes (for
+ * example, when target missed the latest write) are never decoded together.
+ */
+#[derive(Debug, Clone, PartialEq)]
+struct ShardHeader {
+    shard_index: usize,
+    data_shards: usize,
+    parity_shards: usize,
+    span_id: u64,
+    generation: u64,
+    part_len: usize,
+    checksum: u64,
+}
+
+struct ParsedShard {
+    header: ShardHeader,
+    parts: Vec<ShardPart>,
+}
+
+struct ShardPart {
+    generation: u64,
+    len: usize,
+    // None if checksum does not match.
+    data: Option<Vec<u8>>,
+}
+
+// span id, data shards, parity shards, generation and length of every part.
+type ShardLayout = (u64, usize, usize, Vec<(u64, usize)>);
+
+// collects shards until there are enough of them with the same layout to restore span.
+struct ShardCollector {
+    span_id: Option<u64>,
+    shards_by_layout: HashMap<ShardLayout, Vec<ParsedShard>>,
 }
 
 impl ErasureCodingBackend {
     pub fn new(targets: Vec<Box<dyn FarMemoryBackend>>) -> Self {
-        Self {
-            targets,
-            reed_solomon: ReedSolomon::new(3, 2).unwrap(),
-            span_length: RwLock::new(HashMap::new()),
+        let mut backend = Self {
+            targets: Vec::new(),
+            data_shards: DEFAULT_DATA_SHARDS,
+            parity_shards: DEFAULT_PARITY_SHARDS,
+            reed_solomon: ReedSolomon::new(DEFAULT_DATA_SHARDS, DEFAULT_PARITY_SHARDS).unwrap(),
+            next_generation: AtomicU64::new(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64),
+        };
+
+        for target in targets {
+            backend = backend.with_target(target);
         }
+
+        backend
     }
 
     pub fn empty() -> Self {
@@ -27,79 +104,372 @@ impl ErasureCodingBackend {
     }
 
     pub fn with_target(mut self, target: Box<dyn FarMemoryBackend>) -> Self {
-        self.targets.push(target);
+        self.targets.push(BackendWorker::new(format!("ec-shard-{}", self.targets.len()), target));
+        self
+    }
+
+    // number of targets should be equal to `data_shards + parity_shards`.
+    pub fn with_shards(mut self, data_shards: usize, parity_shards: usize) -> Self {
+        self.data_shards = data_shards;
+        self.parity_shards = parity_shards;
+        self.reed_solomon = ReedSolomon::new(data_shards, parity_shards).unwrap();
         self
     }
+
+    fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    // restores span from shards read directly from storage nodes, in any order. Returns None if there are not enough
+    // intact shards.
+    pub fn reconstruct(shards: Vec<Vec<u8>>) -> Option<Vec<u8>> {
+        let mut collector = ShardCollector::new(None);
+        shards.into_iter().find_map(|shard| collector.add(&shard))
+    }
+
+    fn check_targets(&self) {
+        assert_eq!(
+            self.total_shards(),
+            self.targets.len(),
+            "erasure coding with {} data and {} parity shards requires {} targets",
+            self.data_shards,
+            self.parity_shards,
+            self.total_shards()
+        );
+    }
+
+    fn encode(&self, id: &SpanId, data: &[u8]) -> Vec<Vec<u8>> {
+        let generation = self.next_generation.fetch_add(1, Ordering::Relaxed);
+        let shard_len = shard_len(data.len(), self.data_shards);
+
+        let mut shards: Vec<Vec<u8>> = (0..self.total_shards())
+            .map(|i| {
+                let mut shard = if i < self.data_shards {
+                    let start = (i * shard_len).min(data.len());
+                    let end = ((i + 1) * shard_len).min(data.len());
+                    data[start..end].to_vec()
+                } else {
+                    Vec::new()
+                };
+                shard.resize(shard_len, 0);
+                shard
+            })
+            .collect();
+
+        self.reed_solomon.encode(&mut shards).unwrap();
+
+        shards.into_iter()
+            .enumerate()
+            .map(|(shard_index, shard)| {
+                let mut header = ShardHeader {
+                    shard_index,
+                    data_shards: self.data_shards,
+                    parity_shards: self.parity_shards,
+                    span_id: id.id(),
+                    generation,
+                    part_len: data.len(),
+                    checksum: 0,
+                };
+                header.checksum = header.compute_checksum(&shard);
+
+                let mut result = Vec::with_capacity(SHARD_HEADER_SIZE + shard.len());
+                header.write(&mut result);
+                result.extend_from_slice(&shard);
+                result
+            })
+            .collect()
+    }
+
+    fn submit<R: Send + 'static>(&self, shard: usize, results: Sender<(usize, Option<R>)>, task: impl FnOnce(&dyn FarMemoryBackend) -> R + Send + 'static) {
+        self.targets[shard].submit(shard, results, task);
+    }
 }
 
 impl FarMemoryBackend for ErasureCodingBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
         span!(Level::DEBUG, "erasure coded write").in_scope(|| {
-            let data = if prepend {
-                // not optimal at all, but good enough for now
-                let mut data = span.to_vec();
-                data.append(&mut self.swap_in(&id));
-                data
-            } else {
-                span.to_vec()
-            };
-
-            self.span_length.write().unwrap().insert(id.clone(), data.len());
+            self.check_targets();
 
-            let shard_len = data.len() / 3;
-            let shard_len  = if data.len() % 3 == 0 {
-                shard_len
-            } else {
-                shard_len + 1
-            };
+            let (results_sender, results) = channel();
+            for (i, shard) in self.encode(&id, span).into_iter().enumerate() {
+                let id = id.clone();
+                self.submit(i, results_sender.clone(), move |target| target.swap_out(id, &shard, prepend));
+            }
+            drop(results_sender);
 
-            let mut shards = vec![
-                data[0..shard_len].to_vec(),
-                data[shard_len..2*shard_len].to_vec(),
-                {
-                    let mut third_shard = data[2*shard_len..].to_vec();
-                    third_shard.resize(shard_len, 0);
-                    third_shard
-                },
-                vec![0; shard_len],
-                vec![0; shard_len]
-            ];
-
-            self.reed_solomon.encode(&mut shards).unwrap();
-
-            // can be optimized to write in parallel. Data can be written in sync, partity - async.
-            for i in 0..shards.len() {
-                self.targets[i].swap_out(id.clone(), &shards[i], false);
+            // span can be restored as soon as `data_shards` shards are written, remaining writes are finished in background.
+            // Following operations on this span are queued after them on every target.
+            let mut written = 0;
+            let mut failed = 0;
+            for (_, result) in results.iter() {
+                if result.is_some() {
+                    written += 1;
+                    if written >= self.data_shards {
+                        return;
+                    }
+                } else {
+                    failed += 1;
+                }
             }
+
+            panic!("failed to write {} out of {} shards for span {}", failed, self.total_shards(), id.id());
         })
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
         span!(Level::DEBUG, "erasure coded read").in_scope(|| {
-            let mut shards = Vec::new();
+            self.check_targets();
+
+            let (results_sender, results) = channel();
+            for i in 0..self.total_shards() {
+                let id = id.clone();
+                self.submit(i, results_sender.clone(), move |target| target.swap_in(&id));
+            }
+            drop(results_sender);
+
+            // shards from failed targets, or targets that missed some writes, are ignored.
+            let mut collector = ShardCollector::new(Some(id.id()));
+            for (_, shard) in results.iter() {
+                if let Some(span) = shard.and_then(|shard| collector.add(&shard)) {
+                    return span;
+                }
+            }
+
+            panic!("not enough intact shards are available to restore span {}", id.id());
+        })
+    }
 
-            // can be optimized to read async. First 3 shards are enough to restore data.
-            for target in &self.targets {
-                shards.push(target.swap_in(id));
+    fn remove(&self, id: &SpanId) {
+        // not waiting for completion: any following operation on this span is queued after removal on every target.
+        let (results_sender, _) = channel();
+        for i in 0..self.targets.len() {
+            let id = id.clone();
+            self.submit(i, results_sender.clone(), move |target| target.remove(&id));
+        }
+    }
+
+    fn on_stop(&self) {
+        let (results_sender, results) = channel();
+        for i in 0..self.targets.len() {
+            self.submit(i, results_sender.clone(), |target| target.on_stop());
+        }
+        drop(results_sender);
+        results.iter().for_each(drop);
+    }
+}
+
+impl ShardHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&SHARD_HEADER_MAGIC.to_le_bytes());
+        out.push(SHARD_HEADER_VERSION);
+        out.push(self.shard_index as u8);
+        out.push(self.data_shards as u8);
+        out.push(self.parity_shards as u8);
+        out.extend_from_slice(&self.span_id.to_le_bytes());
+        out.extend_from_slice(&self.generation.to_le_bytes());
+        out.extend_from_slice(&(self.part_len as u64).to_le_bytes());
+        out.extend_from_slice(&self.checksum.to_le_bytes());
+    }
+
+    fn read(data: &[u8]) -> Option<Self> {
+        if data.len() < SHARD_HEADER_SIZE
+            || u32::from_le_bytes(data[0..4].try_into().unwrap()) != SHARD_HEADER_MAGIC
+            || data[4] != SHARD_HEADER_VERSION {
+            return None;
+        }
+
+        let header = Self {
+            shard_index: data[5] as usize,
+            data_shards: data[6] as usize,
+            parity_shards: data[7] as usize,
+            span_id: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+            generation: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            part_len: u64::from_le_bytes(data[24..32].try_into().unwrap()) as usize,
+            checksum: u64::from_le_bytes(data[32..40].try_into().unwrap()),
+        };
+
+        if header.data_shards == 0 || header.shard_index >= header.data_shards + header.parity_shards {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    fn compute_checksum(&self, shard_data: &[u8]) -> u64 {
+        let mut header = Vec::with_capacity(SHARD_HEADER_SIZE);
+        self.write(&mut header);
+        fnv1a(fnv1a(FNV_OFFSET_BASIS, &header[0..32]), shard_data)
+    }
+}
+
+impl ParsedShard {
+    fn parse(shard: &[u8]) -> Option<Self> {
+        let mut first_header: Option<ShardHeader> = None;
+        let mut parts = Vec::new();
+
+        let mut offset = 0;
+        while offset < shard.len() {
+            let header = ShardHeader::read(&shard[offset..])?;
+            if let Some(first_header) = &first_header {
+                if first_header.shard_index != header.shard_index
+                    || first_header.data_shards != header.data_shards
+                    || first_header.parity_shards != header.parity_shards
+                    || first_header.span_id != header.span_id {
+                    return None;
+                }
+            }
+
+            let data_start = offset + SHARD_HEADER_SIZE;
+            let data_end = data_start + shard_len(header.part_len, header.data_shards);
+            if data_end > shard.len() {
+                return None;
             }
 
-            let mut result = shards.remove(0);
-            result.append(&mut shards[0]);
-            result.append(&mut shards[1]);
+            let data = &shard[data_start..data_end];
+            let data = if header.compute_checksum(data) == header.checksum {
+                Some(data.to_vec())
+            } else {
+                None
+            };
+            parts.push(ShardPart {
+                generation: header.generation,
+                len: header.part_len,
+                data,
+            });
+
+            first_header.get_or_insert(header);
+            offset = data_end;
+        }
 
-            result[0..*self.span_length.read().unwrap().get(id).unwrap()].to_vec()
+        Some(Self {
+            header: first_header?,
+            parts,
         })
     }
 }
 
+impl ShardCollector {
+    // shards of other spans are ignored if span id is set.
+    fn new(span_id: Option<u64>) -> Self {
+        Self {
+            span_id,
+            shards_by_layout: HashMap::new(),
+        }
+    }
+
+    // returns restored span once enough shards with matching layout are collected.
+    fn add(&mut self, shard: &[u8]) -> Option<Vec<u8>> {
+        let shard = ParsedShard::parse(shard)?;
+        if self.span_id.map(|span_id| span_id != shard.header.span_id).unwrap_or(false) {
+            return None;
+        }
+
+        let layout = (
+            shard.header.span_id,
+            shard.header.data_shards,
+            shard.header.parity_shards,
+            shard.parts.iter().map(|part| (part.generation, part.len)).collect::<Vec<_>>(),
+        );
+
+        let shards = self.shards_by_layout.entry(layout).or_default();
+        if shards.iter().any(|v| v.header.shard_index == shard.header.shard_index) {
+            return None;
+        }
+        shards.push(shard);
+
+        Self::decode(shards)
+    }
+
+    fn decode(shards: &[ParsedShard]) -> Option<Vec<u8>> {
+        let data_shards = shards[0].header.data_shards;
+        let parity_shards = shards[0].header.parity_shards;
+        if shards.len() < data_shards {
+            return None;
+        }
+
+        let reed_solomon = ReedSolomon::new(data_shards, parity_shards).ok()?;
+        let mut result = Vec::new();
+
+        for part in 0..shards[0].parts.len() {
+            let part_len = shards[0].parts[part].len;
+
+            let mut part_shards: Vec<Option<Vec<u8>>> = vec![None; data_shards + parity_shards];
+            for shard in shards {
+                part_shards[shard.header.shard_index] = shard.parts[part].data.clone();
+            }
+            if part_shards.iter().filter(|v| v.is_some()).count() < data_shards {
+                return None;
+            }
+
+            if part_shards[0..data_shards].iter().any(|shard| shard.is_none()) {
+                span!(Level::DEBUG, "reconstruct from parity").in_scope(|| reed_solomon.reconstruct_data(&mut part_shards)).ok()?;
+            }
+
+            let part_start = result.len();
+            for shard in part_shards.into_iter().take(data_shards) {
+                result.extend_from_slice(&shard.unwrap());
+            }
+            result.truncate(part_start + part_len);
+        }
+
+        Some(result)
+    }
+}
+
+fn shard_len(part_len: usize, data_shards: usize) -> usize {
+    // reed solomon does not work with empty shards.
+    ((part_len + data_shards - 1) / data_shards).max(1)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
 #[cfg(test)]
 mod tests {
     use {
+        std::sync::{Mutex, mpsc::Receiver},
         rand::Rng,
         crate::client::InMemoryBackend,
-        super::*,
+        super::{*, super::fault_injection::FaultInjectionBackend},
     };
 
+    struct FailedBackend;
+
+    impl FarMemoryBackend for FailedBackend {
+        fn swap_out(&self, _id: SpanId, _span: &[u8], _prepend: bool) {
+            panic!("storage node is down");
+        }
+
+        fn swap_in(&self, _id: &SpanId) -> Vec<u8> {
+            panic!("storage node is down");
+        }
+    }
+
+    // blocks writes until test releases them.
+    struct SlowBackend {
+        inner: InMemoryBackend,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl FarMemoryBackend for SlowBackend {
+        fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+            let _ = self.release.lock().unwrap().recv();
+            self.inner.swap_out(id, span, prepend);
+        }
+
+        fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+            self.inner.swap_in(id)
+        }
+    }
+
     #[test]
     fn simple_swap_out_swap_in() {
         let data: Vec<u8> = (0..1024).map(|_| rand::thread_rng().gen()).collect();
@@ -118,4 +488,124 @@ mod tests {
 
         assert_eq!(data, result);
     }
+
+    #[test]
+    fn reconstruct_with_failed_targets() {
+        let data: Vec<u8> = (0..1021).map(|_| rand::thread_rng().gen()).collect();
+        let span_id = SpanId::from_id(42);
+
+        let backend = ErasureCodingBackend::empty()
+            .with_target(Box::new(FailedBackend))
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_target(Box::new(FailedBackend))
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_shards(4, 2);
+
+        backend.swap_out(span_id.clone(), &data, false);
+        let result = backend.swap_in(&span_id);
+
+        assert_eq!(data, result);
+    }
+
+    #[test]
+    fn prepend() {
+        let first: Vec<u8> = (0..100).map(|_| rand::thread_rng().gen()).collect();
+        let second: Vec<u8> = (0..33).map(|_| rand::thread_rng().gen()).collect();
+        let span_id = SpanId::from_id(42);
+
+        let backend = ErasureCodingBackend::new(vec![
+            Box::new(InMemoryBackend::new()),
+            Box::new(FailedBackend),
+            Box::new(InMemoryBackend::new()),
+            Box::new(InMemoryBackend::new()),
+            Box::new(InMemoryBackend::new()),
+        ]);
+
+        backend.swap_out(span_id.clone(), &first, false);
+        backend.swap_out(span_id.clone(), &second, true);
+        let result = backend.swap_in(&span_id);
+
+        assert_eq!([second, first].concat(), result);
+    }
+
+    #[test]
+    fn reconstruct_from_shard_headers() {
+        let data: Vec<u8> = (0..1000).map(|_| rand::thread_rng().gen()).collect();
+
+        let backend = ErasureCodingBackend::empty().with_shards(3, 2);
+        let mut shards = backend.encode(&SpanId::from_id(42), &data);
+
+        // corrupted shard is detected by checksum, lost shard is restored from parity.
+        shards[0][SHARD_HEADER_SIZE + 10] ^= 0xff;
+        shards.remove(2);
+        shards.swap(1, 3);
+
+        assert_eq!(None, ErasureCodingBackend::reconstruct(shards[0..3].to_vec()));
+        assert_eq!(Some(data), ErasureCodingBackend::reconstruct(shards));
+    }
+
+    #[test]
+    fn shards_of_different_writes_are_not_mixed() {
+        let old: Vec<u8> = (0..1000).map(|_| rand::thread_rng().gen()).collect();
+        let new: Vec<u8> = (0..1000).map(|_| rand::thread_rng().gen()).collect();
+        let span_id = SpanId::from_id(42);
+
+        let backend = ErasureCodingBackend::empty().with_shards(3, 2);
+        let old_shards = backend.encode(&span_id, &old);
+        let new_shards = backend.encode(&span_id, &new);
+        let other_span_shards = backend.encode(&SpanId::from_id(43), &new);
+
+        // old shard has the same layout and valid checksum, but belongs to another write.
+        let shards = vec![old_shards[0].clone(), new_shards[1].clone(), new_shards[2].clone()];
+        assert_eq!(None, ErasureCodingBackend::reconstruct(shards));
+        let shards = vec![other_span_shards[0].clone(), new_shards[1].clone(), new_shards[2].clone()];
+        assert_eq!(None, ErasureCodingBackend::reconstruct(shards.clone()));
+
+        let shards = [shards, vec![new_shards[3].clone()]].concat();
+        assert_eq!(Some(new), ErasureCodingBackend::reconstruct(shards));
+    }
+
+    #[test]
+    fn recover_from_injected_faults() {
+        let data: Vec<u8> = (0..4096).map(|_| rand::thread_rng().gen()).collect();
+        let span_id = SpanId::from_id(42);
+
+        let targets: Vec<FaultInjectionBackend> = (0..5)
+            .map(|i| FaultInjectionBackend::new(Box::new(InMemoryBackend::new())).with_seed(i))
+            .collect();
+        let controllers: Vec<_> = targets.iter().map(|v| v.controller()).collect();
+        let backend = ErasureCodingBackend::new(targets.into_iter().map(|v| Box::new(v) as Box<dyn FarMemoryBackend>).collect());
+
+        backend.swap_out(span_id.clone(), &data, false);
+
+        // one shard is corrupted and one target is down, which is within parity.
+        controllers[0].set_corruption_rate(1.0);
+        controllers[3].set_error_rate(1.0);
+
+        assert_eq!(data, backend.swap_in(&span_id));
+    }
+
+    #[test]
+    fn swap_out_does_not_wait_for_slow_targets() {
+        let data: Vec<u8> = (0..1024).map(|_| rand::thread_rng().gen()).collect();
+        let span_id = SpanId::from_id(42);
+
+        let (release_first, first_released) = channel();
+        let (release_second, second_released) = channel();
+        let backend = ErasureCodingBackend::new(vec![
+            Box::new(InMemoryBackend::new()),
+            Box::new(SlowBackend { inner: InMemoryBackend::new(), release: Mutex::new(first_released) }),
+            Box::new(InMemoryBackend::new()),
+            Box::new(SlowBackend { inner: InMemoryBackend::new(), release: Mutex::new(second_released) }),
+            Box::new(InMemoryBackend::new()),
+        ]);
+
+        backend.swap_out(span_id.clone(), &data, false);
+        assert_eq!(data, backend.swap_in(&span_id));
+
+        release_first.send(()).unwrap();
+        release_second.send(()).unwrap();
+    }
 }
@@ -0,0 +1,425 @@
+use {
+    std::{
+        sync::{Arc, Mutex, RwLock, atomic::{AtomicU64, Ordering}},
+        collections::HashMap,
+        thread,
+        time::{Duration, Instant},
+    },
+    tracing::{span, Level},
+    rand::{Rng, SeedableRng, rngs::SmallRng},
+    rand_distr::{Distribution, Normal, Exp},
+    crate::client::span::SpanId,
+    super::FarMemoryBackend,
+};
+
+/**
+ * Wraps any backend and injects faults into operations on it: latency, bandwidth limit, errors, dropped swap outs and
+ * corrupted swap ins. Faults are chosen with a seeded random number generator, so that failures are reproducible, and
+ * can be changed at runtime through `FaultInjectionController`.
+ *
+ * Errors are injected as panics, same as failures of real backends. Removes are never failed.
+ */
+pub struct FaultInjectionBackend {
+    inner: Box<dyn FarMemoryBackend>,
+    state: Arc<FaultState>,
+}
+
+// shares state with the backend, so that faults can be changed after backend is passed to client or another backend.
+#[derive(Clone)]
+pub struct FaultInjectionController {
+    state: Arc<FaultState>,
+}
+
+struct FaultState {
+    faults: RwLock<Faults>,
+    rng: Mutex<SmallRng>,
+
+    // when the limited link is free for the next transfer.
+    link_free_at: Mutex<Instant>,
+    // spans with dropped swap out, which are not fully stored in inner backend. Value is true if inner backend still
+    // has data written before the dropped prepend, which has to be removed together with the span.
+    dropped_spans: Mutex<HashMap<SpanId, bool>>,
+
+    injected_errors: AtomicU64,
+    dropped_swap_outs: AtomicU64,
+    corrupted_swap_ins: AtomicU64,
+    injected_delay_micros: AtomicU64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Faults {
+    pub latency: LatencyDistribution,
+    // bytes per second, shared by all operations.
+    pub bandwidth: Option<u64>,
+    // probability of swap out or swap in failing.
+    pub error_rate: f64,
+    // probability of swap out being acknowledged, but not stored.
+    pub drop_rate: f64,
+    // probability of swap in returning data with one flipped bit.
+    pub corruption_rate: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LatencyDistribution {
+    None,
+    Fixed(Duration),
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    Normal {
+        mean: Duration,
+        std_dev: Duration,
+    },
+    Exponential {
+        mean: Duration,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultInjectionStats {
+    pub injected_errors: u64,
+    pub dropped_swap_outs: u64,
+    pub corrupted_swap_ins: u64,
+    pub injected_delay: Duration,
+}
+
+impl FaultInjectionBackend {
+    pub fn new(inner: Box<dyn FarMemoryBackend>) -> Self {
+        Self {
+            inner,
+            state: Arc::new(FaultState {
+                faults: RwLock::new(Faults::none()),
+                rng: Mutex::new(SmallRng::from_entropy()),
+
+                link_free_at: Mutex::new(Instant::now()),
+                dropped_spans: Mutex::new(HashMap::new()),
+
+                injected_errors: AtomicU64::new(0),
+                dropped_swap_outs: AtomicU64::new(0),
+                corrupted_swap_ins: AtomicU64::new(0),
+                injected_delay_micros: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        *self.state.rng.lock().unwrap() = SmallRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn with_faults(self, faults: Faults) -> Self {
+        self.controller().set_faults(faults);
+        self
+    }
+
+    pub fn with_latency(self, latency: LatencyDistribution) -> Self {
+        self.controller().update(|faults| faults.latency = latency);
+        self
+    }
+
+    pub fn with_bandwidth(self, bytes_per_second: u64) -> Self {
+        self.controller().update(|faults| faults.bandwidth = Some(bytes_per_second));
+        self
+    }
+
+    pub fn with_error_rate(self, error_rate: f64) -> Self {
+        self.controller().update(|faults| faults.error_rate = error_rate);
+        self
+    }
+
+    pub fn with_drop_rate(self, drop_rate: f64) -> Self {
+        self.controller().update(|faults| faults.drop_rate = drop_rate);
+        self
+    }
+
+    pub fn with_corruption_rate(self, corruption_rate: f64) -> Self {
+        self.controller().update(|faults| faults.corruption_rate = corruption_rate);
+        self
+    }
+
+    pub fn controller(&self) -> FaultInjectionController {
+        FaultInjectionController {
+            state: self.state.clone(),
+        }
+    }
+
+    pub fn stats(&self) -> FaultInjectionStats {
+        self.controller().stats()
+    }
+}
+
+impl FaultInjectionController {
+    pub fn faults(&self) -> Faults {
+        self.state.faults.read().unwrap().clone()
+    }
+
+    pub fn set_faults(&self, faults: Faults) {
+        *self.state.faults.write().unwrap() = faults;
+    }
+
+    pub fn update<F: FnOnce(&mut Faults)>(&self, f: F) {
+        f(&mut self.state.faults.write().unwrap());
+    }
+
+    pub fn set_latency(&self, latency: LatencyDistribution) {
+        self.update(|faults| faults.latency = latency);
+    }
+
+    pub fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        self.update(|faults| faults.bandwidth = bytes_per_second);
+    }
+
+    pub fn set_error_rate(&self, error_rate: f64) {
+        self.update(|faults| faults.error_rate = error_rate);
+    }
+
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.update(|faults| faults.drop_rate = drop_rate);
+    }
+
+    pub fn set_corruption_rate(&self, corruption_rate: f64) {
+        self.update(|faults| faults.corruption_rate = corruption_rate);
+    }
+
+    // backend behaves the same as inner backend after this.
+    pub fn reset(&self) {
+        self.set_faults(Faults::none());
+    }
+
+    pub fn stats(&self) -> FaultInjectionStats {
+        FaultInjectionStats {
+            injected_errors: self.state.injected_errors.load(Ordering::Relaxed),
+            dropped_swap_outs: self.state.dropped_swap_outs.load(Ordering::Relaxed),
+            corrupted_swap_ins: self.state.corrupted_swap_ins.load(Ordering::Relaxed),
+            injected_delay: Duration::from_micros(self.state.injected_delay_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Faults {
+    pub fn none() -> Self {
+        Self {
+            latency: LatencyDistribution::None,
+            bandwidth: None,
+            error_rate: 0.0,
+            drop_rate: 0.0,
+            corruption_rate: 0.0,
+        }
+    }
+}
+
+impl LatencyDistribution {
+    fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match self {
+            Self::None => Duration::ZERO,
+            Self::Fixed(latency) => *latency,
+            Self::Uniform { min, max } => if min < max { rng.gen_range(*min..*max) } else { *min },
+            Self::Normal { mean, std_dev } => {
+                let distribution = Normal::new(mean.as_secs_f64(), std_dev.as_secs_f64()).unwrap();
+                Duration::from_secs_f64(distribution.sample(rng).max(0.0))
+            },
+            Self::Exponential { mean } => {
+                let distribution = Exp::new(1.0 / mean.as_secs_f64()).unwrap();
+                Duration::from_secs_f64(distribution.sample(rng))
+            },
+        }
+    }
+}
+
+impl FaultState {
+    fn faults(&self) -> Faults {
+        self.faults.read().unwrap().clone()
+    }
+
+    fn happens(&self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.lock().unwrap().gen_bool(probability.min(1.0))
+    }
+
+    fn inject_error(&self, faults: &Faults, operation: &str) {
+        if self.happens(faults.error_rate) {
+            self.injected_errors.fetch_add(1, Ordering::Relaxed);
+            panic!("injected fault: {} failed", operation);
+        }
+    }
+
+    // sleeps for sampled latency plus time to transfer data over the limited link.
+    fn inject_delay(&self, faults: &Faults, bytes: usize) {
+        let started_at = Instant::now();
+
+        let latency = faults.latency.sample(&mut *self.rng.lock().unwrap());
+        let transfer_done_at = faults.bandwidth.map(|bandwidth| {
+            let transfer_time = Duration::from_secs_f64(bytes as f64 / bandwidth as f64);
+
+            // transfers are serialized, same as on a real link.
+            let mut link_free_at = self.link_free_at.lock().unwrap();
+            *link_free_at = (*link_free_at).max(started_at) + transfer_time;
+            *link_free_at
+        });
+
+        let done_at = (started_at + latency).max(transfer_done_at.unwrap_or(started_at));
+        if done_at > started_at {
+            span!(Level::DEBUG, "injected delay").in_scope(|| thread::sleep(done_at - started_at));
+            self.injected_delay_micros.fetch_add((done_at - started_at).as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn corrupt(&self, data: &mut [u8]) {
+        if data.is_empty() {
+            return;
+        }
+
+        let mut rng = self.rng.lock().unwrap();
+        let index = rng.gen_range(0..data.len());
+        data[index] ^= 1 << rng.gen_range(0..8);
+        self.corrupted_swap_ins.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl FarMemoryBackend for FaultInjectionBackend {
+    fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+        let state = &self.state;
+        let faults = state.faults();
+
+        state.inject_error(&faults, "swap out");
+        state.inject_delay(&faults, span.len());
+
+        let mut dropped_spans = state.dropped_spans.lock().unwrap();
+        // prepend to a dropped span has nothing to be prepended to.
+        if (prepend && dropped_spans.contains_key(&id)) || state.happens(faults.drop_rate) {
+            let stored_in_inner = prepend && dropped_spans.get(&id).copied().unwrap_or(true);
+            dropped_spans.insert(id, stored_in_inner);
+            state.dropped_swap_outs.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        dropped_spans.remove(&id);
+        drop(dropped_spans);
+
+        self.inner.swap_out(id, span, prepend);
+    }
+
+    fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+        let state = &self.state;
+        let faults = state.faults();
+
+        state.inject_error(&faults, "swap in");
+        let dropped = state.dropped_spans.lock().unwrap().remove(id);
+        if let Some(stored_in_inner) = dropped {
+            if stored_in_inner {
+                self.inner.remove(id);
+            }
+            panic!("injected fault: span {} was dropped on swap out", id.id());
+        }
+
+        let mut data = self.inner.swap_in(id);
+        state.inject_delay(&faults, data.len());
+
+        if state.happens(faults.corruption_rate) {
+            state.corrupt(&mut data);
+        }
+
+        data
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let dropped = self.state.dropped_spans.lock().unwrap().remove(id);
+        if dropped.unwrap_or(true) {
+            self.inner.remove(id);
+        }
+    }
+
+    fn on_stop(&self) {
+        self.inner.on_stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    fn outcomes(backend: &FaultInjectionBackend) -> Vec<Option<Vec<u8>>> {
+        (0..32).map(|i| {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                backend.swap_out(SpanId::from_id(i), &[i as u8; 16], false);
+                backend.swap_in(&SpanId::from_id(i))
+            }));
+            result.ok()
+        }).collect()
+    }
+
+    #[test]
+    fn seeded_faults_are_reproducible() {
+        let backend = |seed| FaultInjectionBackend::new(Box::new(InMemoryBackend::new()))
+            .with_seed(seed)
+            .with_error_rate(0.2)
+            .with_drop_rate(0.2)
+            .with_corruption_rate(0.2);
+
+        let first = backend(42);
+        let first_outcomes = outcomes(&first);
+        let second = backend(42);
+
+        assert_eq!(first_outcomes, outcomes(&second));
+        assert_eq!(first.stats(), second.stats());
+        assert!(first.stats().injected_errors > 0);
+        assert!(first.stats().dropped_swap_outs > 0);
+        assert!(first.stats().corrupted_swap_ins > 0);
+    }
+
+    #[test]
+    fn faults_are_controlled_at_runtime() {
+        let backend = FaultInjectionBackend::new(Box::new(InMemoryBackend::new())).with_drop_rate(1.0);
+        let controller = backend.controller();
+
+        backend.swap_out(SpanId::from_id(1), &[1, 2, 3], false);
+        backend.swap_out(SpanId::from_id(1), &[0], true);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| backend.swap_in(&SpanId::from_id(1))));
+        assert!(result.is_err());
+
+        controller.reset();
+        backend.swap_out(SpanId::from_id(1), &[1, 2, 3], false);
+        controller.set_corruption_rate(1.0);
+        let corrupted = backend.swap_in(&SpanId::from_id(1));
+        assert_eq!(1, corrupted.iter().zip([1u8, 2, 3].iter()).map(|(a, b)| (a ^ b).count_ones()).sum::<u32>());
+
+        assert_eq!(2, controller.stats().dropped_swap_outs);
+        assert_eq!(1, controller.stats().corrupted_swap_ins);
+    }
+
+    #[test]
+    fn dropped_prepend_is_removed_from_inner() {
+        let backend = FaultInjectionBackend::new(Box::new(InMemoryBackend::new()));
+        let controller = backend.controller();
+
+        backend.swap_out(SpanId::from_id(1), &[1, 2, 3], false);
+        controller.set_drop_rate(1.0);
+        backend.swap_out(SpanId::from_id(1), &[0], true);
+        backend.remove(&SpanId::from_id(1));
+
+        // inner backend has no data left to prepend to.
+        controller.reset();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| backend.swap_out(SpanId::from_id(1), &[4], true)));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn latency_and_bandwidth() {
+        let backend = FaultInjectionBackend::new(Box::new(InMemoryBackend::new()))
+            .with_latency(LatencyDistribution::Fixed(Duration::from_millis(20)))
+            .with_bandwidth(1024 * 1024);
+
+        let started_at = Instant::now();
+        backend.swap_out(SpanId::from_id(1), &[0; 1024], false);
+        assert!(started_at.elapsed() >= Duration::from_millis(20));
+
+        let started_at = Instant::now();
+        backend.swap_in(&SpanId::from_id(1));
+        backend.swap_out(SpanId::from_id(2), &vec![0; 100 * 1024], false);
+        assert!(started_at.elapsed() >= Duration::from_millis(97));
+
+        assert!(backend.stats().injected_delay >= Duration::from_millis(117));
+    }
+}
@@ -30,4 +30,8 @@ impl FarMemoryBackend for InMemoryBackend {
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
         self.spans.write().unwrap().remove(id).unwrap()
     }
+
+    fn remove(&self, id: &SpanId) {
+        self.spans.write().unwrap().remove(id);
+    }
 }
\ No newline at end of file
@@ -93,6 +93,10 @@ impl FarMemoryBackend for InstrumentedBackend {
         res
     }
 
+    fn remove(&self, id: &SpanId) {
+        self.inner.remove(id)
+    }
+
     fn on_stop(&self) {
         self.registry.unregister(Box::new(self.swap_out_bytes.clone())).unwrap();
         self.registry.unregister(Box::new(self.swap_out_time_ms.clone())).unwrap();
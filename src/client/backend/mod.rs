@@ -1,14 +1,19 @@
 use crate::client::span::SpanId;
 
+pub mod compressed_memory;
 pub mod compression;
 pub mod disk;
 pub mod encryption;
 pub mod erasure_coding;
+pub mod fault_injection;
 pub mod in_memory;
 pub mod metrics;
 pub mod network_node;
 pub mod network_sharding;
 pub mod replication;
+pub mod tiered;
+
+mod worker;
 
 pub trait FarMemoryBackend: Send + Sync {
     // far memory backend performs prepend, so when swapping in we can append to existing memory, which is
@@ -16,6 +21,11 @@ pub trait FarMemoryBackend: Send + Sync {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool);
     fn swap_in(&self, id: &SpanId) -> Vec<u8>;
 
+    // drops span data without reading it back, when span is freed.
+    fn remove(&self, id: &SpanId) {
+        self.swap_in(id);
+    }
+
     fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
         self.batch(swap_out_operations, None);
     }
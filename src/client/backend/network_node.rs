@@ -1,53 +1,102 @@
 use {
-    std::{sync::Mutex, ops::DerefMut},
-    tracing::debug_span,
+    std::sync::{OnceLock, atomic::{AtomicUsize, Ordering}},
+    tracing::{span, Level, Instrument},
     tokio::runtime::Runtime,
+    futures::future::join_all,
     crate::{
-        storage::{Client, SwapOutRequest, SpanData, BatchSwapOutOperation, LocalSpanData},
+        storage::{Client, ClientConfig, ClientMetrics, BatchSwapOutOperation, LocalSpanData},
         client::span::SpanId,
     },
     super::{FarMemoryBackend, SwapOutOperation, SwapOutOperationData},
 };
 
+const DEFAULT_CONNECTIONS: usize = 4;
+
+/**
+ * Stores spans on a single storage node. Uses a pool of connections, each of them with pipelined requests, so that
+ * operations from different threads do not wait for each other. Connections are opened on first use.
+ */
 pub struct NetworkNodeBackend {
-    client: Mutex<(Runtime, Client)>,
+    runtime: Runtime,
+
+    endpoint: String,
+    token: String,
+    run_id: String,
+
+    client_config: ClientConfig,
+    total_connections: usize,
+    connections: OnceLock<Vec<Client>>,
+    next_connection: AtomicUsize,
 }
 
 impl NetworkNodeBackend {
     pub fn new(endpoint: &str, token: &str, run_id: String) -> Self {
-        let runtime = Runtime::new().unwrap();
+        Self {
+            runtime: Runtime::new().unwrap(),
+
+            endpoint: endpoint.to_owned(),
+            token: token.to_owned(),
+            run_id,
+
+            client_config: ClientConfig::default(),
+            total_connections: DEFAULT_CONNECTIONS,
+            connections: OnceLock::new(),
+            next_connection: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        assert!(connections > 0, "at least one connection is required");
+        self.total_connections = connections;
+        self
+    }
+
+    pub fn with_client_config(mut self, client_config: ClientConfig) -> Self {
+        self.client_config = client_config;
+        self
+    }
 
-        let client = runtime.block_on(async {
-            let mut client = Client::new(endpoint).await;
-            client.auth(token).await;
-            client.set_run_id(run_id).await;
+    // metrics can be shared by multiple backends, so they are not unregistered on stop.
+    pub fn with_metrics(mut self, metrics: ClientMetrics) -> Self {
+        self.client_config = std::mem::take(&mut self.client_config).with_metrics(metrics);
+        self
+    }
+
+    pub fn connections(&self) -> usize {
+        self.total_connections
+    }
 
-            client
+    fn connection(&self) -> &Client {
+        let connections = self.connections.get_or_init(|| {
+            let (endpoint, token, run_id, client_config) = (&self.endpoint, &self.token, &self.run_id, &self.client_config);
+
+            self.runtime.block_on(join_all((0..self.total_connections).map(|_| async move {
+                let client = Client::connect(endpoint, client_config.clone()).await;
+                client.auth(token).await;
+                client.set_run_id(run_id.clone()).await;
+
+                client
+            })))
         });
 
-        Self {
-            client: Mutex::new((runtime, client)),
-        }
+        &connections[self.next_connection.fetch_add(1, Ordering::Relaxed) % connections.len()]
     }
 }
 
 impl FarMemoryBackend for NetworkNodeBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
-        let lock_span = debug_span!("waiting for network client lock for swap out");
-        let lock_span_guard = lock_span.enter();
-        let mut lock = self.client.lock().unwrap();
-        let (runtime, client) = lock.deref_mut();
-        drop(lock_span_guard);
-        runtime.block_on(async { client.swap_out(id.id(), span.to_vec(), prepend).await });
+        let client = self.connection();
+        self.runtime.block_on(client.swap_out(id.id(), span.to_vec(), prepend).instrument(span!(Level::DEBUG, "network swap out")))
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        let lock_span = debug_span!("waiting for network client lock for swap in");
-        let lock_span_guard = lock_span.enter();
-        let mut lock = self.client.lock().unwrap();
-        let (runtime, client) = lock.deref_mut();
-        drop(lock_span_guard);
-        runtime.block_on(async { client.swap_in(id.id()).await })
+        let client = self.connection();
+        self.runtime.block_on(client.swap_in(id.id()).instrument(span!(Level::DEBUG, "network swap in")))
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let client = self.connection();
+        self.runtime.block_on(client.remove(id.id()).instrument(span!(Level::DEBUG, "network remove")))
     }
 
     fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
@@ -55,13 +104,9 @@ impl FarMemoryBackend for NetworkNodeBackend {
     }
 
     fn batch(&self, swap_out_operations: Vec<SwapOutOperation>, swap_in: Option<&SpanId>) -> Option<Vec<u8>> {
-        let lock_span = debug_span!("waiting for network client lock for batch operation");
-        let lock_span_guard = lock_span.enter();
-        let mut lock = self.client.lock().unwrap();
-        let (runtime, client) = lock.deref_mut();
-        drop(lock_span_guard);
+        let client = self.connection();
 
-        runtime.block_on(async {
+        self.runtime.block_on(async {
             client.batch(swap_out_operations.into_iter().map(|v| BatchSwapOutOperation {
                 span_id: v.id.id(),
                 data: match v.data {
@@ -69,7 +114,15 @@ impl FarMemoryBackend for NetworkNodeBackend {
                     SwapOutOperationData::ReadFrom { ptr, size } => LocalSpanData::ReadFrom { ptr, size },
                 },
                 prepend: v.prepend,
-            }).collect(), swap_in.map(|v| v.id())).await
+            }).collect(), swap_in.map(|v| v.id())).instrument(span!(Level::DEBUG, "network batch")).await
         })
     }
 }
+
+impl Drop for NetworkNodeBackend {
+    fn drop(&mut self) {
+        // reader tasks of connections are aborted on drop, which requires runtime to be alive.
+        let _guard = self.runtime.enter();
+        self.connections.take();
+    }
+}
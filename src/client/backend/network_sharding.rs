@@ -1,6 +1,6 @@
 use {
-    std::sync::Mutex,
-    tracing::debug_span,
+    std::{sync::{Arc, Mutex, RwLock}, collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher}, hash::{Hash, Hasher}},
+    tracing::{debug_span, span, Level},
     tokio::runtime::Runtime,
     crate::{
         storage::{Client, BatchSwapOutOperation, LocalSpanData},
@@ -9,66 +9,205 @@ use {
     super::{FarMemoryBackend, SwapOutOperation, SwapOutOperationData},
 };
 
+const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/**
+ * Shards spans over any number of storage nodes using consistent hashing: every node is placed on the hash ring
+ * at `virtual_nodes` points, and span is stored on the node owning the first point after span hash. When a node
+ * is added or removed, only spans that change owner are moved.
+ *
+ * Each node has its own connection lock, so operations on different nodes run in parallel. Ring itself is behind
+ * RwLock which is only locked for writing when nodes are added or removed.
+ */
 pub struct NetworkShardingBackend {
-    lock: Mutex<()>,
     runtime: Runtime,
+    token: String,
+    run_id: String,
 
-    // TODO: refactor this into vec, lol.
-    client0: Mutex<Client>,
-    client1: Mutex<Client>,
-    client2: Mutex<Client>,
-    client3: Mutex<Client>,
+    ring: RwLock<ShardRing>,
 }
 
-impl NetworkShardingBackend {
-    pub fn new(token: &str, run_id: &str, endpoints: Vec<String>) -> Self {
-        let runtime = Runtime::new().unwrap();
+struct ShardRing {
+    hash_ring: ConsistentHashRing,
+    nodes: HashMap<String, Arc<ShardNode>>,
+}
 
-        let mut clients = runtime.block_on(async move {
-            let tasks = endpoints.iter().map(|endpoint| async {
-                let mut client = Client::new(endpoint).await;
-                client.auth(token).await;
-                client.set_run_id(run_id.to_owned()).await;
+struct ShardNode {
+    // tokio mutex, because lock is held across await points while other nodes are locked concurrently in batches.
+    client: tokio::sync::Mutex<Client>,
+    // spans stored on this node, so that they can be moved when ring changes.
+    spans: Mutex<HashSet<u64>>,
+}
 
-                client
-            }).collect::<Vec<_>>();
+struct ConsistentHashRing {
+    virtual_nodes: usize,
+    points: BTreeMap<u64, String>,
+}
 
+impl NetworkShardingBackend {
+    pub fn new(token: &str, run_id: &str, endpoints: Vec<String>) -> Self {
+        assert!(!endpoints.is_empty(), "sharding backend requires at least one storage node");
+
+        let runtime = Runtime::new().unwrap();
+        let clients = runtime.block_on(async {
+            let tasks = endpoints.iter().map(|endpoint| connect(endpoint, token, run_id)).collect::<Vec<_>>();
             futures::future::join_all(tasks).await
         });
 
+        let mut hash_ring = ConsistentHashRing::new(DEFAULT_VIRTUAL_NODES);
+        let mut nodes = HashMap::new();
+        for (endpoint, client) in endpoints.into_iter().zip(clients.into_iter()) {
+            hash_ring.add_node(&endpoint);
+            nodes.insert(endpoint, Arc::new(ShardNode::new(client)));
+        }
+
         Self {
-            lock: Mutex::new(()),
             runtime,
+            token: token.to_owned(),
+            run_id: run_id.to_owned(),
+
+            ring: RwLock::new(ShardRing {
+                hash_ring,
+                nodes,
+            }),
+        }
+    }
+
+    // should be called before any spans are swapped out.
+    pub fn with_virtual_nodes(self, virtual_nodes: usize) -> Self {
+        {
+            let mut ring = self.ring.write().unwrap();
+            assert!(ring.nodes.values().all(|node| node.spans.lock().unwrap().is_empty()), "virtual nodes can only be changed for empty backend");
+
+            let mut hash_ring = ConsistentHashRing::new(virtual_nodes);
+            for endpoint in ring.nodes.keys() {
+                hash_ring.add_node(endpoint);
+            }
+            ring.hash_ring = hash_ring;
+        }
+
+        self
+    }
+
+    pub fn nodes(&self) -> Vec<String> {
+        self.ring.read().unwrap().nodes.keys().cloned().collect()
+    }
+
+    pub fn add_node(&self, endpoint: &str) {
+        span!(Level::DEBUG, "NetworkShardingBackend::add_node").in_scope(|| {
+            let client = self.runtime.block_on(connect(endpoint, &self.token, &self.run_id));
+
+            let mut ring = self.ring.write().unwrap();
+            assert!(!ring.nodes.contains_key(endpoint), "storage node {} is already part of the ring", endpoint);
+
+            ring.hash_ring.add_node(endpoint);
+            let node = Arc::new(ShardNode::new(client));
+            ring.nodes.insert(endpoint.to_owned(), node.clone());
+
+            // new node takes over parts of the ring from other nodes, so only spans in these parts are moved.
+            for (other_endpoint, other_node) in ring.nodes.iter() {
+                if other_endpoint == endpoint {
+                    continue;
+                }
+
+                let moved: Vec<u64> = other_node.spans.lock().unwrap().iter()
+                    .filter(|span_id| ring.hash_ring.node_for(**span_id) == endpoint)
+                    .cloned()
+                    .collect();
+                self.move_spans(other_node, &node, moved);
+            }
+        });
+    }
 
-            client0: Mutex::new(clients.remove(0)),
-            client1: Mutex::new(clients.remove(0)),
-            client2: Mutex::new(clients.remove(0)),
-            client3: Mutex::new(clients.remove(0)),
+    pub fn remove_node(&self, endpoint: &str) {
+        span!(Level::DEBUG, "NetworkShardingBackend::remove_node").in_scope(|| {
+            let mut ring = self.ring.write().unwrap();
+            assert!(ring.nodes.len() > 1, "cannot remove the last storage node");
+
+            let node = ring.nodes.remove(endpoint).expect("storage node is not part of the ring");
+            ring.hash_ring.remove_node(endpoint);
+
+            let spans: Vec<u64> = node.spans.lock().unwrap().iter().cloned().collect();
+            let mut spans_by_target: HashMap<&str, Vec<u64>> = HashMap::new();
+            for span_id in spans {
+                spans_by_target.entry(ring.hash_ring.node_for(span_id)).or_default().push(span_id);
+            }
+            for (target, spans) in spans_by_target {
+                self.move_spans(&node, &ring.nodes[target], spans);
+            }
+
+            self.runtime.block_on(async { node.client.lock().await.close().await });
+        });
+    }
+
+    fn move_spans(&self, from: &ShardNode, to: &ShardNode, spans: Vec<u64>) {
+        if spans.is_empty() {
+            return;
         }
+
+        self.runtime.block_on(async {
+            let from_client = from.client.lock().await;
+            let to_client = to.client.lock().await;
+
+            for span_id in spans {
+                // span is removed from the source only after target has it, so that it is not lost if write fails.
+                let data = from_client.read(span_id).await;
+                to_client.swap_out(span_id, data, false).await;
+                to.spans.lock().unwrap().insert(span_id);
+
+                from_client.remove(span_id).await;
+                from.spans.lock().unwrap().remove(&span_id);
+            }
+        });
     }
 }
 
 impl FarMemoryBackend for NetworkShardingBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
-        unimplemented!()
+        let ring = self.ring.read().unwrap();
+        let node = ring.node_for(id.id());
+
+        let lock_span = debug_span!("waiting for shard client lock for swap out");
+        self.runtime.block_on(async {
+            let client = {
+                let _guard = lock_span.enter();
+                node.client.lock().await
+            };
+            client.swap_out(id.id(), span.to_vec(), prepend).await;
+        });
+        node.spans.lock().unwrap().insert(id.id());
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        let lock_span = debug_span!("waiting for network client lock for swap in");
-        let lock_span_guard = lock_span.enter();
-        let _lock = self.lock.lock().unwrap();
-        drop(lock_span_guard);
+        let ring = self.ring.read().unwrap();
+        let node = ring.node_for(id.id());
 
-        self.runtime.block_on(async {
-            let mut client = match id.id() % 4 {
-                0 => &self.client0,
-                1 => &self.client1,
-                2 => &self.client2,
-                3 => &self.client3,
-                _ => unreachable!(),
-            }.lock().unwrap();
+        let lock_span = debug_span!("waiting for shard client lock for swap in");
+        let data = self.runtime.block_on(async {
+            let client = {
+                let _guard = lock_span.enter();
+                node.client.lock().await
+            };
             client.swap_in(id.id()).await
-        })
+        });
+        node.spans.lock().unwrap().remove(&id.id());
+
+        data
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let ring = self.ring.read().unwrap();
+        let node = ring.node_for(id.id());
+
+        let lock_span = debug_span!("waiting for shard client lock for remove");
+        self.runtime.block_on(async {
+            let client = {
+                let _guard = lock_span.enter();
+                node.client.lock().await
+            };
+            client.remove(id.id()).await
+        });
+        node.spans.lock().unwrap().remove(&id.id());
     }
 
     fn batch_swap_out(&self, swap_out_operations: Vec<SwapOutOperation>) {
@@ -76,106 +215,180 @@ impl FarMemoryBackend for NetworkShardingBackend {
     }
 
     fn batch(&self, swap_out_operations: Vec<SwapOutOperation>, swap_in: Option<&SpanId>) -> Option<Vec<u8>> {
-        let lock_span = debug_span!("waiting for sharding lock for batch operation");
-        let lock_span_guard = lock_span.enter();
-        let _lock = self.lock.lock().unwrap();
-        drop(lock_span_guard);
-
-        let mut client0 = self.client0.lock().unwrap();
-        let mut client1 = self.client1.lock().unwrap();
-        let mut client2 = self.client2.lock().unwrap();
-        let mut client3 = self.client3.lock().unwrap();
+        let ring = self.ring.read().unwrap();
 
-        self.runtime.block_on(async {
-            let swap_out_operations: Vec<_> = swap_out_operations.into_iter().map(|v| BatchSwapOutOperation {
-                span_id: v.id.id(),
-                data: match v.data {
+        let mut operations_by_node: HashMap<&str, Vec<BatchSwapOutOperation>> = HashMap::new();
+        for operation in swap_out_operations {
+            operations_by_node.entry(ring.hash_ring.node_for(operation.id.id())).or_default().push(BatchSwapOutOperation {
+                span_id: operation.id.id(),
+                data: match operation.data {
                     SwapOutOperationData::Owned(v) => LocalSpanData::Owned(v),
                     SwapOutOperationData::ReadFrom { ptr, size } => LocalSpanData::ReadFrom { ptr, size },
                 },
-                prepend: v.prepend,
-            }).collect();
+                prepend: operation.prepend,
+            });
+        }
+        let swap_in_node = swap_in.map(|span_id| ring.hash_ring.node_for(span_id.id()));
+        if let Some(swap_in_node) = swap_in_node {
+            operations_by_node.entry(swap_in_node).or_default();
+        }
 
-            let mut task0 = Vec::new();
-            let swap_in0 = if let Some(span_id) = swap_in {
-                if span_id.id() % 4 == 0 {
-                    Some(span_id.id())
+        self.runtime.block_on(async {
+            let tasks = operations_by_node.into_iter().map(|(endpoint, operations)| {
+                let node = &ring.nodes[endpoint];
+                let swap_in = if swap_in_node == Some(endpoint) {
+                    swap_in.map(|v| v.id())
                 } else {
                     None
-                }
-            } else {
-                None
-            };
+                };
 
-            let mut task1 = Vec::new();
-            let swap_in1 = if let Some(span_id) = swap_in {
-                if span_id.id() % 4 == 1 {
-                    Some(span_id.id())
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
+                async move {
+                    let swapped_out: Vec<u64> = operations.iter().map(|v| v.span_id).collect();
 
-            let mut task2 = Vec::new();
-            let swap_in2 = if let Some(span_id) = swap_in {
-                if span_id.id() % 4 == 2 {
-                    Some(span_id.id())
-                } else {
-                    None
-                }
-            } else {
-                None
-            };
+                    let result = node.client.lock().await.batch(operations, swap_in).await;
 
-            let mut task3 = Vec::new();
-            let swap_in3 = if let Some(span_id) = swap_in {
-                if span_id.id() % 4 == 3 {
-                    Some(span_id.id())
-                } else {
-                    None
+                    let mut spans = node.spans.lock().unwrap();
+                    spans.extend(swapped_out);
+                    if let Some(swap_in) = swap_in {
+                        spans.remove(&swap_in);
+                    }
+
+                    result
                 }
-            } else {
-                None
-            };
+            }).collect::<Vec<_>>();
 
-            for operation in swap_out_operations {
-                match operation.span_id % 4 {
-                    0 => &mut task0,
-                    1 => &mut task1,
-                    2 => &mut task2,
-                    3 => &mut task3,
-                    _ => unreachable!(),
-                }.push(operation);
-            }
+            futures::future::join_all(tasks).await.into_iter().find_map(|v| v)
+        })
+    }
+}
 
-            let task0 = client0.batch(task0, swap_in0);
-            let task1 = client1.batch(task1, swap_in1);
-            let task2 = client2.batch(task2, swap_in2);
-            let task3 = client3.batch(task3, swap_in3);
+impl ShardRing {
+    fn node_for(&self, span_id: u64) -> &Arc<ShardNode> {
+        &self.nodes[self.hash_ring.node_for(span_id)]
+    }
+}
 
-            let (result0, result1, result2, result3) = futures::future::join4(
-                task0,
-                task1,
-                task2,
-                task3,
-            ).await;
+impl ShardNode {
+    fn new(client: Client) -> Self {
+        Self {
+            client: tokio::sync::Mutex::new(client),
+            spans: Mutex::new(HashSet::new()),
+        }
+    }
+}
 
-            if result0.is_some() {
-                return result0;
-            }
-            if result1.is_some() {
-                return result1;
-            }
-            if result2.is_some() {
-                return result2;
-            }
-            if result3.is_some() {
-                return result3;
-            }
+impl ConsistentHashRing {
+    fn new(virtual_nodes: usize) -> Self {
+        Self {
+            virtual_nodes,
+            points: BTreeMap::new(),
+        }
+    }
 
-            None
-        })
+    fn add_node(&mut self, node: &str) {
+        for i in 0..self.virtual_nodes {
+            self.points.insert(hash(&(node, i)), node.to_owned());
+        }
+    }
+
+    fn remove_node(&mut self, node: &str) {
+        self.points.retain(|_, v| v != node);
+    }
+
+    fn node_for(&self, span_id: u64) -> &str {
+        let span_hash = hash(&span_id);
+        self.points.range(span_hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, node)| node.as_str())
+            .expect("hash ring is empty")
+    }
+}
+
+async fn connect(endpoint: &str, token: &str, run_id: &str) -> Client {
+    let client = Client::new(endpoint).await;
+    client.auth(token).await;
+    client.set_run_id(run_id.to_owned()).await;
+
+    client
+}
+
+fn hash<T: Hash>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::thread,
+        crate::storage::run_server,
+        super::*,
+    };
+
+    #[test]
+    fn spans_are_distributed_across_nodes() {
+        let mut ring = ConsistentHashRing::new(DEFAULT_VIRTUAL_NODES);
+        for node in ["node-0", "node-1", "node-2", "node-3"] {
+            ring.add_node(node);
+        }
+
+        let mut spans_per_node: HashMap<&str, usize> = HashMap::new();
+        for span_id in 0..10000 {
+            *spans_per_node.entry(ring.node_for(span_id)).or_default() += 1;
+        }
+
+        assert_eq!(4, spans_per_node.len());
+        assert!(spans_per_node.values().all(|v| *v > 1500 && *v < 3500), "unbalanced ring: {:?}", spans_per_node);
+    }
+
+    #[test]
+    fn adding_and_removing_node_moves_only_affected_spans() {
+        let mut ring = ConsistentHashRing::new(DEFAULT_VIRTUAL_NODES);
+        for node in ["node-0", "node-1", "node-2"] {
+            ring.add_node(node);
+        }
+        let before: Vec<String> = (0..10000).map(|span_id| ring.node_for(span_id).to_owned()).collect();
+
+        ring.add_node("node-3");
+        let after: Vec<String> = (0..10000).map(|span_id| ring.node_for(span_id).to_owned()).collect();
+        for (before, after) in before.iter().zip(after.iter()) {
+            assert!(before == after || after == "node-3");
+        }
+        let moved = after.iter().filter(|v| *v == "node-3").count();
+        assert!(moved > 1500 && moved < 3500);
+
+        ring.remove_node("node-3");
+        let removed: Vec<String> = (0..10000).map(|span_id| ring.node_for(span_id).to_owned()).collect();
+        assert_eq!(before, removed);
+    }
+
+    #[test]
+    fn spans_are_moved_between_nodes() {
+        for port in [14005, 14006, 14007] {
+            thread::spawn(move || run_server(None, "127.0.0.1".to_owned(), Some(port), "some-token".to_owned(), None, None).unwrap());
+        }
+
+        let backend = NetworkShardingBackend::new("some-token", "some-run", vec!["127.0.0.1:14005".to_owned(), "127.0.0.1:14006".to_owned()]);
+        for span_id in 0..100 {
+            backend.swap_out(SpanId::from_id(span_id), &[span_id as u8; 16], false);
+        }
+
+        backend.add_node("127.0.0.1:14007");
+        let moved = backend.ring.read().unwrap().nodes["127.0.0.1:14007"].spans.lock().unwrap().len();
+        assert!(moved > 0);
+
+        backend.remove_node("127.0.0.1:14005");
+        let total: usize = backend.ring.read().unwrap().nodes.values().map(|node| node.spans.lock().unwrap().len()).sum();
+        assert_eq!(100, total);
+
+        backend.remove(&SpanId::from_id(0));
+        let total: usize = backend.ring.read().unwrap().nodes.values().map(|node| node.spans.lock().unwrap().len()).sum();
+        assert_eq!(99, total);
+
+        for span_id in 1..100 {
+            assert_eq!(vec![span_id as u8; 16], backend.swap_in(&SpanId::from_id(span_id)));
+        }
     }
 }
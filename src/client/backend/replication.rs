@@ -1,48 +1,613 @@
 use {
+    std::{
+        sync::{Arc, Mutex, Condvar, atomic::{AtomicBool, Ordering}, mpsc::channel},
+        collections::{HashMap, HashSet},
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+    tracing::{span, Level, warn},
     crate::client::span::SpanId,
-    tracing::{span, Level},
-    super::FarMemoryBackend,
+    super::{FarMemoryBackend, worker::BackendWorker},
 };
 
+const REPAIR_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_REPAIR_BACKOFF: Duration = Duration::from_secs(60);
+
+/**
+ * Stores every span on all targets. Writes are sent to all targets in parallel and complete once `write_quorum`
+ * targets confirm them. Reads go to the fastest up-to-date replica and fall back to other replicas if it fails; other
+ * copies are then deleted without reading them.
+ *
+ * Every write increments span version, and every replica tracks the version it has, so replicas that missed writes
+ * (or prepends) are never read from. They are repaired in background by copying span from an up-to-date replica.
+ * Replicas that fail repair are retried with exponential backoff, and replicas with writes still in progress are
+ * not repaired.
+ */
 pub struct ReplicationBackend {
-    targets: Vec<Box<dyn FarMemoryBackend>>,
+    state: Arc<ReplicationState>,
+    repair_thread: Option<JoinHandle<()>>,
+    background_repair: bool,
+}
+
+struct ReplicationState {
+    targets: Vec<BackendWorker>,
+    // majority of targets if not set.
+    write_quorum: Option<usize>,
+    is_running: AtomicBool,
+
+    spans: Mutex<HashMap<SpanId, SpanReplicas>>,
+    // spans with operation in progress, so that repair does not interfere with swap in and swap out.
+    busy_spans: Mutex<HashSet<SpanId>>,
+    busy_spans_condvar: Condvar,
+
+    replicas: Mutex<Vec<ReplicaStats>>,
+}
+
+#[derive(Clone)]
+struct SpanReplicas {
+    version: u64,
+    // version of span data each replica has, None if replica has no data for span.
+    replica_versions: Vec<Option<u64>>,
+    // latest version sent to each replica that is not written yet.
+    in_flight_versions: Vec<Option<u64>>,
+}
+
+struct ReplicaStats {
+    // exponentially weighted average of swap in latency.
+    latency: Duration,
+    is_healthy: bool,
+    // repair of unreachable replica is retried with exponential backoff.
+    repair_backoff: Duration,
+    next_repair_at: Instant,
+}
+
+struct BusySpanGuard<'a> {
+    state: &'a ReplicationState,
+    span_id: SpanId,
+}
+
+// marks write as no longer in flight when it completes or fails.
+struct InFlightWriteGuard<'a> {
+    state: &'a ReplicationState,
+    span_id: &'a SpanId,
+    replica: usize,
+    version: u64,
 }
 
 impl ReplicationBackend {
     pub fn new(targets: Vec<Box<dyn FarMemoryBackend>>) -> Self {
-        Self {
-            targets,
+        let mut backend = Self {
+            state: Arc::new(ReplicationState::new(Vec::new(), None)),
+            repair_thread: None,
+            background_repair: true,
+        };
+
+        for target in targets {
+            backend = backend.with_target(target);
         }
+
+        backend
     }
 
     pub fn empty() -> Self {
         Self::new(Vec::new())
     }
 
-    pub fn with_target(mut self, target: Box<dyn FarMemoryBackend>) -> Self {
-        self.targets.push(target);
+    pub fn with_target(self, target: Box<dyn FarMemoryBackend>) -> Self {
+        self.with_state(|mut targets, write_quorum| {
+            targets.push(BackendWorker::new(format!("replica-{}", targets.len()), target));
+            (targets, write_quorum)
+        })
+    }
+
+    // number of replicas that need to confirm write before swap out completes. Majority of targets by default.
+    pub fn with_write_quorum(self, write_quorum: usize) -> Self {
+        self.with_state(|targets, _| {
+            assert!(write_quorum > 0 && write_quorum <= targets.len(), "write quorum should be between 1 and number of targets");
+            (targets, Some(write_quorum))
+        })
+    }
+
+    // stale replicas are then only repaired by calling `repair`.
+    pub fn without_background_repair(mut self) -> Self {
+        self.background_repair = false;
+        self.with_state(|targets, write_quorum| (targets, write_quorum))
+    }
+
+    // number of replicas that missed writes and are waiting for repair.
+    pub fn stale_replicas(&self) -> usize {
+        self.state.spans.lock().unwrap().values().map(|span| span.stale_replicas().len()).sum()
+    }
+
+    // repairs all stale replicas now, without waiting for background repair.
+    pub fn repair(&self) {
+        self.state.repair();
+    }
+
+    fn with_state<F: FnOnce(Vec<BackendWorker>, Option<usize>) -> (Vec<BackendWorker>, Option<usize>)>(mut self, f: F) -> Self {
+        self.stop_repair_thread();
+        let state = Arc::try_unwrap(std::mem::replace(&mut self.state, Arc::new(ReplicationState::new(Vec::new(), None))))
+            .ok()
+            .expect("replication backend is already in use");
+        assert!(state.spans.lock().unwrap().is_empty(), "replication backend can only be configured before use");
+
+        let (targets, write_quorum) = f(state.targets, state.write_quorum);
+        self.state = Arc::new(ReplicationState::new(targets, write_quorum));
+        if self.background_repair {
+            self.start_repair_thread();
+        }
         self
     }
+
+    fn start_repair_thread(&mut self) {
+        let state = self.state.clone();
+        self.repair_thread = Some(thread::Builder::new()
+            .name("replication-repair".to_owned())
+            .spawn(move || {
+                while state.is_running.load(Ordering::Relaxed) {
+                    thread::park_timeout(REPAIR_INTERVAL);
+                    state.repair();
+                }
+            })
+            .unwrap());
+    }
+
+    fn stop_repair_thread(&mut self) {
+        self.state.is_running.store(false, Ordering::Relaxed);
+        if let Some(repair_thread) = self.repair_thread.take() {
+            repair_thread.thread().unpark();
+            repair_thread.join().unwrap();
+        }
+    }
+}
+
+impl ReplicationState {
+    fn new(targets: Vec<BackendWorker>, write_quorum: Option<usize>) -> Self {
+        let replicas = (0..targets.len())
+            .map(|_| ReplicaStats {
+                latency: Duration::ZERO,
+                is_healthy: true,
+                repair_backoff: REPAIR_INTERVAL,
+                next_repair_at: Instant::now(),
+            })
+            .collect();
+
+        Self {
+            targets,
+            write_quorum,
+            is_running: AtomicBool::new(true),
+
+            spans: Mutex::new(HashMap::new()),
+            busy_spans: Mutex::new(HashSet::new()),
+            busy_spans_condvar: Condvar::new(),
+
+            replicas: Mutex::new(replicas),
+        }
+    }
+
+    fn write_quorum(&self) -> usize {
+        self.write_quorum.unwrap_or(self.targets.len() / 2 + 1)
+    }
+
+    fn lock_span(&self, span_id: &SpanId) -> BusySpanGuard<'_> {
+        let mut busy_spans = self.busy_spans.lock().unwrap();
+        while busy_spans.contains(span_id) {
+            busy_spans = self.busy_spans_condvar.wait(busy_spans).unwrap();
+        }
+        busy_spans.insert(span_id.clone());
+
+        BusySpanGuard {
+            state: self,
+            span_id: span_id.clone(),
+        }
+    }
+
+    // sends write to replicas and waits for `wait_for` of them to confirm it. Remaining writes complete in background.
+    // Returns replicas that confirmed write.
+    fn write(self: &Arc<Self>, id: &SpanId, data: Arc<Vec<u8>>, prepend: bool, version: u64, replicas: &[usize], wait_for: usize) -> Vec<usize> {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(id) {
+            for &replica in replicas {
+                span.in_flight_versions[replica] = Some(version);
+            }
+        }
+
+        let (results_sender, results) = channel();
+        for &replica in replicas {
+            let state = self.clone();
+            let id = id.clone();
+            let data = data.clone();
+
+            self.targets[replica].submit(replica, results_sender.clone(), move |target| {
+                let _in_flight = InFlightWriteGuard { state: &state, span_id: &id, replica, version };
+                target.swap_out(id.clone(), &data, prepend);
+                state.on_write_completed(&id, replica, prepend, version);
+            });
+        }
+        drop(results_sender);
+
+        let mut succeeded = Vec::new();
+        for (replica, result) in results.iter() {
+            if result.is_some() {
+                succeeded.push(replica);
+                if succeeded.len() == wait_for {
+                    break;
+                }
+            } else {
+                self.replicas.lock().unwrap()[replica].is_healthy = false;
+            }
+        }
+        succeeded
+    }
+
+    // runs on replica worker, so it is ordered with all other operations on this replica.
+    fn on_write_completed(&self, id: &SpanId, replica: usize, prepend: bool, version: u64) {
+        let mut spans = self.spans.lock().unwrap();
+        if let Some(span) = spans.get_mut(id) {
+            let replica_version = &mut span.replica_versions[replica];
+            if !prepend || *replica_version == Some(version - 1) {
+                *replica_version = Some(version);
+            }
+        }
+        self.replicas.lock().unwrap()[replica].is_healthy = true;
+    }
+
+    fn remove_from_replicas(&self, id: &SpanId, except: Option<usize>) {
+        // not waiting for completion: following operations on this span are queued after removal on every replica.
+        let (results_sender, _) = channel();
+        for replica in 0..self.targets.len() {
+            if Some(replica) != except {
+                let id = id.clone();
+                self.targets[replica].submit(replica, results_sender.clone(), move |target| target.remove(&id));
+            }
+        }
+    }
+
+    fn run_on_all_replicas(&self, task: fn(&dyn FarMemoryBackend)) {
+        let (results_sender, results) = channel();
+        for replica in 0..self.targets.len() {
+            self.targets[replica].submit(replica, results_sender.clone(), task);
+        }
+        drop(results_sender);
+        results.iter().for_each(drop);
+    }
+
+    fn read_candidates(&self, span: &SpanReplicas) -> Vec<usize> {
+        let replicas = self.replicas.lock().unwrap();
+        let mut candidates: Vec<usize> = (0..self.targets.len())
+            .filter(|replica| span.replica_versions[*replica] == Some(span.version))
+            .collect();
+        candidates.sort_by_key(|replica| (!replicas[*replica].is_healthy, replicas[*replica].latency));
+        candidates
+    }
+
+    fn read_from(&self, id: &SpanId, replica: usize) -> Option<Vec<u8>> {
+        let started_at = Instant::now();
+        let (results_sender, results) = channel();
+        let span_id = id.clone();
+        self.targets[replica].submit(replica, results_sender, move |target| target.swap_in(&span_id));
+        let result = results.recv().unwrap().1;
+
+        let mut replicas = self.replicas.lock().unwrap();
+        let stats = &mut replicas[replica];
+        stats.is_healthy = result.is_some();
+        if result.is_some() {
+            stats.latency = (stats.latency * 7 + started_at.elapsed()) / 8;
+        }
+
+        result
+    }
+
+    fn repair(self: &Arc<Self>) {
+        let stale_spans: Vec<SpanId> = self.spans.lock().unwrap().iter()
+            .filter(|(_, span)| !span.stale_replicas().is_empty())
+            .map(|(span_id, _)| span_id.clone())
+            .collect();
+
+        for span_id in stale_spans {
+            if !self.is_running.load(Ordering::Relaxed) {
+                return;
+            }
+
+            span!(Level::DEBUG, "replication repair").in_scope(|| self.repair_span(&span_id));
+        }
+    }
+
+    fn repair_span(self: &Arc<Self>, id: &SpanId) {
+        let _guard = self.lock_span(id);
+        let span = match self.spans.lock().unwrap().get(id) {
+            Some(v) => v.clone(),
+            None => return,
+        };
+
+        // replicas that failed recent repairs are skipped until their backoff expires.
+        let now = Instant::now();
+        let stale: Vec<usize> = {
+            let replicas = self.replicas.lock().unwrap();
+            span.stale_replicas().into_iter().filter(|replica| replicas[*replica].next_repair_at <= now).collect()
+        };
+        if stale.is_empty() {
+            return;
+        }
+
+        // there is no way to read span without removing it, so it is written back to the source replica as well.
+        let data = self.read_candidates(&span).into_iter().find_map(|replica| self.read_from(id, replica).map(|data| (replica, data)));
+        let (source, data) = match data {
+            Some(v) => v,
+            None => {
+                warn!("no up-to-date replica is available to repair span {}", id.id());
+                return;
+            }
+        };
+        // source has no data until it is written back.
+        if let Some(span) = self.spans.lock().unwrap().get_mut(id) {
+            span.replica_versions[source] = None;
+        }
+
+        let mut replicas = stale;
+        replicas.push(source);
+        let succeeded = self.write(id, Arc::new(data), false, span.version, &replicas, replicas.len());
+
+        let mut stats = self.replicas.lock().unwrap();
+        for replica in replicas {
+            let stats = &mut stats[replica];
+            if succeeded.contains(&replica) {
+                stats.repair_backoff = REPAIR_INTERVAL;
+            } else {
+                warn!("failed to repair span {} on replica {}, retrying in {:?}", id.id(), replica, stats.repair_backoff);
+                stats.next_repair_at = Instant::now() + stats.repair_backoff;
+                stats.repair_backoff = (stats.repair_backoff * 2).min(MAX_REPAIR_BACKOFF);
+            }
+        }
+    }
+}
+
+impl SpanReplicas {
+    // replicas that missed writes. Replicas with writes still in progress are not stale yet.
+    fn stale_replicas(&self) -> Vec<usize> {
+        (0..self.replica_versions.len())
+            .filter(|replica| self.replica_versions[*replica] != Some(self.version) && self.in_flight_versions[*replica].is_none())
+            .collect()
+    }
+}
+
+impl Drop for BusySpanGuard<'_> {
+    fn drop(&mut self) {
+        self.state.busy_spans.lock().unwrap().remove(&self.span_id);
+        self.state.busy_spans_condvar.notify_all();
+    }
+}
+
+impl Drop for InFlightWriteGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(span) = self.state.spans.lock().unwrap().get_mut(self.span_id) {
+            if span.in_flight_versions[self.replica] == Some(self.version) {
+                span.in_flight_versions[self.replica] = None;
+            }
+        }
+    }
 }
 
 impl FarMemoryBackend for ReplicationBackend {
     fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
         span!(Level::DEBUG, "replication write").in_scope(|| {
-            // should be parallel, but keeping everything in one thread for now for simplicity
-            for target in &self.targets {
-                target.swap_out(id.clone(), span, prepend);
+            let state = &self.state;
+            let _guard = state.lock_span(&id);
+
+            let version = {
+                let mut spans = state.spans.lock().unwrap();
+                let span_replicas = spans.entry(id.clone()).or_insert_with(|| SpanReplicas {
+                    version: 0,
+                    replica_versions: vec![None; state.targets.len()],
+                    in_flight_versions: vec![None; state.targets.len()],
+                });
+                span_replicas.version += 1;
+                span_replicas.version
+            };
+
+            let all_replicas: Vec<usize> = (0..state.targets.len()).collect();
+            let write_quorum = state.write_quorum();
+            let succeeded = state.write(&id, Arc::new(span.to_vec()), prepend, version, &all_replicas, write_quorum).len();
+            if succeeded < write_quorum {
+                panic!("write quorum is not reached for span {}: {} out of {} replicas confirmed write", id.id(), succeeded, write_quorum);
             }
-        });
+        })
     }
 
     fn swap_in(&self, id: &SpanId) -> Vec<u8> {
-        // can be optimized to run in parallel or to read from one and delete from another
         span!(Level::DEBUG, "replication read").in_scope(|| {
-            let res = self.targets[0].swap_in(id);
-            for target in &self.targets[1..] {
-                target.swap_in(id); // dropped intentionally (see note above)
+            let state = &self.state;
+            let _guard = state.lock_span(id);
+            let span = state.spans.lock().unwrap().get(id).unwrap().clone();
+
+            for replica in state.read_candidates(&span) {
+                let data = match state.read_from(id, replica) {
+                    Some(v) => v,
+                    None => continue,
+                };
+
+                // write may have failed on this replica after candidates were chosen.
+                if state.spans.lock().unwrap()[id].replica_versions[replica] != Some(span.version) {
+                    continue;
+                }
+
+                state.spans.lock().unwrap().remove(id);
+                state.remove_from_replicas(id, Some(replica));
+                return data;
             }
-            res
+
+            panic!("failed to read span {} from any replica", id.id());
         })
     }
+
+    fn remove(&self, id: &SpanId) {
+        let _guard = self.state.lock_span(id);
+        self.state.spans.lock().unwrap().remove(id);
+        self.state.remove_from_replicas(id, None);
+    }
+
+    fn on_stop(&self) {
+        self.state.run_on_all_replicas(|target| target.on_stop());
+    }
+}
+
+impl Drop for ReplicationBackend {
+    fn drop(&mut self) {
+        self.stop_repair_thread();
+        // background writes hold references to state, and should not be the ones to drop it on worker thread.
+        self.state.run_on_all_replicas(|_| {});
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::sync::atomic::AtomicUsize,
+        crate::client::InMemoryBackend,
+        super::{*, super::fault_injection::FaultInjectionBackend},
+    };
+
+    struct FlakyBackend {
+        inner: InMemoryBackend,
+        is_down: Arc<AtomicBool>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    impl FlakyBackend {
+        fn check_is_up(&self) {
+            if self.is_down.load(Ordering::Relaxed) {
+                self.failures.fetch_add(1, Ordering::Relaxed);
+                panic!("storage node is down");
+            }
+        }
+    }
+
+    impl FarMemoryBackend for FlakyBackend {
+        fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+            self.check_is_up();
+            self.inner.swap_out(id, span, prepend);
+        }
+
+        fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+            self.check_is_up();
+            self.inner.swap_in(id)
+        }
+
+        fn remove(&self, id: &SpanId) {
+            self.inner.remove(id);
+        }
+    }
+
+    fn flaky_backend() -> (Box<dyn FarMemoryBackend>, Arc<AtomicBool>, Arc<AtomicUsize>) {
+        let is_down = Arc::new(AtomicBool::new(false));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let backend = FlakyBackend {
+            inner: InMemoryBackend::new(),
+            is_down: is_down.clone(),
+            failures: failures.clone(),
+        };
+        (Box::new(backend), is_down, failures)
+    }
+
+    #[test]
+    fn simple_swap_out_swap_in() {
+        let backend = ReplicationBackend::new(vec![
+            Box::new(InMemoryBackend::new()),
+            Box::new(InMemoryBackend::new()),
+            Box::new(InMemoryBackend::new()),
+        ]);
+
+        backend.swap_out(SpanId::from_id(42), &[1, 2, 3], false);
+        backend.swap_out(SpanId::from_id(42), &[4, 5], true);
+
+        assert_eq!(vec![4, 5, 1, 2, 3], backend.swap_in(&SpanId::from_id(42)));
+    }
+
+    #[test]
+    fn read_fails_over_to_other_replica() {
+        let (first, first_is_down, _) = flaky_backend();
+        let backend = ReplicationBackend::new(vec![first, Box::new(InMemoryBackend::new())])
+            .with_write_quorum(2);
+
+        backend.swap_out(SpanId::from_id(42), &[1, 2, 3], false);
+        first_is_down.store(true, Ordering::Relaxed);
+
+        assert_eq!(vec![1, 2, 3], backend.swap_in(&SpanId::from_id(42)));
+    }
+
+    #[test]
+    fn stale_replica_is_repaired() {
+        let (first, first_is_down, _) = flaky_backend();
+        let (second, second_is_down, _) = flaky_backend();
+        let (third, third_is_down, _) = flaky_backend();
+        let backend = ReplicationBackend::new(vec![first, second, third]).without_background_repair();
+
+        backend.swap_out(SpanId::from_id(42), &[1, 2, 3], false);
+        first_is_down.store(true, Ordering::Relaxed);
+        backend.swap_out(SpanId::from_id(42), &[4, 5], true);
+
+        // write to the first replica may still be in progress after quorum is reached.
+        while backend.stale_replicas() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        first_is_down.store(false, Ordering::Relaxed);
+        assert_eq!(1, backend.stale_replicas());
+
+        backend.repair();
+        assert_eq!(0, backend.stale_replicas());
+
+        // first replica has to be used, because others are down.
+        second_is_down.store(true, Ordering::Relaxed);
+        third_is_down.store(true, Ordering::Relaxed);
+        assert_eq!(vec![4, 5, 1, 2, 3], backend.swap_in(&SpanId::from_id(42)));
+    }
+
+    #[test]
+    fn unreachable_replica_is_repaired_with_backoff() {
+        let (first, first_is_down, first_failures) = flaky_backend();
+        let backend = ReplicationBackend::new(vec![first, Box::new(InMemoryBackend::new()), Box::new(InMemoryBackend::new())])
+            .with_write_quorum(2)
+            .without_background_repair();
+
+        first_is_down.store(true, Ordering::Relaxed);
+        backend.swap_out(SpanId::from_id(42), &[1, 2, 3], false);
+        while backend.stale_replicas() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // first repair attempt fails, and the next one is not made until backoff expires.
+        backend.repair();
+        assert_eq!(2, first_failures.load(Ordering::Relaxed));
+        backend.repair();
+        assert_eq!(2, first_failures.load(Ordering::Relaxed));
+        assert_eq!(1, backend.stale_replicas());
+
+        assert_eq!(vec![1, 2, 3], backend.swap_in(&SpanId::from_id(42)));
+    }
+
+    #[test]
+    fn write_quorum_is_kept_when_targets_are_added() {
+        let backend = ReplicationBackend::empty()
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_write_quorum(1)
+            .with_target(Box::new(InMemoryBackend::new()))
+            .with_target(Box::new(InMemoryBackend::new()))
+            .without_background_repair();
+
+        assert_eq!(1, backend.state.write_quorum());
+    }
+
+    #[test]
+    fn recover_from_injected_faults() {
+        let dropping = FaultInjectionBackend::new(Box::new(InMemoryBackend::new())).with_seed(1).with_drop_rate(1.0);
+        let failing = FaultInjectionBackend::new(Box::new(InMemoryBackend::new())).with_seed(2);
+        let failing_controller = failing.controller();
+        let backend = ReplicationBackend::new(vec![Box::new(dropping), Box::new(failing), Box::new(InMemoryBackend::new())])
+            .with_write_quorum(3);
+
+        backend.swap_out(SpanId::from_id(42), &[1, 2, 3], false);
+        failing_controller.set_error_rate(1.0);
+
+        // first replica lost the write and second one fails, so span is read from the third.
+        assert_eq!(vec![1, 2, 3], backend.swap_in(&SpanId::from_id(42)));
+    }
 }
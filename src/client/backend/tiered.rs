@@ -0,0 +1,628 @@
+use {
+    std::{
+        sync::{Arc, Mutex, Condvar, OnceLock, atomic::{AtomicBool, Ordering}},
+        panic::{self, AssertUnwindSafe},
+        collections::{BTreeMap, HashMap, HashSet},
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+    tracing::{span, Level, warn},
+    prometheus::{
+        Registry,
+        IntCounterVec,
+        IntGaugeVec,
+        CounterVec,
+        register_int_counter_vec_with_registry,
+        register_int_gauge_vec_with_registry,
+        register_counter_vec_with_registry,
+    },
+    crate::client::span::SpanId,
+    super::FarMemoryBackend,
+};
+
+const MIGRATION_INTERVAL: Duration = Duration::from_millis(100);
+// tiers are demoted down to this share of capacity, so that there is space for new spans...
+const HIGH_WATERMARK: f64 = 0.9;
+// ...and are promoted to only up to this share, so that spans do not move back and forth.
+const LOW_WATERMARK: f64 = 0.8;
+
+/**
+ * Composes an ordered list of tiers, from the fastest to the slowest, each with its own capacity. The last tier is
+ * expected to be large enough for all spans.
+ *
+ * Spans are placed by recency: new spans are swapped out to the first tier with free space. In background, the least
+ * recently swapped out spans are demoted from tiers that are close to full, and the most recently swapped out spans
+ * are promoted to faster tiers that have free space. If a span cannot be written to the other tier, it is written back
+ * to the tier it was read from.
+ */
+pub struct TieredBackend {
+    state: Arc<TieredState>,
+    // started on first write, so that tiers can be added before that.
+    migration_thread: OnceLock<JoinHandle<()>>,
+}
+
+struct TieredState {
+    tiers: Vec<Tier>,
+    background_migration: bool,
+    is_running: AtomicBool,
+
+    placement: Mutex<Placement>,
+    // spans with operation in progress, so that migration does not interfere with swap in and swap out.
+    busy_spans: Mutex<HashSet<SpanId>>,
+    busy_spans_condvar: Condvar,
+
+    metrics: Option<TierMetrics>,
+}
+
+struct Tier {
+    name: String,
+    backend: Box<dyn FarMemoryBackend>,
+    capacity: u64,
+}
+
+struct Placement {
+    spans: HashMap<SpanId, SpanPlacement>,
+    next_tick: u64,
+    // spans of every tier by swap out tick, least recent first.
+    tier_spans: Vec<BTreeMap<u64, SpanId>>,
+    tier_bytes: Vec<u64>,
+
+    tier_swap_ins: Vec<u64>,
+    tier_swap_in_time: Vec<Duration>,
+}
+
+#[derive(Clone)]
+struct SpanPlacement {
+    tier: usize,
+    size: u64,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TierStats {
+    pub name: String,
+    pub capacity: u64,
+    pub spans: usize,
+    pub bytes: u64,
+    pub swap_ins: u64,
+    pub average_swap_in_latency: Duration,
+}
+
+struct TierMetrics {
+    registry: Registry,
+
+    spans: IntGaugeVec,
+    bytes: IntGaugeVec,
+    swap_ins: IntCounterVec,
+    swap_in_time_ms: CounterVec,
+    promotions: IntCounterVec,
+    demotions: IntCounterVec,
+}
+
+struct BusySpanGuard<'a> {
+    state: &'a TieredState,
+    span_id: SpanId,
+}
+
+impl TieredBackend {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(TieredState {
+                tiers: Vec::new(),
+                background_migration: true,
+                is_running: AtomicBool::new(true),
+
+                placement: Mutex::new(Placement {
+                    spans: HashMap::new(),
+                    next_tick: 0,
+                    tier_spans: Vec::new(),
+                    tier_bytes: Vec::new(),
+
+                    tier_swap_ins: Vec::new(),
+                    tier_swap_in_time: Vec::new(),
+                }),
+                busy_spans: Mutex::new(HashSet::new()),
+                busy_spans_condvar: Condvar::new(),
+
+                metrics: None,
+            }),
+            migration_thread: OnceLock::new(),
+        }
+    }
+
+    // tiers are ordered from the fastest to the slowest.
+    pub fn with_tier(mut self, name: &str, backend: Box<dyn FarMemoryBackend>, capacity: u64) -> Self {
+        let state = self.state_mut();
+        state.tiers.push(Tier {
+            name: name.to_owned(),
+            backend,
+            capacity,
+        });
+
+        let placement = state.placement.get_mut().unwrap();
+        placement.tier_spans.push(BTreeMap::new());
+        placement.tier_bytes.push(0);
+        placement.tier_swap_ins.push(0);
+        placement.tier_swap_in_time.push(Duration::ZERO);
+
+        self
+    }
+
+    pub fn with_metrics(mut self, registry: Registry) -> Self {
+        self.state_mut().metrics = Some(TierMetrics::new(registry));
+        self
+    }
+
+    // spans are then only moved between tiers by calling `migrate`.
+    pub fn with_background_migration(mut self, background_migration: bool) -> Self {
+        self.state_mut().background_migration = background_migration;
+        self
+    }
+
+    pub fn stats(&self) -> Vec<TierStats> {
+        let placement = self.state.placement.lock().unwrap();
+        self.state.tiers.iter().enumerate()
+            .map(|(index, tier)| TierStats {
+                name: tier.name.clone(),
+                capacity: tier.capacity,
+                spans: placement.tier_spans[index].len(),
+                bytes: placement.tier_bytes[index],
+                swap_ins: placement.tier_swap_ins[index],
+                average_swap_in_latency: if placement.tier_swap_ins[index] > 0 {
+                    placement.tier_swap_in_time[index] / placement.tier_swap_ins[index] as u32
+                } else {
+                    Duration::ZERO
+                },
+            })
+            .collect()
+    }
+
+    // moves spans between tiers now, without waiting for background migration.
+    pub fn migrate(&self) {
+        self.state.migrate();
+    }
+
+    fn state_mut(&mut self) -> &mut TieredState {
+        Arc::get_mut(&mut self.state).expect("tiered backend can only be configured before use")
+    }
+
+    fn start_migration_thread(&self) {
+        if !self.state.background_migration {
+            return;
+        }
+
+        self.migration_thread.get_or_init(|| {
+            let state = self.state.clone();
+            thread::Builder::new()
+                .name("tier-migration".to_owned())
+                .spawn(move || {
+                    while state.is_running.load(Ordering::Relaxed) {
+                        thread::park_timeout(MIGRATION_INTERVAL);
+                        // failed tier should not stop migration between other tiers.
+                        if panic::catch_unwind(AssertUnwindSafe(|| state.migrate())).is_err() {
+                            warn!("failed to migrate spans between tiers");
+                        }
+                    }
+                })
+                .unwrap()
+        });
+    }
+}
+
+impl TieredState {
+    fn lock_span(&self, span_id: &SpanId) -> BusySpanGuard<'_> {
+        let mut busy_spans = self.busy_spans.lock().unwrap();
+        while busy_spans.contains(span_id) {
+            busy_spans = self.busy_spans_condvar.wait(busy_spans).unwrap();
+        }
+        busy_spans.insert(span_id.clone());
+
+        BusySpanGuard {
+            state: self,
+            span_id: span_id.clone(),
+        }
+    }
+
+    fn try_lock_span(&self, span_id: &SpanId) -> Option<BusySpanGuard<'_>> {
+        if !self.busy_spans.lock().unwrap().insert(span_id.clone()) {
+            return None;
+        }
+
+        Some(BusySpanGuard {
+            state: self,
+            span_id: span_id.clone(),
+        })
+    }
+
+    fn first_tier_with_space(&self, placement: &Placement, size: u64) -> usize {
+        (0..self.tiers.len())
+            .find(|tier| placement.tier_bytes[*tier] + size <= self.tiers[*tier].capacity)
+            .unwrap_or(self.tiers.len() - 1)
+    }
+
+    fn migrate(&self) {
+        for tier in 0..self.tiers.len().saturating_sub(1) {
+            if !self.is_running.load(Ordering::Relaxed) {
+                return;
+            }
+
+            span!(Level::DEBUG, "demote spans").in_scope(|| self.demote_from(tier));
+            span!(Level::DEBUG, "promote spans").in_scope(|| self.promote_to(tier));
+        }
+    }
+
+    fn demote_from(&self, tier: usize) {
+        let high_watermark = (self.tiers[tier].capacity as f64 * HIGH_WATERMARK) as u64;
+
+        loop {
+            let span_id = {
+                let placement = self.placement.lock().unwrap();
+                if placement.tier_bytes[tier] <= high_watermark {
+                    return;
+                }
+
+                match placement.tier_spans[tier].values().next() {
+                    Some(v) => v.clone(),
+                    None => return,
+                }
+            };
+
+            if !self.move_span(&span_id, tier, tier + 1) {
+                return;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.demotions.with_label_values(&[&self.tiers[tier].name]).inc();
+            }
+        }
+    }
+
+    fn promote_to(&self, tier: usize) {
+        let low_watermark = (self.tiers[tier].capacity as f64 * LOW_WATERMARK) as u64;
+
+        loop {
+            // the most recent span from all slower tiers.
+            let candidate = {
+                let placement = self.placement.lock().unwrap();
+                (tier + 1..self.tiers.len())
+                    .filter_map(|lower_tier| placement.tier_spans[lower_tier].iter().next_back())
+                    .max_by_key(|(tick, _)| **tick)
+                    .map(|(_, span_id)| placement.spans[span_id].clone())
+                    .filter(|span| placement.tier_bytes[tier] + span.size <= low_watermark)
+                    .map(|span| (placement.tier_spans[span.tier][&span.tick].clone(), span.tier))
+            };
+
+            let (span_id, from_tier) = match candidate {
+                Some(v) => v,
+                None => return,
+            };
+
+            if !self.move_span(&span_id, from_tier, tier) {
+                return;
+            }
+            if let Some(metrics) = &self.metrics {
+                metrics.promotions.with_label_values(&[&self.tiers[tier].name]).inc();
+            }
+        }
+    }
+
+    // returns false if span is busy, no longer in `from` tier, or cannot be written to `to` tier.
+    fn move_span(&self, span_id: &SpanId, from: usize, to: usize) -> bool {
+        let _guard = match self.try_lock_span(span_id) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        let span = match self.placement.lock().unwrap().spans.get(span_id) {
+            Some(v) if v.tier == from => v.clone(),
+            _ => return false,
+        };
+
+        // backends cannot read span without removing it, so it is written back to `from` tier if `to` tier fails.
+        let data = self.tiers[from].backend.swap_in(span_id);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.tiers[to].backend.swap_out(span_id.clone(), &data, false)));
+        if result.is_err() {
+            warn!("failed to move span {} from tier {} to tier {}", span_id.id(), self.tiers[from].name, self.tiers[to].name);
+            self.tiers[from].backend.swap_out(span_id.clone(), &data, false);
+            return false;
+        }
+
+        let mut placement = self.placement.lock().unwrap();
+        placement.remove(span_id);
+        placement.insert(span_id.clone(), to, span.size, span.tick);
+        self.update_gauges(&placement);
+
+        true
+    }
+
+    fn update_gauges(&self, placement: &Placement) {
+        if let Some(metrics) = &self.metrics {
+            for (index, tier) in self.tiers.iter().enumerate() {
+                metrics.spans.with_label_values(&[&tier.name]).set(placement.tier_spans[index].len() as i64);
+                metrics.bytes.with_label_values(&[&tier.name]).set(placement.tier_bytes[index] as i64);
+            }
+        }
+    }
+}
+
+impl Placement {
+    fn insert(&mut self, span_id: SpanId, tier: usize, size: u64, tick: u64) {
+        self.tier_spans[tier].insert(tick, span_id.clone());
+        self.tier_bytes[tier] += size;
+        self.spans.insert(span_id, SpanPlacement {
+            tier,
+            size,
+            tick,
+        });
+    }
+
+    fn remove(&mut self, span_id: &SpanId) -> Option<SpanPlacement> {
+        let span = self.spans.remove(span_id)?;
+        self.tier_spans[span.tier].remove(&span.tick);
+        self.tier_bytes[span.tier] -= span.size;
+        Some(span)
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        tick
+    }
+}
+
+impl TierMetrics {
+    fn new(registry: Registry) -> Self {
+        Self {
+            registry: registry.clone(),
+
+            spans: register_int_gauge_vec_with_registry!(
+                "client_tier_spans",
+                "spans stored in tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+            bytes: register_int_gauge_vec_with_registry!(
+                "client_tier_bytes",
+                "bytes stored in tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+            swap_ins: register_int_counter_vec_with_registry!(
+                "client_tier_swap_ins",
+                "total swap ins served by tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+            swap_in_time_ms: register_counter_vec_with_registry!(
+                "client_tier_swap_in_time",
+                "total time spent swapping in from tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+            promotions: register_int_counter_vec_with_registry!(
+                "client_tier_promotions",
+                "spans promoted to tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+            demotions: register_int_counter_vec_with_registry!(
+                "client_tier_demotions",
+                "spans demoted from tier",
+                &["tier"],
+                registry
+            ).unwrap(),
+        }
+    }
+
+    fn unregister(&self) {
+        self.registry.unregister(Box::new(self.spans.clone())).unwrap();
+        self.registry.unregister(Box::new(self.bytes.clone())).unwrap();
+        self.registry.unregister(Box::new(self.swap_ins.clone())).unwrap();
+        self.registry.unregister(Box::new(self.swap_in_time_ms.clone())).unwrap();
+        self.registry.unregister(Box::new(self.promotions.clone())).unwrap();
+        self.registry.unregister(Box::new(self.demotions.clone())).unwrap();
+    }
+}
+
+impl Drop for BusySpanGuard<'_> {
+    fn drop(&mut self) {
+        self.state.busy_spans.lock().unwrap().remove(&self.span_id);
+        self.state.busy_spans_condvar.notify_all();
+    }
+}
+
+impl FarMemoryBackend for TieredBackend {
+    fn swap_out(&self, id: SpanId, span: &[u8], prepend: bool) {
+        self.start_migration_thread();
+        let state = &self.state;
+        let _guard = state.lock_span(&id);
+
+        let (tier, previous_tier) = {
+            let mut placement = state.placement.lock().unwrap();
+            let tick = placement.next_tick();
+            let previous = placement.remove(&id);
+
+            match previous {
+                Some(previous) if prepend => {
+                    placement.insert(id.clone(), previous.tier, previous.size + span.len() as u64, tick);
+                    (previous.tier, None)
+                },
+                previous => {
+                    let tier = state.first_tier_with_space(&placement, span.len() as u64);
+                    placement.insert(id.clone(), tier, span.len() as u64, tick);
+                    (tier, previous.map(|v| v.tier).filter(|v| *v != tier))
+                }
+            }
+        };
+
+        if let Some(previous_tier) = previous_tier {
+            state.tiers[previous_tier].backend.remove(&id);
+        }
+        state.tiers[tier].backend.swap_out(id, span, prepend);
+
+        state.update_gauges(&state.placement.lock().unwrap());
+    }
+
+    fn swap_in(&self, id: &SpanId) -> Vec<u8> {
+        let state = &self.state;
+        let _guard = state.lock_span(id);
+
+        let tier = state.placement.lock().unwrap().spans[id].tier;
+
+        let started_at = Instant::now();
+        let data = state.tiers[tier].backend.swap_in(id);
+        let swap_in_time = started_at.elapsed();
+
+        let mut placement = state.placement.lock().unwrap();
+        placement.remove(id);
+        placement.tier_swap_ins[tier] += 1;
+        placement.tier_swap_in_time[tier] += swap_in_time;
+        if let Some(metrics) = &state.metrics {
+            metrics.swap_ins.with_label_values(&[&state.tiers[tier].name]).inc();
+            metrics.swap_in_time_ms.with_label_values(&[&state.tiers[tier].name]).inc_by(swap_in_time.as_micros() as f64 / 1000.0);
+        }
+        state.update_gauges(&placement);
+
+        data
+    }
+
+    fn remove(&self, id: &SpanId) {
+        let state = &self.state;
+        let _guard = state.lock_span(id);
+
+        let span = state.placement.lock().unwrap().remove(id);
+        if let Some(span) = span {
+            state.tiers[span.tier].backend.remove(id);
+        }
+    }
+
+    fn on_stop(&self) {
+        if let Some(metrics) = &self.state.metrics {
+            metrics.unregister();
+        }
+
+        for tier in &self.state.tiers {
+            tier.backend.on_stop();
+        }
+    }
+}
+
+impl Drop for TieredBackend {
+    fn drop(&mut self) {
+        self.state.is_running.store(false, Ordering::Relaxed);
+        if let Some(migration_thread) = self.migration_thread.take() {
+            migration_thread.thread().unpark();
+            migration_thread.join().unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    struct FailedBackend;
+
+    impl FarMemoryBackend for FailedBackend {
+        fn swap_out(&self, _id: SpanId, _span: &[u8], _prepend: bool) {
+            panic!("storage node is down");
+        }
+
+        fn swap_in(&self, _id: &SpanId) -> Vec<u8> {
+            panic!("storage node is down");
+        }
+    }
+
+    fn tiered_backend(fast_tier_capacity: u64) -> TieredBackend {
+        TieredBackend::new()
+            .with_tier("fast", Box::new(InMemoryBackend::new()), fast_tier_capacity)
+            .with_tier("slow", Box::new(InMemoryBackend::new()), u64::MAX)
+            .with_background_migration(false)
+    }
+
+    fn tier_of(backend: &TieredBackend, span_id: u64) -> usize {
+        backend.state.placement.lock().unwrap().spans[&SpanId::from_id(span_id)].tier
+    }
+
+    #[test]
+    fn spans_are_placed_in_first_tier_with_space() {
+        let backend = tiered_backend(8000);
+        for i in 0..4 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 3000], false);
+        }
+
+        let stats = backend.stats();
+        assert_eq!((2, 6000), (stats[0].spans, stats[0].bytes));
+        assert_eq!((2, 6000), (stats[1].spans, stats[1].bytes));
+
+        backend.swap_out(SpanId::from_id(3), &[42], true);
+        assert_eq!(1, tier_of(&backend, 3));
+
+        assert_eq!([vec![42], vec![3; 3000]].concat(), backend.swap_in(&SpanId::from_id(3)));
+        for i in 0..3 {
+            assert_eq!(vec![i as u8; 3000], backend.swap_in(&SpanId::from_id(i)));
+        }
+
+        let stats = backend.stats();
+        assert_eq!(2, stats[0].swap_ins);
+        assert_eq!(2, stats[1].swap_ins);
+    }
+
+    #[test]
+    fn least_recent_spans_are_demoted() {
+        let backend = tiered_backend(10000);
+        for i in 0..3 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 3000], false);
+        }
+        backend.swap_out(SpanId::from_id(2), &vec![2; 500], true);
+
+        backend.migrate();
+        assert_eq!(1, tier_of(&backend, 0));
+        assert_eq!(0, tier_of(&backend, 1));
+        assert_eq!(0, tier_of(&backend, 2));
+        assert_eq!(vec![0; 3000], backend.swap_in(&SpanId::from_id(0)));
+    }
+
+    #[test]
+    fn most_recent_spans_are_promoted() {
+        let registry = Registry::new();
+        let backend = tiered_backend(10000).with_metrics(registry.clone());
+        for i in 0..5 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 3000], false);
+        }
+        assert_eq!(1, tier_of(&backend, 3));
+        assert_eq!(1, tier_of(&backend, 4));
+
+        backend.swap_in(&SpanId::from_id(0));
+        backend.swap_in(&SpanId::from_id(1));
+        backend.migrate();
+
+        // only one span fits under low watermark, and it is the most recent one.
+        assert_eq!(0, tier_of(&backend, 4));
+        assert_eq!(1, tier_of(&backend, 3));
+        assert_eq!(1, backend.state.metrics.as_ref().unwrap().promotions.with_label_values(&["fast"]).get());
+
+        backend.on_stop();
+        assert!(registry.gather().is_empty());
+    }
+
+    #[test]
+    fn span_stays_in_tier_if_move_fails() {
+        let backend = TieredBackend::new()
+            .with_tier("fast", Box::new(InMemoryBackend::new()), 10000)
+            .with_tier("slow", Box::new(FailedBackend), u64::MAX)
+            .with_background_migration(false);
+        for i in 0..3 {
+            backend.swap_out(SpanId::from_id(i), &vec![i as u8; 3200], false);
+        }
+
+        // fast tier is over high watermark, but the least recent span cannot be demoted.
+        backend.migrate();
+        assert_eq!(0, tier_of(&backend, 0));
+        for i in 0..3 {
+            assert_eq!(vec![i as u8; 3200], backend.swap_in(&SpanId::from_id(i)));
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use {
+    std::{
+        sync::mpsc::{channel, Sender},
+        thread::{self, JoinHandle},
+        panic::{self, AssertUnwindSafe},
+    },
+    super::FarMemoryBackend,
+};
+
+/**
+ * Runs operations on a backend in its own thread. Operations submitted to the same worker are executed in order,
+ * so that backends combining multiple targets can run them in parallel without reordering writes and reads of a span.
+ * Panics in target backend are caught and reported as `None` result, so that failed targets can be tolerated.
+ */
+pub struct BackendWorker {
+    sender: Option<Sender<BackendTask>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+type BackendTask = Box<dyn FnOnce(&dyn FarMemoryBackend) + Send>;
+
+impl BackendWorker {
+    pub fn new(name: String, target: Box<dyn FarMemoryBackend>) -> Self {
+        let (sender, tasks) = channel::<BackendTask>();
+        let worker = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                for task in tasks {
+                    task(target.as_ref());
+                }
+            })
+            .unwrap();
+
+        Self {
+            sender: Some(sender),
+            worker: Some(worker),
+        }
+    }
+
+    // result is sent together with `index`, so that results of multiple workers can be collected from one channel.
+    pub fn submit<R: Send + 'static>(&self, index: usize, results: Sender<(usize, Option<R>)>, task: impl FnOnce(&dyn FarMemoryBackend) -> R + Send + 'static) {
+        self.sender.as_ref().unwrap().send(Box::new(move |target| {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| task(target))).ok();
+            // receiver is gone when operation already completed without waiting for this worker.
+            let _ = results.send((index, result));
+        })).unwrap();
+    }
+}
+
+impl Drop for BackendWorker {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        self.worker.take().unwrap().join().unwrap();
+    }
+}
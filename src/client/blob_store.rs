@@ -0,0 +1,568 @@
+use {
+    std::{collections::HashMap, io::{self, Read, Write, Seek, SeekFrom}, ops::Range, sync::Mutex},
+    tracing::{span, Level},
+    serde::{Serialize, Deserialize},
+    super::{
+        client::FarMemoryClient,
+        span::SpanId,
+    },
+};
+
+const DEFAULT_SPAN_SIZE: usize = 2 * 1024 * 1024; // 2 MB
+
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, Serialize, Deserialize)]
+pub struct BlobId(u64);
+
+impl BlobId {
+    pub fn from_id(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
+/**
+ * stores byte blobs of any size. Small blobs are packed together into shared spans (first fit), large blobs are split
+ * across spans of their own. Space of deleted small blobs is reused by new blobs, and span is freed once it has no blobs
+ * left in it.
+ *
+ * all methods take &self, so store can be shared between threads. Deleting a blob while it is being read is not supported.
+ */
+pub struct FarMemoryBlobStore {
+    client: FarMemoryClient,
+    span_size: usize,
+    small_blob_max_size: usize,
+
+    state: Mutex<BlobStoreState>,
+}
+
+struct BlobStoreState {
+    next_blob_id: u64,
+    blobs: HashMap<BlobId, BlobLocation>,
+    pack_spans: HashMap<SpanId, PackSpan>,
+}
+
+#[derive(Clone)]
+enum BlobLocation {
+    Packed {
+        span_id: SpanId,
+        offset: usize,
+        len: usize,
+    },
+    Chunked {
+        spans: Vec<SpanId>,
+        len: usize,
+    },
+    // empty blobs do not take any space, so they are not stored in spans
+    Empty,
+}
+
+struct PackSpan {
+    free_extents: Vec<Range<usize>>, // sorted and merged
+    used: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FarMemoryBlobStoreStats {
+    pub blobs: usize,
+    pub small_blobs: usize,
+    pub large_blobs: usize,
+    pub stored_bytes: usize, // total length of all blobs
+    pub allocated_bytes: usize, // total size of all spans used by the store
+
+    pub pack_spans: usize,
+    pub pack_free_bytes: usize,
+    pub pack_largest_free_extent: usize,
+    // share of free space in pack spans that is outside of the largest free extent of its span, so it can only fit
+    // blobs smaller than that extent (0 - no fragmentation)
+    pub pack_fragmentation: f64,
+    // unused space in the last spans of large blobs
+    pub large_blobs_tail_waste: usize,
+}
+
+impl BlobLocation {
+    fn len(&self) -> usize {
+        match self {
+            Self::Packed { len, .. } => *len,
+            Self::Chunked { len, .. } => *len,
+            Self::Empty => 0,
+        }
+    }
+}
+
+impl PackSpan {
+    fn allocate(&mut self, len: usize) -> Option<usize> {
+        let index = self.free_extents.iter().position(|v| v.len() >= len)?;
+        let offset = self.free_extents[index].start;
+        self.free_extents[index].start += len;
+        if self.free_extents[index].is_empty() {
+            self.free_extents.remove(index);
+        }
+        self.used += len;
+        Some(offset)
+    }
+
+    fn release(&mut self, extent: Range<usize>) {
+        self.used -= extent.len();
+
+        let index = self.free_extents.partition_point(|v| v.start < extent.start);
+        self.free_extents.insert(index, extent);
+
+        // merge with neighbours
+        if index + 1 < self.free_extents.len() && self.free_extents[index].end == self.free_extents[index + 1].start {
+            self.free_extents[index].end = self.free_extents.remove(index + 1).end;
+        }
+        if index > 0 && self.free_extents[index - 1].end == self.free_extents[index].start {
+            self.free_extents[index - 1].end = self.free_extents.remove(index).end;
+        }
+    }
+}
+
+impl FarMemoryBlobStore {
+    pub fn new(client: FarMemoryClient) -> Self {
+        Self::with_span_size(client, DEFAULT_SPAN_SIZE)
+    }
+
+    // blobs up to 1/8 of span size are packed together, larger ones get spans of their own.
+    pub fn with_span_size(client: FarMemoryClient, span_size: usize) -> Self {
+        Self {
+            client,
+            span_size,
+            small_blob_max_size: span_size / 8,
+
+            state: Mutex::new(BlobStoreState {
+                next_blob_id: 0,
+                blobs: HashMap::new(),
+                pack_spans: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn put(&self, data: &[u8]) -> BlobId {
+        if data.len() <= self.small_blob_max_size {
+            return self.put_packed(data);
+        }
+
+        let mut writer = self.writer();
+        writer.write_all(data).unwrap();
+        writer.finish()
+    }
+
+    // blob is written in parts, without having to keep all of it in local memory.
+    pub fn writer(&self) -> FarMemoryBlobWriter<'_> {
+        FarMemoryBlobWriter {
+            store: self,
+            spans: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn get(&self, id: &BlobId) -> Option<Vec<u8>> {
+        let mut reader = self.reader(id)?;
+        let mut data = vec![0; reader.len()];
+        reader.read_exact(&mut data).unwrap();
+        Some(data)
+    }
+
+    pub fn reader(&self, id: &BlobId) -> Option<FarMemoryBlobReader<'_>> {
+        let location = self.state.lock().unwrap().blobs.get(id)?.clone();
+        Some(FarMemoryBlobReader {
+            store: self,
+            location,
+            position: 0,
+        })
+    }
+
+    pub fn blob_len(&self, id: &BlobId) -> Option<usize> {
+        self.state.lock().unwrap().blobs.get(id).map(|v| v.len())
+    }
+
+    pub fn contains(&self, id: &BlobId) -> bool {
+        self.state.lock().unwrap().blobs.contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().blobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn delete(&self, id: &BlobId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let location = match state.blobs.remove(id) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        match location {
+            BlobLocation::Packed { span_id, offset, len } => {
+                let pack_span = state.pack_spans.get_mut(&span_id).unwrap();
+                pack_span.release(offset..(offset + len));
+                if pack_span.used == 0 {
+                    state.pack_spans.remove(&span_id);
+                    self.client.free_span(&span_id);
+                }
+            },
+            BlobLocation::Chunked { spans, .. } => {
+                for span_id in &spans {
+                    self.client.free_span(span_id);
+                }
+            },
+            BlobLocation::Empty => {},
+        }
+
+        true
+    }
+
+    pub fn stats(&self) -> FarMemoryBlobStoreStats {
+        let state = self.state.lock().unwrap();
+        let mut stats = FarMemoryBlobStoreStats::default();
+
+        for location in state.blobs.values() {
+            stats.blobs += 1;
+            stats.stored_bytes += location.len();
+
+            match location {
+                BlobLocation::Packed { .. } | BlobLocation::Empty => stats.small_blobs += 1,
+                BlobLocation::Chunked { spans, len } => {
+                    stats.large_blobs += 1;
+                    stats.allocated_bytes += spans.len() * self.span_size;
+                    stats.large_blobs_tail_waste += spans.len() * self.span_size - len;
+                },
+            }
+        }
+
+        let mut largest_free_extents_total = 0;
+        for pack_span in state.pack_spans.values() {
+            let largest_free_extent = pack_span.free_extents.iter().map(|v| v.len()).max().unwrap_or(0);
+
+            stats.pack_spans += 1;
+            stats.allocated_bytes += self.span_size;
+            stats.pack_free_bytes += self.span_size - pack_span.used;
+            stats.pack_largest_free_extent = stats.pack_largest_free_extent.max(largest_free_extent);
+            largest_free_extents_total += largest_free_extent;
+        }
+
+        if stats.pack_free_bytes > 0 {
+            stats.pack_fragmentation = 1.0 - largest_free_extents_total as f64 / stats.pack_free_bytes as f64;
+        }
+
+        stats
+    }
+
+    // space is reserved under the lock, while span allocation and copying (which may need to swap in) happen outside of it.
+    // Reserved space counts as used, so the span is not freed before the blob is inserted.
+    fn put_packed(&self, data: &[u8]) -> BlobId {
+        if data.is_empty() {
+            return self.insert_blob(&mut self.state.lock().unwrap(), BlobLocation::Empty);
+        }
+
+        let allocated = self.state.lock().unwrap().pack_spans.iter_mut()
+            .find_map(|(span_id, pack_span)| pack_span.allocate(data.len()).map(|offset| (span_id.clone(), offset)));
+        let (span_id, offset) = match allocated {
+            Some(v) => v,
+            None => span!(Level::DEBUG, "FarMemoryBlobStore - new pack span").in_scope(|| {
+                let span_id = self.client.allocate_span(self.span_size);
+                let mut pack_span = PackSpan {
+                    free_extents: vec![0..self.span_size],
+                    used: 0,
+                };
+                let offset = pack_span.allocate(data.len()).unwrap();
+                self.state.lock().unwrap().pack_spans.insert(span_id.clone(), pack_span);
+                (span_id, offset)
+            }),
+        };
+
+        let ptr = self.client.span_ptr(&span_id);
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.add(offset), data.len());
+        }
+        self.client.decrease_refs_for_span(&span_id);
+
+        self.insert_blob(&mut self.state.lock().unwrap(), BlobLocation::Packed { span_id, offset, len: data.len() })
+    }
+
+    fn insert_blob(&self, state: &mut BlobStoreState, location: BlobLocation) -> BlobId {
+        let id = BlobId(state.next_blob_id);
+        state.next_blob_id += 1;
+        state.blobs.insert(id, location);
+        id
+    }
+}
+
+impl Drop for FarMemoryBlobStore {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        for span_id in state.pack_spans.keys() {
+            self.client.free_span(span_id);
+        }
+        for location in state.blobs.values() {
+            if let BlobLocation::Chunked { spans, .. } = location {
+                for span_id in spans {
+                    self.client.free_span(span_id);
+                }
+            }
+        }
+    }
+}
+
+pub struct FarMemoryBlobWriter<'a> {
+    store: &'a FarMemoryBlobStore,
+    spans: Vec<SpanId>,
+    len: usize,
+}
+
+impl<'a> FarMemoryBlobWriter<'a> {
+    pub fn finish(mut self) -> BlobId {
+        let spans = std::mem::take(&mut self.spans);
+
+        if self.len <= self.store.small_blob_max_size {
+            // blob turned out to be small, so it is moved to a shared span
+            let mut data = vec![0; self.len];
+            if let Some(span_id) = spans.first() {
+                let ptr = self.store.client.span_ptr(span_id);
+                unsafe {
+                    std::ptr::copy_nonoverlapping(ptr, data.as_mut_ptr(), self.len);
+                }
+                self.store.client.decrease_refs_for_span(span_id);
+            }
+            for span_id in &spans {
+                self.store.client.free_span(span_id);
+            }
+            return self.store.put_packed(&data);
+        }
+
+        let mut state = self.store.state.lock().unwrap();
+        self.store.insert_blob(&mut state, BlobLocation::Chunked { spans, len: self.len })
+    }
+}
+
+impl<'a> Write for FarMemoryBlobWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let span_size = self.store.span_size;
+        if self.len == self.spans.len() * span_size {
+            self.spans.push(self.store.client.allocate_span(span_size));
+        }
+
+        let span_id = &self.spans[self.len / span_size];
+        let offset = self.len % span_size;
+        let to_write = buf.len().min(span_size - offset);
+
+        let ptr = self.store.client.span_ptr(span_id);
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), ptr.add(offset), to_write);
+        }
+        self.store.client.decrease_refs_for_span(span_id);
+
+        self.len += to_write;
+        Ok(to_write)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Drop for FarMemoryBlobWriter<'a> {
+    fn drop(&mut self) {
+        // writer was dropped without finishing
+        for span_id in &self.spans {
+            self.store.client.free_span(span_id);
+        }
+    }
+}
+
+pub struct FarMemoryBlobReader<'a> {
+    store: &'a FarMemoryBlobStore,
+    location: BlobLocation,
+    position: usize,
+}
+
+impl<'a> FarMemoryBlobReader<'a> {
+    pub fn len(&self) -> usize {
+        self.location.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Read for FarMemoryBlobReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len().saturating_sub(self.position);
+        if remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let span_size = self.store.span_size;
+        let (span_id, offset, available) = match &self.location {
+            BlobLocation::Packed { span_id, offset, .. } => (span_id, offset + self.position, remaining),
+            BlobLocation::Empty => unreachable!("empty blob has nothing to read"),
+            BlobLocation::Chunked { spans, .. } => {
+                let offset = self.position % span_size;
+                (&spans[self.position / span_size], offset, remaining.min(span_size - offset))
+            },
+        };
+        let to_read = buf.len().min(available);
+
+        let ptr = self.store.client.span_ptr(span_id);
+        unsafe {
+            std::ptr::copy_nonoverlapping(ptr.add(offset), buf.as_mut_ptr(), to_read);
+        }
+        self.store.client.decrease_refs_for_span(span_id);
+
+        self.position += to_read;
+        Ok(to_read)
+    }
+}
+
+impl<'a> Seek for FarMemoryBlobReader<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => (self.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.position as u64).checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position as usize;
+                Ok(position)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    fn test_data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn small_and_large_blobs() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let store = FarMemoryBlobStore::with_span_size(client.clone(), 64 * 1024);
+
+        let small: Vec<_> = (0..20).map(|i| store.put(&test_data(1000 + i, i as u8))).collect();
+        let large = store.put(&test_data(200 * 1024, 7));
+        let empty = store.put(&[]);
+
+        let stats = store.stats();
+        assert_eq!(1, stats.pack_spans);
+        assert_eq!(1, stats.large_blobs);
+        assert_eq!(4 * 64 * 1024 - 200 * 1024, stats.large_blobs_tail_waste);
+
+        let spans: Vec<_> = {
+            let state = store.state.lock().unwrap();
+            state.pack_spans.keys().cloned().chain(state.blobs.values().flat_map(|v| match v {
+                BlobLocation::Chunked { spans, .. } => spans.clone(),
+                _ => vec![],
+            })).collect()
+        };
+        client.swap_out_spans_fully(&spans);
+        assert_eq!(0, client.total_local_memory());
+        for (i, id) in small.iter().enumerate() {
+            assert_eq!(Some(test_data(1000 + i, i as u8)), store.get(id));
+        }
+        assert_eq!(Some(test_data(200 * 1024, 7)), store.get(&large));
+        assert_eq!(Some(vec![]), store.get(&empty));
+    }
+
+    #[test]
+    fn streaming_write_and_read() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let store = FarMemoryBlobStore::with_span_size(client, 64 * 1024);
+        let data = test_data(300 * 1024, 1);
+
+        let mut writer = store.writer();
+        for part in data.chunks(10_000) {
+            writer.write_all(part).unwrap();
+        }
+        let id = writer.finish();
+
+        let mut reader = store.reader(&id).unwrap();
+        reader.seek(SeekFrom::Start(100 * 1024 - 10)).unwrap();
+        let mut buf = vec![0; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&data[(100 * 1024 - 10)..(100 * 1024 + 10)], &buf[..]);
+
+        let mut result = Vec::new();
+        store.reader(&id).unwrap().read_to_end(&mut result).unwrap();
+        assert_eq!(data, result);
+    }
+
+    #[test]
+    fn delete_reclaims_space() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let store = FarMemoryBlobStore::with_span_size(client.clone(), 64 * 1024);
+
+        let ids: Vec<_> = (0..8).map(|i| store.put(&test_data(8 * 1024, i))).collect();
+        assert_eq!(1, store.stats().pack_spans);
+
+        store.delete(&ids[1]);
+        store.delete(&ids[3]);
+        let stats = store.stats();
+        assert_eq!(16 * 1024, stats.pack_free_bytes);
+        assert_eq!(0.5, stats.pack_fragmentation);
+
+        // freed space is reused
+        let id = store.put(&test_data(8 * 1024, 42));
+        assert_eq!(1, store.stats().pack_spans);
+        assert_eq!(Some(test_data(8 * 1024, 42)), store.get(&id));
+
+        for id in ids.iter().chain(std::iter::once(&id)) {
+            store.delete(id);
+        }
+        assert!(!store.delete(&ids[0]));
+        assert_eq!(0, store.stats().pack_spans);
+        assert_eq!(0, client.total_local_spans());
+    }
+
+    #[test]
+    fn fragmentation_is_per_span() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let store = FarMemoryBlobStore::with_span_size(client, 64 * 1024);
+
+        let ids: Vec<_> = (0..16).map(|i| store.put(&test_data(8 * 1024, i))).collect();
+        assert_eq!(2, store.stats().pack_spans);
+
+        // one free extent in each span, so any of them fits a blob of that size
+        store.delete(&ids[1]);
+        store.delete(&ids[9]);
+        let stats = store.stats();
+        assert_eq!(16 * 1024, stats.pack_free_bytes);
+        assert_eq!(8 * 1024, stats.pack_largest_free_extent);
+        assert_eq!(0.0, stats.pack_fragmentation);
+    }
+
+    #[test]
+    fn empty_blobs_and_drop() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let store = FarMemoryBlobStore::with_span_size(client.clone(), 64 * 1024);
+
+        let first_empty = store.put(&[]);
+        assert_eq!(0, store.stats().pack_spans);
+
+        let small = store.put(&test_data(1000, 1));
+        let second_empty = store.writer().finish();
+        assert!(store.delete(&first_empty));
+        assert!(store.delete(&second_empty));
+        assert_eq!(Some(test_data(1000, 1)), store.get(&small));
+
+        store.put(&test_data(200 * 1024, 2));
+        drop(store);
+        assert_eq!(0, client.total_local_spans());
+    }
+}
@@ -0,0 +1,730 @@
+use {
+    std::{marker::PhantomData, ops::{Bound, RangeBounds}, collections::VecDeque},
+    tracing::{span, Level},
+    super::{
+        client::FarMemoryClient,
+        span::SpanId,
+    },
+};
+
+const DEFAULT_PAGE_SIZE: usize = 64 * 1024;
+const DEFAULT_PREFETCH_LEAVES: usize = 4;
+
+// page header: level (u32), number of keys (u32), next leaf (u64, span id + 1, 0 if there is none)
+const HEADER_SIZE: usize = 16;
+const LEVEL_OFFSET: usize = 0;
+const LEN_OFFSET: usize = 4;
+const NEXT_LEAF_OFFSET: usize = 8;
+
+/**
+ * B+tree where every node is a page stored in its own span. Keys and values are copied as raw bytes, so both have to be Copy.
+ * Inner nodes are pinned, so they stay local unless there is nothing else left to swap out, and a lookup usually needs to
+ * swap in a single leaf only. Leaves are linked together, which is used for range iteration with leaf prefetching.
+ *
+ * remove does not rebalance the tree: leaves can become underfull (or even empty) and are filled again by later inserts.
+ */
+pub struct FarMemoryBTreeMap<K, V> {
+    client: FarMemoryClient,
+    layout: PageLayout,
+    root: SpanId,
+    height: usize, // 0 when root is a leaf
+    len: usize,
+    prefetch_leaves: usize,
+
+    _phantom: PhantomData<(K, V)>,
+}
+
+#[derive(Clone)]
+struct PageLayout {
+    page_size: usize,
+    // one slot on each page is spare, so that entry can be inserted before page is split.
+    leaf_slots: usize,
+    leaf_values_offset: usize,
+    inner_slots: usize,
+    inner_children_offset: usize,
+}
+
+impl PageLayout {
+    fn new<K, V>(page_size: usize) -> Self {
+        let key_size = std::mem::size_of::<K>();
+        let value_size = std::mem::size_of::<V>();
+        let child_size = std::mem::size_of::<u64>();
+
+        let leaf_slots = page_size.saturating_sub(HEADER_SIZE) / (key_size + value_size).max(1);
+        let inner_slots = page_size.saturating_sub(HEADER_SIZE + child_size) / (key_size + child_size);
+        if leaf_slots < 4 || inner_slots < 4 {
+            panic!("page size {} is too small for keys of size {} and values of size {}", page_size, key_size, value_size);
+        }
+
+        Self {
+            page_size,
+            leaf_slots,
+            leaf_values_offset: HEADER_SIZE + leaf_slots * key_size,
+            inner_slots,
+            inner_children_offset: HEADER_SIZE + inner_slots * key_size,
+        }
+    }
+
+    fn max_leaf_len(&self) -> usize {
+        self.leaf_slots - 1
+    }
+
+    fn max_inner_len(&self) -> usize {
+        self.inner_slots - 1
+    }
+}
+
+impl<K: Ord + Copy, V: Copy> FarMemoryBTreeMap<K, V> {
+    pub fn new(client: FarMemoryClient) -> Self {
+        Self::with_page_size(client, DEFAULT_PAGE_SIZE)
+    }
+
+    pub fn with_page_size(client: FarMemoryClient, page_size: usize) -> Self {
+        let layout = PageLayout::new::<K, V>(page_size);
+        let root = new_page::<K, V>(&client, &layout, 0);
+
+        Self {
+            client,
+            layout,
+            root,
+            height: 0,
+            len: 0,
+            prefetch_leaves: DEFAULT_PREFETCH_LEAVES,
+
+            _phantom: PhantomData,
+        }
+    }
+
+    // how many leaves ahead to swap in while iterating over range.
+    pub fn with_prefetch_leaves(mut self, prefetch_leaves: usize) -> Self {
+        self.prefetch_leaves = prefetch_leaves;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let (_, leaf) = self.find_leaf(key);
+        let leaf = self.open(leaf);
+        leaf.leaf_search(key).ok().map(|i| leaf.value(i))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let (path, leaf_id) = self.find_leaf(&key);
+
+        let mut split = {
+            let leaf = self.open(leaf_id);
+            match leaf.leaf_search(&key) {
+                Ok(i) => {
+                    let old_value = leaf.value(i);
+                    leaf.set_value(i, value);
+                    return Some(old_value);
+                },
+                Err(i) => leaf.leaf_insert_at(i, key, value),
+            };
+
+            if leaf.len() > self.layout.max_leaf_len() {
+                Some(self.split_leaf(&leaf))
+            } else {
+                None
+            }
+        };
+
+        self.len += 1;
+
+        // separators are inserted into parents until there is a parent that does not overflow
+        for (inner_id, child_index) in path.into_iter().rev() {
+            let (separator, right) = match split.take() {
+                Some(v) => v,
+                None => break,
+            };
+
+            let inner = self.open(inner_id);
+            inner.inner_insert_at(child_index, separator, right);
+            if inner.len() > self.layout.max_inner_len() {
+                split = Some(self.split_inner(&inner));
+            }
+        }
+
+        if let Some((separator, right)) = split {
+            self.grow_root(separator, right);
+        }
+
+        None
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let value = {
+            let (_, leaf) = self.find_leaf(key);
+            let leaf = self.open(leaf);
+            let i = leaf.leaf_search(key).ok()?;
+            let value = leaf.value(i);
+            leaf.leaf_remove_at(i);
+            value
+        };
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn iter(&self) -> FarMemoryBTreeMapRange<'_, K, V> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> FarMemoryBTreeMapRange<'_, K, V> {
+        let (leaf, position) = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                let (_, leaf) = self.find_leaf(key);
+                let leaf = self.open(leaf);
+                let position = match leaf.leaf_search(key) {
+                    Ok(i) => if let Bound::Excluded(_) = range.start_bound() { i + 1 } else { i },
+                    Err(i) => i,
+                };
+                (leaf, position)
+            },
+            Bound::Unbounded => (self.open(self.leftmost_leaf()), 0),
+        };
+
+        let mut iter = FarMemoryBTreeMapRange {
+            map: self,
+            leaf: None,
+            position: 0,
+            end: range.end_bound().cloned(),
+            prefetched: VecDeque::new(),
+        };
+        iter.enter_leaf(leaf, position);
+        iter
+    }
+
+    pub fn swap_out(&self) {
+        let mut pages = Vec::new();
+        self.collect_pages(&self.root, self.height, &mut pages);
+        self.client.swap_out_spans_fully(&pages);
+    }
+
+    // returns path of (inner page, child index) from the root and leaf that should contain the key.
+    fn find_leaf(&self, key: &K) -> (Vec<(SpanId, usize)>, SpanId) {
+        let mut path = Vec::with_capacity(self.height);
+        let mut current = self.root.clone();
+
+        for _ in 0..self.height {
+            let (child_index, child) = {
+                let page = self.open(current.clone());
+                let child_index = page.inner_child_index(key);
+                (child_index, page.child(child_index))
+            };
+            path.push((current, child_index));
+            current = child;
+        }
+
+        (path, current)
+    }
+
+    fn leftmost_leaf(&self) -> SpanId {
+        let mut current = self.root.clone();
+        for _ in 0..self.height {
+            current = self.open(current).child(0);
+        }
+        current
+    }
+
+    fn split_leaf(&self, leaf: &Page<'_, K, V>) -> (K, SpanId) {
+        span!(Level::DEBUG, "FarMemoryBTreeMap::split_leaf").in_scope(|| {
+            let right_id = new_page::<K, V>(&self.client, &self.layout, 0);
+            let right = self.open(right_id.clone());
+
+            let len = leaf.len();
+            let mid = len / 2;
+            unsafe {
+                copy_elements(leaf.keys_ptr(), mid, right.keys_ptr(), 0, len - mid);
+                copy_elements(leaf.values_ptr(), mid, right.values_ptr(), 0, len - mid);
+            }
+            right.set_len(len - mid);
+            leaf.set_len(mid);
+
+            right.set_next_leaf(leaf.next_leaf());
+            leaf.set_next_leaf(Some(right_id.clone()));
+
+            (right.key(0), right_id)
+        })
+    }
+
+    fn split_inner(&self, inner: &Page<'_, K, V>) -> (K, SpanId) {
+        span!(Level::DEBUG, "FarMemoryBTreeMap::split_inner").in_scope(|| {
+            let right_id = new_page::<K, V>(&self.client, &self.layout, inner.level());
+            let right = self.open(right_id.clone());
+
+            // middle key moves up to the parent
+            let len = inner.len();
+            let mid = len / 2;
+            let separator = inner.key(mid);
+            unsafe {
+                copy_elements(inner.keys_ptr(), mid + 1, right.keys_ptr(), 0, len - mid - 1);
+                copy_elements(inner.children_ptr(), mid + 1, right.children_ptr(), 0, len - mid);
+            }
+            right.set_len(len - mid - 1);
+            inner.set_len(mid);
+
+            (separator, right_id)
+        })
+    }
+
+    fn grow_root(&mut self, separator: K, right: SpanId) {
+        let root_id = new_page::<K, V>(&self.client, &self.layout, self.height as u32 + 1);
+        {
+            let root = self.open(root_id.clone());
+            root.set_key(0, separator);
+            root.set_child(0, &self.root);
+            root.set_child(1, &right);
+            root.set_len(1);
+        }
+
+        self.root = root_id;
+        self.height += 1;
+    }
+
+    // up to `limit` leaves that come after the leaf that contains the key and start before the end of the range. Only
+    // inner pages are read, and they are normally local.
+    fn leaves_after(&self, key: &K, end: &Bound<K>, limit: usize) -> Vec<SpanId> {
+        let mut leaves = Vec::new();
+        if self.height > 0 {
+            self.collect_leaves_after(&self.root, self.height, key, end, false, limit, &mut leaves);
+        }
+        leaves
+    }
+
+    fn collect_leaves_after(&self, page: &SpanId, level: usize, key: &K, end: &Bound<K>, started: bool, limit: usize, leaves: &mut Vec<SpanId>) {
+        let page = self.open(page.clone());
+        let from = if started { 0 } else { page.inner_child_index(key) };
+
+        for i in from..=page.len() {
+            if leaves.len() >= limit {
+                return;
+            }
+
+            // all keys of this child and the following ones are past the end of the range
+            if i > 0 && is_past_end(&page.key(i - 1), end) {
+                return;
+            }
+
+            let child = page.child(i);
+            let child_started = started || i > from;
+            if level == 1 {
+                if child_started {
+                    leaves.push(child);
+                }
+            } else {
+                self.collect_leaves_after(&child, level - 1, key, end, child_started, limit, leaves);
+            }
+        }
+    }
+}
+
+// page traversal does not depend on keys and values, so that pages can be freed on drop.
+impl<K, V> FarMemoryBTreeMap<K, V> {
+    fn collect_pages(&self, page_id: &SpanId, level: usize, pages: &mut Vec<SpanId>) {
+        if level > 0 {
+            let page = self.open(page_id.clone());
+            for i in 0..=page.len() {
+                self.collect_pages(&page.child(i), level - 1, pages);
+            }
+        }
+        pages.push(page_id.clone());
+    }
+
+    fn open(&self, span_id: SpanId) -> Page<'_, K, V> {
+        Page::open(&self.client, &self.layout, span_id)
+    }
+}
+
+impl<K, V> Drop for FarMemoryBTreeMap<K, V> {
+    fn drop(&mut self) {
+        let mut pages = Vec::new();
+        self.collect_pages(&self.root, self.height, &mut pages);
+        for page in &pages {
+            self.client.free_span(page);
+        }
+    }
+}
+
+fn is_past_end<K: Ord>(key: &K, end: &Bound<K>) -> bool {
+    match end {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    }
+}
+
+fn new_page<K: Copy, V: Copy>(client: &FarMemoryClient, layout: &PageLayout, level: u32) -> SpanId {
+    let span_id = client.allocate_span(layout.page_size);
+    {
+        let page = Page::<K, V>::open(client, layout, span_id.clone());
+        page.set_level(level);
+        page.set_len(0);
+        page.set_next_leaf(None);
+    }
+
+    if level > 0 {
+        client.pin_span(&span_id);
+    }
+
+    span_id
+}
+
+pub struct FarMemoryBTreeMapRange<'a, K, V> {
+    map: &'a FarMemoryBTreeMap<K, V>,
+    leaf: Option<Page<'a, K, V>>,
+    position: usize,
+    end: Bound<K>,
+    // leaves ahead of the current one that were requested to be prefetched, at most `prefetch_leaves` of them.
+    prefetched: VecDeque<SpanId>,
+}
+
+impl<'a, K: Ord + Copy, V: Copy> FarMemoryBTreeMapRange<'a, K, V> {
+    fn enter_leaf(&mut self, leaf: Page<'a, K, V>, position: usize) {
+        match self.prefetched.iter().position(|v| *v == leaf.span_id) {
+            Some(i) => { self.prefetched.drain(..=i); },
+            None => self.prefetched.clear(),
+        };
+
+        // window is refilled once half of it is consumed, so that inner pages are not walked for every leaf.
+        if self.map.prefetch_leaves > 0 && self.prefetched.len() <= self.map.prefetch_leaves / 2 && leaf.len() > 0 {
+            let leaves: Vec<_> = self.map.leaves_after(&leaf.key(0), &self.end, self.map.prefetch_leaves)
+                .into_iter()
+                .filter(|v| !self.prefetched.contains(v))
+                .collect();
+
+            self.prefetched.extend(leaves.iter().cloned());
+            self.map.client.prefetch_spans(leaves);
+        }
+
+        self.leaf = Some(leaf);
+        self.position = position;
+    }
+}
+
+impl<'a, K: Ord + Copy, V: Copy> Iterator for FarMemoryBTreeMapRange<'a, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let leaf = self.leaf.as_ref()?;
+
+            if self.position < leaf.len() {
+                let key = leaf.key(self.position);
+                if is_past_end(&key, &self.end) {
+                    self.leaf = None;
+                    return None;
+                }
+
+                let value = leaf.value(self.position);
+                self.position += 1;
+                return Some((key, value));
+            }
+
+            let next_leaf = leaf.next_leaf();
+            self.leaf = None;
+            match next_leaf {
+                Some(next_leaf) => {
+                    let next_leaf = self.map.open(next_leaf);
+                    self.enter_leaf(next_leaf, 0);
+                },
+                None => return None,
+            }
+        }
+    }
+}
+
+/**
+ * page is accessed in place while this is alive. Span memory has no alignment guarantees, so all reads and writes
+ * are unaligned.
+ */
+struct Page<'a, K, V> {
+    client: &'a FarMemoryClient,
+    layout: &'a PageLayout,
+    span_id: SpanId,
+    ptr: *mut u8,
+
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<'a, K, V> Page<'a, K, V> {
+    fn open(client: &'a FarMemoryClient, layout: &'a PageLayout, span_id: SpanId) -> Self {
+        let ptr = client.span_ptr(&span_id);
+
+        Self {
+            client,
+            layout,
+            span_id,
+            ptr,
+
+            _phantom: PhantomData,
+        }
+    }
+
+    fn len(&self) -> usize {
+        unsafe { (self.ptr.add(LEN_OFFSET) as *const u32).read_unaligned() as usize }
+    }
+
+    fn children_ptr(&self) -> *mut u64 {
+        unsafe { self.ptr.add(self.layout.inner_children_offset) as *mut u64 }
+    }
+
+    fn child(&self, i: usize) -> SpanId {
+        SpanId::from_id(unsafe { self.children_ptr().add(i).read_unaligned() })
+    }
+}
+
+impl<'a, K: Copy, V: Copy> Page<'a, K, V> {
+
+    fn level(&self) -> u32 {
+        unsafe { (self.ptr.add(LEVEL_OFFSET) as *const u32).read_unaligned() }
+    }
+
+    fn set_level(&self, level: u32) {
+        unsafe { (self.ptr.add(LEVEL_OFFSET) as *mut u32).write_unaligned(level) }
+    }
+
+    fn set_len(&self, len: usize) {
+        unsafe { (self.ptr.add(LEN_OFFSET) as *mut u32).write_unaligned(len as u32) }
+    }
+
+    fn next_leaf(&self) -> Option<SpanId> {
+        match unsafe { (self.ptr.add(NEXT_LEAF_OFFSET) as *const u64).read_unaligned() } {
+            0 => None,
+            id => Some(SpanId::from_id(id - 1)),
+        }
+    }
+
+    fn set_next_leaf(&self, next_leaf: Option<SpanId>) {
+        let id = next_leaf.map(|v| v.id() + 1).unwrap_or(0);
+        unsafe { (self.ptr.add(NEXT_LEAF_OFFSET) as *mut u64).write_unaligned(id) }
+    }
+
+    fn keys_ptr(&self) -> *mut K {
+        unsafe { self.ptr.add(HEADER_SIZE) as *mut K }
+    }
+
+    fn values_ptr(&self) -> *mut V {
+        unsafe { self.ptr.add(self.layout.leaf_values_offset) as *mut V }
+    }
+
+    fn key(&self, i: usize) -> K {
+        unsafe { self.keys_ptr().add(i).read_unaligned() }
+    }
+
+    fn set_key(&self, i: usize, key: K) {
+        unsafe { self.keys_ptr().add(i).write_unaligned(key) }
+    }
+
+    fn value(&self, i: usize) -> V {
+        unsafe { self.values_ptr().add(i).read_unaligned() }
+    }
+
+    fn set_value(&self, i: usize, value: V) {
+        unsafe { self.values_ptr().add(i).write_unaligned(value) }
+    }
+
+    fn set_child(&self, i: usize, child: &SpanId) {
+        unsafe { self.children_ptr().add(i).write_unaligned(child.id()) }
+    }
+
+    fn leaf_insert_at(&self, i: usize, key: K, value: V) {
+        let len = self.len();
+        unsafe {
+            copy_elements(self.keys_ptr(), i, self.keys_ptr(), i + 1, len - i);
+            copy_elements(self.values_ptr(), i, self.values_ptr(), i + 1, len - i);
+        }
+        self.set_key(i, key);
+        self.set_value(i, value);
+        self.set_len(len + 1);
+    }
+
+    fn leaf_remove_at(&self, i: usize) {
+        let len = self.len();
+        unsafe {
+            copy_elements(self.keys_ptr(), i + 1, self.keys_ptr(), i, len - i - 1);
+            copy_elements(self.values_ptr(), i + 1, self.values_ptr(), i, len - i - 1);
+        }
+        self.set_len(len - 1);
+    }
+
+    // separator goes to position i, and its right child goes right after child i.
+    fn inner_insert_at(&self, i: usize, separator: K, right: SpanId) {
+        let len = self.len();
+        unsafe {
+            copy_elements(self.keys_ptr(), i, self.keys_ptr(), i + 1, len - i);
+            copy_elements(self.children_ptr(), i + 1, self.children_ptr(), i + 2, len - i);
+        }
+        self.set_key(i, separator);
+        self.set_child(i + 1, &right);
+        self.set_len(len + 1);
+    }
+}
+
+impl<'a, K: Ord + Copy, V: Copy> Page<'a, K, V> {
+    fn leaf_search(&self, key: &K) -> Result<usize, usize> {
+        let mut low = 0;
+        let mut high = self.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.key(mid).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(low)
+    }
+
+    // keys that are equal to separator are stored in the right subtree.
+    fn inner_child_index(&self, key: &K) -> usize {
+        let mut low = 0;
+        let mut high = self.len();
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.key(mid) <= *key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+}
+
+impl<'a, K, V> Drop for Page<'a, K, V> {
+    fn drop(&mut self) {
+        self.client.decrease_refs_for_span(&self.span_id);
+    }
+}
+
+// byte-wise copy, because elements within page are not aligned.
+unsafe fn copy_elements<T>(src: *mut T, src_index: usize, dst: *mut T, dst_index: usize, count: usize) {
+    let size = std::mem::size_of::<T>();
+    std::ptr::copy(
+        (src as *const u8).add(src_index * size),
+        (dst as *mut u8).add(dst_index * size),
+        count * size,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn insert_and_get() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client, 4096);
+
+        for i in 0..10_000u64 {
+            assert_eq!(None, map.insert((i * 7919) % 10_000, i));
+        }
+        assert_eq!(10_000, map.len());
+        assert_eq!(Some(1), map.insert(7919, 42));
+
+        assert_eq!(Some(42), map.get(&7919));
+        assert_eq!(Some(2), map.get(&((2 * 7919) % 10_000)));
+        assert_eq!(None, map.get(&10_000));
+    }
+
+    #[test]
+    fn remove() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client, 4096);
+
+        for i in 0..5_000u32 {
+            map.insert(i, i * 2);
+        }
+        for i in (0..5_000u32).filter(|v| v % 3 != 0) {
+            assert_eq!(Some(i * 2), map.remove(&i));
+        }
+
+        assert_eq!(None, map.remove(&1));
+        assert_eq!(1667, map.len());
+        assert_eq!(Some(6), map.get(&3));
+        assert_eq!(None, map.get(&4));
+        assert_eq!((0..5_000u32).filter(|v| v % 3 == 0).collect::<Vec<_>>(), map.iter().map(|v| v.0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn range() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client, 4096);
+
+        for i in (0..20_000u64).rev() {
+            map.insert(i * 2, i);
+        }
+
+        assert_eq!((500..1000u64).map(|v| (v * 2, v)).collect::<Vec<_>>(), map.range(1000..2000).collect::<Vec<_>>());
+        assert_eq!((500..=1000u64).map(|v| (v * 2, v)).collect::<Vec<_>>(), map.range(999..=2000).collect::<Vec<_>>());
+        assert_eq!(vec![(39_998, 19_999)], map.range((Bound::Excluded(39_996), Bound::Unbounded)).collect::<Vec<_>>());
+        assert_eq!(20_000, map.iter().count());
+    }
+
+    #[test]
+    fn inner_pages_stay_local() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 256 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client.clone(), 4096);
+
+        for i in 0..50_000u64 {
+            map.insert(i, i + 1);
+        }
+        assert!(client.total_remote_memory() > 0);
+        assert!(map.height > 0);
+
+        let mut inner_pages = Vec::new();
+        map.collect_pages(&map.root, map.height, &mut inner_pages);
+        for page in inner_pages.iter().filter(|v| client.is_span_pinned(v)) {
+            assert_eq!(4096, client.span_local_memory_usage(page));
+        }
+
+        assert_eq!(Some(12_346), map.get(&12_345));
+        assert_eq!((40_000..40_100u64).map(|v| (v, v + 1)).collect::<Vec<_>>(), map.range(40_000..40_100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn prefetch_stays_within_range() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client, 4096);
+        for i in 0..50_000u64 {
+            map.insert(i, i);
+        }
+
+        assert_eq!(0, map.leaves_after(&0, &Bound::Excluded(10), 4).len());
+        assert_eq!(4, map.leaves_after(&0, &Bound::Unbounded, 4).len());
+    }
+
+    #[test]
+    fn drop_frees_pages() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut map = FarMemoryBTreeMap::with_page_size(client.clone(), 4096);
+        for i in 0..10_000u64 {
+            map.insert(i, i);
+        }
+
+        drop(map);
+        assert_eq!(0, client.total_local_memory());
+        assert_eq!(0, client.total_local_spans());
+    }
+
+    #[test]
+    #[should_panic(expected = "too small")]
+    fn page_size_smaller_than_header() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1024);
+        FarMemoryBTreeMap::<u64, u64>::with_page_size(client, 8);
+    }
+}
@@ -49,12 +49,22 @@ impl FarMemoryBuffer {
         self.client.swap_out_spans_fully(&self.spans);
     }
 
+    pub fn free(self) {
+        for span in &self.spans {
+            self.client.free_span(span);
+        }
+    }
+
     pub fn append(&mut self, bytes: Vec<u8>) {
+        self.extend_from_slice(&bytes);
+    }
+
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) {
         let mut i = 0;
 
         while i < bytes.len() {
             let free_space = self.total_capacity() - self.len;
-            let len_to_add = free_space.min(bytes.len());
+            let len_to_add = free_space.min(bytes.len() - i);
             self.append_to_last_span(&bytes[i..(i + len_to_add)]);
             i += len_to_add;
 
@@ -64,6 +74,36 @@ impl FarMemoryBuffer {
         }
     }
 
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        let spans_needed = (len + self.span_size - 1) / self.span_size;
+        for span in self.spans.drain(spans_needed..) {
+            self.client.free_span(&span);
+        }
+        self.len = len;
+    }
+
+    // new bytes are zeroed.
+    pub fn resize(&mut self, len: usize) {
+        if len <= self.len {
+            self.truncate(len);
+            return;
+        }
+
+        let zeros = vec![0; (len - self.len).min(self.span_size)];
+        while self.len < len {
+            let to_add = zeros.len().min(len - self.len);
+            self.extend_from_slice(&zeros[..to_add]);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
     fn grow(&mut self) {
         self.spans.push(self.client.allocate_span(self.span_size))
     }
@@ -92,31 +132,55 @@ impl FarMemoryBuffer {
         self.len
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(super) fn span_size(&self) -> usize {
+        self.span_size
+    }
+
+    pub(super) fn client(&self) -> &FarMemoryClient {
+        &self.client
+    }
+
+    // span that holds byte at this position, offset within that span and how many bytes of this buffer the span holds after it.
+    pub(super) fn span_at(&self, position: usize) -> (&SpanId, usize, usize) {
+        let span_offset = position % self.span_size;
+        let span_end = (position - span_offset + self.span_size).min(self.len);
+        (&self.spans[position / self.span_size], span_offset, span_end - position)
+    }
+
     fn total_capacity(&self) -> usize {
         self.spans.len() * self.span_size
     }
 
     pub fn slice(&self, range: Range<usize>) -> Vec<u8> {
-        let mut i = range.start;
         let mut result = vec![0; range.len()];
+        self.read_into(range.start, &mut result);
+        result
+    }
+
+    // copies bytes starting at `start_at` directly into `dst`.
+    pub fn read_into(&self, start_at: usize, dst: &mut [u8]) {
+        let mut i = start_at;
+        let range_end = start_at + dst.len();
 
-        while i < range.end {
+        while i < range_end {
             let span_index = i / self.span_size;
             let span_offset = i % self.span_size;
 
             let span_id = &self.spans[span_index];
             let ptr = self.client.span_ptr(span_id);
-            let bytes_to_read = (self.span_size - span_offset).min(range.end - i);
+            let bytes_to_read = (self.span_size - span_offset).min(range_end - i);
 
             unsafe {
-                std::ptr::copy(ptr.offset(span_offset as isize), result.as_mut_ptr().offset((i - range.start) as isize), bytes_to_read);
+                std::ptr::copy(ptr.offset(span_offset as isize), dst.as_mut_ptr().offset((i - start_at) as isize), bytes_to_read);
             }
             self.client.decrease_refs_for_span(span_id);
 
             i += bytes_to_read;
         }
-
-        result
     }
 
     pub fn write_range(&self, start_at: usize, range: &[u8]) {
@@ -203,6 +267,24 @@ mod tests {
         assert_eq!(vec![1, 2, 3], buffer.slice(3..6));
     }
 
+    #[test]
+    fn truncate_and_resize() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024);
+        let mut buffer = FarMemoryBuffer::zeros_with_span_size(client.clone(), 0, 4);
+        buffer.append(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(3, client.total_local_spans());
+
+        buffer.truncate(3);
+        assert_eq!(1, client.total_local_spans());
+
+        buffer.resize(6);
+        assert_eq!(vec![1, 2, 3, 0, 0, 0], buffer.slice(0..buffer.len()));
+
+        buffer.clear();
+        assert_eq!(0, buffer.len());
+        assert_eq!(0, client.total_local_spans());
+    }
+
     #[test]
     fn append_twice() {
         let mut buffer = FarMemoryBuffer::new(FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024));
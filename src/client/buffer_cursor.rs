@@ -0,0 +1,189 @@
+use {
+    std::io::{self, Read, Write, Seek, SeekFrom, BufRead},
+    super::{
+        buffer::FarMemoryBuffer,
+        span::SpanId,
+    },
+};
+
+/**
+ * file-like access to far memory buffer, works the same way as `std::io::Cursor`. Writing past the end of the buffer
+ * extends it (filling the gap with zeros if position is past the end).
+ *
+ * `fill_buf` returns bytes directly from span memory, so that span is kept in use until the cursor moves to another span
+ * or is modified.
+ */
+pub struct FarMemoryBufferCursor {
+    buffer: FarMemoryBuffer,
+    position: u64,
+    current_span: Option<(SpanId, *mut u8)>,
+}
+
+impl FarMemoryBufferCursor {
+    pub fn new(buffer: FarMemoryBuffer) -> Self {
+        Self {
+            buffer,
+            position: 0,
+            current_span: None,
+        }
+    }
+
+    pub fn into_inner(mut self) -> FarMemoryBuffer {
+        self.release_span();
+        let client = self.buffer.client().clone();
+        std::mem::replace(&mut self.buffer, FarMemoryBuffer::new(client))
+    }
+
+    pub fn get_ref(&self) -> &FarMemoryBuffer {
+        &self.buffer
+    }
+
+    pub fn get_mut(&mut self) -> &mut FarMemoryBuffer {
+        self.release_span();
+        &mut self.buffer
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn set_position(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    fn remaining(&self) -> usize {
+        (self.buffer.len() as u64).saturating_sub(self.position) as usize
+    }
+
+    fn release_span(&mut self) {
+        if let Some((span_id, _)) = self.current_span.take() {
+            self.buffer.client().decrease_refs_for_span(&span_id);
+        }
+    }
+}
+
+impl Read for FarMemoryBufferCursor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.remaining());
+        self.buffer.read_into(self.position as usize, &mut buf[..len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl BufRead for FarMemoryBufferCursor {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.remaining() == 0 {
+            self.release_span();
+            return Ok(&[]);
+        }
+
+        let (span_id, offset, available) = self.buffer.span_at(self.position as usize);
+        let ptr = match &self.current_span {
+            Some((current_span_id, ptr)) if current_span_id == span_id => *ptr,
+            _ => {
+                let span_id = span_id.clone();
+                self.release_span();
+                let ptr = self.buffer.client().span_ptr(&span_id);
+                self.current_span = Some((span_id, ptr));
+                ptr
+            },
+        };
+
+        Ok(unsafe { std::slice::from_raw_parts(ptr.add(offset), available) })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position += amt as u64;
+    }
+}
+
+impl Write for FarMemoryBufferCursor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.release_span();
+
+        let position = self.position as usize;
+        if position > self.buffer.len() {
+            self.buffer.resize(position);
+        }
+
+        let overwrite_len = (self.buffer.len() - position).min(buf.len());
+        self.buffer.write_range(position, &buf[..overwrite_len]);
+        self.buffer.extend_from_slice(&buf[overwrite_len..]);
+
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for FarMemoryBufferCursor {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(position) => {
+                self.position = position;
+                return Ok(position);
+            },
+            SeekFrom::End(offset) => (self.buffer.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+
+        match base.checked_add_signed(offset) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")),
+        }
+    }
+}
+
+impl Drop for FarMemoryBufferCursor {
+    fn drop(&mut self) {
+        self.release_span();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::{FarMemoryClient, InMemoryBackend},
+        super::*,
+    };
+
+    #[test]
+    fn write_seek_read() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024);
+        let mut cursor = FarMemoryBufferCursor::new(FarMemoryBuffer::zeros_with_span_size(client, 0, 4));
+
+        cursor.write_all(b"hello, far memory").unwrap();
+        cursor.seek(SeekFrom::Start(7)).unwrap();
+        cursor.write_all(b"FAR").unwrap();
+        cursor.seek(SeekFrom::End(2)).unwrap();
+        cursor.write_all(b"!").unwrap();
+
+        let mut result = Vec::new();
+        cursor.seek(SeekFrom::Current(-20)).unwrap();
+        cursor.read_to_end(&mut result).unwrap();
+
+        assert_eq!(b"hello, FAR memory\0\0!".to_vec(), result);
+        assert!(cursor.seek(SeekFrom::Current(-21)).is_err());
+    }
+
+    #[test]
+    fn buf_read_lines() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024);
+        let buffer = FarMemoryBuffer::zeros_with_span_size(client, 0, 5);
+        let mut cursor = FarMemoryBufferCursor::new(buffer);
+        cursor.write_all(b"first line\nsecond\n\nlast").unwrap();
+
+        let buffer = cursor.into_inner();
+        buffer.swap_out();
+
+        let lines: Vec<_> = FarMemoryBufferCursor::new(buffer).lines().map(|v| v.unwrap()).collect();
+        assert_eq!(vec!["first line", "second", "", "last"], lines);
+    }
+}
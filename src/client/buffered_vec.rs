@@ -1,11 +1,15 @@
 use {
-    std::{marker::PhantomData, time::Instant},
+    std::{marker::PhantomData, ops::Range},
     super::{
         FarMemoryClient,
         buffer::FarMemoryBuffer,
     },
 };
 
+/**
+ * items are stored as raw bytes in far memory buffer, so only Copy types are allowed (nothing would run their destructors,
+ * and copies of pointers to heap memory would outlive it).
+ */
 pub struct FarMemoryBufferedVec<T> {
     buffer: FarMemoryBuffer,
     len: usize,
@@ -13,7 +17,7 @@ pub struct FarMemoryBufferedVec<T> {
     _phantom: PhantomData<T>,
 }
 
-impl<T> FarMemoryBufferedVec<T> {
+impl<T: Copy> FarMemoryBufferedVec<T> {
     pub fn new(client: FarMemoryClient) -> Self {
         Self {
             buffer: FarMemoryBuffer::new(client),
@@ -34,42 +38,132 @@ impl<T> FarMemoryBufferedVec<T> {
     }
 
     pub fn to_local_vec(&self) -> Vec<T> {
-        let size = std::mem::size_of::<T>();
+        self.read_range(0..self.len)
+    }
 
-        let data = self.buffer.slice(0..(self.len() * size));
+    pub fn append(&mut self, vec: Vec<T>) {
+        self.buffer.extend_from_slice(as_bytes(&vec));
+        self.len += vec.len();
+    }
 
-        unsafe {
-            let res = Vec::from_raw_parts(data.as_ptr() as *mut T, self.len(), self.len());
-            std::mem::forget(data); // to prevent double free
-            res
+    pub fn push(&mut self, item: T) {
+        self.buffer.extend_from_slice(as_bytes(std::slice::from_ref(&item)));
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
         }
+
+        let item = self.get(self.len - 1);
+        self.len -= 1;
+        self.buffer.truncate(self.len * std::mem::size_of::<T>());
+        item
     }
 
-    pub fn append(&mut self, vec: Vec<T>) {
-        for item in vec {
-            self.push(item);
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len {
+            return None;
         }
+
+        let mut item = std::mem::MaybeUninit::<T>::uninit();
+        self.buffer.read_into(index * std::mem::size_of::<T>(), unsafe {
+            std::slice::from_raw_parts_mut(item.as_mut_ptr() as *mut u8, std::mem::size_of::<T>())
+        });
+        Some(unsafe { item.assume_init() })
     }
 
-    pub fn push(&mut self, item: T) {
-        let ptr = &item as *const _ as *const u8;
-        let data = unsafe { std::slice::from_raw_parts(ptr, std::mem::size_of_val(&item)) }.to_vec();
-        self.buffer.append(data);
-        self.len += 1;
+    pub fn set(&self, index: usize, item: T) {
+        if index >= self.len {
+            panic!("index {} is out of bounds for vec of length {}", index, self.len);
+        }
+
+        self.buffer.write_range(index * std::mem::size_of::<T>(), as_bytes(std::slice::from_ref(&item)));
     }
 
-    pub fn get(&self, index: usize) -> T {
-        let size = std::mem::size_of::<T>();
+    // copies items in range directly into a new local vec, without reading anything else.
+    pub fn read_range(&self, range: Range<usize>) -> Vec<T> {
+        if range.start > range.end || range.end > self.len {
+            panic!("range {}..{} is out of bounds for vec of length {}", range.start, range.end, self.len);
+        }
 
-        let entry = self.buffer.slice((index * size)..((index + 1) * size));
+        let mut result: Vec<T> = Vec::with_capacity(range.len());
         unsafe {
-            std::ptr::read(entry.as_ptr() as *const _)
+            self.buffer.read_into(range.start * std::mem::size_of::<T>(), std::slice::from_raw_parts_mut(
+                result.as_mut_ptr() as *mut u8,
+                range.len() * std::mem::size_of::<T>()
+            ));
+            result.set_len(range.len());
+        }
+        result
+    }
+
+    pub fn iter(&self) -> FarMemoryBufferedVecIterator<'_, T> {
+        FarMemoryBufferedVecIterator {
+            vec: self,
+            index: 0,
+            batch: Vec::new(),
+            batch_position: 0,
         }
     }
 
     pub fn len(&self) -> usize {
         self.len
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/**
+ * reads items in batches of about a span, so only one batch is kept in local memory at a time.
+ */
+pub struct FarMemoryBufferedVecIterator<'a, T> {
+    vec: &'a FarMemoryBufferedVec<T>,
+    index: usize,
+    batch: Vec<T>,
+    batch_position: usize,
+}
+
+impl<'a, T: Copy> Iterator for FarMemoryBufferedVecIterator<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch_position == self.batch.len() {
+            if self.index == self.vec.len {
+                return None;
+            }
+
+            let batch_len = (self.vec.buffer.span_size() / std::mem::size_of::<T>().max(1)).max(1);
+            let batch_end = (self.index + batch_len).min(self.vec.len);
+            self.batch = self.vec.read_range(self.index..batch_end);
+            self.batch_position = 0;
+            self.index = batch_end;
+        }
+
+        let item = self.batch[self.batch_position];
+        self.batch_position += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.vec.len - self.index + self.batch.len() - self.batch_position;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, T: Copy> ExactSizeIterator for FarMemoryBufferedVecIterator<'a, T> {}
+
+impl<T> Drop for FarMemoryBufferedVec<T> {
+    fn drop(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+fn as_bytes<T: Copy>(items: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(items.as_ptr() as *const u8, std::mem::size_of_val(items)) }
 }
 
 #[cfg(test)]
@@ -82,33 +176,72 @@ mod tests {
     #[test]
     fn get() {
         let vec = FarMemoryBufferedVec::from_vec(
-            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024), 
+            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024),
             vec![10.02, 9.02, 8.02, 7.02, 6.02, 5.02, 4.02, 3.02, 2.02, 1.02]
         );
-        
+
         assert_eq!(10, vec.len());
-        assert_eq!(10.02, vec.get(0));
-        assert_eq!(9.02, vec.get(1));
-        assert_eq!(8.02, vec.get(2));
-        assert_eq!(7.02, vec.get(3));
-        assert_eq!(6.02, vec.get(4));
-        assert_eq!(5.02, vec.get(5));
-        assert_eq!(4.02, vec.get(6));
-        assert_eq!(3.02, vec.get(7));
-        assert_eq!(2.02, vec.get(8));
-        assert_eq!(1.02, vec.get(9));
+        assert_eq!(Some(10.02), vec.get(0));
+        assert_eq!(Some(9.02), vec.get(1));
+        assert_eq!(Some(8.02), vec.get(2));
+        assert_eq!(Some(7.02), vec.get(3));
+        assert_eq!(Some(6.02), vec.get(4));
+        assert_eq!(Some(5.02), vec.get(5));
+        assert_eq!(Some(4.02), vec.get(6));
+        assert_eq!(Some(3.02), vec.get(7));
+        assert_eq!(Some(2.02), vec.get(8));
+        assert_eq!(Some(1.02), vec.get(9));
+        assert_eq!(None, vec.get(10));
     }
 
     #[test]
     fn to_local_vec() {
         let vec = FarMemoryBufferedVec::from_vec(
-            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024), 
+            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024),
             vec![10.02, 9.02, 8.02, 7.02, 6.02, 5.02, 4.02, 3.02, 2.02, 1.02]
         );
-        
+
         assert_eq!(
             vec![10.02, 9.02, 8.02, 7.02, 6.02, 5.02, 4.02, 3.02, 2.02, 1.02],
             vec.to_local_vec()
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn set_pop_and_read_range() {
+        let mut vec = FarMemoryBufferedVec::from_vec(
+            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024),
+            vec![1u32, 2, 3, 4, 5]
+        );
+
+        vec.set(1, 20);
+        assert_eq!(Some(5), vec.pop());
+        assert_eq!(4, vec.len());
+        assert_eq!(vec![20, 3], vec.read_range(1..3));
+
+        vec.push(6);
+        assert_eq!(vec![1, 20, 3, 4, 6], vec.to_local_vec());
+    }
+
+    #[test]
+    fn iter_across_spans() {
+        let vec = FarMemoryBufferedVec::from_vec(
+            FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024),
+            (0..1_000_000u64).collect()
+        );
+        vec.swap_out();
+
+        assert_eq!(1_000_000, vec.iter().len());
+        assert_eq!((0..1_000_000u64).sum::<u64>(), vec.iter().sum::<u64>());
+    }
+
+    #[test]
+    fn drop_frees_spans() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1000 * 1024 * 1024);
+        let vec = FarMemoryBufferedVec::from_vec(client.clone(), (0..1_000_000u64).collect());
+        assert!(client.total_local_spans() > 0);
+
+        drop(vec);
+        assert_eq!(0, client.total_local_spans());
+    }
+}
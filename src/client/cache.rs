@@ -0,0 +1,535 @@
+use {
+    std::{collections::{BTreeMap, HashMap, HashSet}, hash::Hash, sync::Mutex, time::{Duration, Instant}},
+    tracing::{span, Level},
+    prometheus::{Registry, IntCounter, IntGauge, Opts, core::Collector},
+    super::{
+        client::FarMemoryClient,
+        serialized_object::FarMemorySerialized,
+        codec::{SerializationCodec, BincodeCodec},
+    },
+};
+
+/**
+ * LRU cache with two tiers: hot entries are kept as plain values in local memory, and once local tier is over its capacity,
+ * least recently used entries are demoted to far memory objects instead of being evicted. Demoted entry is promoted back to
+ * local tier on hit. Keys of all entries stay in local memory.
+ *
+ * local tier capacity is in weight units, by default every entry weighs `size_of::<K>() + size_of::<V>()` bytes.
+ * IMPORTANT: default weigher does not see heap data owned by keys and values (contents of String, Vec and so on), so for
+ * such types local capacity is effectively a number of entries times a constant. Use `with_weigher` to count actual sizes.
+ * Entries only leave the cache when they are removed or their TTL expires.
+ *
+ * serialization and far memory access happen outside of the state lock. While an entry is being moved between tiers, it
+ * is not visible to other threads (lookups miss), and an insert or remove of the same key takes precedence over the move.
+ */
+pub struct FarMemoryCache<K, V, C = BincodeCodec> {
+    client: FarMemoryClient,
+    local_capacity: usize,
+    default_ttl: Option<Duration>,
+    weigher: fn(&K, &V) -> usize,
+
+    state: Mutex<CacheState<K, V, C>>,
+    metrics: Option<CacheMetrics>,
+}
+
+struct CacheState<K, V, C> {
+    local: HashMap<K, LocalEntry<V>>,
+    lru: BTreeMap<u64, K>, // access tick to key, for local entries only
+    next_tick: u64,
+    local_weight: usize,
+
+    remote: HashMap<K, RemoteEntry<V, C>>,
+    moving: HashSet<K>, // keys that are being promoted or demoted
+}
+
+struct LocalEntry<V> {
+    value: V,
+    expires_at: Option<Instant>,
+    weight: usize,
+    tick: u64,
+}
+
+struct RemoteEntry<V, C> {
+    object: FarMemorySerialized<V, C>,
+    expires_at: Option<Instant>,
+}
+
+struct CacheMetrics {
+    registry: Registry,
+
+    local_hits: IntCounter,
+    remote_hits: IntCounter,
+    misses: IntCounter,
+    demotions: IntCounter,
+    expirations: IntCounter,
+
+    local_entries: IntGauge,
+    remote_entries: IntGauge,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone, C: SerializationCodec<V>> FarMemoryCache<K, V, C> {
+    // metrics are registered in client metrics registry (if client tracks metrics), labeled with cache name. Fails if
+    // metrics of another cache with the same name are still registered.
+    pub fn new(client: FarMemoryClient, name: &str, local_capacity: usize) -> Result<Self, prometheus::Error> {
+        let metrics = client.metrics_registry().map(|registry| CacheMetrics::new(registry, name)).transpose()?;
+
+        Ok(Self {
+            client,
+            local_capacity,
+            default_ttl: None,
+            weigher: |_, _| std::mem::size_of::<K>() + std::mem::size_of::<V>(),
+
+            state: Mutex::new(CacheState {
+                local: HashMap::new(),
+                lru: BTreeMap::new(),
+                next_tick: 0,
+                local_weight: 0,
+
+                remote: HashMap::new(),
+                moving: HashSet::new(),
+            }),
+            metrics,
+        })
+    }
+
+    // ttl for entries inserted with `insert`.
+    pub fn with_default_ttl(mut self, ttl: Duration) -> Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    // weight of an entry in local tier, should include heap data owned by key and value.
+    pub fn with_weigher(mut self, weigher: fn(&K, &V) -> usize) -> Self {
+        self.weigher = weigher;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.local.len() + state.remote.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn local_len(&self) -> usize {
+        self.state.lock().unwrap().local.len()
+    }
+
+    pub fn remote_len(&self) -> usize {
+        self.state.lock().unwrap().remote.len()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_with_expiration(key, value, self.default_ttl.map(|ttl| Instant::now() + ttl));
+    }
+
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) {
+        self.insert_with_expiration(key, value, Some(Instant::now() + ttl));
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if let Some(entry) = state.local.get(key) {
+            if is_expired(entry.expires_at, now) {
+                state.remove_local(key);
+                self.on_expired(&state);
+                return None;
+            }
+
+            let value = entry.value.clone();
+            state.touch(key);
+            self.inc(|v| &v.local_hits);
+            return Some(value);
+        }
+
+        let entry = match state.remote.remove(key) {
+            Some(v) => v,
+            None => {
+                self.inc(|v| &v.misses);
+                return None;
+            }
+        };
+
+        if is_expired(entry.expires_at, now) {
+            self.on_expired(&state);
+            drop(state);
+            entry.object.free();
+            return None;
+        }
+
+        state.moving.insert(key.clone());
+        drop(state);
+
+        let value = span!(Level::DEBUG, "FarMemoryCache - promote").in_scope(|| {
+            let value = entry.object.to_local();
+            entry.object.free();
+            value
+        });
+        self.inc(|v| &v.remote_hits);
+
+        let weight = (self.weigher)(key, &value);
+        let mut state = self.state.lock().unwrap();
+        if state.moving.remove(key) {
+            state.insert_local(key.clone(), value.clone(), entry.expires_at, weight);
+            let demoted = state.take_over_capacity(self.local_capacity);
+            self.update_gauges(&state);
+            drop(state);
+
+            self.demote(demoted);
+        }
+
+        Some(value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match state.local.get(key) {
+            Some(entry) => !is_expired(entry.expires_at, now),
+            None => state.remote.get(key).map(|v| !is_expired(v.expires_at, now)).unwrap_or(false),
+        }
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        // entry that is being moved between tiers is dropped once the move finishes
+        state.moving.remove(key);
+
+        let (value, expires_at) = match state.remove_local(key) {
+            Some(entry) => {
+                self.update_gauges(&state);
+                (entry.value, entry.expires_at)
+            },
+            None => {
+                let entry = state.remote.remove(key)?;
+                self.update_gauges(&state);
+                drop(state);
+
+                let value = entry.object.to_local();
+                entry.object.free();
+                (value, entry.expires_at)
+            },
+        };
+
+        if is_expired(expires_at, now) {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    // drops all expired entries. Otherwise they are dropped only when accessed.
+    pub fn purge_expired(&self) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        let expired_local: Vec<_> = state.local.iter()
+            .filter(|(_, entry)| is_expired(entry.expires_at, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired_local {
+            state.remove_local(key);
+        }
+
+        let expired_remote: Vec<_> = state.remote.iter()
+            .filter(|(_, entry)| is_expired(entry.expires_at, now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired_remote {
+            state.remote.remove(key).unwrap().object.free();
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.expirations.inc_by((expired_local.len() + expired_remote.len()) as u64);
+        }
+        self.update_gauges(&state);
+    }
+
+    fn insert_with_expiration(&self, key: K, value: V, expires_at: Option<Instant>) {
+        let weight = (self.weigher)(&key, &value);
+
+        let mut state = self.state.lock().unwrap();
+        let replaced = state.remote.remove(&key);
+        state.remove_local(&key);
+        state.moving.remove(&key);
+
+        state.insert_local(key, value, expires_at, weight);
+        let demoted = state.take_over_capacity(self.local_capacity);
+        self.update_gauges(&state);
+        drop(state);
+
+        if let Some(entry) = replaced {
+            entry.object.free();
+        }
+        self.demote(demoted);
+    }
+
+    // entries are serialized to far memory without holding the lock, and are added to remote tier only if their keys were
+    // not inserted or removed in the meantime.
+    fn demote(&self, entries: Vec<(K, LocalEntry<V>)>) {
+        for (key, entry) in entries {
+            let object = span!(Level::DEBUG, "FarMemoryCache - demote").in_scope(|| {
+                FarMemorySerialized::from_value(self.client.clone(), entry.value)
+            });
+
+            let mut state = self.state.lock().unwrap();
+            if state.moving.remove(&key) {
+                state.remote.insert(key, RemoteEntry {
+                    object,
+                    expires_at: entry.expires_at,
+                });
+                self.update_gauges(&state);
+            } else {
+                drop(state);
+                object.free();
+            }
+            self.inc(|v| &v.demotions);
+        }
+    }
+
+    fn on_expired(&self, state: &CacheState<K, V, C>) {
+        self.inc(|v| &v.expirations);
+        self.inc(|v| &v.misses);
+        self.update_gauges(state);
+    }
+
+    fn inc<F: Fn(&CacheMetrics) -> &IntCounter>(&self, counter: F) {
+        if let Some(metrics) = &self.metrics {
+            counter(metrics).inc();
+        }
+    }
+
+    fn update_gauges(&self, state: &CacheState<K, V, C>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.local_entries.set(state.local.len() as i64);
+            metrics.remote_entries.set(state.remote.len() as i64);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V, C> CacheState<K, V, C> {
+    fn insert_local(&mut self, key: K, value: V, expires_at: Option<Instant>, weight: usize) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        self.lru.insert(tick, key.clone());
+        self.local_weight += weight;
+        self.local.insert(key, LocalEntry {
+            value,
+            expires_at,
+            weight,
+            tick,
+        });
+    }
+
+    fn remove_local(&mut self, key: &K) -> Option<LocalEntry<V>> {
+        let entry = self.local.remove(key)?;
+        self.lru.remove(&entry.tick);
+        self.local_weight -= entry.weight;
+        Some(entry)
+    }
+
+    // removes least recently used entries from local tier until it fits into capacity. Keys are marked as moving.
+    fn take_over_capacity(&mut self, capacity: usize) -> Vec<(K, LocalEntry<V>)> {
+        let mut entries = Vec::new();
+        while self.local_weight > capacity {
+            let key = match self.lru.values().next() {
+                Some(v) => v.clone(),
+                None => break,
+            };
+
+            let entry = self.remove_local(&key).unwrap();
+            self.moving.insert(key.clone());
+            entries.push((key, entry));
+        }
+        entries
+    }
+
+    fn touch(&mut self, key: &K) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+
+        let entry = self.local.get_mut(key).unwrap();
+        self.lru.remove(&entry.tick);
+        entry.tick = tick;
+        self.lru.insert(tick, key.clone());
+    }
+}
+
+impl<K, V, C> Drop for FarMemoryCache<K, V, C> {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        for (_, entry) in state.remote.drain() {
+            entry.object.free();
+        }
+
+        if let Some(metrics) = &self.metrics {
+            metrics.unregister();
+        }
+    }
+}
+
+impl CacheMetrics {
+    // either all metrics are registered or none of them, so that failed cache does not block its name.
+    fn new(registry: Registry, name: &str) -> Result<Self, prometheus::Error> {
+        let opts = |metric: &str, help: &str| Opts::new(metric, help).const_label("cache", name);
+
+        let metrics = Self {
+            registry,
+
+            local_hits: IntCounter::with_opts(opts("client_cache_local_hits", "cache hits served from local tier"))?,
+            remote_hits: IntCounter::with_opts(opts("client_cache_remote_hits", "cache hits that promoted entry from far memory"))?,
+            misses: IntCounter::with_opts(opts("client_cache_misses", "cache misses, including expired entries"))?,
+            demotions: IntCounter::with_opts(opts("client_cache_demotions", "entries demoted from local tier to far memory"))?,
+            expirations: IntCounter::with_opts(opts("client_cache_expirations", "entries dropped because their ttl expired"))?,
+
+            local_entries: IntGauge::with_opts(opts("client_cache_local_entries", "entries in local tier"))?,
+            remote_entries: IntGauge::with_opts(opts("client_cache_remote_entries", "entries in far memory"))?,
+        };
+
+        for (i, collector) in metrics.collectors().into_iter().enumerate() {
+            if let Err(err) = metrics.registry.register(collector) {
+                for registered in metrics.collectors().into_iter().take(i) {
+                    metrics.registry.unregister(registered).unwrap();
+                }
+                return Err(err);
+            }
+        }
+
+        Ok(metrics)
+    }
+
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.local_hits.clone()),
+            Box::new(self.remote_hits.clone()),
+            Box::new(self.misses.clone()),
+            Box::new(self.demotions.clone()),
+            Box::new(self.expirations.clone()),
+
+            Box::new(self.local_entries.clone()),
+            Box::new(self.remote_entries.clone()),
+        ]
+    }
+
+    fn unregister(&self) {
+        for collector in self.collectors() {
+            self.registry.unregister(collector).unwrap();
+        }
+    }
+}
+
+fn is_expired(expires_at: Option<Instant>, now: Instant) -> bool {
+    expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::thread,
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn demote_and_promote() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let cache: FarMemoryCache<u64, String> = FarMemoryCache::new(client, "test", 3).unwrap()
+            .with_weigher(|_, _| 1);
+
+        for i in 0..5 {
+            cache.insert(i, format!("value {}", i));
+        }
+        assert_eq!(3, cache.local_len());
+        assert_eq!(2, cache.remote_len());
+
+        // 0 is promoted, and 2 is the least recently used one now
+        assert_eq!(Some("value 0".to_owned()), cache.get(&0));
+        assert_eq!(Some("value 3".to_owned()), cache.get(&3));
+        {
+            let state = cache.state.lock().unwrap();
+            assert!(state.local.contains_key(&0));
+            assert!(state.remote.contains_key(&1));
+            assert!(state.remote.contains_key(&2));
+        }
+
+        assert_eq!(Some("value 1".to_owned()), cache.remove(&1));
+        assert_eq!(None, cache.get(&1));
+        assert_eq!(4, cache.len());
+    }
+
+    #[test]
+    fn ttl() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let cache: FarMemoryCache<u64, u64> = FarMemoryCache::new(client, "test", 1).unwrap()
+            .with_weigher(|_, _| 1)
+            .with_default_ttl(Duration::from_millis(50));
+
+        cache.insert(1, 10);
+        cache.insert(2, 20);
+        cache.insert_with_ttl(3, 30, Duration::from_secs(60));
+        assert_eq!(Some(10), cache.get(&1));
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(None, cache.get(&1));
+        assert!(!cache.contains_key(&2));
+        cache.purge_expired();
+
+        assert_eq!(1, cache.len());
+        assert_eq!(Some(30), cache.get(&3));
+    }
+
+    #[test]
+    fn metrics() {
+        let mut client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let registry = Registry::new();
+        client.track_metrics(registry.clone());
+
+        {
+            let cache: FarMemoryCache<u64, u64> = FarMemoryCache::new(client.clone(), "test", 2).unwrap()
+                .with_weigher(|_, _| 1);
+            for i in 0..4 {
+                cache.insert(i, i * 10);
+            }
+            cache.get(&3);
+            cache.get(&0);
+            cache.get(&100);
+
+            let metric = |name: &str| registry.gather().into_iter()
+                .find(|v| v.get_name() == name)
+                .map(|v| (v.get_metric()[0].get_counter().get_value() + v.get_metric()[0].get_gauge().get_value()) as u64)
+                .unwrap();
+            assert_eq!(1, metric("client_cache_local_hits"));
+            assert_eq!(1, metric("client_cache_remote_hits"));
+            assert_eq!(1, metric("client_cache_misses"));
+            assert_eq!(3, metric("client_cache_demotions"));
+            assert_eq!(2, metric("client_cache_local_entries"));
+            assert_eq!(2, metric("client_cache_remote_entries"));
+        }
+
+        assert!(registry.gather().iter().all(|v| !v.get_name().starts_with("client_cache")));
+    }
+
+    #[test]
+    fn duplicate_cache_name() {
+        let mut client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        client.track_metrics(Registry::new());
+
+        let cache: FarMemoryCache<u64, u64> = FarMemoryCache::new(client.clone(), "test", 2).unwrap();
+        assert!(FarMemoryCache::<u64, u64>::new(client.clone(), "test", 2).is_err());
+        assert!(FarMemoryCache::<u64, u64>::new(client.clone(), "other", 2).is_ok());
+
+        cache.insert(1, 10);
+        assert_eq!(Some(10), cache.get(&1));
+        drop(cache);
+
+        // name can be used again once the first cache is gone
+        assert!(FarMemoryCache::<u64, u64>::new(client, "test", 2).is_ok());
+    }
+}
@@ -1,5 +1,10 @@
 use {
-    std::{sync::{Arc, atomic::{AtomicU64, Ordering, AtomicBool}, RwLock, Mutex}, collections::HashMap, thread, time::{Instant, Duration}},
+    std::{
+        sync::{Arc, atomic::{AtomicU64, Ordering, AtomicBool}, RwLock, Mutex, OnceLock, mpsc::{sync_channel, SyncSender, Receiver, RecvTimeoutError}},
+        collections::{HashMap, HashSet},
+        thread,
+        time::{Instant, Duration},
+    },
     tracing::{Level, span, info, debug, warn},
     crossbeam::utils::Backoff,
     prometheus::{Registry, register_int_gauge_with_registry, IntGauge, IntCounter, register_int_counter_with_registry},
@@ -14,6 +19,8 @@ use {
     },
 };
 
+const PREFETCH_QUEUE_SIZE: usize = 1024;
+
 #[derive(Clone)]
 pub struct FarMemoryClient {
     span_id_counter: Arc<AtomicU64>,
@@ -29,6 +36,10 @@ pub struct FarMemoryClient {
 
     swap_in_out_lock: Arc<Mutex<()>>,
     span_states: Arc<RwLock<HashMap<SpanId, Mutex<SpanState>>>>,
+    // pinned spans are swapped out only when there are no other spans left to evict.
+    pinned_spans: Arc<RwLock<HashSet<SpanId>>>,
+    // spans to swap in ahead of access, handled by a single prefetch thread started on first use.
+    prefetch_queue: Arc<OnceLock<SyncSender<SpanId>>>,
 
     object_registry: Arc<ObjectRegistry>,
 
@@ -82,6 +93,8 @@ impl FarMemoryClient {
 
             swap_in_out_lock: Arc::new(Mutex::new(())),
             span_states: Arc::new(RwLock::new(HashMap::new())),
+            pinned_spans: Arc::new(RwLock::new(HashSet::new())),
+            prefetch_queue: Arc::new(OnceLock::new()),
 
             object_registry: Arc::new(ObjectRegistry::new()),
 
@@ -102,6 +115,11 @@ impl FarMemoryClient {
         self.start_metrics_thread();
     }
 
+    // registry passed to `track_metrics`, so that data structures can register their own metrics next to client ones.
+    pub fn metrics_registry(&self) -> Option<Registry> {
+        self.metrics.as_ref().map(|v| v.registry.clone())
+    }
+
     pub fn start_swap_out_thread(&self) {
         thread::Builder::new().name("swap-out".to_owned())
             .spawn(swap_out_thread(
@@ -136,6 +154,10 @@ impl FarMemoryClient {
         self.swap_out_min_size = Some(swap_out_min_size);
     }
 
+    pub fn local_memory_limit(&self) -> u64 {
+        self.local_memory_max_threshold
+    }
+
     pub fn allocate_span(&self, span_size: usize) -> SpanId {
         span!(Level::DEBUG, "allocate_span - ensure local memory limit").in_scope(|| {
             self.ensure_local_memory_under_limit(self.local_memory_max_threshold - span_size as u64, true);
@@ -150,6 +172,15 @@ impl FarMemoryClient {
     }
 
     pub fn span_ptr(&self, id: &SpanId) -> *mut u8 {
+        self.try_span_ptr(id).unwrap_or_else(|| panic!("span {} does not exist", id.id()))
+    }
+
+    // same as span_ptr, but returns None if span does not exist (for example, when it was freed already).
+    fn try_span_ptr(&self, id: &SpanId) -> Option<*mut u8> {
+        if !self.span_states.read().unwrap().contains_key(id) {
+            return None;
+        }
+
         let started_at = Instant::now();
 
         self.replacement_policy.on_span_access(id);
@@ -163,7 +194,10 @@ impl FarMemoryClient {
             let mut waiting_for_span_lock_guard = None;
             loop {
                 let span_states = self.span_states.read().unwrap();
-                let mut span_state = span_states[id].lock().unwrap();
+                let mut span_state = match span_states.get(id) {
+                    Some(v) => v.lock().unwrap(),
+                    None => return None,
+                };
                 match &*span_state {
                     SpanState::Free => {
                         drop(waiting_for_span_lock_guard);
@@ -177,7 +211,7 @@ impl FarMemoryClient {
                             if let Some(metrics) = &self.metrics {
                                 metrics.access_latency_micros_local.inc_by((Instant::now() - started_at).as_micros() as u64);
                             }
-                            return span.ptr();
+                            return Some(span.ptr());
                         } else {
                             // span is not local, so will need to swap it in
                             // marking it as in swapping state
@@ -194,7 +228,7 @@ impl FarMemoryClient {
                         if let Some(metrics) = &self.metrics {
                             metrics.access_latency_micros_local.inc_by((Instant::now() - started_at).as_micros() as u64);
                         }
-                        return span.ptr();
+                        return Some(span.ptr());
                     },
                     SpanState::Swapping => {
                         // waiting for swap out to finish to swap back in again
@@ -254,17 +288,97 @@ impl FarMemoryClient {
                 metrics.access_latency_micros_swap_in.inc_by((Instant::now() - started_at).as_micros() as u64);
             }
 
-            ptr
+            Some(ptr)
         })
     }
 
+    // if span is still in use (for example, by prefetching), waits for it to be released first.
+    pub fn free_span(&self, span_id: &SpanId) {
+        let backoff = Backoff::new();
+        let _guard = loop {
+            let guard = span!(Level::DEBUG, "waiting for lock").in_scope(|| self.swap_in_out_lock.lock().unwrap());
+
+            let mut span_states = self.span_states.write().unwrap();
+            if *span_states[span_id].lock().unwrap() == SpanState::Free {
+                span_states.remove(span_id);
+                break guard;
+            }
+
+            drop(span_states);
+            drop(guard);
+            backoff.snooze();
+        };
+
+        let span = self.spans.write().unwrap().remove(span_id).unwrap();
+        if span.remote_memory_usage() > 0 {
+            self.backend.remove(span_id);
+        }
+
+        self.pinned_spans.write().unwrap().remove(span_id);
+        self.replacement_policy.on_span_free(span_id);
+    }
+
+    // pinned span is kept local as long as there are other spans that can be swapped out instead.
+    pub fn pin_span(&self, span_id: &SpanId) {
+        self.pinned_spans.write().unwrap().insert(span_id.clone());
+    }
+
+    pub fn unpin_span(&self, span_id: &SpanId) {
+        self.pinned_spans.write().unwrap().remove(span_id);
+    }
+
+    pub fn is_span_pinned(&self, span_id: &SpanId) -> bool {
+        self.pinned_spans.read().unwrap().contains(span_id)
+    }
+
+    // starts swapping in spans in background, so they are likely to be local by the time they are accessed.
+    // prefetching is only a hint: spans are skipped when prefetch queue is full.
+    pub fn prefetch_spans(&self, spans: Vec<SpanId>) {
+        let queue = self.prefetch_queue.get_or_init(|| {
+            let (sender, receiver) = sync_channel(PREFETCH_QUEUE_SIZE);
+            thread::Builder::new().name("prefetch".to_owned())
+                .spawn(prefetch_thread(self.clone(), receiver))
+                .unwrap();
+            sender
+        });
+
+        for span_id in spans {
+            if self.is_span_local(&span_id) {
+                continue;
+            }
+
+            if queue.try_send(span_id).is_err() {
+                break;
+            }
+        }
+    }
+
     pub fn span_local_memory_usage(&self, span_id: &SpanId) -> usize {
         self.spans.read().unwrap().get(&span_id).unwrap().local_memory_usage()
     }
 
     pub fn swap_out_spans_fully(&self, spans: &[SpanId]) {
+        let _guard = span!(Level::DEBUG, "waiting for lock").in_scope(|| self.swap_in_out_lock.lock().unwrap());
+
         for span in spans {
-            self.swap_out_span(span, self.spans.read().unwrap().get(span).unwrap().local_memory_usage());
+            let local_memory_usage = {
+                let span_states = self.span_states.read().unwrap();
+                let mut span_state = span_states[span].lock().unwrap();
+                if *span_state != SpanState::Free {
+                    debug!(span_id=span.id(), "skipping span that is in use or in process of swapping");
+                    continue;
+                }
+
+                let local_memory_usage = self.spans.read().unwrap().get(span).unwrap().local_memory_usage();
+                if local_memory_usage == 0 {
+                    continue;
+                }
+
+                *span_state = SpanState::Swapping;
+                local_memory_usage
+            };
+
+            self.swap_out_span(span, local_memory_usage);
         }
     }
 
@@ -450,6 +564,9 @@ impl FarMemoryClient {
         let possible_swap_out_spans: Vec<SpanId> = self.spans.read().unwrap().keys().cloned().collect(); // TODO: remove this completely
 
         let mut spans_for_eviction = span!(Level::DEBUG, "querying replacement policy").in_scope(|| self.replacement_policy.pick_for_eviction(&possible_swap_out_spans));
+        let pinned_spans = self.pinned_spans.read().unwrap().clone();
+        let mut deferred_pinned_spans = Vec::new();
+        let mut evicting_pinned_spans = false;
 
         span!(Level::DEBUG, "picking spans for eviction", total_spans=possible_swap_out_spans.len()).in_scope(|| {
             'spans_picking: loop {
@@ -459,10 +576,21 @@ impl FarMemoryClient {
 
                 let span_id = loop {
                     if let Some(span_id) = spans_for_eviction.next() {
+                        if !evicting_pinned_spans && pinned_spans.contains(&span_id) {
+                            deferred_pinned_spans.push(span_id);
+                            continue;
+                        }
                         break span_id;
+                    } else if !deferred_pinned_spans.is_empty() {
+                        // nothing else is left, so have to evict pinned spans as well
+                        debug!("evicting pinned spans");
+                        evicting_pinned_spans = true;
+                        spans_for_eviction = Box::new(std::mem::take(&mut deferred_pinned_spans).into_iter());
+                        continue;
                     } else {
                         warn!("there are no spans to evict remaining that can be picked");
                         if strict {
+                            evicting_pinned_spans = false;
                             spans_for_eviction = span!(Level::DEBUG, "querying replacement policy").in_scope(|| self.replacement_policy.pick_for_eviction(&possible_swap_out_spans));
                             continue;
                         } else {
@@ -473,7 +601,14 @@ impl FarMemoryClient {
 
                 {
                     let span_states = self.span_states.read().unwrap();
-                    let mut span_state = span_states[&span_id].lock().unwrap();
+                    let mut span_state = match span_states.get(&span_id) {
+                        Some(v) => v.lock().unwrap(),
+                        None => {
+                            // replacement policy may still return span that was freed
+                            debug!(span_id=span_id.id(), "skipping span that was freed");
+                            continue;
+                        }
+                    };
                     match &*span_state {
                         SpanState::Free => {
                             let spans = self.spans.read().unwrap();
@@ -558,6 +693,12 @@ impl FarMemoryClient {
         object_id
     }
 
+    pub fn remove_object(&self, object_id: &ObjectId) {
+        if let Some(span_id) = self.object_registry.remove_object(object_id) {
+            self.free_span(&span_id);
+        }
+    }
+
     pub fn get_object(&self, object_id: &ObjectId) -> ObjectLocation {
         self.object_registry.get_object(object_id)
     }
@@ -703,6 +844,29 @@ fn swap_out_thread(client: FarMemoryClient, target_memory_usage: u64) -> impl Fn
     }
 }
 
+fn prefetch_thread(client: FarMemoryClient, queue: Receiver<SpanId>) -> impl FnOnce() -> () {
+    move || {
+        while client.is_running() {
+            let span_id = match queue.recv_timeout(Duration::from_millis(100)) {
+                Ok(v) => v,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            span!(Level::DEBUG, "prefetch span", span_id = span_id.id()).in_scope(|| {
+                // span may have been swapped in or freed while it was waiting in the queue.
+                if client.is_span_local(&span_id) {
+                    return;
+                }
+
+                if client.try_span_ptr(&span_id).is_some() {
+                    client.decrease_refs_for_span(&span_id);
+                }
+            });
+        }
+    }
+}
+
 fn report_metrics_thread(client: FarMemoryClient) -> impl FnOnce() -> () {
     move || {
         while client.is_running() {
@@ -758,4 +922,21 @@ mod tests {
         assert_eq!(20, client.total_local_memory()); // first part (5) and second (5) were both swapped, so +10.
         assert_eq!(0, client.total_remote_memory());
     }
+
+    #[test]
+    fn prefetch_skips_freed_spans() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 1024);
+        let freed = client.allocate_span(20);
+        let span = client.allocate_span(20);
+        client.swap_out_spans_fully(&[freed.clone(), span.clone()]);
+        client.free_span(&freed);
+
+        client.prefetch_spans(vec![freed, span.clone()]);
+
+        let started_at = Instant::now();
+        while !client.is_span_local(&span) {
+            assert!(Instant::now() - started_at < Duration::from_secs(5), "span was not prefetched");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
 }
@@ -0,0 +1,96 @@
+use {
+    rkyv::{Archive, Deserialize, Infallible, ser::serializers::AllocSerializer},
+    serde::{Serialize, de::DeserializeOwned},
+};
+
+/**
+ * codec defines how serialized objects are laid out in far memory. It is picked per object type, so
+ * that hot objects can use a format that can be read without deserializing everything first.
+ */
+pub trait SerializationCodec<T> {
+    fn serialize(value: &T) -> Vec<u8>;
+    fn deserialize(bytes: &[u8]) -> T;
+}
+
+/**
+ * codec that allows to access fields of serialized objects in place (directly in span memory).
+ */
+pub trait ZeroCopySerializationCodec<T>: SerializationCodec<T> {
+    type Archived;
+
+    /// bytes are expected to be aligned to `align_of::<Self::Archived>()`.
+    unsafe fn access(bytes: &[u8]) -> &Self::Archived;
+}
+
+// default codec, works with anything that implements serde traits.
+pub struct BincodeCodec;
+
+impl<T: Serialize + DeserializeOwned> SerializationCodec<T> for BincodeCodec {
+    fn serialize(value: &T) -> Vec<u8> {
+        bincode::serialize(value).unwrap()
+    }
+
+    fn deserialize(bytes: &[u8]) -> T {
+        bincode::deserialize_from(bytes).unwrap()
+    }
+}
+
+// zero-copy codec based on rkyv archived format.
+pub struct RkyvCodec;
+
+impl<T> SerializationCodec<T> for RkyvCodec where T: Archive + rkyv::Serialize<AllocSerializer<256>>, T::Archived: Deserialize<T, Infallible> {
+    fn serialize(value: &T) -> Vec<u8> {
+        rkyv::to_bytes::<_, 256>(value).unwrap().into_vec()
+    }
+
+    fn deserialize(bytes: &[u8]) -> T {
+        if is_aligned_for::<T::Archived>(bytes) {
+            unsafe { rkyv::archived_root::<T>(bytes) }.deserialize(&mut Infallible).unwrap()
+        } else {
+            let copy = AlignedBytes::copy_from(bytes);
+            unsafe { rkyv::archived_root::<T>(copy.as_slice()) }.deserialize(&mut Infallible).unwrap()
+        }
+    }
+}
+
+impl<T> ZeroCopySerializationCodec<T> for RkyvCodec where T: Archive + rkyv::Serialize<AllocSerializer<256>>, T::Archived: Deserialize<T, Infallible> {
+    type Archived = T::Archived;
+
+    unsafe fn access(bytes: &[u8]) -> &Self::Archived {
+        // data in spans is written by this client, so validation is skipped to keep access cheap.
+        rkyv::archived_root::<T>(bytes)
+    }
+}
+
+pub fn is_aligned_for<A>(bytes: &[u8]) -> bool {
+    bytes.as_ptr() as usize % std::mem::align_of::<A>() == 0
+}
+
+/**
+ * span memory is not guaranteed to be aligned for archived types (offsets of objects within span are), so in that
+ * case data is copied to a buffer with alignment that is enough for archived types.
+ */
+pub struct AlignedBytes {
+    data: Vec<u128>,
+    len: usize,
+}
+
+impl AlignedBytes {
+    pub fn copy_from(bytes: &[u8]) -> Self {
+        let mut data = vec![0u128; (bytes.len() + 15) / 16];
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), data.as_mut_ptr() as *mut u8, bytes.len());
+        }
+
+        Self {
+            data,
+            len: bytes.len(),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.data.as_ptr() as *const u8, self.len)
+        }
+    }
+}
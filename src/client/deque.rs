@@ -0,0 +1,385 @@
+use {
+    std::{collections::VecDeque, marker::PhantomData, sync::{Arc, Mutex, Condvar, MutexGuard}},
+    tracing::{span, Level},
+    super::{
+        client::FarMemoryClient,
+        span::SpanId,
+    },
+};
+
+const DEFAULT_PREFETCH_CHUNKS: usize = 2;
+
+/**
+ * queue made of chunks, one span per chunk. Head and tail chunks are pinned, so that producers and consumers work with local
+ * memory, while the middle of the queue can be swapped out when backlog grows. When consumer moves on to the next chunk,
+ * chunks after it are prefetched.
+ *
+ * can be cloned and shared between threads. Items are copied as raw bytes, so they have to be Copy.
+ */
+pub struct FarMemoryDeque<T> {
+    inner: Arc<FarMemoryDequeInner<T>>,
+}
+
+struct FarMemoryDequeInner<T> {
+    client: FarMemoryClient,
+    chunk_len: usize, // items per chunk
+    max_len: Option<usize>,
+    prefetch_chunks: usize,
+
+    state: Mutex<DequeState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+
+    _phantom: PhantomData<T>,
+}
+
+struct DequeState {
+    chunks: VecDeque<SpanId>,
+    head: usize, // position of the first item in the first chunk
+    len: usize,
+    closed: bool,
+}
+
+impl<T> Clone for FarMemoryDeque<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T: Copy + Send> FarMemoryDeque<T> {
+    pub fn new(client: FarMemoryClient) -> Self {
+        let chunk_size = 2 * 1024 * 1024;
+        Self::with_chunk_len(client, chunk_size / std::mem::size_of::<T>().max(1))
+    }
+
+    pub fn with_chunk_len(client: FarMemoryClient, chunk_len: usize) -> Self {
+        Self {
+            inner: Arc::new(FarMemoryDequeInner {
+                client,
+                chunk_len: chunk_len.max(1),
+                max_len: None,
+                prefetch_chunks: DEFAULT_PREFETCH_CHUNKS,
+
+                state: Mutex::new(DequeState {
+                    chunks: VecDeque::new(),
+                    head: 0,
+                    len: 0,
+                    closed: false,
+                }),
+                not_empty: Condvar::new(),
+                not_full: Condvar::new(),
+
+                _phantom: PhantomData,
+            }),
+        }
+    }
+
+    // producers are blocked (or rejected in non-blocking mode) when queue has this many items.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        Arc::get_mut(&mut self.inner).expect("cannot configure deque after it was cloned").max_len = Some(max_len);
+        self
+    }
+
+    pub fn with_prefetch_chunks(mut self, prefetch_chunks: usize) -> Self {
+        Arc::get_mut(&mut self.inner).expect("cannot configure deque after it was cloned").prefetch_chunks = prefetch_chunks;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.state.lock().unwrap().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // waits while queue is full. Returns item back if queue is closed.
+    pub fn push_back(&self, item: T) -> Result<(), T> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            while self.inner.is_full(&state) {
+                state = self.inner.not_full.wait(state).unwrap();
+            }
+            if !self.inner.needs_tail_chunk(&state) {
+                break;
+            }
+            state = self.inner.add_tail_chunk(state);
+        }
+        self.inner.push_back_locked(&mut state, item)
+    }
+
+    // returns item back if queue is full or closed.
+    pub fn try_push_back(&self, item: T) -> Result<(), T> {
+        let mut state = self.inner.state.lock().unwrap();
+        loop {
+            if self.inner.is_full(&state) {
+                return Err(item);
+            }
+            if !self.inner.needs_tail_chunk(&state) {
+                break;
+            }
+            state = self.inner.add_tail_chunk(state);
+        }
+        self.inner.push_back_locked(&mut state, item)
+    }
+
+    // waits for an item. Returns None only when queue is closed and there is nothing left in it.
+    pub fn pop_front(&self) -> Option<T> {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.len == 0 {
+            if state.closed {
+                return None;
+            }
+            state = self.inner.not_empty.wait(state).unwrap();
+        }
+        let (item, consumed_chunk) = self.inner.pop_front_locked(&mut state);
+        drop(state);
+
+        self.inner.free_chunk(consumed_chunk);
+        Some(item)
+    }
+
+    pub fn try_pop_front(&self) -> Option<T> {
+        let mut state = self.inner.state.lock().unwrap();
+        if state.len == 0 {
+            return None;
+        }
+        let (item, consumed_chunk) = self.inner.pop_front_locked(&mut state);
+        drop(state);
+
+        self.inner.free_chunk(consumed_chunk);
+        Some(item)
+    }
+
+    // no more items can be pushed after this. Consumers get remaining items and then None.
+    pub fn close(&self) {
+        self.inner.state.lock().unwrap().closed = true;
+        self.inner.not_empty.notify_all();
+        self.inner.not_full.notify_all();
+    }
+}
+
+impl<T: Copy> FarMemoryDequeInner<T> {
+    fn is_full(&self, state: &MutexGuard<DequeState>) -> bool {
+        !state.closed && self.max_len.map(|max_len| state.len >= max_len).unwrap_or(false)
+    }
+
+    fn needs_tail_chunk(&self, state: &MutexGuard<DequeState>) -> bool {
+        !state.closed && state.head + state.len == state.chunks.len() * self.chunk_len
+    }
+
+    // allocates and pins the new chunk without holding the lock, so that other producers and consumers are not blocked
+    // while client makes room for it. Chunk is dropped if another producer has added one in the meantime.
+    fn add_tail_chunk<'a>(&'a self, state: MutexGuard<'a, DequeState>) -> MutexGuard<'a, DequeState> {
+        drop(state);
+
+        let chunk = span!(Level::DEBUG, "FarMemoryDeque - new tail chunk").in_scope(|| {
+            let chunk = self.client.allocate_span(self.chunk_len * std::mem::size_of::<T>());
+            self.client.pin_span(&chunk);
+            chunk
+        });
+
+        let mut state = self.state.lock().unwrap();
+        if !self.needs_tail_chunk(&state) {
+            drop(state);
+            self.client.free_span(&chunk);
+            return self.state.lock().unwrap();
+        }
+
+        // previous tail chunk is in the middle of the queue now, unless it is head as well
+        if state.chunks.len() > 1 {
+            self.client.unpin_span(state.chunks.back().unwrap());
+        }
+        state.chunks.push_back(chunk);
+        state
+    }
+
+    // tail chunk is expected to have room for the item, unless queue is closed.
+    fn push_back_locked(&self, state: &mut MutexGuard<DequeState>, item: T) -> Result<(), T> {
+        if state.closed {
+            return Err(item);
+        }
+
+        let tail = state.head + state.len;
+        let chunk = &state.chunks[tail / self.chunk_len];
+        let ptr = self.client.span_ptr(chunk) as *mut T;
+        unsafe {
+            ptr.add(tail % self.chunk_len).write_unaligned(item);
+        }
+        self.client.decrease_refs_for_span(chunk);
+
+        state.len += 1;
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    // returns chunk that was fully consumed, if any. It is freed by the caller after releasing the lock, because freeing
+    // waits for the span to be released by prefetching.
+    fn pop_front_locked(&self, state: &mut MutexGuard<DequeState>) -> (T, Option<SpanId>) {
+        let chunk = &state.chunks[0];
+        let ptr = self.client.span_ptr(chunk) as *const T;
+        let item = unsafe { ptr.add(state.head).read_unaligned() };
+        self.client.decrease_refs_for_span(chunk);
+
+        state.head += 1;
+        state.len -= 1;
+
+        let mut consumed_chunk = None;
+        if state.head == self.chunk_len {
+            span!(Level::DEBUG, "FarMemoryDeque - next head chunk").in_scope(|| {
+                consumed_chunk = state.chunks.pop_front();
+                state.head = 0;
+
+                if let Some(head) = state.chunks.front() {
+                    self.client.pin_span(head);
+                }
+
+                let prefetch_until = (1 + self.prefetch_chunks).min(state.chunks.len());
+                if prefetch_until > 1 {
+                    self.client.prefetch_spans(state.chunks.range(1..prefetch_until).cloned().collect());
+                }
+            });
+        }
+
+        self.not_full.notify_one();
+        (item, consumed_chunk)
+    }
+
+    fn free_chunk(&self, chunk: Option<SpanId>) {
+        if let Some(chunk) = chunk {
+            self.client.free_span(&chunk);
+        }
+    }
+}
+
+impl<T> Drop for FarMemoryDequeInner<T> {
+    fn drop(&mut self) {
+        for chunk in &self.state.get_mut().unwrap().chunks {
+            self.client.free_span(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::thread,
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn push_and_pop_in_order() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let deque = FarMemoryDeque::with_chunk_len(client.clone(), 10);
+
+        for i in 0..95u32 {
+            deque.push_back(i).unwrap();
+        }
+        assert_eq!(10, client.total_local_spans());
+
+        for i in 0..50u32 {
+            assert_eq!(Some(i), deque.try_pop_front());
+        }
+        assert_eq!(45, deque.len());
+        assert_eq!(5, client.total_local_spans());
+
+        for i in 50..95u32 {
+            assert_eq!(Some(i), deque.pop_front());
+        }
+        assert_eq!(None, deque.try_pop_front());
+    }
+
+    #[test]
+    fn head_and_tail_stay_local() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 64 * 1024);
+        let deque = FarMemoryDeque::with_chunk_len(client.clone(), 512);
+
+        for i in 0..100_000u64 {
+            deque.push_back(i).unwrap();
+        }
+        assert!(client.total_remote_memory() > 0);
+
+        {
+            let state = deque.inner.state.lock().unwrap();
+            assert_eq!(4096, client.span_local_memory_usage(state.chunks.front().unwrap()));
+            assert_eq!(4096, client.span_local_memory_usage(state.chunks.back().unwrap()));
+        }
+
+        for i in 0..100_000u64 {
+            assert_eq!(Some(i), deque.try_pop_front());
+        }
+    }
+
+    #[test]
+    fn producers_and_consumers() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let deque = FarMemoryDeque::with_chunk_len(client, 100).with_max_len(1000);
+
+        let producers: Vec<_> = (0..4u64).map(|producer| {
+            let deque = deque.clone();
+            thread::spawn(move || for i in 0..10_000u64 {
+                deque.push_back(producer * 10_000 + i).unwrap();
+            })
+        }).collect();
+
+        let consumers: Vec<_> = (0..2).map(|_| {
+            let deque = deque.clone();
+            thread::spawn(move || {
+                let mut items = Vec::new();
+                while let Some(item) = deque.pop_front() {
+                    items.push(item);
+                }
+                items
+            })
+        }).collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        deque.close();
+
+        let mut items: Vec<u64> = consumers.into_iter().flat_map(|v| v.join().unwrap()).collect();
+        items.sort();
+        assert_eq!((0..40_000).collect::<Vec<_>>(), items);
+    }
+
+    #[test]
+    fn concurrent_producers_do_not_leak_chunks() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let deque = FarMemoryDeque::with_chunk_len(client.clone(), 1);
+
+        let producers: Vec<_> = (0..4u64).map(|producer| {
+            let deque = deque.clone();
+            thread::spawn(move || for i in 0..1000u64 {
+                deque.push_back(producer * 1000 + i).unwrap();
+            })
+        }).collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // chunks allocated by producers that lost the race are freed
+        assert_eq!(4000, deque.len());
+        assert_eq!(4000, client.total_local_spans());
+    }
+
+    #[test]
+    fn push_to_closed_deque_and_drop() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let deque = FarMemoryDeque::with_chunk_len(client.clone(), 10);
+        for i in 0..25u32 {
+            deque.push_back(i).unwrap();
+        }
+
+        deque.close();
+        assert_eq!(Err(25), deque.push_back(25));
+        assert_eq!(Err(26), deque.try_push_back(26));
+        assert_eq!(Some(0), deque.pop_front());
+
+        drop(deque);
+        assert_eq!(0, client.total_local_spans());
+    }
+}
@@ -1,101 +1,671 @@
 use {
-    std::{marker::PhantomData, hash::{Hash, Hasher}, collections::hash_map::DefaultHasher},
+    std::{marker::PhantomData, hash::{Hash, Hasher}, collections::{HashMap, hash_map::DefaultHasher}, ops::{Deref, DerefMut}},
+    tracing::{span, Level},
     super::{
-        object::{FarMemory, FarMemoryLocal},
         client::FarMemoryClient,
+        buffer::FarMemoryBuffer,
+        object::ObjectId,
+        span::SpanId,
     },
 };
 
+const MAX_LOAD_FACTOR: f32 = 1.0;
+const BUCKET_SIZE: usize = std::mem::size_of::<u64>();
+const INDEX_INIT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/**
+ * both buckets index and nodes are stored in far memory: index is a buffer of object ids (one per bucket) and
+ * each node is a far memory object holding key, value and id of the next node in the chain.
+ *
+ * keys and values are copied into far memory byte by byte and objects are not aligned within spans, so both are
+ * required to be `Copy` and nodes are only accessed with unaligned reads and writes.
+ */
 pub struct FarMemoryHashMap<K, V> {
     client: FarMemoryClient,
-    index: Vec<Option<FarMemoryHashMapNode<K, V>>>,
+    index: FarMemoryBuffer,
+    buckets: usize,
+    len: usize,
+
+    // number of nodes per span, so that spans can be swapped out without reading nodes.
+    node_spans: HashMap<SpanId, usize>,
 
     _phantom: PhantomData<(K, V)>,
 }
 
-impl<K: Hash + PartialEq, V> FarMemoryHashMap<K, V> {
+#[derive(Clone, Copy)]
+struct FarMemoryHashMapNode<K, V> {
+    key: K,
+    value: V,
+
+    next: Option<u64>,
+}
+
+impl<K: Hash + PartialEq + Copy, V: Copy> FarMemoryHashMap<K, V> {
     pub fn new(client: FarMemoryClient, slots: usize) -> Self {
+        let buckets = slots.max(1);
+
         Self {
+            index: empty_index(client.clone(), buckets),
             client,
-            index: (0..slots).into_iter().map(|_| None).collect(),
+            buckets,
+            len: 0,
+
+            node_spans: HashMap::new(),
 
             _phantom: PhantomData,
         }
     }
 
-    pub fn insert(&mut self, key: K, value: V) {
-        let index = self.index_for_key(&key);
-        let key = FarMemory::from_value(self.client.clone(), key);
-        let value = FarMemory::from_value(self.client.clone(), value);
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let bucket = self.bucket_for_key(&key);
 
-        let slot = self.index.get_mut(index).unwrap();
-        if let Some(node) = slot {
-            node.insert(key, value);
-        } else {
-            *slot = Some(FarMemoryHashMapNode::new(key, value));
+        if let Some(object) = self.find_in_bucket(bucket, &key) {
+            return Some(self.with_node(&object, |node| std::mem::replace(&mut node.value, value)));
         }
+
+        self.insert_new(bucket, key, value);
+        None
+    }
+
+    pub fn get(&self, key: &K) -> Option<FarMemoryHashMapValue<'_, K, V>> {
+        let object = self.find_in_bucket(self.bucket_for_key(key), key)?;
+        Some(FarMemoryHashMapValue { value: self.read_node(&object).value, _phantom: PhantomData })
     }
 
-    pub fn get(&self, key: &K) -> Option<FarMemoryLocal<V>> {
-        let index = self.index_for_key(&key);
+    pub fn get_mut(&mut self, key: &K) -> Option<FarMemoryHashMapValueMut<'_, K, V>> {
+        let object = self.find_in_bucket(self.bucket_for_key(key), key)?;
+        Some(FarMemoryHashMapValueMut::new(self.node_ref(object)))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.find_in_bucket(self.bucket_for_key(key), key).is_some()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let bucket = self.bucket_for_key(key);
 
-        let slot = self.index.get(index).unwrap();
-        if let Some(node) = slot {
-            let node_key = node.key.to_local();
-            if *node_key == *key {
-                Some(node.value.to_local())
-            } else {
-                node.get(key)
+        let mut prev: Option<ObjectId> = None;
+        let mut current = self.bucket_head(bucket);
+        while let Some(object) = current {
+            let node = self.read_node(&object);
+            let next = node.next.map(ObjectId::from_id);
+            if node.key != *key {
+                prev = Some(object);
+                current = next;
+                continue;
             }
-        } else {
-            None
+
+            // unlink node from the chain
+            match &prev {
+                Some(prev) => self.with_node(prev, |prev| prev.next = node.next),
+                None => self.set_bucket_head(bucket, next),
+            };
+
+            self.remove_node(&object);
+            self.len -= 1;
+            return Some(node.value);
         }
+
+        None
     }
 
-    fn index_for_key(&self, key: &K) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        let hash = hasher.finish();
+    pub fn entry(&mut self, key: K) -> FarMemoryHashMapEntry<'_, K, V> {
+        match self.find_in_bucket(self.bucket_for_key(&key), &key) {
+            Some(object) => FarMemoryHashMapEntry::Occupied(FarMemoryHashMapOccupiedEntry { map: self, key, object }),
+            None => FarMemoryHashMapEntry::Vacant(FarMemoryHashMapVacantEntry { map: self, key }),
+        }
+    }
 
-        (hash % self.index.len() as u64) as usize
+    pub fn iter(&self) -> FarMemoryHashMapIterator<'_, K, V> {
+        FarMemoryHashMapIterator {
+            map: self,
+            bucket: 0,
+            next_node: None,
+        }
     }
-}
 
-pub struct FarMemoryHashMapNode<K, V> {
-    key: FarMemory<K>,
-    value: FarMemory<V>,
+    pub fn keys(&self) -> impl Iterator<Item = FarMemoryHashMapKey<'_, K, V>> {
+        self.iter().map(|v| FarMemoryHashMapKey { key: v.key, _phantom: PhantomData })
+    }
 
-    next: Option<Box<FarMemoryHashMapNode<K, V>>>,
-}
+    pub fn values(&self) -> impl Iterator<Item = FarMemoryHashMapValue<'_, K, V>> {
+        self.iter().map(|v| FarMemoryHashMapValue { value: v.value, _phantom: PhantomData })
+    }
 
-impl<K: PartialEq, V> FarMemoryHashMapNode<K, V> {
-    pub fn new(key: FarMemory<K>, value: FarMemory<V>) -> Self {
-        Self {
+    pub fn swap_out(&self) {
+        self.client.swap_out_spans_fully(&self.node_spans.keys().cloned().collect::<Vec<_>>());
+        self.index.swap_out();
+    }
+
+    pub fn clear(&mut self) {
+        self.remove_all_nodes();
+    }
+
+    fn insert_new(&mut self, bucket: usize, key: K, value: V) -> ObjectId {
+        let node = FarMemoryHashMapNode {
             key,
             value,
+            next: self.bucket_head(bucket).map(|v| v.id()),
+        };
+
+        let object = self.client.put_object(unsafe {
+            std::slice::from_raw_parts(
+                (&node as *const _) as *const u8,
+                std::mem::size_of::<FarMemoryHashMapNode<K, V>>()
+            ).to_vec()
+        });
+        *self.node_spans.entry(self.client.get_object(&object).span_id).or_insert(0) += 1;
+
+        self.set_bucket_head(bucket, Some(object.clone()));
+        self.len += 1;
 
-            next: None,
+        if self.len as f32 > self.buckets as f32 * MAX_LOAD_FACTOR {
+            self.resize(self.buckets * 2);
         }
+
+        object
+    }
+
+    fn resize(&mut self, buckets: usize) {
+        span!(Level::DEBUG, "FarMemoryHashMap::resize", buckets).in_scope(|| {
+            let new_index = empty_index(self.client.clone(), buckets);
+
+            for bucket in 0..self.buckets {
+                let mut current = self.bucket_head(bucket);
+                while let Some(object) = current {
+                    // nodes stay where they are, only links are updated
+                    current = self.with_node(&object, |node| {
+                        let new_bucket = bucket_for_key(&node.key, buckets);
+                        let new_head = read_bucket(&new_index, new_bucket);
+                        write_bucket(&new_index, new_bucket, Some(&object));
+                        std::mem::replace(&mut node.next, new_head.map(|v| v.id())).map(ObjectId::from_id)
+                    });
+                }
+            }
+
+            let old_index = std::mem::replace(&mut self.index, new_index);
+            old_index.free();
+            self.buckets = buckets;
+        })
+    }
+
+    fn find_in_bucket(&self, bucket: usize, key: &K) -> Option<ObjectId> {
+        let mut current = self.bucket_head(bucket);
+        while let Some(object) = current {
+            let node = self.read_node(&object);
+            if node.key == *key {
+                return Some(object);
+            }
+            current = node.next.map(ObjectId::from_id);
+        }
+
+        None
+    }
+
+    fn bucket_for_key(&self, key: &K) -> usize {
+        bucket_for_key(key, self.buckets)
+    }
+
+    fn with_node<R>(&self, object: &ObjectId, f: impl FnOnce(&mut FarMemoryHashMapNode<K, V>) -> R) -> R {
+        let node_ref = self.node_ref(object.clone());
+        let mut node = node_ref.read();
+        let result = f(&mut node);
+        node_ref.write(node);
+        result
+    }
+
+    fn read_node(&self, object: &ObjectId) -> FarMemoryHashMapNode<K, V> {
+        self.node_ref(object.clone()).read()
+    }
+}
+
+// operations that only move nodes around as bytes and do not need any bounds on keys and values, so that they
+// can be used from drop.
+impl<K, V> FarMemoryHashMap<K, V> {
+    fn bucket_head(&self, bucket: usize) -> Option<ObjectId> {
+        read_bucket(&self.index, bucket)
+    }
+
+    fn set_bucket_head(&self, bucket: usize, object: Option<ObjectId>) {
+        write_bucket(&self.index, bucket, object.as_ref())
+    }
+
+    fn next_node(&self, object: &ObjectId) -> Option<ObjectId> {
+        let node = self.node_ref(object.clone());
+        unsafe { std::ptr::addr_of!((*node.ptr).next).read_unaligned() }.map(ObjectId::from_id)
+    }
+
+    // frees slot of the node. Span of the node is freed by the client once its last object is removed.
+    fn remove_node(&mut self, object: &ObjectId) {
+        let span_id = self.client.get_object(object).span_id;
+        self.client.remove_object(object);
+
+        let nodes = self.node_spans.get_mut(&span_id).unwrap();
+        *nodes -= 1;
+        if *nodes == 0 {
+            self.node_spans.remove(&span_id);
+        }
+    }
+
+    fn remove_all_nodes(&mut self) {
+        for bucket in 0..self.buckets {
+            let mut current = self.bucket_head(bucket);
+            while let Some(object) = current {
+                current = self.next_node(&object);
+                self.remove_node(&object);
+            }
+            self.set_bucket_head(bucket, None);
+        }
+        self.len = 0;
+    }
+
+    fn node_ref(&self, object: ObjectId) -> NodeRef<'_, K, V> {
+        let location = self.client.get_object(&object);
+        let ptr = unsafe { self.client.span_ptr(&location.span_id).add(location.offset) } as *mut FarMemoryHashMapNode<K, V>;
+
+        NodeRef {
+            client: self.client.clone(),
+            span_id: location.span_id,
+            ptr,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Drop for FarMemoryHashMap<K, V> {
+    fn drop(&mut self) {
+        self.remove_all_nodes();
+        std::mem::replace(&mut self.index, FarMemoryBuffer::new(self.client.clone())).free();
+    }
+}
+
+fn bucket_for_key<K: Hash>(key: &K, buckets: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+
+    (hash % buckets as u64) as usize
+}
+
+fn empty_index(client: FarMemoryClient, buckets: usize) -> FarMemoryBuffer {
+    let mut index = FarMemoryBuffer::new(client);
+
+    let mut remaining = buckets * BUCKET_SIZE;
+    while remaining > 0 {
+        let chunk = remaining.min(INDEX_INIT_CHUNK_SIZE);
+        index.append(vec![0; chunk]);
+        remaining -= chunk;
+    }
+
+    index
+}
+
+// buckets store object id + 1, so that zero means empty bucket
+fn read_bucket(index: &FarMemoryBuffer, bucket: usize) -> Option<ObjectId> {
+    let bytes = index.slice((bucket * BUCKET_SIZE)..((bucket + 1) * BUCKET_SIZE));
+    match u64::from_le_bytes(bytes.try_into().unwrap()) {
+        0 => None,
+        id => Some(ObjectId::from_id(id - 1)),
+    }
+}
+
+fn write_bucket(index: &FarMemoryBuffer, bucket: usize, object: Option<&ObjectId>) {
+    let value = object.map(|v| v.id() + 1).unwrap_or(0);
+    index.write_range(bucket * BUCKET_SIZE, &value.to_le_bytes());
+}
+
+// keeps span of the node in use while it is referenced. Nodes are at arbitrary offsets within spans, so pointer
+// is never dereferenced directly.
+struct NodeRef<'a, K, V> {
+    client: FarMemoryClient,
+    span_id: SpanId,
+    ptr: *mut FarMemoryHashMapNode<K, V>,
+    _phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, K: Copy, V: Copy> NodeRef<'a, K, V> {
+    fn read(&self) -> FarMemoryHashMapNode<K, V> {
+        unsafe { self.ptr.read_unaligned() }
+    }
+
+    fn write(&self, node: FarMemoryHashMapNode<K, V>) {
+        unsafe { self.ptr.write_unaligned(node) }
+    }
+
+    fn read_value(&self) -> V {
+        unsafe { std::ptr::addr_of!((*self.ptr).value).read_unaligned() }
+    }
+
+    fn write_value(&self, value: V) {
+        unsafe { std::ptr::addr_of_mut!((*self.ptr).value).write_unaligned(value) }
+    }
+}
+
+impl<'a, K, V> Drop for NodeRef<'a, K, V> {
+    fn drop(&mut self) {
+        self.client.decrease_refs_for_span(&self.span_id);
+    }
+}
+
+pub struct FarMemoryHashMapRef<'a, K, V> {
+    key: K,
+    value: V,
+    _phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, K, V> FarMemoryHashMapRef<'a, K, V> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn value(&self) -> &V {
+        &self.value
+    }
+}
+
+pub struct FarMemoryHashMapKey<'a, K, V> {
+    key: K,
+    _phantom: PhantomData<&'a V>,
+}
+
+impl<'a, K, V> Deref for FarMemoryHashMapKey<'a, K, V> {
+    type Target = K;
+
+    fn deref(&self) -> &Self::Target {
+        &self.key
     }
+}
+
+pub struct FarMemoryHashMapValue<'a, K, V> {
+    value: V,
+    _phantom: PhantomData<&'a K>,
+}
+
+impl<'a, K, V> Deref for FarMemoryHashMapValue<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
 
-    pub fn insert(&mut self, key: FarMemory<K>, value: FarMemory<V>) {
-        if let Some(node) = &mut self.next {
-            node.insert(key, value);
-        } else {
-            self.next = Some(Box::new(FarMemoryHashMapNode::new(key, value)));
+// value is modified locally and written back to far memory on drop
+pub struct FarMemoryHashMapValueMut<'a, K: Copy, V: Copy> {
+    node: NodeRef<'a, K, V>,
+    value: V,
+}
+
+impl<'a, K: Copy, V: Copy> FarMemoryHashMapValueMut<'a, K, V> {
+    fn new(node: NodeRef<'a, K, V>) -> Self {
+        Self {
+            value: node.read_value(),
+            node,
         }
     }
+}
+
+impl<'a, K: Copy, V: Copy> Deref for FarMemoryHashMapValueMut<'a, K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<'a, K: Copy, V: Copy> DerefMut for FarMemoryHashMapValueMut<'a, K, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.value
+    }
+}
+
+impl<'a, K: Copy, V: Copy> Drop for FarMemoryHashMapValueMut<'a, K, V> {
+    fn drop(&mut self) {
+        self.node.write_value(self.value);
+    }
+}
 
-    pub fn get(&self, key: &K) -> Option<FarMemoryLocal<V>> {
-        if let Some(node) = &self.next {
-            let node_key = node.key.to_local();
-            if *node_key == *key {
-                Some(node.value.to_local())
-            } else {
-                node.get(key)
+pub struct FarMemoryHashMapIterator<'a, K, V> {
+    map: &'a FarMemoryHashMap<K, V>,
+    bucket: usize,
+    next_node: Option<ObjectId>,
+}
+
+impl<'a, K: Hash + PartialEq + Copy, V: Copy> Iterator for FarMemoryHashMapIterator<'a, K, V> {
+    type Item = FarMemoryHashMapRef<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next_node.is_none() {
+            if self.bucket >= self.map.buckets {
+                return None;
             }
-        } else {
-            None
+
+            self.next_node = self.map.bucket_head(self.bucket);
+            self.bucket += 1;
+        }
+
+        let node = self.map.read_node(&self.next_node.take().unwrap());
+        self.next_node = node.next.map(ObjectId::from_id);
+
+        Some(FarMemoryHashMapRef { key: node.key, value: node.value, _phantom: PhantomData })
+    }
+}
+
+pub enum FarMemoryHashMapEntry<'a, K, V> {
+    Occupied(FarMemoryHashMapOccupiedEntry<'a, K, V>),
+    Vacant(FarMemoryHashMapVacantEntry<'a, K, V>),
+}
+
+impl<'a, K: Hash + PartialEq + Copy, V: Copy> FarMemoryHashMapEntry<'a, K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Self::Occupied(entry) => &entry.key,
+            Self::Vacant(entry) => &entry.key,
         }
     }
+
+    pub fn or_insert(self, default: V) -> FarMemoryHashMapValueMut<'a, K, V> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> FarMemoryHashMapValueMut<'a, K, V> {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Self::Occupied(entry) => {
+                entry.map.with_node(&entry.object, |node| f(&mut node.value));
+                Self::Occupied(entry)
+            },
+            Self::Vacant(entry) => Self::Vacant(entry),
+        }
+    }
+}
+
+impl<'a, K: Hash + PartialEq + Copy, V: Copy + Default> FarMemoryHashMapEntry<'a, K, V> {
+    pub fn or_default(self) -> FarMemoryHashMapValueMut<'a, K, V> {
+        self.or_insert_with(V::default)
+    }
+}
+
+pub struct FarMemoryHashMapOccupiedEntry<'a, K, V> {
+    map: &'a mut FarMemoryHashMap<K, V>,
+    key: K,
+    object: ObjectId,
+}
+
+impl<'a, K: Hash + PartialEq + Copy, V: Copy> FarMemoryHashMapOccupiedEntry<'a, K, V> {
+    pub fn get(&self) -> FarMemoryHashMapValue<'_, K, V> {
+        FarMemoryHashMapValue { value: self.map.read_node(&self.object).value, _phantom: PhantomData }
+    }
+
+    pub fn get_mut(&mut self) -> FarMemoryHashMapValueMut<'_, K, V> {
+        FarMemoryHashMapValueMut::new(self.map.node_ref(self.object.clone()))
+    }
+
+    pub fn into_mut(self) -> FarMemoryHashMapValueMut<'a, K, V> {
+        let map: &'a FarMemoryHashMap<K, V> = self.map;
+        FarMemoryHashMapValueMut::new(map.node_ref(self.object))
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        self.map.with_node(&self.object, |node| std::mem::replace(&mut node.value, value))
+    }
+
+    pub fn remove(self) -> V {
+        self.map.remove(&self.key).unwrap()
+    }
+}
+
+pub struct FarMemoryHashMapVacantEntry<'a, K, V> {
+    map: &'a mut FarMemoryHashMap<K, V>,
+    key: K,
+}
+
+impl<'a, K: Hash + PartialEq + Copy, V: Copy> FarMemoryHashMapVacantEntry<'a, K, V> {
+    pub fn insert(self, value: V) -> FarMemoryHashMapValueMut<'a, K, V> {
+        let map = self.map;
+        let bucket = map.bucket_for_key(&self.key);
+        let object = map.insert_new(bucket, self.key, value);
+
+        let map: &'a FarMemoryHashMap<K, V> = map;
+        FarMemoryHashMapValueMut::new(map.node_ref(object))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    fn test_client() -> FarMemoryClient {
+        FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024)
+    }
+
+    #[test]
+    fn insert_and_get() {
+        let mut map = FarMemoryHashMap::new(test_client(), 16);
+        assert_eq!(None, map.insert(1u64, 10u64));
+        assert_eq!(None, map.insert(2u64, 20u64));
+
+        assert_eq!(2, map.len());
+        assert_eq!(10, *map.get(&1).unwrap());
+        assert_eq!(20, *map.get(&2).unwrap());
+        assert!(map.get(&3).is_none());
+    }
+
+    #[test]
+    fn insert_returns_old_value() {
+        let mut map = FarMemoryHashMap::new(test_client(), 16);
+        map.insert(1u64, 10u64);
+
+        assert_eq!(Some(10), map.insert(1u64, 20u64));
+        assert_eq!(1, map.len());
+        assert_eq!(20, *map.get(&1).unwrap());
+    }
+
+    #[test]
+    fn remove() {
+        let mut map = FarMemoryHashMap::new(test_client(), 1); // single bucket, so all nodes are chained
+        map.insert(1u64, 10u64);
+        map.insert(2u64, 20u64);
+        map.insert(3u64, 30u64);
+
+        assert_eq!(Some(20), map.remove(&2));
+        assert_eq!(None, map.remove(&2));
+        assert_eq!(2, map.len());
+        assert_eq!(10, *map.get(&1).unwrap());
+        assert_eq!(30, *map.get(&3).unwrap());
+    }
+
+    #[test]
+    fn entry() {
+        let mut map = FarMemoryHashMap::new(test_client(), 16);
+
+        *map.entry(1u64).or_insert(0u64) += 5;
+        *map.entry(1u64).or_insert(0u64) += 5;
+        map.entry(2u64).and_modify(|v| *v += 1).or_default();
+
+        assert_eq!(10, *map.get(&1).unwrap());
+        assert_eq!(0, *map.get(&2).unwrap());
+    }
+
+    #[test]
+    fn iter_and_resize() {
+        let mut map = FarMemoryHashMap::new(test_client(), 2);
+        for i in 0..1000u64 {
+            map.insert(i, i * 2);
+        }
+
+        assert_eq!(1000, map.len());
+        assert!(map.buckets >= 1000);
+
+        let mut entries: Vec<_> = map.iter().map(|v| (*v.key(), *v.value())).collect();
+        entries.sort();
+        assert_eq!((0..1000u64).map(|i| (i, i * 2)).collect::<Vec<_>>(), entries);
+
+        assert_eq!(1000, map.keys().count());
+        assert_eq!((0..1000u64).map(|i| i * 2).sum::<u64>(), map.values().map(|v| *v).sum::<u64>());
+    }
+
+    #[test]
+    fn get_after_swap_out() {
+        let client = test_client();
+        let mut map = FarMemoryHashMap::new(client.clone(), 16);
+        for i in 0..100u64 {
+            map.insert(i, i + 1);
+        }
+
+        map.swap_out();
+        assert_eq!(0, client.total_local_memory());
+
+        assert_eq!(43, *map.get(&42).unwrap());
+    }
+
+    #[test]
+    fn drop_releases_nodes_and_index() {
+        let client = test_client();
+
+        let mut map = FarMemoryHashMap::new(client.clone(), 16);
+        for i in 0..100u64 {
+            map.insert(i, i + 1);
+        }
+        let local_memory = client.total_local_memory();
+        drop(map);
+
+        // node slots and index are released, so the same map does not need any more memory
+        let mut map = FarMemoryHashMap::new(client.clone(), 16);
+        for i in 0..100u64 {
+            map.insert(i, i + 1);
+        }
+        assert_eq!(local_memory, client.total_local_memory());
+    }
+
+    #[test]
+    fn drop_frees_node_spans() {
+        let client = test_client();
+        let spans = client.total_local_spans();
+
+        let mut map = FarMemoryHashMap::new(client.clone(), 16);
+        for i in 0..100u64 {
+            map.insert(i, i + 1);
+        }
+        drop(map);
+
+        assert_eq!(spans, client.total_local_spans());
+        assert_eq!(0, client.total_local_memory());
+    }
 }
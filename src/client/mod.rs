@@ -5,13 +5,22 @@ use {
 
 pub use self::{
     buffer::FarMemoryBuffer,
+    buffer_cursor::FarMemoryBufferCursor,
     buffered_vec::FarMemoryBufferedVec,
-    vec::FarMemoryVec,
+    cache::FarMemoryCache,
+    vec::{FarMemoryVec, FarMemoryVecSlice},
     client::FarMemoryClient,
     span::SpanId,
-    serialized_object::FarMemorySerialized,
+    serialized_object::{FarMemorySerialized, FarMemoryArchived},
+    codec::{SerializationCodec, ZeroCopySerializationCodec, BincodeCodec, RkyvCodec},
     serialized_object_vec::FarMemorySerializedObjectVec,
-    hashmap::FarMemoryHashMap,
+    hashmap::{FarMemoryHashMap, FarMemoryHashMapEntry},
+    btree::FarMemoryBTreeMap,
+    deque::FarMemoryDeque,
+    table::{FarMemoryTable, FarMemoryTableError, FarMemoryTableSchema, FarMemoryColumnType, FarMemoryValue, FarMemoryTablePredicate, FarMemoryTableRows},
+    tensor::{FarMemoryTensor, FarMemoryTensorView},
+    algorithms::{FarMemoryExternalAlgorithms, FarMemoryExternalStorage},
+    blob_store::{FarMemoryBlobStore, FarMemoryBlobStoreStats, FarMemoryBlobWriter, FarMemoryBlobReader, BlobId},
     backend::{
         FarMemoryBackend,
         in_memory::InMemoryBackend,
@@ -39,14 +48,23 @@ pub use self::{
 pub mod backend;
 pub mod replacement;
 
+mod algorithms;
+mod blob_store;
+mod btree;
 mod buffer;
+mod buffer_cursor;
 mod buffered_vec;
+mod cache;
 mod client;
+mod codec;
+mod deque;
 mod hashmap;
 mod object;
 mod serialized_object;
 mod serialized_object_vec;
 mod span;
+mod table;
+mod tensor;
 mod vec;
 
 /**
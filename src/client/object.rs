@@ -11,9 +11,19 @@ use {
     },
 };
 
-#[derive(Clone, Eq, PartialEq, Hash)]
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct ObjectId(u64);
 
+impl ObjectId {
+    pub fn from_id(id: u64) -> Self {
+        Self(id)
+    }
+
+    pub fn id(&self) -> u64 {
+        self.0
+    }
+}
+
 #[derive(Clone)]
 pub struct ObjectLocation {
     pub span_id: SpanId,
@@ -42,6 +52,8 @@ pub struct ObjectRegistry {
 
     object_mapping: RwLock<HashMap<ObjectId, ObjectLocation>>,
     slots_by_size_class: Mutex<HashMap<usize, Vec<ObjectSlot>>>,
+    // number of objects per span, so that span can be freed when its last object is removed. Locked after slots.
+    objects_by_span: Mutex<HashMap<SpanId, usize>>,
 }
 
 impl ObjectRegistry {
@@ -50,6 +62,7 @@ impl ObjectRegistry {
             object_id_counter: AtomicU64::new(0),
             object_mapping: RwLock::new(HashMap::new()),
             slots_by_size_class: Mutex::new(HashMap::new()),
+            objects_by_span: Mutex::new(HashMap::new()),
         }
     }
 
@@ -87,6 +100,7 @@ impl ObjectRegistry {
         } else {
             panic!("it is not expected that slot size is smaller than object size: {}, size class is {}", slots_by_size_class[0].len, size_class);
         };
+        *self.objects_by_span.lock().unwrap().entry(slot.span_id.clone()).or_insert(0) += 1;
 
         let location = ObjectLocation {
             span_id: slot.span_id,
@@ -121,23 +135,52 @@ impl ObjectRegistry {
         self.object_mapping.read().unwrap().get(object_id).unwrap().clone()
     }
 
-    pub fn size_class_for_object(&self, object_size: usize) -> usize {
-        // TODO: implement actual size classes
+    // returns span of the object if it was the last object in it. Slots of that span are dropped, so the span
+    // can be freed.
+    pub fn remove_object(&self, object_id: &ObjectId) -> Option<SpanId> {
+        let location = self.object_mapping.write().unwrap().remove(object_id).unwrap();
+        let size_class = self.size_class_for_object(location.len);
+
+        let mut slots_by_size_class = self.slots_by_size_class.lock().unwrap();
+        let slots = slots_by_size_class.get_mut(&size_class).unwrap();
+
+        let mut objects_by_span = self.objects_by_span.lock().unwrap();
+        let objects = objects_by_span.get_mut(&location.span_id).unwrap();
+        *objects -= 1;
+        if *objects == 0 {
+            objects_by_span.remove(&location.span_id);
+            slots.retain(|slot| slot.span_id != location.span_id);
+            return Some(location.span_id);
+        }
 
+        // slot is returned to its size class, so it can be reused for the next object
+        slots.push(ObjectSlot {
+            span_id: location.span_id,
+            offset: location.offset,
+            len: size_class,
+        });
+        None
+    }
+
+    pub fn size_class_for_object(&self, object_size: usize) -> usize {
         // for flights in dataframe demo
         if object_size >= 110 && object_size <= 120 {
-            return 120;
+            return 128;
         } else if object_size >= 310 && object_size <= 400 {
             return 400;
         } else if object_size >= 400 && object_size <= 500 {
-            return 500;
+            return 512;
         }
 
-        if object_size != 8200 && object_size != 8 {
-            panic!("this object size is not supported: {:?}", object_size);
+        // generic size classes: multiples of 16 for small objects, then 4 classes per power of two. All size classes
+        // are multiples of 16, so objects start at 16 byte aligned offsets within span and archived objects can be
+        // accessed in place (codec falls back to a copy if span memory itself is not aligned).
+        if object_size <= 128 {
+            return object_size.max(1).next_multiple_of(16);
         }
 
-        object_size
+        let step = object_size.next_power_of_two() / 8;
+        object_size.next_multiple_of(step)
     }
 }
 
@@ -221,4 +264,21 @@ mod tests {
 
         assert_eq!(42, object.to_local().v);
     }
+
+    #[test]
+    fn span_is_released_with_last_object() {
+        let registry = ObjectRegistry::new();
+        let span_id = SpanId::from_id(0);
+
+        let first = registry.next_object_id();
+        registry.add_span_for_object(span_id.clone(), 1024, first.clone(), 100);
+        let second = registry.next_object_id();
+        assert_eq!(span_id, registry.put_object(second.clone(), 100).unwrap().span_id);
+
+        assert!(registry.remove_object(&first).is_none());
+        assert_eq!(Some(span_id), registry.remove_object(&second));
+
+        // no slots are left in released span
+        assert!(registry.put_object(registry.next_object_id(), 100).is_none());
+    }
 }
@@ -42,4 +42,8 @@ impl ReplacementPolicy for LeastRecentlyUsedReplacementPolicy {
     fn on_span_swap_in(&self, span_id: &SpanId) {
         self.cache.write().unwrap().put(span_id.clone(), ());
     }
+
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.cache.write().unwrap().pop_entry(span_id);
+    }
 }
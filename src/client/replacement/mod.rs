@@ -24,6 +24,7 @@ pub trait ReplacementPolicy: Send + Sync {
     fn on_span_access(&self, span_id: &SpanId) {}
     fn on_span_swap_out(&self, span_id: &SpanId, partial: bool) {}
     fn on_span_swap_in(&self, span_id: &SpanId) {}
+    fn on_span_free(&self, span_id: &SpanId) {}
     fn on_stop(&self) {}
 }
 
@@ -67,7 +68,7 @@ impl ReplacementPolicy for MostRecentlyUsedReplacementPolicy {
         let history = self.history.read().unwrap();
         let spans: Vec<_> = spans.iter()
             .map(|v| (v, history.get(v).unwrap_or(&0)))
-            .sorted_by_key(|v| 0 - v.1)
+            .sorted_by_key(|v| std::cmp::Reverse(*v.1))
             .map(|a| a.0.clone())
             .collect();
         Box::new(spans.into_iter())
@@ -76,6 +77,10 @@ impl ReplacementPolicy for MostRecentlyUsedReplacementPolicy {
     fn on_span_access(&self, span_id: &SpanId) {
         self.history.write().unwrap().insert(span_id.clone(), self.counter.fetch_add(1, Ordering::Relaxed));;
     }
+
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.history.write().unwrap().remove(span_id);
+    }
 }
 
 // current best when combined with MostRecentlyUsedReplacementPolicy
@@ -124,6 +129,11 @@ impl ReplacementPolicy for PreferRemoteSpansReplacementPolicy {
         self.inner.on_span_swap_out(span_id, partial);
         self.remote_spans.write().unwrap().insert(span_id.clone());
     }
+
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.inner.on_span_free(span_id);
+        self.remote_spans.write().unwrap().remove(span_id);
+    }
 }
 
 pub struct ReplayReplacementPolicy {
@@ -207,6 +217,10 @@ impl ReplacementPolicy for ReplayReplacementPolicy {
         self.fallback.on_span_swap_in(span_id)
     }
 
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.fallback.on_span_free(span_id)
+    }
+
     fn on_stop(&self) {
         if self.record_mode {
             fs::write(&self.history_file_path, &serde_json::to_vec(&*self.history.read().unwrap()).unwrap()).unwrap();
@@ -61,6 +61,10 @@ impl ReplacementPolicy for RemoteReplayReplacementPolicy {
         self.fallback.on_span_swap_in(span_id)
     }
 
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.fallback.on_span_free(span_id)
+    }
+
     fn on_stop(&self) {
         // TODO: flush state
         self.fallback.on_stop()
@@ -41,6 +41,10 @@ impl ReplacementPolicy for TrackingReplacementPolicy {
         self.inner.on_span_swap_in(span_id)
     }
 
+    fn on_span_free(&self, span_id: &SpanId) {
+        self.inner.on_span_free(span_id)
+    }
+
     fn on_stop(&self) {
         // TODO: flush stats
         self.inner.on_stop()
@@ -1,17 +1,20 @@
 use {
     std::{marker::PhantomData, ops::Deref},
-    tracing::{span, Level},
-    serde::{Serialize, de::DeserializeOwned},
-    super::{FarMemoryClient, object::ObjectId, span::SpanId},
+    super::{
+        FarMemoryClient,
+        object::ObjectId,
+        span::SpanId,
+        codec::{SerializationCodec, ZeroCopySerializationCodec, BincodeCodec, AlignedBytes, is_aligned_for},
+    },
 };
 
-pub struct FarMemorySerialized<T> {
+pub struct FarMemorySerialized<T, C = BincodeCodec> {
     client: FarMemoryClient,
     object: ObjectId,
-    _phantom: PhantomData<T>,
+    _phantom: PhantomData<(T, C)>,
 }
 
-impl<T> FarMemorySerialized<T> {
+impl<T, C> FarMemorySerialized<T, C> {
     pub fn is_local(&self) -> bool {
         self.client.is_object_local(&self.object)
     }
@@ -19,12 +22,20 @@ impl<T> FarMemorySerialized<T> {
     pub fn span(&self) -> SpanId {
         self.client.get_object(&self.object).span_id
     }
+
+    pub(super) fn object(&self) -> &ObjectId {
+        &self.object
+    }
+
+    // removes object from far memory. Clones of this object should not be used after that.
+    pub fn free(self) {
+        self.client.remove_object(&self.object);
+    }
 }
 
-impl <T: Serialize> FarMemorySerialized<T> {
+impl<T, C: SerializationCodec<T>> FarMemorySerialized<T, C> {
     pub fn from_value(client: FarMemoryClient, value: T) -> Self {
-        // TODO: use rkyv instead for better performance?
-        let serialized = bincode::serialize(&value).unwrap();
+        let serialized = C::serialize(&value);
         let object = client.put_object(serialized);
 
         Self {
@@ -33,9 +44,7 @@ impl <T: Serialize> FarMemorySerialized<T> {
             _phantom: PhantomData,
         }
     }
-}
 
-impl <T: DeserializeOwned> FarMemorySerialized<T> {
     pub fn to_local(&self) -> T {
         let location = self.client.get_object(&self.object);
         let bytes = unsafe {
@@ -43,7 +52,7 @@ impl <T: DeserializeOwned> FarMemorySerialized<T> {
             std::slice::from_raw_parts(ptr, location.len)
         };
 
-        let data = bincode::deserialize_from(bytes).unwrap();
+        let data = C::deserialize(bytes);
         self.client.decrease_refs_for_span(&location.span_id);
 
         // returning just data, because it is owned, and spans refs are already decreased
@@ -51,7 +60,37 @@ impl <T: DeserializeOwned> FarMemorySerialized<T> {
     }
 }
 
-impl<T> Clone for FarMemorySerialized<T> {
+impl<T, C: ZeroCopySerializationCodec<T>> FarMemorySerialized<T, C> {
+    /// provides access to archived object without deserializing it. Span stays in use until returned value is dropped.
+    pub fn to_archived(&self) -> FarMemoryArchived<T, C> {
+        let location = self.client.get_object(&self.object);
+        let bytes = unsafe {
+            let ptr = self.client.span_ptr(&location.span_id).add(location.offset);
+            std::slice::from_raw_parts(ptr as *const u8, location.len)
+        };
+
+        let data = if is_aligned_for::<C::Archived>(bytes) {
+            ArchivedData::InSpan {
+                span_id: location.span_id,
+                ptr: bytes.as_ptr(),
+                len: bytes.len(),
+            }
+        } else {
+            // object offset in span is not aligned, so have to make a copy
+            let copy = AlignedBytes::copy_from(bytes);
+            self.client.decrease_refs_for_span(&location.span_id);
+            ArchivedData::Copied(copy)
+        };
+
+        FarMemoryArchived {
+            client: self.client.clone(),
+            data,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, C> Clone for FarMemorySerialized<T, C> {
     fn clone(&self) -> Self {
         // TODO: ref count for objects to avoid leaking memory
         Self {
@@ -61,3 +100,93 @@ impl<T> Clone for FarMemorySerialized<T> {
         }
     }
 }
+
+pub struct FarMemoryArchived<T, C: ZeroCopySerializationCodec<T>> {
+    client: FarMemoryClient,
+    data: ArchivedData,
+    _phantom: PhantomData<(T, C)>,
+}
+
+enum ArchivedData {
+    InSpan {
+        span_id: SpanId,
+        ptr: *const u8,
+        len: usize,
+    },
+    Copied(AlignedBytes),
+}
+
+impl<T, C: ZeroCopySerializationCodec<T>> Deref for FarMemoryArchived<T, C> {
+    type Target = C::Archived;
+
+    fn deref(&self) -> &Self::Target {
+        let bytes = match &self.data {
+            ArchivedData::InSpan { span_id: _, ptr, len } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+            ArchivedData::Copied(data) => data.as_slice(),
+        };
+
+        unsafe { C::access(bytes) }
+    }
+}
+
+impl<T, C: ZeroCopySerializationCodec<T>> Drop for FarMemoryArchived<T, C> {
+    fn drop(&mut self) {
+        if let ArchivedData::InSpan { span_id, .. } = &self.data {
+            self.client.decrease_refs_for_span(span_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        serde::{Serialize, Deserialize},
+        crate::client::{InMemoryBackend, RkyvCodec},
+        super::*,
+    };
+
+    #[derive(Serialize, Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, Debug, PartialEq)]
+    struct TestRecord {
+        id: u64,
+        name: String,
+        values: Vec<u32>,
+    }
+
+    fn test_record() -> TestRecord {
+        TestRecord {
+            id: 42,
+            name: "some record".to_owned(),
+            values: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn bincode_to_local() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 10 * 1024 * 1024);
+        let object: FarMemorySerialized<TestRecord> = FarMemorySerialized::from_value(client, test_record());
+
+        assert_eq!(test_record(), object.to_local());
+    }
+
+    #[test]
+    fn rkyv_to_local() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 10 * 1024 * 1024);
+        let object: FarMemorySerialized<TestRecord, RkyvCodec> = FarMemorySerialized::from_value(client, test_record());
+
+        assert_eq!(test_record(), object.to_local());
+    }
+
+    #[test]
+    fn rkyv_to_archived_after_swap_out() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 10 * 1024 * 1024);
+        let object: FarMemorySerialized<TestRecord, RkyvCodec> = FarMemorySerialized::from_value(client.clone(), test_record());
+
+        client.swap_out_spans_fully(&[object.span()]);
+        assert!(!object.is_local());
+
+        let archived = object.to_archived();
+        assert_eq!(42, archived.id);
+        assert_eq!("some record", archived.name.as_str());
+        assert_eq!(&[1, 2, 3], archived.values.as_slice());
+    }
+}
@@ -1,19 +1,24 @@
 use {
-    std::collections::HashMap,
-    serde::{Serialize, de::DeserializeOwned},
+    std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex, mpsc::{sync_channel, Receiver}}, thread::{self, JoinHandle}, marker::PhantomData},
     tracing::{span, Level},
     super::{
         serialized_object::FarMemorySerialized,
         client::FarMemoryClient,
+        codec::{SerializationCodec, BincodeCodec},
+        object::ObjectId,
+        span::SpanId,
     },
 };
 
-pub struct FarMemorySerializedObjectVec<T> {
+const DEFAULT_PREFETCH_SPANS: usize = 4;
+const PAR_ITER_CHANNEL_SIZE: usize = 1024;
+
+pub struct FarMemorySerializedObjectVec<T, C = BincodeCodec> {
     client: FarMemoryClient,
-    objects: Vec<FarMemorySerialized<T>>,
+    objects: Vec<FarMemorySerialized<T, C>>,
 }
 
-impl<T> FarMemorySerializedObjectVec<T> {
+impl<T, C> FarMemorySerializedObjectVec<T, C> {
     pub fn new(client: FarMemoryClient) -> Self {
         Self {
             client,
@@ -24,31 +29,243 @@ impl<T> FarMemorySerializedObjectVec<T> {
     pub fn len(&self) -> usize {
         self.objects.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    pub fn swap_out(&self) {
+        self.client.swap_out_spans_fully(&self.objects_by_span().into_iter().map(|v| v.0).collect::<Vec<_>>());
+    }
+
+    // removes all objects from far memory.
+    pub fn clear(&mut self) {
+        for object in self.objects.drain(..) {
+            object.free();
+        }
+    }
+
+    // size of serialized object in far memory.
+    pub(super) fn object_size(&self, index: usize) -> usize {
+        self.client.get_object(self.objects[index].object()).len
+    }
+
+    // indices of objects grouped by span they are stored in. Spans that are (at least partially) local go first.
+    fn objects_by_span(&self) -> Vec<(SpanId, Vec<usize>)> {
+        let mut span_positions = HashMap::new();
+        let mut spans: Vec<(SpanId, Vec<usize>)> = Vec::new();
+
+        for (index, object) in self.objects.iter().enumerate() {
+            let span_id = object.span();
+            let position = *span_positions.entry(span_id.clone()).or_insert_with(|| {
+                spans.push((span_id, Vec::new()));
+                spans.len() - 1
+            });
+            spans[position].1.push(index);
+        }
+
+        spans.sort_by_key(|(span_id, _)| self.client.span_local_memory_usage(span_id) == 0);
+        spans
+    }
 }
 
-impl<T: Serialize> FarMemorySerializedObjectVec<T> {
+impl<T, C: SerializationCodec<T>> FarMemorySerializedObjectVec<T, C> {
     pub fn push(&mut self, object: T) {
         self.objects.push(FarMemorySerialized::from_value(self.client.clone(), object));
     }
-}
 
-impl<T: DeserializeOwned> FarMemorySerializedObjectVec<T> {
     pub fn get(&self, index: usize) -> Option<T> {
         self.objects.get(index).map(|v| v.to_local())
     }
 
-    pub fn iter(&self) -> FarMemorySerializedObjectVecIterator<T> {
+    pub fn iter(&self) -> FarMemorySerializedObjectVecIterator<T, C> {
         FarMemorySerializedObjectVecIterator::new(self.objects.iter())
     }
+
+    /// yields (index, object) going span by span, so that every span is swapped in once. Next spans are prefetched while
+    /// current one is processed.
+    pub fn iter_indexed(&self) -> FarMemorySerializedObjectVecIndexedIterator<T, C> {
+        self.iter_indexed_with_prefetch(DEFAULT_PREFETCH_SPANS)
+    }
+
+    pub fn iter_indexed_with_prefetch(&self, prefetch_spans: usize) -> FarMemorySerializedObjectVecIndexedIterator<T, C> {
+        FarMemorySerializedObjectVecIndexedIterator {
+            vec: self,
+            spans: self.objects_by_span(),
+            span_index: 0,
+            position: 0,
+            current_span: None,
+            prefetch_spans,
+            prefetched_until: 0,
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> T {
+        let object = self.objects.remove(index);
+        let value = object.to_local();
+        object.free();
+        value
+    }
+
+    pub fn set(&mut self, index: usize, value: T) {
+        let object = FarMemorySerialized::from_value(self.client.clone(), value);
+        std::mem::replace(&mut self.objects[index], object).free();
+    }
+
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let mut keep = vec![true; self.objects.len()];
+        for (index, value) in self.iter_indexed() {
+            keep[index] = f(&value);
+        }
+
+        let objects = std::mem::take(&mut self.objects);
+        for (object, keep) in objects.into_iter().zip(keep.into_iter()) {
+            if keep {
+                self.objects.push(object);
+            } else {
+                object.free();
+            }
+        }
+    }
+
+    /// spans are deserialized in parallel by `threads` threads. Objects are yielded as (index, object) in no particular order.
+    /// Iterator borrows the vec, so that objects are not freed while threads read them, and joins threads when dropped.
+    pub fn par_iter(&self, threads: usize) -> FarMemorySerializedObjectVecParIterator<'_, T> where T: Send + 'static, C: 'static {
+        let jobs: VecDeque<(SpanId, Vec<(usize, ObjectId)>)> = self.objects_by_span()
+            .into_iter()
+            .map(|(span_id, indices)| (span_id, indices.into_iter().map(|i| (i, self.objects[i].object().clone())).collect()))
+            .collect();
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        let (sender, receiver) = sync_channel(PAR_ITER_CHANNEL_SIZE);
+        let threads = (0..threads.max(1)).map(|_| {
+            let client = self.client.clone();
+            let jobs = jobs.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || loop {
+                let (span_id, objects) = match jobs.lock().unwrap().pop_front() {
+                    Some(v) => v,
+                    None => return,
+                };
+
+                let _guard = span!(Level::DEBUG, "par_iter - process span", span_id = span_id.id()).entered();
+                let ptr = client.span_ptr(&span_id);
+                for (index, object) in objects {
+                    let location = client.get_object(&object);
+                    let value = C::deserialize(unsafe { std::slice::from_raw_parts(ptr.add(location.offset), location.len) });
+                    if sender.send((index, value)).is_err() {
+                        // iterator was dropped
+                        client.decrease_refs_for_span(&span_id);
+                        return;
+                    }
+                }
+                client.decrease_refs_for_span(&span_id);
+            })
+        }).collect();
+
+        FarMemorySerializedObjectVecParIterator {
+            receiver: Some(receiver),
+            threads,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+pub struct FarMemorySerializedObjectVecIndexedIterator<'a, T, C = BincodeCodec> {
+    vec: &'a FarMemorySerializedObjectVec<T, C>,
+    spans: Vec<(SpanId, Vec<usize>)>,
+    span_index: usize,
+    position: usize, // position within objects of current span
+    current_span: Option<*mut u8>, // current span is kept in use until all of its objects are read
+    prefetch_spans: usize,
+    prefetched_until: usize,
+}
+
+impl<'a, T, C> FarMemorySerializedObjectVecIndexedIterator<'a, T, C> {
+    fn enter_span(&mut self) -> *mut u8 {
+        let prefetch_from = self.prefetched_until.max(self.span_index + 1);
+        let prefetch_until = (self.span_index + 1 + self.prefetch_spans).min(self.spans.len());
+        if prefetch_from < prefetch_until {
+            self.vec.client.prefetch_spans(self.spans[prefetch_from..prefetch_until].iter().map(|v| v.0.clone()).collect());
+            self.prefetched_until = prefetch_until;
+        }
+
+        let ptr = self.vec.client.span_ptr(&self.spans[self.span_index].0);
+        self.current_span = Some(ptr);
+        self.position = 0;
+        ptr
+    }
+
+    fn release_span(&mut self) {
+        if self.current_span.take().is_some() {
+            self.vec.client.decrease_refs_for_span(&self.spans[self.span_index].0);
+        }
+    }
+}
+
+impl<'a, T, C: SerializationCodec<T>> Iterator for FarMemorySerializedObjectVecIndexedIterator<'a, T, C> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.span_index < self.spans.len() {
+            let ptr = match self.current_span {
+                Some(ptr) => ptr,
+                None => self.enter_span(),
+            };
+
+            if let Some(index) = self.spans[self.span_index].1.get(self.position).cloned() {
+                self.position += 1;
+                let location = self.vec.client.get_object(self.vec.objects[index].object());
+                let value = C::deserialize(unsafe { std::slice::from_raw_parts(ptr.add(location.offset), location.len) });
+                return Some((index, value));
+            }
+
+            self.release_span();
+            self.span_index += 1;
+        }
+
+        None
+    }
+}
+
+impl<'a, T, C> Drop for FarMemorySerializedObjectVecIndexedIterator<'a, T, C> {
+    fn drop(&mut self) {
+        self.release_span();
+    }
+}
+
+pub struct FarMemorySerializedObjectVecParIterator<'a, T> {
+    receiver: Option<Receiver<(usize, T)>>,
+    threads: Vec<JoinHandle<()>>,
+    _phantom: PhantomData<&'a ()>,
+}
+
+impl<'a, T> Iterator for FarMemorySerializedObjectVecParIterator<'a, T> {
+    type Item = (usize, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.as_ref()?.recv().ok()
+    }
 }
 
-pub struct FarMemorySerializedObjectVecIterator<'a, T> {
-    objects: std::slice::Iter<'a, FarMemorySerialized<T>>,
-    remote_objects_by_span: HashMap<u64, Vec<&'a FarMemorySerialized<T>>>,
+impl<'a, T> Drop for FarMemorySerializedObjectVecParIterator<'a, T> {
+    fn drop(&mut self) {
+        // threads that are still running stop on their next send once receiver is gone
+        self.receiver.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+pub struct FarMemorySerializedObjectVecIterator<'a, T, C = BincodeCodec> {
+    objects: std::slice::Iter<'a, FarMemorySerialized<T, C>>,
+    remote_objects_by_span: HashMap<u64, Vec<&'a FarMemorySerialized<T, C>>>,
 }
 
-impl<'a, T> FarMemorySerializedObjectVecIterator<'a, T> {
-    pub fn new(objects: std::slice::Iter<'a, FarMemorySerialized<T>>) -> Self {
+impl<'a, T, C> FarMemorySerializedObjectVecIterator<'a, T, C> {
+    pub fn new(objects: std::slice::Iter<'a, FarMemorySerialized<T, C>>) -> Self {
         Self {
             objects,
             remote_objects_by_span: HashMap::new(),
@@ -56,7 +273,7 @@ impl<'a, T> FarMemorySerializedObjectVecIterator<'a, T> {
     }
 }
 
-impl<'a, T: DeserializeOwned> Iterator for FarMemorySerializedObjectVecIterator<'a, T> {
+impl<'a, T, C: SerializationCodec<T>> Iterator for FarMemorySerializedObjectVecIterator<'a, T, C> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -112,3 +329,75 @@ impl<'a, T: DeserializeOwned> Iterator for FarMemorySerializedObjectVecIterator<
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    fn test_vec(client: FarMemoryClient, len: u64) -> FarMemorySerializedObjectVec<(u64, String)> {
+        let mut vec = FarMemorySerializedObjectVec::new(client);
+        for i in 0..len {
+            vec.push((i, format!("object {}", i)));
+        }
+        vec
+    }
+
+    #[test]
+    fn iter_indexed_after_swap_out() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let vec = test_vec(client.clone(), 100_000);
+        vec.swap_out();
+        assert_eq!(0, client.total_local_memory());
+
+        let mut seen = vec![false; vec.len()];
+        for (index, value) in vec.iter_indexed() {
+            assert_eq!(index as u64, value.0);
+            assert_eq!(format!("object {}", index), value.1);
+            seen[index] = true;
+        }
+        assert!(seen.into_iter().all(|v| v));
+    }
+
+    #[test]
+    fn remove_set_retain() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = test_vec(client, 10);
+
+        assert_eq!((3, "object 3".to_owned()), vec.remove(3));
+        vec.set(0, (100, "replaced".to_owned()));
+        vec.retain(|v| v.0 % 2 == 0);
+
+        assert_eq!(5, vec.len());
+        assert_eq!(Some((100, "replaced".to_owned())), vec.get(0));
+        assert_eq!(vec![100, 2, 4, 6, 8], (0..vec.len()).map(|i| vec.get(i).unwrap().0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn par_iter() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let vec = test_vec(client, 100_000);
+        vec.swap_out();
+
+        let mut indices: Vec<_> = vec.par_iter(4).map(|(index, value)| {
+            assert_eq!(index as u64, value.0);
+            index
+        }).collect();
+        indices.sort();
+        assert_eq!((0..100_000).collect::<Vec<_>>(), indices);
+    }
+
+    #[test]
+    fn par_iter_dropped_early() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let vec = test_vec(client.clone(), 100_000);
+        vec.swap_out();
+
+        // threads are joined when iterator is dropped, so no span is left in use
+        assert_eq!(10, vec.par_iter(4).take(10).count());
+        vec.swap_out();
+        assert_eq!(0, client.total_local_spans());
+    }
+}
@@ -0,0 +1,820 @@
+use {
+    std::{collections::{HashMap, VecDeque}, io::Read, cmp::Ordering},
+    tracing::{span, Level},
+    chrono::{NaiveDate, Datelike},
+    thiserror::Error,
+    super::{
+        client::FarMemoryClient,
+        span::SpanId,
+    },
+};
+
+const DEFAULT_CHUNK_ROWS: usize = 64 * 1024;
+const DEFAULT_PREFETCH_CHUNKS: usize = 2;
+
+#[derive(Error, Debug)]
+pub enum FarMemoryTableError {
+    #[error("failed to read csv record: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("failed to parse \"{value}\" as {column_type:?}")]
+    Parse {
+        value: String,
+        column_type: FarMemoryColumnType,
+    },
+    #[error("csv record {record} has no field for column \"{column}\"")]
+    MissingField {
+        record: usize,
+        column: String,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FarMemoryColumnType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    I32,
+    I64,
+    F32,
+    F64,
+    Date,
+    String, // dictionary encoded, dictionary is kept in local memory
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum FarMemoryValue {
+    Null,
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Date(NaiveDate),
+    String(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FarMemoryTableSchema {
+    columns: Vec<(String, FarMemoryColumnType)>,
+}
+
+#[derive(Clone, Debug)]
+pub enum FarMemoryTablePredicate {
+    Eq(String, FarMemoryValue),
+    NotEq(String, FarMemoryValue),
+    Lt(String, FarMemoryValue),
+    Le(String, FarMemoryValue),
+    Gt(String, FarMemoryValue),
+    Ge(String, FarMemoryValue),
+    IsNull(String),
+    IsNotNull(String),
+    And(Vec<FarMemoryTablePredicate>),
+    Or(Vec<FarMemoryTablePredicate>),
+    Not(Box<FarMemoryTablePredicate>),
+}
+
+/**
+ * columnar table. Every column is split into chunks of `chunk_rows` values, one span per chunk. Chunk starts with null
+ * bitmap (bit is set when value is present) followed by fixed width values. Rows are appended to local chunks first, which
+ * are moved to far memory once they are full.
+ *
+ * queries only touch chunks of columns they need: predicate columns are read first, and projected columns are read only
+ * for chunks that have at least one matching row.
+ */
+pub struct FarMemoryTable {
+    client: FarMemoryClient,
+    schema: FarMemoryTableSchema,
+    chunk_rows: usize,
+    prefetch_chunks: usize,
+    len: usize,
+
+    columns: Vec<Column>,
+}
+
+struct Column {
+    column_type: FarMemoryColumnType,
+    chunks: Vec<SpanId>,
+    pending: Vec<u8>, // chunk that is not full yet
+
+    dictionary: Vec<String>,
+    dictionary_index: HashMap<String, u32>,
+}
+
+enum ResolvedPredicate {
+    Compare(usize, Ordering, bool, FarMemoryValue), // column, expected ordering, negate, value
+    IsNull(usize),
+    And(Vec<ResolvedPredicate>),
+    Or(Vec<ResolvedPredicate>),
+    Not(Box<ResolvedPredicate>),
+}
+
+struct ChunkView<'a> {
+    table: &'a FarMemoryTable,
+    span_id: Option<SpanId>,
+    ptr: *const u8,
+}
+
+impl FarMemoryColumnType {
+    fn width(&self) -> usize {
+        match self {
+            Self::Bool | Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 | Self::I32 | Self::F32 | Self::Date | Self::String => 4,
+            Self::U64 | Self::I64 | Self::F64 => 8,
+        }
+    }
+
+    fn matches(&self, value: &FarMemoryValue) -> bool {
+        matches!((self, value),
+            (_, FarMemoryValue::Null)
+            | (Self::Bool, FarMemoryValue::Bool(_))
+            | (Self::U8, FarMemoryValue::U8(_))
+            | (Self::U16, FarMemoryValue::U16(_))
+            | (Self::U32, FarMemoryValue::U32(_))
+            | (Self::U64, FarMemoryValue::U64(_))
+            | (Self::I32, FarMemoryValue::I32(_))
+            | (Self::I64, FarMemoryValue::I64(_))
+            | (Self::F32, FarMemoryValue::F32(_))
+            | (Self::F64, FarMemoryValue::F64(_))
+            | (Self::Date, FarMemoryValue::Date(_))
+            | (Self::String, FarMemoryValue::String(_))
+        )
+    }
+
+    // empty field is null.
+    pub fn parse(&self, s: &str) -> Result<FarMemoryValue, FarMemoryTableError> {
+        if s.is_empty() {
+            return Ok(FarMemoryValue::Null);
+        }
+
+        let value = match self {
+            Self::Bool => s.to_lowercase().parse().ok().map(FarMemoryValue::Bool),
+            Self::U8 => s.parse().ok().map(FarMemoryValue::U8),
+            Self::U16 => s.parse().ok().map(FarMemoryValue::U16),
+            Self::U32 => s.parse().ok().map(FarMemoryValue::U32),
+            Self::U64 => s.parse().ok().map(FarMemoryValue::U64),
+            Self::I32 => s.parse().ok().map(FarMemoryValue::I32),
+            Self::I64 => s.parse().ok().map(FarMemoryValue::I64),
+            Self::F32 => s.parse().ok().map(FarMemoryValue::F32),
+            Self::F64 => s.parse().ok().map(FarMemoryValue::F64),
+            Self::Date => NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().map(FarMemoryValue::Date),
+            Self::String => Some(FarMemoryValue::String(s.to_owned())),
+        };
+
+        value.ok_or_else(|| FarMemoryTableError::Parse {
+            value: s.to_owned(),
+            column_type: *self,
+        })
+    }
+}
+
+impl FarMemoryValue {
+    pub fn is_null(&self) -> bool {
+        *self == Self::Null
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            Self::U32(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::U64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::I64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Self::F32(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::F64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_date(&self) -> Option<NaiveDate> {
+        match self {
+            Self::Date(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl FarMemoryTableSchema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_column(mut self, name: &str, column_type: FarMemoryColumnType) -> Self {
+        if self.column_index(name).is_some() {
+            panic!("column \"{}\" is already defined", name);
+        }
+        self.columns.push((name.to_owned(), column_type));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    pub fn columns(&self) -> &[(String, FarMemoryColumnType)] {
+        &self.columns
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|v| v.0 == name)
+    }
+
+    fn column_index_or_panic(&self, name: &str) -> usize {
+        match self.column_index(name) {
+            Some(v) => v,
+            None => panic!("unknown column \"{}\"", name),
+        }
+    }
+}
+
+impl FarMemoryTable {
+    pub fn new(client: FarMemoryClient, schema: FarMemoryTableSchema) -> Self {
+        Self::with_chunk_rows(client, schema, DEFAULT_CHUNK_ROWS)
+    }
+
+    pub fn with_chunk_rows(client: FarMemoryClient, schema: FarMemoryTableSchema, chunk_rows: usize) -> Self {
+        let chunk_rows = chunk_rows.max(1);
+        let columns = schema.columns.iter()
+            .map(|(_, column_type)| Column {
+                column_type: *column_type,
+                chunks: Vec::new(),
+                pending: vec![0; chunk_size(chunk_rows, column_type)],
+
+                dictionary: Vec::new(),
+                dictionary_index: HashMap::new(),
+            })
+            .collect();
+
+        Self {
+            client,
+            schema,
+            chunk_rows,
+            prefetch_chunks: DEFAULT_PREFETCH_CHUNKS,
+            len: 0,
+
+            columns,
+        }
+    }
+
+    // while query processes a chunk, this many next chunks of columns it needs are swapped in in background.
+    pub fn with_prefetch_chunks(mut self, prefetch_chunks: usize) -> Self {
+        self.prefetch_chunks = prefetch_chunks;
+        self
+    }
+
+    pub fn schema(&self) -> &FarMemoryTableSchema {
+        &self.schema
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn swap_out(&self) {
+        let spans: Vec<_> = self.columns.iter().flat_map(|v| v.chunks.iter().cloned()).collect();
+        self.client.swap_out_spans_fully(&spans);
+    }
+
+    pub fn push_row(&mut self, row: Vec<FarMemoryValue>) {
+        if row.len() != self.columns.len() {
+            panic!("row has {} values, but table has {} columns", row.len(), self.columns.len());
+        }
+
+        let row_in_chunk = self.len % self.chunk_rows;
+        for (column, value) in self.columns.iter_mut().zip(row.into_iter()) {
+            if !column.column_type.matches(&value) {
+                panic!("value {:?} does not match column type {:?}", value, column.column_type);
+            }
+            column.write_pending(self.chunk_rows, row_in_chunk, value);
+        }
+        self.len += 1;
+
+        if self.len % self.chunk_rows == 0 {
+            span!(Level::DEBUG, "FarMemoryTable - moving chunks to far memory").in_scope(|| {
+                for column in &mut self.columns {
+                    let span_id = self.client.allocate_span(column.pending.len());
+                    let ptr = self.client.span_ptr(&span_id);
+                    unsafe {
+                        std::ptr::copy_nonoverlapping(column.pending.as_ptr(), ptr, column.pending.len());
+                    }
+                    self.client.decrease_refs_for_span(&span_id);
+
+                    column.chunks.push(span_id);
+                    column.pending.fill(0);
+                }
+            });
+        }
+    }
+
+    // csv columns are mapped to table columns by position, extra csv columns are ignored. Returns number of loaded rows.
+    // Rows before a malformed record stay in the table.
+    pub fn load_csv<R: Read>(&mut self, reader: &mut csv::Reader<R>, max_rows: Option<usize>) -> Result<usize, FarMemoryTableError> {
+        let mut records = reader.records();
+        let mut loaded = 0;
+        while max_rows.map(|max_rows| loaded < max_rows).unwrap_or(true) {
+            let record = match records.next() {
+                Some(v) => v?,
+                None => break,
+            };
+
+            let row = self.schema.columns.iter()
+                .enumerate()
+                .map(|(i, (name, column_type))| match record.get(i) {
+                    Some(field) => column_type.parse(field),
+                    None => Err(FarMemoryTableError::MissingField { record: loaded, column: name.clone() }),
+                })
+                .collect::<Result<_, _>>()?;
+            self.push_row(row);
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+
+    pub fn get(&self, row: usize, column: &str) -> FarMemoryValue {
+        self.row(row, &[column]).pop().unwrap()
+    }
+
+    pub fn row(&self, row: usize, columns: &[&str]) -> Vec<FarMemoryValue> {
+        if row >= self.len {
+            panic!("row {} is out of bounds for table with {} rows", row, self.len);
+        }
+
+        let chunk = row / self.chunk_rows;
+        columns.iter()
+            .map(|name| {
+                let column = self.schema.column_index_or_panic(name);
+                self.chunk_view(column, chunk).value(column, row % self.chunk_rows)
+            })
+            .collect()
+    }
+
+    pub fn select(&self, columns: &[&str]) -> FarMemoryTableRows<'_> {
+        self.rows(None, columns)
+    }
+
+    pub fn filter(&self, predicate: FarMemoryTablePredicate, columns: &[&str]) -> FarMemoryTableRows<'_> {
+        let predicate = self.resolve_predicate(predicate);
+        self.rows(Some(predicate), columns)
+    }
+
+    fn rows(&self, predicate: Option<ResolvedPredicate>, columns: &[&str]) -> FarMemoryTableRows<'_> {
+        let projection: Vec<_> = columns.iter().map(|name| self.schema.column_index_or_panic(name)).collect();
+
+        let mut predicate_columns = Vec::new();
+        if let Some(predicate) = &predicate {
+            predicate.columns(&mut predicate_columns);
+        }
+        predicate_columns.sort();
+        predicate_columns.dedup();
+
+        FarMemoryTableRows {
+            table: self,
+            predicate,
+            predicate_columns,
+            projection,
+            chunk: 0,
+            prefetched_until: 0,
+            rows: VecDeque::new(),
+        }
+    }
+
+    fn resolve_predicate(&self, predicate: FarMemoryTablePredicate) -> ResolvedPredicate {
+        let compare = |name: String, ordering: Ordering, negate: bool, value: FarMemoryValue| {
+            let column = self.schema.column_index_or_panic(&name);
+            let column_type = self.columns[column].column_type;
+            if value.is_null() || !column_type.matches(&value) {
+                panic!("cannot compare column \"{}\" of type {:?} with {:?}", name, column_type, value);
+            }
+            ResolvedPredicate::Compare(column, ordering, negate, value)
+        };
+
+        match predicate {
+            FarMemoryTablePredicate::Eq(name, value) => compare(name, Ordering::Equal, false, value),
+            FarMemoryTablePredicate::NotEq(name, value) => compare(name, Ordering::Equal, true, value),
+            FarMemoryTablePredicate::Lt(name, value) => compare(name, Ordering::Less, false, value),
+            FarMemoryTablePredicate::Le(name, value) => compare(name, Ordering::Greater, true, value),
+            FarMemoryTablePredicate::Gt(name, value) => compare(name, Ordering::Greater, false, value),
+            FarMemoryTablePredicate::Ge(name, value) => compare(name, Ordering::Less, true, value),
+            FarMemoryTablePredicate::IsNull(name) => ResolvedPredicate::IsNull(self.schema.column_index_or_panic(&name)),
+            FarMemoryTablePredicate::IsNotNull(name) => ResolvedPredicate::Not(Box::new(
+                ResolvedPredicate::IsNull(self.schema.column_index_or_panic(&name))
+            )),
+            FarMemoryTablePredicate::And(predicates) => ResolvedPredicate::And(predicates.into_iter().map(|v| self.resolve_predicate(v)).collect()),
+            FarMemoryTablePredicate::Or(predicates) => ResolvedPredicate::Or(predicates.into_iter().map(|v| self.resolve_predicate(v)).collect()),
+            FarMemoryTablePredicate::Not(predicate) => ResolvedPredicate::Not(Box::new(self.resolve_predicate(*predicate))),
+        }
+    }
+
+    fn total_chunks(&self) -> usize {
+        self.len.div_ceil(self.chunk_rows)
+    }
+
+    fn rows_in_chunk(&self, chunk: usize) -> usize {
+        (self.len - chunk * self.chunk_rows).min(self.chunk_rows)
+    }
+
+    fn chunk_view(&self, column: usize, chunk: usize) -> ChunkView<'_> {
+        let column_data = &self.columns[column];
+        match column_data.chunks.get(chunk) {
+            Some(span_id) => ChunkView {
+                table: self,
+                span_id: Some(span_id.clone()),
+                ptr: self.client.span_ptr(span_id),
+            },
+            None => ChunkView {
+                table: self,
+                span_id: None,
+                ptr: column_data.pending.as_ptr(),
+            },
+        }
+    }
+}
+
+impl Drop for FarMemoryTable {
+    fn drop(&mut self) {
+        for span_id in self.columns.iter().flat_map(|v| v.chunks.iter()) {
+            self.client.free_span(span_id);
+        }
+    }
+}
+
+impl Column {
+    fn write_pending(&mut self, chunk_rows: usize, row: usize, value: FarMemoryValue) {
+        let offset = bitmap_size(chunk_rows) + row * self.column_type.width();
+        let bytes = match value {
+            FarMemoryValue::Null => return,
+            FarMemoryValue::Bool(v) => vec![v as u8],
+            FarMemoryValue::U8(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::U16(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::U32(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::U64(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::I32(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::I64(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::F32(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::F64(v) => v.to_ne_bytes().to_vec(),
+            FarMemoryValue::Date(v) => v.num_days_from_ce().to_ne_bytes().to_vec(),
+            FarMemoryValue::String(v) => {
+                let code = match self.dictionary_index.get(&v) {
+                    Some(code) => *code,
+                    None => {
+                        let code = self.dictionary.len() as u32;
+                        self.dictionary.push(v.clone());
+                        self.dictionary_index.insert(v, code);
+                        code
+                    }
+                };
+                code.to_ne_bytes().to_vec()
+            },
+        };
+
+        self.pending[row / 8] |= 1 << (row % 8);
+        self.pending[offset..offset + bytes.len()].copy_from_slice(&bytes);
+    }
+}
+
+impl ResolvedPredicate {
+    fn columns(&self, columns: &mut Vec<usize>) {
+        match self {
+            Self::Compare(column, ..) | Self::IsNull(column) => columns.push(*column),
+            Self::And(predicates) | Self::Or(predicates) => predicates.iter().for_each(|v| v.columns(columns)),
+            Self::Not(predicate) => predicate.columns(columns),
+        }
+    }
+
+    fn matches(&self, views: &HashMap<usize, ChunkView>, row: usize) -> bool {
+        match self {
+            Self::Compare(column, ordering, negate, value) => {
+                // comparisons with null are always false, same as in SQL.
+                match views[column].compare(*column, row, value) {
+                    Some(v) => (v == *ordering) != *negate,
+                    None => false,
+                }
+            },
+            Self::IsNull(column) => views[column].is_null(row),
+            Self::And(predicates) => predicates.iter().all(|v| v.matches(views, row)),
+            Self::Or(predicates) => predicates.iter().any(|v| v.matches(views, row)),
+            Self::Not(predicate) => !predicate.matches(views, row),
+        }
+    }
+}
+
+impl<'a> ChunkView<'a> {
+    fn is_null(&self, row: usize) -> bool {
+        unsafe { *self.ptr.add(row / 8) & (1 << (row % 8)) == 0 }
+    }
+
+    fn read<T: Copy>(&self, column: usize, row: usize) -> T {
+        let chunk_rows = self.table.chunk_rows;
+        let offset = bitmap_size(chunk_rows) + row * self.table.columns[column].column_type.width();
+        unsafe { (self.ptr.add(offset) as *const T).read_unaligned() }
+    }
+
+    fn value(&self, column: usize, row: usize) -> FarMemoryValue {
+        if self.is_null(row) {
+            return FarMemoryValue::Null;
+        }
+
+        let column_data = &self.table.columns[column];
+        match column_data.column_type {
+            FarMemoryColumnType::Bool => FarMemoryValue::Bool(self.read::<u8>(column, row) != 0),
+            FarMemoryColumnType::U8 => FarMemoryValue::U8(self.read(column, row)),
+            FarMemoryColumnType::U16 => FarMemoryValue::U16(self.read(column, row)),
+            FarMemoryColumnType::U32 => FarMemoryValue::U32(self.read(column, row)),
+            FarMemoryColumnType::U64 => FarMemoryValue::U64(self.read(column, row)),
+            FarMemoryColumnType::I32 => FarMemoryValue::I32(self.read(column, row)),
+            FarMemoryColumnType::I64 => FarMemoryValue::I64(self.read(column, row)),
+            FarMemoryColumnType::F32 => FarMemoryValue::F32(self.read(column, row)),
+            FarMemoryColumnType::F64 => FarMemoryValue::F64(self.read(column, row)),
+            FarMemoryColumnType::Date => FarMemoryValue::Date(NaiveDate::from_num_days_from_ce_opt(self.read(column, row)).unwrap()),
+            FarMemoryColumnType::String => FarMemoryValue::String(column_data.dictionary[self.read::<u32>(column, row) as usize].clone()),
+        }
+    }
+
+    fn compare(&self, column: usize, row: usize, value: &FarMemoryValue) -> Option<Ordering> {
+        if self.is_null(row) {
+            return None;
+        }
+
+        match value {
+            // avoids copying string out of dictionary
+            FarMemoryValue::String(value) => {
+                let column_data = &self.table.columns[column];
+                Some(column_data.dictionary[self.read::<u32>(column, row) as usize].as_str().cmp(value.as_str()))
+            },
+            _ => self.value(column, row).partial_cmp(value),
+        }
+    }
+}
+
+impl<'a> Drop for ChunkView<'a> {
+    fn drop(&mut self) {
+        if let Some(span_id) = &self.span_id {
+            self.table.client.decrease_refs_for_span(span_id);
+        }
+    }
+}
+
+/**
+ * rows are produced one chunk at a time, so only chunks of one chunk index (for needed columns) are in use at a time.
+ */
+pub struct FarMemoryTableRows<'a> {
+    table: &'a FarMemoryTable,
+    predicate: Option<ResolvedPredicate>,
+    predicate_columns: Vec<usize>,
+    projection: Vec<usize>,
+
+    chunk: usize,
+    prefetched_until: usize,
+    rows: VecDeque<Vec<FarMemoryValue>>,
+}
+
+impl<'a> FarMemoryTableRows<'a> {
+    fn prefetch(&mut self) {
+        let prefetch_until = (self.chunk + 1 + self.table.prefetch_chunks).min(self.table.columns[0].chunks.len());
+        if prefetch_until <= self.prefetched_until.max(self.chunk + 1) {
+            return;
+        }
+
+        let from = self.prefetched_until.max(self.chunk + 1);
+        let mut columns = self.predicate_columns.clone();
+        columns.extend(self.projection.iter());
+        columns.sort();
+        columns.dedup();
+
+        let spans = columns.iter()
+            .flat_map(|column| self.table.columns[*column].chunks[from..prefetch_until].iter().cloned())
+            .collect();
+        self.table.client.prefetch_spans(spans);
+        self.prefetched_until = prefetch_until;
+    }
+
+    fn load_chunk(&mut self) {
+        let rows_in_chunk = self.table.rows_in_chunk(self.chunk);
+
+        let matching_rows: Vec<usize> = match &self.predicate {
+            Some(predicate) => {
+                let views: HashMap<usize, ChunkView> = self.predicate_columns.iter()
+                    .map(|column| (*column, self.table.chunk_view(*column, self.chunk)))
+                    .collect();
+                (0..rows_in_chunk).filter(|row| predicate.matches(&views, *row)).collect()
+            },
+            None => (0..rows_in_chunk).collect(),
+        };
+
+        if matching_rows.is_empty() {
+            return;
+        }
+
+        let views: Vec<_> = self.projection.iter().map(|column| self.table.chunk_view(*column, self.chunk)).collect();
+        for row in matching_rows {
+            self.rows.push_back(self.projection.iter().zip(views.iter()).map(|(column, view)| view.value(*column, row)).collect());
+        }
+    }
+}
+
+impl<'a> Iterator for FarMemoryTableRows<'a> {
+    type Item = Vec<FarMemoryValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.rows.is_empty() {
+            if self.chunk >= self.table.total_chunks() {
+                return None;
+            }
+
+            if self.table.columns.is_empty() {
+                return None;
+            }
+
+            self.prefetch();
+            self.load_chunk();
+            self.chunk += 1;
+        }
+
+        self.rows.pop_front()
+    }
+}
+
+fn bitmap_size(chunk_rows: usize) -> usize {
+    chunk_rows.div_ceil(8).next_multiple_of(8)
+}
+
+fn chunk_size(chunk_rows: usize, column_type: &FarMemoryColumnType) -> usize {
+    bitmap_size(chunk_rows) + chunk_rows * column_type.width()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    fn test_schema() -> FarMemoryTableSchema {
+        FarMemoryTableSchema::new()
+            .with_column("id", FarMemoryColumnType::U32)
+            .with_column("date", FarMemoryColumnType::Date)
+            .with_column("airport", FarMemoryColumnType::String)
+            .with_column("delay", FarMemoryColumnType::F32)
+    }
+
+    fn test_table(client: FarMemoryClient, rows: u32) -> FarMemoryTable {
+        let mut table = FarMemoryTable::with_chunk_rows(client, test_schema(), 100);
+        let start_date = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        for i in 0..rows {
+            table.push_row(vec![
+                FarMemoryValue::U32(i),
+                FarMemoryValue::Date(start_date + chrono::Duration::days((i % 365) as i64)),
+                FarMemoryValue::String(["SFO", "JFK", "LAX"][(i % 3) as usize].to_owned()),
+                if i % 5 == 0 { FarMemoryValue::Null } else { FarMemoryValue::F32(i as f32 / 2.0) },
+            ]);
+        }
+        table
+    }
+
+    #[test]
+    fn push_and_read_rows() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let table = test_table(client.clone(), 1050);
+        table.swap_out();
+        assert_eq!(0, client.total_local_memory());
+
+        assert_eq!(1050, table.len());
+        assert_eq!(FarMemoryValue::Null, table.get(500, "delay"));
+        assert_eq!(
+            vec![FarMemoryValue::String("SFO".to_owned()), FarMemoryValue::F32(250.5), FarMemoryValue::Date(NaiveDate::from_ymd_opt(2020, 5, 16).unwrap())],
+            table.row(501, &["airport", "delay", "date"])
+        );
+        assert_eq!(FarMemoryValue::U32(1049), table.get(1049, "id")); // row in local chunk
+
+        let ids: Vec<_> = table.select(&["id"]).map(|v| v[0].as_u32().unwrap()).collect();
+        assert_eq!((0..1050).collect::<Vec<_>>(), ids);
+    }
+
+    #[test]
+    fn filter_touches_only_needed_columns() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let table = test_table(client.clone(), 1000).with_prefetch_chunks(0);
+        table.swap_out();
+
+        let predicate = FarMemoryTablePredicate::And(vec![
+            FarMemoryTablePredicate::Eq("airport".to_owned(), FarMemoryValue::String("JFK".to_owned())),
+            FarMemoryTablePredicate::Lt("id".to_owned(), FarMemoryValue::U32(300)),
+        ]);
+        let delays: Vec<_> = table.filter(predicate, &["delay"]).map(|v| v[0].as_f32()).collect();
+
+        let expected: Vec<_> = (0..300u32).filter(|i| i % 3 == 1).map(|i| if i % 5 == 0 { None } else { Some(i as f32 / 2.0) }).collect();
+        assert_eq!(expected, delays);
+
+        // date column was not needed by this query
+        let date_column = table.schema().column_index("date").unwrap();
+        for chunk in &table.columns[date_column].chunks {
+            assert_eq!(0, client.span_local_memory_usage(chunk));
+        }
+
+        // projected column is only read for chunks with matching rows
+        let delay_column = table.schema().column_index("delay").unwrap();
+        assert!(client.span_local_memory_usage(&table.columns[delay_column].chunks[2]) > 0);
+        assert_eq!(0, client.span_local_memory_usage(&table.columns[delay_column].chunks[3]));
+    }
+
+    #[test]
+    fn load_csv() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let schema = FarMemoryTableSchema::new()
+            .with_column("date", FarMemoryColumnType::Date)
+            .with_column("cancelled", FarMemoryColumnType::Bool)
+            .with_column("delay", FarMemoryColumnType::F32);
+        let mut table = FarMemoryTable::with_chunk_rows(client, schema, 2);
+
+        let csv_data = "date,cancelled,delay,ignored\n2022-04-01,False,12.5,a\n2022-04-02,True,,b\n2022-04-03,false,-3,c\n";
+        let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+        assert_eq!(2, table.load_csv(&mut reader, Some(2)).unwrap());
+        assert_eq!(1, table.load_csv(&mut reader, None).unwrap());
+
+        let rows: Vec<_> = table.filter(FarMemoryTablePredicate::Ge("date".to_owned(), FarMemoryValue::Date(NaiveDate::from_ymd_opt(2022, 4, 2).unwrap())), &["cancelled", "delay"]).collect();
+        assert_eq!(vec![
+            vec![FarMemoryValue::Bool(true), FarMemoryValue::Null],
+            vec![FarMemoryValue::Bool(false), FarMemoryValue::F32(-3.0)],
+        ], rows);
+    }
+
+    #[test]
+    fn load_csv_malformed_row() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let schema = FarMemoryTableSchema::new()
+            .with_column("id", FarMemoryColumnType::U32)
+            .with_column("delay", FarMemoryColumnType::F32);
+        let mut table = FarMemoryTable::with_chunk_rows(client.clone(), schema, 2);
+
+        let csv_data = "id,delay\n1,12.5\n2,not a number\n3,1\n";
+        let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+        match table.load_csv(&mut reader, None) {
+            Err(FarMemoryTableError::Parse { value, column_type }) => {
+                assert_eq!("not a number", value);
+                assert_eq!(FarMemoryColumnType::F32, column_type);
+            },
+            other => panic!("expected parse error, got {:?}", other),
+        }
+        assert_eq!(1, table.len());
+
+        let csv_data = "id,delay\n4\n";
+        let mut reader = csv::Reader::from_reader(csv_data.as_bytes());
+        assert!(table.load_csv(&mut reader, None).is_err());
+
+        // chunks are freed with the table
+        table.push_row(vec![FarMemoryValue::U32(5), FarMemoryValue::Null]);
+        assert_eq!(2, client.total_local_spans());
+        drop(table);
+        assert_eq!(0, client.total_local_spans());
+    }
+}
@@ -0,0 +1,341 @@
+use {
+    std::{io::{self, Read}, marker::PhantomData, ops::{Deref, Range}},
+    tracing::{span, Level},
+    super::{
+        client::FarMemoryClient,
+        span::SpanId,
+    },
+};
+
+const DEFAULT_SPAN_SIZE: usize = 2 * 1024 * 1024; // 2 MB
+const DEFAULT_PREFETCH_SPANS: usize = 4;
+
+/**
+ * dense tensor stored in row-major order. Last dimension is a row, and every span holds a block of whole rows (or a single
+ * row if it does not fit into span size), so that any row can be used in place without copying.
+ *
+ * items are copied as raw bytes, so only Copy types are allowed.
+ */
+pub struct FarMemoryTensor<T> {
+    client: FarMemoryClient,
+    shape: Vec<usize>,
+    spans: Vec<SpanId>,
+    rows: usize,
+    row_len: usize,
+    span_rows: usize, // rows per span
+    prefetch_spans: usize,
+
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy> FarMemoryTensor<T> {
+    pub fn from_slice(client: FarMemoryClient, shape: &[usize], data: &[T]) -> Self {
+        Self::from_slice_with_span_size(client, shape, data, DEFAULT_SPAN_SIZE)
+    }
+
+    pub fn from_slice_with_span_size(client: FarMemoryClient, shape: &[usize], data: &[T], span_size: usize) -> Self {
+        if data.len() != shape.iter().product::<usize>() {
+            panic!("tensor of shape {:?} cannot be created from {} items", shape, data.len());
+        }
+
+        let mut bytes = unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) };
+        Self::from_reader_with_span_size(client, shape, &mut bytes, span_size).unwrap()
+    }
+
+    pub fn zeros(client: FarMemoryClient, shape: &[usize]) -> Self {
+        let len = shape.iter().product::<usize>() * std::mem::size_of::<T>();
+        Self::from_reader_with_span_size(client, shape, &mut io::repeat(0).take(len as u64), DEFAULT_SPAN_SIZE).unwrap()
+    }
+
+    // reads items as raw bytes in native byte order, one span at a time, so that the whole tensor never has to be in
+    // local memory.
+    pub fn from_reader<R: Read>(client: FarMemoryClient, shape: &[usize], reader: &mut R) -> io::Result<Self> {
+        Self::from_reader_with_span_size(client, shape, reader, DEFAULT_SPAN_SIZE)
+    }
+
+    pub fn from_reader_with_span_size<R: Read>(client: FarMemoryClient, shape: &[usize], reader: &mut R, span_size: usize) -> io::Result<Self> {
+        let row_len = shape.last().cloned().unwrap_or(1);
+        let rows = if shape.len() > 1 { shape[..shape.len() - 1].iter().product() } else { 1 };
+        let row_size = row_len * std::mem::size_of::<T>();
+        let span_rows = if row_size == 0 { 1 } else { (span_size / row_size).max(1) };
+
+        let mut tensor = Self {
+            client,
+            shape: shape.to_vec(),
+            spans: Vec::new(),
+            rows,
+            row_len,
+            span_rows,
+            prefetch_spans: DEFAULT_PREFETCH_SPANS,
+
+            _phantom: PhantomData,
+        };
+
+        if row_size == 0 {
+            return Ok(tensor);
+        }
+
+        span!(Level::DEBUG, "FarMemoryTensor - loading").in_scope(|| {
+            let mut row = 0;
+            while row < rows {
+                let rows_in_span = span_rows.min(rows - row);
+                let span_size = rows_in_span * row_size;
+
+                let span_id = tensor.client.allocate_span(span_size);
+                let ptr = tensor.client.span_ptr(&span_id);
+                let result = reader.read_exact(unsafe { std::slice::from_raw_parts_mut(ptr, span_size) });
+                tensor.client.decrease_refs_for_span(&span_id);
+                tensor.spans.push(span_id);
+
+                if let Err(err) = result {
+                    // spans read so far are freed when tensor is dropped
+                    return Err(err);
+                }
+
+                row += rows_in_span;
+            }
+
+            Ok(tensor)
+        })
+    }
+
+    // while streaming through tensor (in matmul), this many next spans are swapped in in background.
+    pub fn with_prefetch_spans(mut self, prefetch_spans: usize) -> Self {
+        self.prefetch_spans = prefetch_spans;
+        self
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows * self.row_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn row_len(&self) -> usize {
+        self.row_len
+    }
+
+    pub fn swap_out(&self) {
+        self.client.swap_out_spans_fully(&self.spans);
+    }
+
+    pub fn to_local_vec(&self) -> Vec<T> {
+        self.view_rows(0..self.rows).to_vec()
+    }
+
+    pub fn row(&self, row: usize) -> FarMemoryTensorView<'_, T> {
+        self.view_rows(row..(row + 1))
+    }
+
+    // rows within one span are used in place (span is kept in use until view is dropped), rows spanning multiple spans are
+    // copied to local memory.
+    pub fn view_rows(&self, rows: Range<usize>) -> FarMemoryTensorView<'_, T> {
+        if rows.start > rows.end || rows.end > self.rows {
+            panic!("rows {}..{} are out of bounds for tensor with {} rows", rows.start, rows.end, self.rows);
+        }
+
+        if rows.is_empty() || self.row_len == 0 {
+            return FarMemoryTensorView::Local(Vec::new());
+        }
+
+        let first_span = rows.start / self.span_rows;
+        let last_span = (rows.end - 1) / self.span_rows;
+        if first_span == last_span {
+            let span_id = &self.spans[first_span];
+            let ptr = self.client.span_ptr(span_id) as *const T;
+            let offset = (rows.start % self.span_rows) * self.row_len;
+            return FarMemoryTensorView::Span {
+                client: &self.client,
+                span_id: span_id.clone(),
+                ptr: unsafe { ptr.add(offset) },
+                len: rows.len() * self.row_len,
+            };
+        }
+
+        let mut result = Vec::with_capacity(rows.len() * self.row_len);
+        self.for_each_block(rows, |block| result.extend_from_slice(block));
+        FarMemoryTensorView::Local(result)
+    }
+
+    // calls function for every block of rows that is stored in the same span, prefetching spans ahead.
+    fn for_each_block<F: FnMut(&[T])>(&self, rows: Range<usize>, mut f: F) {
+        if rows.is_empty() || self.row_len == 0 {
+            return;
+        }
+
+        let first_span = rows.start / self.span_rows;
+        let last_span = (rows.end - 1) / self.span_rows;
+
+        for span_index in first_span..=last_span {
+            if self.prefetch_spans > 0 && (span_index - first_span) % self.prefetch_spans == 0 {
+                let prefetch_from = span_index + 1;
+                let prefetch_until = (span_index + 1 + self.prefetch_spans).min(last_span + 1);
+                if prefetch_from < prefetch_until {
+                    self.client.prefetch_spans(self.spans[prefetch_from..prefetch_until].to_vec());
+                }
+            }
+
+            let span_first_row = span_index * self.span_rows;
+            let block_start = rows.start.max(span_first_row);
+            let block_end = rows.end.min(span_first_row + self.span_rows);
+
+            let span_id = &self.spans[span_index];
+            let ptr = self.client.span_ptr(span_id) as *const T;
+            f(unsafe {
+                std::slice::from_raw_parts(ptr.add((block_start - span_first_row) * self.row_len), (block_end - block_start) * self.row_len)
+            });
+            self.client.decrease_refs_for_span(span_id);
+        }
+    }
+}
+
+impl FarMemoryTensor<f32> {
+    // out = W x, where tensor is W viewed as a matrix of (rows, row_len).
+    pub fn mat_vec(&self, x: &[f32], out: &mut [f32]) {
+        self.mat_vec_rows(0..self.rows, x, out);
+    }
+
+    // same as `mat_vec`, but only using a range of rows as a matrix (for example, weights of one layer).
+    pub fn mat_vec_rows(&self, rows: Range<usize>, x: &[f32], out: &mut [f32]) {
+        self.matmul_rows(rows, x, 1, out);
+    }
+
+    // multiplies every vector in a batch (`batch` vectors of row_len laid out one after another) by the matrix, reading
+    // weights only once. Result is `batch` vectors of rows.len() laid out the same way.
+    pub fn matmul_rows(&self, rows: Range<usize>, x: &[f32], batch: usize, out: &mut [f32]) {
+        if x.len() != batch * self.row_len {
+            panic!("input of {} items does not match batch of {} vectors of {} items", x.len(), batch, self.row_len);
+        }
+        if out.len() != batch * rows.len() {
+            panic!("output of {} items does not match batch of {} vectors of {} items", out.len(), batch, rows.len());
+        }
+
+        let out_len = rows.len();
+        let mut row = 0;
+        self.for_each_block(rows, |block| {
+            for weights in block.chunks_exact(self.row_len) {
+                for (b, x) in x.chunks_exact(self.row_len).enumerate() {
+                    out[b * out_len + row] = weights.iter().zip(x.iter()).fold(0.0, |acc, (w, x)| acc + w * x);
+                }
+                row += 1;
+            }
+        });
+    }
+}
+
+pub enum FarMemoryTensorView<'a, T> {
+    Span {
+        client: &'a FarMemoryClient,
+        span_id: SpanId,
+        ptr: *const T,
+        len: usize,
+    },
+    Local(Vec<T>),
+}
+
+impl<'a, T> Deref for FarMemoryTensorView<'a, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            Self::Span { ptr, len, .. } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+            Self::Local(v) => v,
+        }
+    }
+}
+
+impl<T> Drop for FarMemoryTensor<T> {
+    fn drop(&mut self) {
+        for span_id in &self.spans {
+            self.client.free_span(span_id);
+        }
+    }
+}
+
+impl<'a, T> Drop for FarMemoryTensorView<'a, T> {
+    fn drop(&mut self) {
+        if let Self::Span { client, span_id, .. } = self {
+            client.decrease_refs_for_span(span_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::client::InMemoryBackend,
+        super::*,
+    };
+
+    #[test]
+    fn view_rows() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let data: Vec<u32> = (0..600).collect();
+        let tensor = FarMemoryTensor::from_slice_with_span_size(client.clone(), &[2, 30, 10], &data, 160); // 4 rows per span
+        tensor.swap_out();
+
+        assert_eq!(60, tensor.rows());
+        assert_eq!(15, client.total_remote_spans());
+
+        assert_eq!((130..140).collect::<Vec<_>>(), tensor.row(13).to_vec());
+        assert!(matches!(tensor.view_rows(4..8), FarMemoryTensorView::Span { .. }));
+        assert_eq!((50..230).collect::<Vec<_>>(), tensor.view_rows(5..23).to_vec());
+        assert_eq!(data, tensor.to_local_vec());
+    }
+
+    #[test]
+    fn mat_vec_and_batch() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let weights: Vec<f32> = (0..(50 * 8)).map(|v| (v % 7) as f32 - 3.0).collect();
+        let tensor = FarMemoryTensor::from_slice_with_span_size(client, &[50, 8], &weights, 100).with_prefetch_spans(2);
+        tensor.swap_out();
+
+        let x: Vec<f32> = (0..16).map(|v| v as f32 / 4.0).collect();
+        let expected = |rows: Range<usize>, x: &[f32]| -> Vec<f32> {
+            rows.map(|row| (0..8).map(|i| weights[row * 8 + i] * x[i]).sum()).collect()
+        };
+
+        let mut out = vec![0.0; 50];
+        tensor.mat_vec(&x[..8], &mut out);
+        assert_eq!(expected(0..50, &x[..8]), out);
+
+        let mut out = vec![0.0; 20];
+        tensor.matmul_rows(10..20, &x, 2, &mut out);
+        assert_eq!(expected(10..20, &x[..8]), out[..10]);
+        assert_eq!(expected(10..20, &x[8..]), out[10..]);
+    }
+
+    #[test]
+    fn from_reader() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let data: Vec<u8> = (0..100u32).flat_map(|v| (v as f32).to_ne_bytes()).collect();
+
+        let mut reader = &data[..];
+        let first = FarMemoryTensor::<f32>::from_reader(client.clone(), &[5, 10], &mut reader).unwrap();
+        let second = FarMemoryTensor::<f32>::from_reader(client.clone(), &[50], &mut reader).unwrap();
+        assert!(FarMemoryTensor::<f32>::from_reader(client, &[1], &mut reader).is_err());
+
+        assert_eq!((0..50).map(|v| v as f32).collect::<Vec<_>>(), first.to_local_vec());
+        assert_eq!((50..100).map(|v| v as f32).collect::<Vec<_>>(), second.row(0).to_vec());
+    }
+
+    #[test]
+    fn drop_frees_spans() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let tensor = FarMemoryTensor::<f32>::from_slice_with_span_size(client.clone(), &[100, 10], &[1.0; 1000], 400);
+        assert_eq!(10, client.total_local_spans());
+
+        drop(tensor);
+        assert_eq!(0, client.total_local_spans());
+    }
+}
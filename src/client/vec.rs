@@ -1,25 +1,56 @@
 use {
-    std::{ops::Deref, fmt::Debug, marker::PhantomData},
+    std::{ops::{Deref, Range, RangeBounds, Bound}, fmt::Debug, marker::PhantomData},
     tracing::{span, Level},
     super::{FarMemoryClient, span::SpanId},
 };
 
+const DEFAULT_CHUNK_SIZE: usize = 2 * 1024 * 1024; // 2 MB
+const DEFAULT_PREFETCH_CHUNKS: usize = 2;
+
+/**
+ * vector is split into fixed-size chunks, one span per chunk, so that accessing an element only needs its chunk to be local.
+ * Vector created with `from_vec` has a single chunk for all of the data, which allows `to_local_vec` to work in place.
+ */
 pub struct FarMemoryVec<T> {
     client: FarMemoryClient,
-    span: SpanId,
+    chunks: Vec<SpanId>,
+    chunk_len: usize, // elements per chunk
     len: usize,
+    prefetch_chunks: usize,
 
     _phantom: PhantomData<T>,
 }
 
 pub struct FarMemoryLocalVec<T> {
     client: FarMemoryClient,
-    span: SpanId,
+    span: Option<SpanId>, // None when data was copied from multiple chunks
     vec: Vec<T>,
 }
 
 impl <T> FarMemoryVec<T> {
+    pub fn new(client: FarMemoryClient) -> Self {
+        let chunk_len = DEFAULT_CHUNK_SIZE / std::mem::size_of::<T>().max(1);
+        Self::with_chunk_len(client, chunk_len)
+    }
+
+    pub fn with_chunk_len(client: FarMemoryClient, chunk_len: usize) -> Self {
+        Self {
+            client,
+            chunks: Vec::new(),
+            chunk_len: chunk_len.max(1),
+            len: 0,
+            prefetch_chunks: DEFAULT_PREFETCH_CHUNKS,
+
+            _phantom: PhantomData,
+        }
+    }
+
     pub fn from_vec(client: FarMemoryClient, vec: Vec<T>) -> Self {
+        if vec.is_empty() {
+            // spans can't be empty, so first chunk is allocated on push.
+            return Self::new(client);
+        }
+
         let size = std::mem::size_of::<T>() * vec.len();
         let span = client.allocate_span(size);
         let ptr = client.span_ptr(&span);
@@ -30,29 +61,348 @@ impl <T> FarMemoryVec<T> {
         }
         client.decrease_refs_for_span(&span);
 
+        let len = vec.len();
+        let mut vec = vec;
+        unsafe {
+            vec.set_len(0); // elements are owned by far memory now
+        }
+
         Self {
             client,
-            span,
-            len: vec.len(),
+            chunks: vec![span],
+            chunk_len: len,
+            len,
+            prefetch_chunks: DEFAULT_PREFETCH_CHUNKS,
 
             _phantom: PhantomData,
         }
     }
 
-    pub fn to_local_vec(&self) -> FarMemoryLocalVec<T> {
-        span!(Level::DEBUG, "FarMemoryVec::to_local_vec", span_id=self.span.id()).in_scope(|| {
-            let ptr = self.client.span_ptr(&self.span) as *const T;
-            if self.len * std::mem::size_of::<T>() != self.client.span_local_memory_usage(&self.span) {
-                panic!("memory needed for mem does not match size of memory allocated");
+    // how many chunks ahead to swap in while iterating.
+    pub fn with_prefetch_chunks(mut self, prefetch_chunks: usize) -> Self {
+        self.prefetch_chunks = prefetch_chunks;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn swap_out(&self) {
+        self.client.swap_out_spans_fully(&self.chunks);
+    }
+
+    pub fn to_local_vec(&self) -> FarMemoryLocalVec<T> where T: Copy {
+        span!(Level::DEBUG, "FarMemoryVec::to_local_vec", chunks=self.chunks.len()).in_scope(|| {
+            if self.chunks.len() == 1 {
+                // all data is in a single span, so it can be used in place
+                let span = self.chunks[0].clone();
+                let ptr = self.client.span_ptr(&span) as *mut T;
+
+                return FarMemoryLocalVec {
+                    client: self.client.clone(),
+                    span: Some(span),
+                    vec: unsafe { Vec::from_raw_parts(ptr, self.len, self.len) },
+                };
             }
 
             FarMemoryLocalVec {
                 client: self.client.clone(),
-                span: self.span.clone(),
-                vec: unsafe { Vec::from_raw_parts(ptr as *mut T, self.len, self.len) },
+                span: None,
+                vec: self.slice(..).to_vec(),
             }
         })
     }
+
+    fn chunk_size(&self) -> usize {
+        self.chunk_len * std::mem::size_of::<T>()
+    }
+
+    fn capacity(&self) -> usize {
+        self.chunks.len() * self.chunk_len
+    }
+
+    fn free_chunks_after(&mut self, len: usize) {
+        let chunks_needed = (len + self.chunk_len - 1) / self.chunk_len;
+        for chunk in self.chunks.drain(chunks_needed..) {
+            self.client.free_span(&chunk);
+        }
+    }
+}
+
+impl<T: Copy> FarMemoryVec<T> {
+    pub fn push(&mut self, value: T) {
+        if self.len == self.capacity() {
+            self.chunks.push(self.client.allocate_span(self.chunk_size()));
+        }
+
+        self.len += 1;
+        self.set(self.len - 1, value);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let value = self.get(self.len - 1);
+        self.truncate(self.len - 1);
+        value
+    }
+
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len {
+            return None;
+        }
+
+        let (span, offset) = self.location(index);
+        let ptr = self.client.span_ptr(span) as *const T;
+        let value = unsafe { ptr.add(offset).read_unaligned() };
+        self.client.decrease_refs_for_span(span);
+
+        Some(value)
+    }
+
+    pub fn set(&mut self, index: usize, value: T) {
+        if index >= self.len {
+            panic!("index {} is out of bounds for vec of length {}", index, self.len);
+        }
+
+        let (span, offset) = self.location(index);
+        let ptr = self.client.span_ptr(span) as *mut T;
+        unsafe {
+            ptr.add(offset).write_unaligned(value);
+        }
+        self.client.decrease_refs_for_span(span);
+    }
+
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        let mut i = 0;
+        while i < values.len() {
+            if self.len == self.capacity() {
+                self.chunks.push(self.client.allocate_span(self.chunk_size()));
+            }
+
+            // fill the last chunk with as many values as possible at once
+            let (span, offset) = self.location(self.len);
+            let to_copy = (self.chunk_len - offset).min(values.len() - i);
+            let ptr = self.client.span_ptr(span) as *mut T;
+            unsafe {
+                // span memory is not aligned for T, so values are copied as bytes
+                std::ptr::copy_nonoverlapping(values.as_ptr().add(i) as *const u8, ptr.add(offset) as *mut u8, to_copy * std::mem::size_of::<T>());
+            }
+            self.client.decrease_refs_for_span(span);
+
+            self.len += to_copy;
+            i += to_copy;
+        }
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        self.len = len;
+        self.free_chunks_after(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> FarMemoryVecSlice<'_, T> {
+        FarMemoryVecSlice {
+            vec: self,
+            range: resolve_range(range, self.len),
+        }
+    }
+
+    pub fn iter(&self) -> FarMemoryVecIterator<'_, T> {
+        FarMemoryVecIterator::new(self, 0..self.len)
+    }
+
+    fn location(&self, index: usize) -> (&SpanId, usize) {
+        (&self.chunks[index / self.chunk_len], index % self.chunk_len)
+    }
+}
+
+impl<T> Drop for FarMemoryVec<T> {
+    fn drop(&mut self) {
+        for chunk in &self.chunks {
+            self.client.free_span(chunk);
+        }
+    }
+}
+
+impl<T: Copy> Extend<T> for FarMemoryVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        // values are written in batches, so that every chunk is accessed once per batch instead of once per value
+        let mut batch = Vec::with_capacity(self.chunk_len.min(4096));
+        for value in iter {
+            batch.push(value);
+            if batch.len() == batch.capacity() {
+                self.extend_from_slice(&batch);
+                batch.clear();
+            }
+        }
+        self.extend_from_slice(&batch);
+    }
+}
+
+/**
+ * view of a part of the vector. Nothing is swapped in until elements are accessed, and then only chunks that
+ * contain these elements are.
+ */
+pub struct FarMemoryVecSlice<'a, T> {
+    vec: &'a FarMemoryVec<T>,
+    range: Range<usize>,
+}
+
+impl<'a, T: Copy> FarMemoryVecSlice<'a, T> {
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        self.vec.get(self.range.start + index)
+    }
+
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> FarMemoryVecSlice<'a, T> {
+        let range = resolve_range(range, self.len());
+        FarMemoryVecSlice {
+            vec: self.vec,
+            range: (self.range.start + range.start)..(self.range.start + range.end),
+        }
+    }
+
+    pub fn iter(&self) -> FarMemoryVecIterator<'a, T> {
+        FarMemoryVecIterator::new(self.vec, self.range.clone())
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        let mut result: Vec<T> = Vec::with_capacity(self.len());
+        let mut i = self.range.start;
+
+        while i < self.range.end {
+            let (span, offset) = self.vec.location(i);
+            let to_copy = (self.vec.chunk_len - offset).min(self.range.end - i);
+            let ptr = self.vec.client.span_ptr(span) as *const T;
+            unsafe {
+                std::ptr::copy_nonoverlapping(ptr.add(offset) as *const u8, result.as_mut_ptr().add(result.len()) as *mut u8, to_copy * std::mem::size_of::<T>());
+                result.set_len(result.len() + to_copy);
+            }
+            self.vec.client.decrease_refs_for_span(span);
+
+            i += to_copy;
+        }
+
+        result
+    }
+}
+
+/**
+ * keeps the current chunk local while iterating over it, and swaps in the next chunks in background.
+ */
+pub struct FarMemoryVecIterator<'a, T> {
+    vec: &'a FarMemoryVec<T>,
+    range: Range<usize>,
+    current_chunk: Option<(usize, *const T)>,
+    prefetched_until: usize, // index of the first chunk that was not prefetched yet
+}
+
+impl<'a, T> FarMemoryVecIterator<'a, T> {
+    fn new(vec: &'a FarMemoryVec<T>, range: Range<usize>) -> Self {
+        Self {
+            vec,
+            range,
+            current_chunk: None,
+            prefetched_until: 0,
+        }
+    }
+
+    fn enter_chunk(&mut self, chunk: usize) -> *const T {
+        self.release_chunk();
+
+        let prefetch_until = (chunk + 1 + self.vec.prefetch_chunks).min((self.range.end + self.vec.chunk_len - 1) / self.vec.chunk_len);
+        let prefetch_from = self.prefetched_until.max(chunk + 1);
+        if prefetch_from < prefetch_until {
+            self.vec.client.prefetch_spans(self.vec.chunks[prefetch_from..prefetch_until].to_vec());
+            self.prefetched_until = prefetch_until;
+        }
+
+        let ptr = self.vec.client.span_ptr(&self.vec.chunks[chunk]) as *const T;
+        self.current_chunk = Some((chunk, ptr));
+        ptr
+    }
+
+    fn release_chunk(&mut self) {
+        if let Some((chunk, _)) = self.current_chunk.take() {
+            self.vec.client.decrease_refs_for_span(&self.vec.chunks[chunk]);
+        }
+    }
+}
+
+impl<'a, T: Copy> Iterator for FarMemoryVecIterator<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.range.is_empty() {
+            self.release_chunk();
+            return None;
+        }
+
+        let index = self.range.start;
+        self.range.start += 1;
+
+        let chunk = index / self.vec.chunk_len;
+        let ptr = match self.current_chunk {
+            Some((current_chunk, ptr)) if current_chunk == chunk => ptr,
+            _ => self.enter_chunk(chunk),
+        };
+
+        Some(unsafe { ptr.add(index % self.vec.chunk_len).read_unaligned() })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.range.len(), Some(self.range.len()))
+    }
+}
+
+impl<'a, T> Drop for FarMemoryVecIterator<'a, T> {
+    fn drop(&mut self) {
+        self.release_chunk();
+    }
+}
+
+fn resolve_range<R: RangeBounds<usize>>(range: R, len: usize) -> Range<usize> {
+    let start = match range.start_bound() {
+        Bound::Included(v) => *v,
+        Bound::Excluded(v) => v + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(v) => v + 1,
+        Bound::Excluded(v) => *v,
+        Bound::Unbounded => len,
+    };
+
+    if start > end || end > len {
+        panic!("range {}..{} is out of bounds for length {}", start, end, len);
+    }
+
+    start..end
 }
 
 impl<T> Deref for FarMemoryLocalVec<T> {
@@ -83,10 +433,12 @@ impl<T: std::cmp::PartialEq> PartialEq<FarMemoryLocalVec<T>> for Vec<T> {
 
 impl<T> Drop for FarMemoryLocalVec<T> {
     fn drop(&mut self) {
-        let mut v = Vec::new();
-        std::mem::swap(&mut self.vec, &mut v);
-        std::mem::forget(v);
-        self.client.decrease_refs_for_span(&self.span);
+        if let Some(span) = &self.span {
+            let mut v = Vec::new();
+            std::mem::swap(&mut self.vec, &mut v);
+            std::mem::forget(v);
+            self.client.decrease_refs_for_span(span);
+        }
     }
 }
 
@@ -127,4 +479,79 @@ mod tests {
             vec.to_local_vec()
         );
     }
+
+    #[test]
+    fn push_onto_empty_from_vec() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = FarMemoryVec::from_vec(client.clone(), Vec::<u64>::new());
+        assert_eq!(0, client.total_local_spans());
+
+        vec.push(1);
+        vec.push(2);
+        assert_eq!(vec![1, 2], vec.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drop_frees_chunks() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = FarMemoryVec::with_chunk_len(client.clone(), 1000);
+        vec.extend(0..10_000u32);
+        vec.swap_out();
+
+        drop(vec);
+        assert_eq!(0, client.total_local_spans());
+        assert_eq!(0, client.total_remote_spans());
+    }
+
+    #[test]
+    fn push_pop_get_set() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = FarMemoryVec::with_chunk_len(client, 3);
+
+        for i in 0..10u64 {
+            vec.push(i);
+        }
+        vec.set(4, 42);
+
+        assert_eq!(10, vec.len());
+        assert_eq!(Some(42), vec.get(4));
+        assert_eq!(None, vec.get(10));
+        assert_eq!(Some(9), vec.pop());
+        assert_eq!(Some(8), vec.pop());
+        assert_eq!(vec![0, 1, 2, 3, 42, 5, 6, 7], vec.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn extend_and_truncate() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = FarMemoryVec::with_chunk_len(client.clone(), 1000);
+
+        vec.extend(0..10_000u32);
+        assert_eq!(10, client.total_local_spans());
+
+        vec.truncate(2500);
+        assert_eq!(3, client.total_local_spans());
+        assert_eq!(2500, vec.len());
+        assert_eq!(Some(2499), vec.get(2499));
+
+        vec.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(vec![2498, 2499, 1, 2, 3], vec.iter().skip(2498).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn slice_after_swap_out() {
+        let client = FarMemoryClient::new(Box::new(InMemoryBackend::new()), 100 * 1024 * 1024);
+        let mut vec = FarMemoryVec::with_chunk_len(client.clone(), 1000);
+        vec.extend(0..10_000u32);
+        vec.swap_out();
+
+        let slice = vec.slice(1500..2500);
+        assert_eq!(1000, slice.len());
+        assert_eq!((1500..2500).collect::<Vec<_>>(), slice.to_vec());
+        assert_eq!(Some(1510), slice.slice(10..).get(0));
+        assert_eq!(2, client.total_local_spans());
+
+        assert_eq!((0..10_000u32).sum::<u32>(), vec.iter().sum::<u32>());
+        assert_eq!((0..10_000u32).collect::<Vec<_>>(), *vec.to_local_vec());
+    }
 }
@@ -2,7 +2,6 @@ use {
     std::{fs::File, time::Instant, hint::black_box},
     tracing::{info, warn, span, Level},
     prometheus::Registry,
-    serde::{Serialize, Deserialize},
     chrono::NaiveDate,
     rand::Rng,
     rand_distr::Zipf,
@@ -17,75 +16,80 @@ use {
             InstrumentedBackend,
             PreferRemoteSpansReplacementPolicy,
             LeastRecentlyUsedReplacementPolicy,
-            FarMemorySerializedObjectVec,
+            FarMemoryTable,
+            FarMemoryTableSchema,
+            FarMemoryColumnType,
+            FarMemoryValue,
+            FarMemoryTablePredicate,
         },
         manager::ManagerClient,
     },
 };
 
-#[derive(Serialize, Deserialize, Debug, Default)]
-struct FlightData {
-    flight_date: NaiveDate,
-    airline: String,
-    origin: String,
-    destination: String,
-    cancelled: bool,
-    diverted: bool,
-    crs_dep_time: u16,
-    dep_time: Option<f32>,
-    dep_delay_minutes: Option<f32>,
-    dep_delay: Option<f32>,
-    arr_time: Option<f32>,
-    arr_delay_minutes: Option<f32>,
-    air_time: Option<f32>,
-    crs_elapsed_time: Option<f32>,
-    actual_elapsed_time: Option<f32>,
-    distance: f32,
-    year: u32,
-    quarter: u8,
-    month: u8,
-    day_of_month: u8,
-    day_of_week: u8,
-    marketing_airline_network: String,
-    operated_or_branded_code_share_partners: String,
-    dot_id_marketing_airline: u32,
-    iata_code_marketing_airline: String,
-    flight_number_marketing_airline: u32,
-    operating_airline: String,
-    dot_id_operating_airline: u32,
-    iata_code_operating_airline: String,
-    tail_number: String,
-    flight_number_operating_airline: u32,
-    origin_airport_id: u32,
-    origin_airport_seq_id: u32,
-    origin_city_market_id: u32,
-    origin_city_name: String,
-    origin_city_state: String,
-    origin_state_fips: u32,
-    origin_state_name: String,
-    origin_wac: u32,
-    dest_airport_id: u32,
-    dest_airport_seq_id: u32,
-    dest_city_market_id: u32,
-    dest_city_name: String,
-    dest_state: String,
-    dest_state_fips: u32,
-    dest_state_name: String,
-    dest_wac: u32,
-    dest_del_15: Option<f32>,
-    departure_delay_groups: Option<f32>,
-    dep_time_blk: String,
-    taxi_out: Option<f32>,
-    wheels_off: Option<f32>,
-    wheels_on: Option<f32>,
-    taxi_in: Option<f32>,
-    crs_arr_time: u32,
-    arr_delay: Option<f32>,
-    arr_del_15: Option<f32>,
-    arrival_delay_groups: Option<f32>,
-    arr_time_blk: String,
-    distance_group: u32,
-    div_airport_landings: Option<f32>,
+// columns in the same order as in dataset csv files
+fn flights_schema() -> FarMemoryTableSchema {
+    FarMemoryTableSchema::new()
+        .with_column("flight_date", FarMemoryColumnType::Date)
+        .with_column("airline", FarMemoryColumnType::String)
+        .with_column("origin", FarMemoryColumnType::String)
+        .with_column("destination", FarMemoryColumnType::String)
+        .with_column("cancelled", FarMemoryColumnType::Bool)
+        .with_column("diverted", FarMemoryColumnType::Bool)
+        .with_column("crs_dep_time", FarMemoryColumnType::U16)
+        .with_column("dep_time", FarMemoryColumnType::F32)
+        .with_column("dep_delay_minutes", FarMemoryColumnType::F32)
+        .with_column("dep_delay", FarMemoryColumnType::F32)
+        .with_column("arr_time", FarMemoryColumnType::F32)
+        .with_column("arr_delay_minutes", FarMemoryColumnType::F32)
+        .with_column("air_time", FarMemoryColumnType::F32)
+        .with_column("crs_elapsed_time", FarMemoryColumnType::F32)
+        .with_column("actual_elapsed_time", FarMemoryColumnType::F32)
+        .with_column("distance", FarMemoryColumnType::F32)
+        .with_column("year", FarMemoryColumnType::U32)
+        .with_column("quarter", FarMemoryColumnType::U8)
+        .with_column("month", FarMemoryColumnType::U8)
+        .with_column("day_of_month", FarMemoryColumnType::U8)
+        .with_column("day_of_week", FarMemoryColumnType::U8)
+        .with_column("marketing_airline_network", FarMemoryColumnType::String)
+        .with_column("operated_or_branded_code_share_partners", FarMemoryColumnType::String)
+        .with_column("dot_id_marketing_airline", FarMemoryColumnType::U32)
+        .with_column("iata_code_marketing_airline", FarMemoryColumnType::String)
+        .with_column("flight_number_marketing_airline", FarMemoryColumnType::U32)
+        .with_column("operating_airline", FarMemoryColumnType::String)
+        .with_column("dot_id_operating_airline", FarMemoryColumnType::U32)
+        .with_column("iata_code_operating_airline", FarMemoryColumnType::String)
+        .with_column("tail_number", FarMemoryColumnType::String)
+        .with_column("flight_number_operating_airline", FarMemoryColumnType::U32)
+        .with_column("origin_airport_id", FarMemoryColumnType::U32)
+        .with_column("origin_airport_seq_id", FarMemoryColumnType::U32)
+        .with_column("origin_city_market_id", FarMemoryColumnType::U32)
+        .with_column("origin_city_name", FarMemoryColumnType::String)
+        .with_column("origin_city_state", FarMemoryColumnType::String)
+        .with_column("origin_state_fips", FarMemoryColumnType::U32)
+        .with_column("origin_state_name", FarMemoryColumnType::String)
+        .with_column("origin_wac", FarMemoryColumnType::U32)
+        .with_column("dest_airport_id", FarMemoryColumnType::U32)
+        .with_column("dest_airport_seq_id", FarMemoryColumnType::U32)
+        .with_column("dest_city_market_id", FarMemoryColumnType::U32)
+        .with_column("dest_city_name", FarMemoryColumnType::String)
+        .with_column("dest_state", FarMemoryColumnType::String)
+        .with_column("dest_state_fips", FarMemoryColumnType::U32)
+        .with_column("dest_state_name", FarMemoryColumnType::String)
+        .with_column("dest_wac", FarMemoryColumnType::U32)
+        .with_column("dest_del_15", FarMemoryColumnType::F32)
+        .with_column("departure_delay_groups", FarMemoryColumnType::F32)
+        .with_column("dep_time_blk", FarMemoryColumnType::String)
+        .with_column("taxi_out", FarMemoryColumnType::F32)
+        .with_column("wheels_off", FarMemoryColumnType::F32)
+        .with_column("wheels_on", FarMemoryColumnType::F32)
+        .with_column("taxi_in", FarMemoryColumnType::F32)
+        .with_column("crs_arr_time", FarMemoryColumnType::U32)
+        .with_column("arr_delay", FarMemoryColumnType::F32)
+        .with_column("arr_del_15", FarMemoryColumnType::F32)
+        .with_column("arrival_delay_groups", FarMemoryColumnType::F32)
+        .with_column("arr_time_blk", FarMemoryColumnType::String)
+        .with_column("distance_group", FarMemoryColumnType::U32)
+        .with_column("div_airport_landings", FarMemoryColumnType::F32)
 }
 
 #[derive(Debug)]
@@ -96,54 +100,46 @@ struct FlightsQuery {
 }
 
 struct DemoDataFramePipeline {
-    dataframe: FarMemorySerializedObjectVec<FlightData>,
+    dataframe: FarMemoryTable,
 }
 
 impl DemoDataFramePipeline {
-    pub fn new(dataframe: FarMemorySerializedObjectVec<FlightData>) -> Self {
+    pub fn new(dataframe: FarMemoryTable) -> Self {
         Self {
             dataframe,
         }
     }
 
-    pub fn pick_random(&self, zipf_s: f32) -> FlightData {
+    // returns flight_date, origin_airport_id and dot_id_operating_airline of a random flight.
+    pub fn pick_random(&self, zipf_s: f32) -> Vec<FarMemoryValue> {
         let index = rand::thread_rng().sample(Zipf::new(self.dataframe.len() as u64 - 1, zipf_s).unwrap()).floor() as u64 - 1; // -1 because zipf returns [1; n]. Not clear why -2 is needed though.
-        self.dataframe.get(index as usize).expect(&format!("failed to get entry for index {} with dataframe len {}", index, self.dataframe.len()))
+        self.dataframe.row(index as usize, &["flight_date", "origin_airport_id", "dot_id_operating_airline"])
     }
 
     /* get average delay based on arr_delay */
     pub fn get_average_delay_with_criteria(&self, query: FlightsQuery) -> Option<f32> {
-        let mut dataframe_iter = span!(Level::DEBUG, "creating dataframe iterator").in_scope(|| self.dataframe.iter());
+        let mut conditions = vec![FarMemoryTablePredicate::IsNotNull("arr_delay".to_owned())];
+        if let Some(after_date) = query.after_date {
+            conditions.push(FarMemoryTablePredicate::Lt("flight_date".to_owned(), FarMemoryValue::Date(after_date)));
+        }
+        if let Some(airline_code) = query.airline_code {
+            conditions.push(FarMemoryTablePredicate::Eq("dot_id_operating_airline".to_owned(), FarMemoryValue::U32(airline_code)));
+        }
+        if let Some(origin_airport_id) = query.origin_airport_id {
+            conditions.push(FarMemoryTablePredicate::Eq("origin_airport_id".to_owned(), FarMemoryValue::U32(origin_airport_id)));
+        }
+
+        let rows = span!(Level::DEBUG, "creating dataframe iterator")
+            .in_scope(|| self.dataframe.filter(FarMemoryTablePredicate::And(conditions), &["arr_delay"]));
 
         let mut total_delay = 0.0;
         let mut total_objects = 0;
         span!(Level::DEBUG, "iterating").in_scope(|| {
-            while let Some(v) = dataframe_iter.next() {
-                if !(query.after_date.is_none() || query.after_date.unwrap() > v.flight_date) {
-                    continue;
-                }
-
-                if !(query.airline_code.is_none() || query.airline_code.unwrap() == v.dot_id_operating_airline) {
-                    continue;
-                }
-
-                if !(query.origin_airport_id.is_none() || query.origin_airport_id.unwrap() == v.origin_airport_id) {
-                    continue;
-                }
-
-                if !v.arr_delay.is_some() {
-                    continue;
-                }
-
-                if total_objects >= 10_000 {
-                    break;
-                }
-
+            for row in rows.take(10_000) {
                 total_objects += 1;
-                total_delay += v.arr_delay.unwrap();
+                total_delay += row[0].as_f32().unwrap();
             }
         });
-        span!(Level::DEBUG, "iterator drop").in_scope(|| std::mem::drop(dataframe_iter));
 
         if total_objects == 0 {
             None
@@ -207,7 +203,7 @@ pub fn run_dataframe_demo(metrics: Registry, run_id: String, token: &str, storag
     client.start_swap_out_thread();
 
     // demo app
-    let mut dataframe: FarMemorySerializedObjectVec<FlightData> = FarMemorySerializedObjectVec::new(client.clone());
+    let mut dataframe = FarMemoryTable::new(client.clone(), flights_schema());
     let dataframe_size_limit = 20_000_000; // 20M for 17.5GB memory.
 
     span!(Level::INFO, "loading data").in_scope(|| {
@@ -215,75 +211,10 @@ pub fn run_dataframe_demo(metrics: Registry, run_id: String, token: &str, storag
             for year in 2018..2023 {
                 let file_name = format!("./data/flights/Combined_Flights_{}.csv", year);
                 let mut reader = csv::Reader::from_reader(File::open(file_name).unwrap());
-                for row in reader.records() {
-                    let row = row.unwrap();
-                    dataframe.push(FlightData {
-                        flight_date: NaiveDate::parse_from_str(&row[0], "%Y-%m-%d").unwrap(),
-                        airline: row[1].to_owned(),
-                        origin: row[2].to_owned(),
-                        destination: row[3].to_owned(),
-                        cancelled: parse_bool(&row[4]),
-                        diverted: parse_bool(&row[5]),
-                        crs_dep_time: row[6].parse().unwrap(),
-                        dep_time: parse_option_f32(&row[7]),
-                        dep_delay_minutes: parse_option_f32(&row[8]),
-                        dep_delay: parse_option_f32(&row[9]),
-                        arr_time: parse_option_f32(&row[10]),
-                        arr_delay_minutes: parse_option_f32(&row[11]),
-                        air_time: parse_option_f32(&row[12]),
-                        crs_elapsed_time: parse_option_f32(&row[13]),
-                        actual_elapsed_time: parse_option_f32(&row[14]),
-                        distance: row[15].parse().unwrap(),
-                        year: row[16].parse().unwrap(),
-                        quarter: row[17].parse().unwrap(),
-                        month: row[18].parse().unwrap(),
-                        day_of_month: row[19].parse().unwrap(),
-                        day_of_week: row[20].parse().unwrap(),
-                        marketing_airline_network: row[21].parse().unwrap(),
-                        operated_or_branded_code_share_partners: row[22].parse().unwrap(),
-                        dot_id_marketing_airline: row[23].parse().unwrap(),
-                        iata_code_marketing_airline: row[24].parse().unwrap(),
-                        flight_number_marketing_airline: row[25].parse().unwrap(),
-                        operating_airline: row[26].parse().unwrap(),
-                        dot_id_operating_airline: row[27].parse().unwrap(),
-                        iata_code_operating_airline: row[28].parse().unwrap(),
-                        tail_number: row[29].parse().unwrap(),
-                        flight_number_operating_airline: row[30].parse().unwrap(),
-                        origin_airport_id: row[31].parse().unwrap(),
-                        origin_airport_seq_id: row[32].parse().unwrap(),
-                        origin_city_market_id: row[33].parse().unwrap(),
-                        origin_city_name: row[34].parse().unwrap(),
-                        origin_city_state: row[35].parse().unwrap(),
-                        origin_state_fips: row[36].parse().unwrap(),
-                        origin_state_name: row[37].parse().unwrap(),
-                        origin_wac: row[38].parse().unwrap(),
-                        dest_airport_id: row[39].parse().unwrap(),
-                        dest_airport_seq_id: row[40].parse().unwrap(),
-                        dest_city_market_id: row[41].parse().unwrap(),
-                        dest_city_name: row[42].parse().unwrap(),
-                        dest_state: row[43].parse().unwrap(),
-                        dest_state_fips: row[44].parse().unwrap(),
-                        dest_state_name: row[45].parse().unwrap(),
-                        dest_wac: row[46].parse().unwrap(),
-                        dest_del_15: parse_option_f32(&row[47]),
-                        departure_delay_groups: parse_option_f32(&row[48]),
-                        dep_time_blk: row[49].parse().unwrap(),
-                        taxi_out: parse_option_f32(&row[50]),
-                        wheels_off: parse_option_f32(&row[51]),
-                        wheels_on: parse_option_f32(&row[52]),
-                        taxi_in: parse_option_f32(&row[53]),
-                        crs_arr_time: row[54].parse().unwrap(),
-                        arr_delay: parse_option_f32(&row[55]),
-                        arr_del_15: parse_option_f32(&row[56]),
-                        arrival_delay_groups: parse_option_f32(&row[57]),
-                        arr_time_blk: row[58].parse().unwrap(),
-                        distance_group: row[59].parse().unwrap(),
-                        div_airport_landings: parse_option_f32(&row[60]),
-                    });
-
-                    if dataframe.len() >= dataframe_size_limit {
-                        break 'loading;
-                    }
+                dataframe.load_csv(&mut reader, Some(dataframe_size_limit - dataframe.len())).unwrap();
+
+                if dataframe.len() >= dataframe_size_limit {
+                    break 'loading;
                 }
             }
         }
@@ -328,7 +259,7 @@ pub fn run_dataframe_demo(metrics: Registry, run_id: String, token: &str, storag
     total_queries as f32
 }
 
-fn random_query_for_similar_flights(flight: FlightData) -> FlightsQuery {
+fn random_query_for_similar_flights(flight: Vec<FarMemoryValue>) -> FlightsQuery {
     let mut query = FlightsQuery {
         after_date: None,
         origin_airport_id: None,
@@ -336,32 +267,16 @@ fn random_query_for_similar_flights(flight: FlightData) -> FlightsQuery {
     };
 
     if rand::thread_rng().gen_bool(0.7) {
-        query.after_date = Some(flight.flight_date);
+        query.after_date = flight[0].as_date();
     }
 
     if rand::thread_rng().gen_bool(0.7) {
-        query.origin_airport_id = Some(flight.origin_airport_id);
+        query.origin_airport_id = flight[1].as_u32();
     }
 
     if rand::thread_rng().gen_bool(0.7) {
-        query.airline_code = Some(flight.dot_id_operating_airline);
+        query.airline_code = flight[2].as_u32();
     }
 
     query
 }
-
-fn parse_bool(s: &str) -> bool {
-    let s = s.to_lowercase();
-    match s.parse() {
-        Ok(v) => v,
-        Err(_) => panic!("failed to parse \"{}\" as bool", s),
-    }
-}
-
-fn parse_option_f32(s: &str) -> Option<f32> {
-    if s.is_empty() {
-        None
-    } else {
-        Some(s.parse().unwrap())
-    }
-}
@@ -14,7 +14,7 @@ use {
             LocalDiskBackend,
             FarMemoryBackend,
             FarMemoryBufferedVec,
-            FarMemoryVec,
+            FarMemoryTensor,
             ReplicationBackend,
             ErasureCodingBackend,
             InstrumentedBackend,
@@ -104,7 +104,7 @@ impl Vocab {
     }
 
     fn get_token(&self, idx: usize) -> String {
-        let (st, en) = (self.offsets.get(idx), self.offsets.get(idx + 1));
+        let (st, en) = (self.offsets.get(idx).unwrap(), self.offsets.get(idx + 1).unwrap());
         let b = self.bytes.slice(st..en);
         String::from_utf8(b).unwrap()
     }
@@ -112,31 +112,37 @@ impl Vocab {
 
 struct LlamaWeights<Layer> {
     /// (vocab_size, dim)
-    // embeddings: Emb,
-    embeddings_far: FarMemoryVec<Ty>,
+    embeddings: FarMemoryTensor<Ty>,
 
     layers: Vec<Layer>,
     /// (dim,)
-    rms_final: FarMemoryVec<Ty>,
+    rms_final: FarMemoryTensor<Ty>,
     /// (seq_len, head_size/2)
-    rope_real: FarMemoryVec<Ty>,
+    rope_real: FarMemoryTensor<Ty>,
     /// (seq_len, head_size/2)
-    rope_imag: FarMemoryVec<Ty>,
-    wcls: Option<FarMemoryVec<Ty>>,
+    rope_imag: FarMemoryTensor<Ty>,
+    wcls: Option<FarMemoryTensor<Ty>>,
 }
 
 struct LayerWeights<Buf> {
-    // rms_attn: Rms,
-    rms_attn: FarMemoryVec<Ty>,
-
-    rms_ffn: FarMemoryVec<Ty>,
-    wq: FarMemoryVec<Ty>,
-    wk: FarMemoryVec<Ty>,
-    wv: FarMemoryVec<Ty>,
-    wo: FarMemoryVec<Ty>,
-    w1: FarMemoryVec<Ty>,
-    w2: FarMemoryVec<Ty>,
-    w3: FarMemoryVec<Ty>,
+    /// (dim,)
+    rms_attn: FarMemoryTensor<Ty>,
+    /// (dim,)
+    rms_ffn: FarMemoryTensor<Ty>,
+    /// (dim, dim)
+    wq: FarMemoryTensor<Ty>,
+    /// (dim, dim)
+    wk: FarMemoryTensor<Ty>,
+    /// (dim, dim)
+    wv: FarMemoryTensor<Ty>,
+    /// (dim, dim)
+    wo: FarMemoryTensor<Ty>,
+    /// (hidden_dim, dim)
+    w1: FarMemoryTensor<Ty>,
+    /// (dim, hidden_dim)
+    w2: FarMemoryTensor<Ty>,
+    /// (hidden_dim, dim)
+    w3: FarMemoryTensor<Ty>,
     /// (seq_len, dim)
     k_cache: Buf,
     /// (seq_len, dim)
@@ -149,46 +155,32 @@ type Llama2CPUFloat = LlamaWeights<CPULayerFloat>;
 
 impl Llama2CPUFloat {
     fn load_weights(client: FarMemoryClient, cfg: &Config, path: &str) -> Self {
-        let (weights, wcls) = load_raw_karpathy(cfg, path);
-        let embeddings = weights[0].clone();
-
-        // Go over all layered weights, and make layer chunk out of them
-        let mut w_layer_iters = weights[1..10]
-            .iter()
-            .map(|v| {
-                let csize = v.len() / cfg.n_layers;
-                v.chunks(csize).map(|l| l.to_vec())
-            })
-            .collect::<Vec<_>>();
+        let mut weights = load_karpathy(client, cfg, path);
 
         let layers = (0..cfg.n_layers)
-            .map(|l| LayerWeights::<Vec<Ty>> {
-                rms_attn: client.vec(w_layer_iters[0].next().unwrap()),
-                wq: client.vec(w_layer_iters[1].next().unwrap()),
-                wk: client.vec(w_layer_iters[2].next().unwrap()),
-                wv: client.vec(w_layer_iters[3].next().unwrap()),
-                wo: client.vec(w_layer_iters[4].next().unwrap()),
-                rms_ffn: client.vec(w_layer_iters[5].next().unwrap()),
-                w1: client.vec(w_layer_iters[6].next().unwrap()),
-                w2: client.vec(w_layer_iters[7].next().unwrap()),
-                w3: client.vec(w_layer_iters[8].next().unwrap()),
+            .map(|_| LayerWeights::<Vec<Ty>> {
+                rms_attn: weights.rms_attn.remove(0),
+                wq: weights.wq.remove(0),
+                wk: weights.wk.remove(0),
+                wv: weights.wv.remove(0),
+                wo: weights.wo.remove(0),
+                rms_ffn: weights.rms_ffn.remove(0),
+                w1: weights.w1.remove(0),
+                w2: weights.w2.remove(0),
+                w3: weights.w3.remove(0),
                 k_cache: vec![0 as Ty; cfg.seq_len * cfg.dim],
                 v_cache: vec![0 as Ty; cfg.seq_len * cfg.dim],
             })
             .collect();
 
-        let rms_final = client.vec(weights[10].clone());
-        let rope_real = client.vec(weights[11].clone());
-        let rope_imag = client.vec(weights[12].clone());
-
         Self {
-            embeddings_far: client.vec(embeddings),
+            embeddings: weights.embeddings,
 
             layers,
-            rms_final,
-            rope_real,
-            rope_imag,
-            wcls: wcls.map(|v| client.vec(v)),
+            rms_final: weights.rms_final,
+            rope_real: weights.rope_real,
+            rope_imag: weights.rope_imag,
+            wcls: weights.wcls,
         }
     }
 }
@@ -244,37 +236,33 @@ impl <T: DefaultBuffer> ExecutionState<T> {
     }
 }
 
-impl EmbeddingTable<Vec<Ty>> for Vec<Ty> {
-    fn token_to_resid_stream(&self, pos: usize, dst: &mut Vec<Ty>, cfg: &Config) {
-        let dim = dst.len();
-        self.chunks_exact(dim)
-            .skip(pos)
-            .take(1)
-            .for_each(|src| dst.as_mut_slice().copy_from_slice(src));
+impl EmbeddingTable<Vec<Ty>> for FarMemoryTensor<Ty> {
+    fn token_to_resid_stream(&self, pos: usize, dst: &mut Vec<Ty>, _cfg: &Config) {
+        dst.as_mut_slice().copy_from_slice(&self.row(pos));
     }
 }
 
-impl LinearWeight<Vec<Ty>> for Vec<Ty> {
+impl LinearWeight<Vec<Ty>> for FarMemoryTensor<Ty> {
     fn mat_vec(&self, vec: &Vec<Ty>, dst: &mut Vec<Ty>) {
-        matmul(dst, vec, &self); // in_dim is inferred from x. need to remove from this function dig
+        FarMemoryTensor::mat_vec(self, vec, dst); // weights are streamed span by span
     }
 }
 
-impl RMSNormWeight<Vec<Ty>> for Vec<Ty> {
+impl RMSNormWeight<Vec<Ty>> for FarMemoryTensor<Ty> {
     fn rms_norm(&self, vec: &Vec<Ty>, out: &mut Vec<Ty>) {
         let inv_denom = _norm_const(vec);
 
-        let w_it = self.iter();
-        let normed = vec.iter().zip(w_it).map(|(xx, ww)| xx * ww * inv_denom);
+        let w = self.view_rows(0..self.rows());
+        let normed = vec.iter().zip(w.iter()).map(|(xx, ww)| xx * ww * inv_denom);
         out.iter_mut().zip(normed).for_each(|(dst, src)| *dst = src);
     }
 
     fn inplace_rms_norm(&self, vec: &mut Vec<Ty>) {
         let inv_denom = _norm_const(vec);
 
-        let w_it = self.iter();
+        let w = self.view_rows(0..self.rows());
         vec.iter_mut()
-            .zip(w_it)
+            .zip(w.iter())
             .for_each(|(dst, w)| (*dst) *= inv_denom * w);
     }
 }
@@ -288,8 +276,8 @@ pub trait LlamaExecuter<Buffer> {
 pub trait LlamaLayer<Buffer> {
     /// RMS norm residual stream and get Q,K,V matrices
     fn rms_and_qkv(&self, config: &Config, state: &mut ExecutionState<Buffer>);
-    /// Rotate q and k heads according to position in seq (RoPE)
-    fn rope(&self, pos: usize, cfg: &Config, state: &mut ExecutionState<Buffer>, rope_imag: &Buffer, rope_real: &Buffer);
+    /// Rotate q and k heads according to position in seq (RoPE), using rope rows for this position
+    fn rope(&self, cfg: &Config, state: &mut ExecutionState<Buffer>, rope_imag: &[Ty], rope_real: &[Ty]);
     /// Cache sequence of Q, K (to be used for attention computation)
     fn cache_kv(&mut self, pos: usize, cfg: &Config, state: &ExecutionState<Buffer>);
     /// (per head) Calculate Attention weights, accumulate value according to weights
@@ -322,24 +310,24 @@ where
 {
     fn step(&mut self, token: usize, pos: usize, cfg: &Config, state: &mut ExecutionState<Vec<Ty>>) {
         // copy token embedding to residual stream
-        self.embeddings_far.to_local_vec().token_to_resid_stream(token, &mut state.x, cfg);
+        self.embeddings.token_to_resid_stream(token, &mut state.x, cfg);
 
         for ld in self.layers.iter_mut() {
             ld.rms_and_qkv(cfg, state);
-            ld.rope(pos, cfg, state, &self.rope_imag.to_local_vec(), &self.rope_real.to_local_vec());
+            ld.rope(cfg, state, &self.rope_imag.row(pos), &self.rope_real.row(pos));
             ld.cache_kv(pos, cfg, state);
             ld.attention(pos, cfg, state);
             ld.merge_heads_to_resid_stream(state);
             ld.ffn(state);
         }
 
-        self.rms_final.to_local_vec().inplace_rms_norm(&mut state.x);
+        self.rms_final.inplace_rms_norm(&mut state.x);
 
         if self.wcls.is_none() {
-            self.embeddings_far.to_local_vec().mat_vec(&state.x, &mut state.logits);
+            self.embeddings.mat_vec(&state.x, &mut state.logits);
         } else {
             let w = self.wcls.as_ref().unwrap();
-            w.to_local_vec().mat_vec(&state.x, &mut state.logits);
+            w.mat_vec(&state.x, &mut state.logits);
         }
     }
 }
@@ -348,21 +336,21 @@ where
 impl LlamaLayer<Vec<Ty>> for LayerWeights<Vec<Ty>>
 {
     fn rms_and_qkv(&self, config: &Config, state: &mut ExecutionState<Vec<Ty>>) {
-        self.rms_attn.to_local_vec().rms_norm(&state.x, &mut state.xb);
-        self.wq.to_local_vec().mat_vec(&state.xb, &mut state.q);
-        self.wk.to_local_vec().mat_vec(&state.xb, &mut state.k);
-        self.wv.to_local_vec().mat_vec(&state.xb, &mut state.v);
+        self.rms_attn.rms_norm(&state.x, &mut state.xb);
+        self.wq.mat_vec(&state.xb, &mut state.q);
+        self.wk.mat_vec(&state.xb, &mut state.k);
+        self.wv.mat_vec(&state.xb, &mut state.v);
     }
 
-    fn rope(&self, pos: usize, cfg: &Config, state: &mut ExecutionState<Vec<Ty>>, rope_imag: &Vec<Ty>, rope_real: &Vec<Ty>) {
+    fn rope(&self, cfg: &Config, state: &mut ExecutionState<Vec<Ty>>, rope_imag: &[Ty], rope_real: &[Ty]) {
         let head_size = cfg.dim / cfg.n_heads;
 
         let q_heads = state.q.chunks_exact_mut(head_size);
         let k_heads  = state.k.chunks_exact_mut(head_size);
 
         for (q, k) in q_heads.zip(k_heads) {
-            let mut re = rope_real[pos * head_size / 2..].iter().take(head_size / 2);
-            let mut im = rope_imag[pos * head_size / 2..].iter().take(head_size / 2);
+            let mut re = rope_real.iter().take(head_size / 2);
+            let mut im = rope_imag.iter().take(head_size / 2);
 
             for (qq, kk) in q.chunks_exact_mut(2).zip(k.chunks_exact_mut(2)) {
                 let (q0, q1) = (qq[0], qq[1]);
@@ -429,7 +417,7 @@ impl LlamaLayer<Vec<Ty>> for LayerWeights<Vec<Ty>>
         // merge heads
         // at this point result of all heads is in x[1],
         // Linearly merge all heads into a new buffer x[2]
-        self.wo.to_local_vec().mat_vec(&state.xb, &mut state.xb2);
+        self.wo.mat_vec(&state.xb, &mut state.xb2);
 
         // add attention result to residual stream
         state
@@ -441,13 +429,13 @@ impl LlamaLayer<Vec<Ty>> for LayerWeights<Vec<Ty>>
 
     fn ffn(&self, state: &mut ExecutionState<Vec<Ty>>) {
         // normalize redisual stream before FFN
-        self.rms_ffn.to_local_vec().rms_norm(&state.x, &mut state.xb);
+        self.rms_ffn.rms_norm(&state.x, &mut state.xb);
 
         // FFN:
         // z = SiLU(W1 \dot x) * (W3 \dot x)
         // out = (W2 \dot z)
-        self.w1.to_local_vec().mat_vec(&state.xb, &mut state.h1);
-        self.w3.to_local_vec().mat_vec(&state.xb, &mut state.h2);
+        self.w1.mat_vec(&state.xb, &mut state.h1);
+        self.w3.mat_vec(&state.xb, &mut state.h2);
 
         // silu hidden
         for h1 in state.h1.iter_mut() {
@@ -460,7 +448,7 @@ impl LlamaLayer<Vec<Ty>> for LayerWeights<Vec<Ty>>
         for (h1, &h2) in state.h1.iter_mut().zip(state.h2.iter()) {
             *h1 *= h2;
         }
-        self.w2.to_local_vec().mat_vec(&state.h1, &mut state.xb);
+        self.w2.mat_vec(&state.h1, &mut state.xb);
 
         // add FFN result to residual stream
         state
@@ -471,42 +459,47 @@ impl LlamaLayer<Vec<Ty>> for LayerWeights<Vec<Ty>>
     }
 }
 
-/// Load raw weights from Karpathy's models
-fn load_raw_karpathy(cfg: &Config, path: &str) -> ([Vec<Ty>; 13], Option<Vec<Ty>>) {
-    let mut model_bin = File::open(path).unwrap();
+struct KarpathyWeights {
+    embeddings: FarMemoryTensor<Ty>,
+    rms_attn: Vec<FarMemoryTensor<Ty>>,
+    wq: Vec<FarMemoryTensor<Ty>>,
+    wk: Vec<FarMemoryTensor<Ty>>,
+    wv: Vec<FarMemoryTensor<Ty>>,
+    wo: Vec<FarMemoryTensor<Ty>>,
+    rms_ffn: Vec<FarMemoryTensor<Ty>>,
+    w1: Vec<FarMemoryTensor<Ty>>,
+    w2: Vec<FarMemoryTensor<Ty>>,
+    w3: Vec<FarMemoryTensor<Ty>>,
+    rms_final: FarMemoryTensor<Ty>,
+    rope_real: FarMemoryTensor<Ty>,
+    rope_imag: FarMemoryTensor<Ty>,
+    wcls: Option<FarMemoryTensor<Ty>>,
+}
+
+/// Load weights from Karpathy's models directly into far memory tensors (one tensor per layer for layered weights)
+fn load_karpathy(client: FarMemoryClient, cfg: &Config, path: &str) -> KarpathyWeights {
+    let mut model_bin = io::BufReader::new(File::open(path).unwrap());
 
     model_bin.seek(SeekFrom::Start(CONF_SIZE as u64)).unwrap();
 
-    let mut f = |s: usize| _alloc_and_read(&mut model_bin, s);
+    let mut f = |shape: &[usize]| FarMemoryTensor::<Ty>::from_reader(client.clone(), shape, &mut model_bin).unwrap();
     let head_size = cfg.dim / cfg.n_heads;
-    (
-        [
-            f(cfg.vocab_size * cfg.dim),
-            f(cfg.n_layers * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.dim),
-            f(cfg.n_layers * cfg.dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.n_layers * cfg.dim * cfg.hidden_dim),
-            f(cfg.dim),
-            f(cfg.seq_len * (head_size / 2)),
-            f(cfg.seq_len * (head_size / 2)),
-        ],
-        cfg.shared_weights.then(|| f(cfg.vocab_size * cfg.dim)),
-    )
-}
 
-fn _alloc_and_read(file: &mut File, size: usize) -> Vec<Ty> {
-    let bytes_to_read = size * std::mem::size_of::<Ty>();
-    let mut raw_w_data = vec![0; bytes_to_read];
-    file.read_exact(&mut raw_w_data).unwrap();
-    unsafe {
-        let float_ptr = raw_w_data.as_ptr() as *const Ty;
-        let data = std::slice::from_raw_parts(float_ptr, size);
-        data.to_vec()
+    KarpathyWeights {
+        embeddings: f(&[cfg.vocab_size, cfg.dim]),
+        rms_attn: (0..cfg.n_layers).map(|_| f(&[cfg.dim])).collect(),
+        wq: (0..cfg.n_layers).map(|_| f(&[cfg.dim, cfg.dim])).collect(),
+        wk: (0..cfg.n_layers).map(|_| f(&[cfg.dim, cfg.dim])).collect(),
+        wv: (0..cfg.n_layers).map(|_| f(&[cfg.dim, cfg.dim])).collect(),
+        wo: (0..cfg.n_layers).map(|_| f(&[cfg.dim, cfg.dim])).collect(),
+        rms_ffn: (0..cfg.n_layers).map(|_| f(&[cfg.dim])).collect(),
+        w1: (0..cfg.n_layers).map(|_| f(&[cfg.hidden_dim, cfg.dim])).collect(),
+        w2: (0..cfg.n_layers).map(|_| f(&[cfg.dim, cfg.hidden_dim])).collect(),
+        w3: (0..cfg.n_layers).map(|_| f(&[cfg.hidden_dim, cfg.dim])).collect(),
+        rms_final: f(&[cfg.dim]),
+        rope_real: f(&[cfg.seq_len, head_size / 2]),
+        rope_imag: f(&[cfg.seq_len, head_size / 2]),
+        wcls: cfg.shared_weights.then(|| f(&[cfg.vocab_size, cfg.dim])),
     }
 }
 
@@ -542,16 +535,6 @@ fn cdf_sample(probs: &[Ty]) -> usize {
     probs.len() - 1
 }
 
-fn matmul(out: &mut [Ty], x: &[Ty], w: &[Ty]) {
-    let stride = x.len();
-    for (row, out_elem) in w.chunks_exact(stride).zip(out.iter_mut()) {
-        *out_elem = row
-            .iter()
-            .zip(x.iter())
-            .fold(0 as Ty, |acc, (&_w, &_x)| acc + _w * _x);
-    }
-}
-
 /// We can safely borrow disjoin parts of slices, but its really hard for the borrow checker to know that this is safe
 unsafe fn _unchecked_mut_slice(s: &[Ty], offset: usize, size: usize) -> &mut [Ty] {
     let ptr: *mut f32 = s.as_ptr() as *mut Ty;
@@ -5,7 +5,6 @@ use {
     rand_distr::Zipf,
     aes_gcm::{aead::{KeyInit, Aead, AeadCore}, Aes256Gcm},
     prometheus::Registry,
-    serde::{Serialize, Deserialize},
     indicatif::ProgressIterator,
     crate::{
         client::{
@@ -18,8 +17,9 @@ use {
             InstrumentedBackend,
             PreferRemoteSpansReplacementPolicy,
             LeastRecentlyUsedReplacementPolicy,
-            FarMemorySerialized,
             FarMemoryHashMap,
+            FarMemoryBlobStore,
+            BlobId,
         },
         manager::ManagerClient,
     },
@@ -29,16 +29,18 @@ const PICTURE_SIZE: usize = 8 * 1024;
 
 struct DemoWebService {
     users: FarMemoryHashMap<UserId, PictureId>,
-    pictures: Vec<FarMemorySerialized<Picture>>,
+    pictures: FarMemoryBlobStore,
+    picture_blobs: Vec<BlobId>,
 
     cipher: Aes256Gcm,
 }
 
 impl DemoWebService {
-    pub fn new(users: FarMemoryHashMap<UserId, PictureId>, pictures: Vec<FarMemorySerialized<Picture>>) -> Self {
+    pub fn new(users: FarMemoryHashMap<UserId, PictureId>, pictures: FarMemoryBlobStore, picture_blobs: Vec<BlobId>) -> Self {
         Self {
             users,
             pictures,
+            picture_blobs,
 
             cipher: Aes256Gcm::new(&Aes256Gcm::generate_key(OsRng)), // AIFM uses AES-CBC, but it doesn't matter for evaluation performance.
         }
@@ -53,8 +55,8 @@ impl DemoWebService {
             .choose(&mut rand::thread_rng())
             .unwrap();
 
-        let picture = &self.pictures.get(picture_to_get as usize).unwrap().to_local().picture_data;
-        let encrypted_picture = self.encrypt_picture(picture);
+        let picture = self.pictures.get(&self.picture_blobs[picture_to_get as usize]).unwrap();
+        let encrypted_picture = self.encrypt_picture(&picture);
 
         // yes, encrpyted data cannot be compressed, but it still a good way to simulate CPU load. AIFM does the same for their evaluation.
         let compressed_picture = self.compress_picture(&encrypted_picture);
@@ -82,7 +84,7 @@ impl DemoWebService {
     }
 }
 
-#[derive(Eq, PartialEq, Hash, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Copy, Debug)]
 struct UserId {
     id: u64,
 }
@@ -95,7 +97,7 @@ impl UserId {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 struct PictureId {
     picture_id: u64,
 }
@@ -108,7 +110,7 @@ impl PictureId {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 struct Picture {
     picture_data: Vec<u8>,
 }
@@ -206,13 +208,14 @@ pub fn run_web_service_demo(metrics: Registry, run_id: String, token: &str, stor
     let pictures = generate_pictures(total_pictures);
     println!("finished generating pictures");
 
-    let pictures: Vec<_> = pictures.into_iter().map(|v| FarMemorySerialized::from_value(client.clone(), v)).collect();
+    let picture_store = FarMemoryBlobStore::new(client.clone());
+    let picture_blobs: Vec<_> = pictures.into_iter().map(|v| picture_store.put(&v.picture_data)).collect();
     println!("finished moving pictures to far memory");
 
-    let total_users = pictures.len() * 64; // ratio as in AIFM evaluation (2M pictures vs 128M users).
+    let total_users = picture_blobs.len() * 64; // ratio as in AIFM evaluation (2M pictures vs 128M users).
 
     // users table is more light compared to AIFM evaluation: approx. 2GB vs 10GB.
-    let users = generate_users(total_users, pictures.len(), zipf_s);
+    let users = generate_users(total_users, picture_blobs.len(), zipf_s);
     println!("finished generating users");
 
     let mut far_memory_users = FarMemoryHashMap::new(client.clone(), (total_users as f32 * 0.75) as usize);
@@ -223,7 +226,7 @@ pub fn run_web_service_demo(metrics: Registry, run_id: String, token: &str, stor
 
     println!("total memory, local: {}MB, remote: {}MB", client.total_local_memory() / (1024 * 1024), client.total_remote_memory() / (1024 * 1024));
 
-    let web_service = DemoWebService::new(far_memory_users, pictures);
+    let web_service = DemoWebService::new(far_memory_users, picture_store, picture_blobs);
 
     let mut total_requests = 0;
     let started_at = Instant::now();
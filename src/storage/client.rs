@@ -1,62 +1,151 @@
 use {
-    std::{thread, time::Duration, io::{Write, Read}, sync::atomic::{AtomicU64, Ordering}},
-    tracing::{span, Level},
-    tokio::{net::{TcpStream, TcpSocket}, io::{AsyncReadExt, AsyncWriteExt}},
-    super::{BUFFER_SIZE, protocol::{StorageRequest, StorageRequestBody, StorageResponse, SpanData, SwapOutRequest}},
+    std::{time::Duration, collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}}},
+    tracing::{span, error, warn, Level, Instrument},
+    async_recursion::async_recursion,
+    tokio::{
+        net::{TcpStream, TcpSocket, tcp::{OwnedReadHalf, OwnedWriteHalf}},
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+        task::JoinHandle,
+        time::timeout,
+    },
+    prometheus::{Registry, IntCounterVec, IntGaugeVec, register_int_counter_vec_with_registry, register_int_gauge_vec_with_registry},
+    thiserror::Error,
+    super::{BUFFER_SIZE, protocol::{StorageRequest, StorageRequestBody, StorageResponse, StorageResponseBody, SpanData, SwapOutRequest}},
 };
 
+const DEFAULT_MAX_CONNECT_ATTEMPTS: u32 = 10;
+
+/**
+ * Client for storage server. Requests are pipelined: any number of them can be in flight at the same time, and
+ * responses are matched to requests by request id in a background task reading from the connection.
+ *
+ * When connection fails or request times out, client reconnects with exponential backoff, authenticates and sets run
+ * id again. Requests that are safe to repeat (everything except prepends) are then retried. If storage cannot be
+ * reached after `max_connect_attempts`, client gives up: pending and following requests fail, so that callers can
+ * fail over to other storage nodes.
+ */
 pub struct Client {
-    stream: TcpStream,
+    addr: String,
+    config: ClientConfig,
+
+    // replaced on reconnect. Lock is held while reconnecting, so that concurrent requests wait for new connection.
+    connection: tokio::sync::Mutex<Arc<Connection>>,
+    session: Mutex<Session>,
     request_id: AtomicU64,
+    // set when reconnecting gave up.
+    is_unreachable: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct ClientConfig {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+
+    max_retries: u32,
+    max_connect_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+
+    metrics: Option<ClientMetrics>,
+}
+
+// shared by all clients with the same config, so that connection pools do not register metrics more than once.
+#[derive(Clone)]
+pub struct ClientMetrics {
+    registry: Registry,
+
+    connections: IntGaugeVec,
+    connects: IntCounterVec,
+    connect_failures: IntCounterVec,
+    disconnects: IntCounterVec,
+    request_timeouts: IntCounterVec,
+    retries: IntCounterVec,
+}
+
+// replayed after reconnect.
+struct Session {
+    token: Option<String>,
+    run_id: Option<String>,
+}
+
+struct Connection {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    pending: Arc<Mutex<PendingResponses>>,
+    reader: JoinHandle<()>,
+
+    addr: String,
+    metrics: Option<ClientMetrics>,
+}
+
+struct PendingResponses {
+    senders: HashMap<u64, oneshot::Sender<StorageResponseBody>>,
+    is_closed: bool,
+}
+
+#[derive(Error, Debug)]
+enum RequestError {
+    #[error("failed to write request: {0}")]
+    Write(std::io::Error),
+    #[error("request timed out")]
+    Timeout,
+    #[error("connection closed before response was received")]
+    ConnectionClosed,
 }
 
 impl Client {
     pub async fn new(addr: &str) -> Self {
-        let socket = TcpSocket::new_v4().unwrap();
-        socket.set_recv_buffer_size(BUFFER_SIZE).unwrap();
-        socket.set_send_buffer_size(BUFFER_SIZE).unwrap();
+        Self::connect(addr, ClientConfig::default()).await
+    }
 
-        let mut stream = socket.connect(addr.parse().unwrap()).await;
-        while !stream.is_ok() {
-            eprintln!("connection failed: {:?}", stream.err().unwrap());
-            thread::sleep(Duration::from_secs(1));
-            stream = TcpStream::connect(addr).await;
-        }
-        let stream = stream.unwrap();
-        stream.set_nodelay(true).unwrap();
+    pub async fn connect(addr: &str, config: ClientConfig) -> Self {
+        let connection = Connection::connect(addr, &config).await.unwrap_or_else(|err| panic!("{}", err));
 
         Self {
-            stream,
+            addr: addr.to_owned(),
+            config,
+
+            connection: tokio::sync::Mutex::new(Arc::new(connection)),
+            session: Mutex::new(Session {
+                token: None,
+                run_id: None,
+            }),
             request_id: AtomicU64::new(0),
+            is_unreachable: AtomicBool::new(false),
         }
     }
 
-    pub async fn auth(&mut self, token: &str) {
+    pub async fn auth(&self, token: &str) {
+        self.session.lock().unwrap().token = Some(token.to_owned());
+
         match self.request(StorageRequestBody::Auth {
             token: token.to_owned(),
         }).await {
-            StorageResponse::Ok => (),
+            StorageResponseBody::Ok => (),
             other => panic!("unexpected auth response: {:?}", other),
         }
     }
 
-    pub async fn set_run_id(&mut self, run_id: String) {
+    pub async fn set_run_id(&self, run_id: String) {
+        self.session.lock().unwrap().run_id = Some(run_id.clone());
+
         match self.request(StorageRequestBody::SetRunId {
             run_id,
         }).await {
-            StorageResponse::Ok => (),
+            StorageResponseBody::Ok => (),
             other => panic!("unexpected set run id response: {:?}", other),
         }
     }
 
-    pub async fn swap_out(&mut self, span_id: u64, data: Vec<u8>, prepend: bool) {
+    pub async fn swap_out(&self, span_id: u64, data: Vec<u8>, prepend: bool) {
         match self.request(StorageRequestBody::SwapOut(SwapOutRequest { span_id, prepend, data: SpanData::Inline(data) })).await {
-            StorageResponse::Ok => (),
+            StorageResponseBody::Ok => (),
             other => panic!("unexpected swap out response: {:?}", other),
         }
     }
 
-    pub async fn batch(&mut self, swap_out: Vec<BatchSwapOutOperation>, swap_in: Option<u64>) -> Option<Vec<u8>> {
+    pub async fn batch(&self, swap_out: Vec<BatchSwapOutOperation>, swap_in: Option<u64>) -> Option<Vec<u8>> {
         let mut reqs: Vec<_> = swap_out.iter().map(|v| StorageRequestBody::SwapOut(SwapOutRequest {
             span_id: v.span_id,
             prepend: v.prepend,
@@ -72,20 +161,10 @@ impl Client {
         let mut swap_in_result = None;
 
         match self.request_with_external_span_data(req, local_span_data).await {
-            StorageResponse::Batch(responses) => for res in responses {
+            StorageResponseBody::Batch(responses) => for res in responses {
                 match res {
-                    StorageResponse::Ok => (),
-                    StorageResponse::SwapIn { span_id: _, data } => swap_in_result = Some(match data {
-                       SpanData::Inline(data) => data,
-                       SpanData::Concat { data } => data.concat(),
-                       SpanData::External { len } => {
-                           let reading_span = span!(Level::DEBUG, "reading span body", len);
-                           let _reading_span_guard = reading_span.enter();
-                           let mut data = vec![0u8; len as usize];
-                           self.stream.read_exact(&mut data).await.unwrap();
-                           data
-                        },
-                    }),
+                    StorageResponseBody::Ok => (),
+                    StorageResponseBody::SwapIn { span_id: _, data } => swap_in_result = Some(span_data_into_vec(data)),
                     other => panic!("unexpected one of batch swap out responses: {:?}", other),
                 }
             },
@@ -95,105 +174,482 @@ impl Client {
         swap_in_result
     }
 
-    pub async fn swap_in(&mut self, span_id: u64) -> Vec<u8> {
-        let data = match self.request(StorageRequestBody::SwapIn { span_id }).await {
-            StorageResponse::SwapIn { span_id: _, data } => data,
+    pub async fn swap_in(&self, span_id: u64) -> Vec<u8> {
+        match self.request(StorageRequestBody::SwapIn { span_id }).await {
+            StorageResponseBody::SwapIn { span_id: _, data } => span_data_into_vec(data),
             other => panic!("unexpected swap in response: {:?}", other),
+        }
+    }
+
+    pub async fn read(&self, span_id: u64) -> Vec<u8> {
+        match self.request(StorageRequestBody::Read { span_id }).await {
+            StorageResponseBody::SwapIn { span_id: _, data } => span_data_into_vec(data),
+            other => panic!("unexpected read response: {:?}", other),
+        }
+    }
+
+    pub async fn remove(&self, span_id: u64) {
+        match self.request(StorageRequestBody::Remove { span_id }).await {
+            StorageResponseBody::Ok => {},
+            other => panic!("unexpected remove response: {:?}", other),
+        }
+    }
+
+    async fn request(&self, request: StorageRequestBody) -> StorageResponseBody {
+        let mut span_data = Vec::new();
+        let body = extract_span_data_from_request(request, &mut span_data);
+        self.request_with_external_span_data(body, span_data).await
+    }
+
+    async fn request_with_external_span_data(&self, body: StorageRequestBody, span_data: Vec<LocalSpanData>) -> StorageResponseBody {
+        let is_retryable = is_retryable(&body);
+        let request = StorageRequest { body, request_id: self.next_request_id() };
+
+        let mut attempt = 0;
+        let response = loop {
+            if self.is_unreachable.load(Ordering::Relaxed) {
+                panic!("storage at {} is unreachable", self.addr);
+            }
+
+            let connection = self.connection.lock().await.clone();
+            let err = match connection.request(&request, &span_data, &self.config).await {
+                Ok(v) => break v,
+                Err(err) => err,
+            };
+
+            if let Some(metrics) = &self.config.metrics {
+                if let RequestError::Timeout = err {
+                    metrics.request_timeouts.with_label_values(&[&self.addr]).inc();
+                }
+            }
+            warn!("request {} to storage at {} failed: {}", request.request_id, self.addr, err);
+
+            // reconnecting even if request is not retried, so that following requests succeed.
+            self.reconnect(&connection).await;
+
+            if !is_retryable || attempt >= self.config.max_retries {
+                panic!("request to storage at {} failed: {}", self.addr, err);
+            }
+
+            attempt += 1;
+            if let Some(metrics) = &self.config.metrics {
+                metrics.retries.with_label_values(&[&self.addr]).inc();
+            }
         };
 
-        match data {
-            SpanData::Inline(data) => data,
-            SpanData::Concat { data } => data.concat(),
-            SpanData::External { len } => {
-                let reading_span = span!(Level::DEBUG, "reading span body", len);
-                let _reading_span_scope = reading_span.enter();
-                let mut data = vec![0u8; len as usize];
-                self.stream.read_exact(&mut data).await.unwrap();
-                data
-            },
+        span!(Level::DEBUG, "dropping local span data").in_scope(|| drop(span_data));
+
+        response
+    }
+
+    async fn reconnect(&self, failed_connection: &Arc<Connection>) {
+        let mut connection = self.connection.lock().await;
+        if !Arc::ptr_eq(&connection, failed_connection) || self.is_unreachable.load(Ordering::Relaxed) {
+            // already reconnected by another request, or gave up.
+            return;
+        }
+
+        failed_connection.close();
+        if let Some(metrics) = &self.config.metrics {
+            metrics.disconnects.with_label_values(&[&self.addr]).inc();
+        }
+
+        match self.restore_session().instrument(span!(Level::DEBUG, "reconnecting")).await {
+            Ok(v) => *connection = Arc::new(v),
+            Err(err) => {
+                error!("giving up on storage at {}: {}", self.addr, err);
+                self.is_unreachable.store(true, Ordering::Relaxed);
+            }
         }
     }
 
-    async fn request(&mut self, request: StorageRequestBody) -> StorageResponse {
-        let request_id = self.next_request_id();
+    async fn restore_session(&self) -> Result<Connection, String> {
+        let (token, run_id) = {
+            let session = self.session.lock().unwrap();
+            (session.token.clone(), session.run_id.clone())
+        };
 
-        let writing_request_span = span!(Level::DEBUG, "writing request", request_id);
-        let writing_request_guard = writing_request_span.enter();
-        self.write_request(StorageRequest { body: request, request_id }).await;
-        drop(writing_request_guard);
+        let mut backoff = self.config.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            let connection = Connection::connect(&self.addr, &self.config).await?;
 
-        let reading_response_span = span!(Level::DEBUG, "reading response");
-        let _reading_response_guard = reading_response_span.enter();
-        self.read_response().await
+            let mut requests = Vec::new();
+            if let Some(token) = &token {
+                requests.push(StorageRequestBody::Auth { token: token.clone() });
+            }
+            if let Some(run_id) = &run_id {
+                requests.push(StorageRequestBody::SetRunId { run_id: run_id.clone() });
+            }
+
+            let mut result = Ok(());
+            for body in requests {
+                let request = StorageRequest { body, request_id: self.next_request_id() };
+                result = match connection.request(&request, &[], &self.config).await {
+                    Ok(StorageResponseBody::Ok) => Ok(()),
+                    Ok(other) => panic!("unexpected response when restoring session: {:?}", other),
+                    Err(err) => Err(err),
+                };
+                if result.is_err() {
+                    break;
+                }
+            }
+
+            match result {
+                Ok(()) => return Ok(connection),
+                Err(err) => {
+                    connection.close();
+                    attempts += 1;
+                    if attempts >= self.config.max_connect_attempts {
+                        return Err(format!("failed to restore session with storage at {} after {} attempts: {}", self.addr, attempts, err));
+                    }
+
+                    warn!("failed to restore session with storage at {}: {}", self.addr, err);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                }
+            }
+        }
     }
 
-    async fn request_with_external_span_data(&mut self, body: StorageRequestBody, span_data: Vec<LocalSpanData>) -> StorageResponse {
-        let request_id = self.next_request_id();
+    pub async fn close(&self) {
+        self.connection.lock().await.writer.lock().await.shutdown().await.unwrap();
+    }
 
-        let writing_request_span = span!(Level::DEBUG, "writing request", request_id);
-        let writing_request_guard = writing_request_span.enter();
-        self.write_request_with_external_span_data(StorageRequest { body, request_id }, span_data).await;
-        drop(writing_request_guard);
+    fn next_request_id(&self) -> u64 {
+        self.request_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
 
-        let reading_response_span = span!(Level::DEBUG, "reading response");
-        let _reading_response_guard = reading_response_span.enter();
-        self.read_response().await
+impl ClientConfig {
+    pub fn new() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+
+            max_retries: 3,
+            max_connect_attempts: DEFAULT_MAX_CONNECT_ATTEMPTS,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+
+            metrics: None,
+        }
     }
 
-    async fn write_request(&mut self, mut request: StorageRequest) {
-        let mut span_data = Vec::new();
-        request.body = extract_span_data_from_request(request.body, &mut span_data);
-        self.write_request_with_external_span_data(request, span_data).await
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // time to wait for response after request is written.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
     }
 
-    async fn write_request_with_external_span_data(&mut self, request: StorageRequest, span_data: Vec<LocalSpanData>) {
-        let serialized = span!(Level::DEBUG, "serialize").in_scope(|| bincode::serialize(&request).unwrap());
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    // after that many failed attempts to connect in a row, client gives up and fails requests.
+    pub fn with_max_connect_attempts(mut self, max_connect_attempts: u32) -> Self {
+        assert!(max_connect_attempts > 0, "at least one connect attempt is required");
+        self.max_connect_attempts = max_connect_attempts;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
 
-        let write_header_span = span!(Level::DEBUG, "write header");
-        let write_header_span_guard = write_header_span.enter();
-        self.stream.write_all(&(serialized.len() as u64).to_be_bytes()).await.unwrap();
-        drop(write_header_span_guard);
+    pub fn with_metrics(mut self, metrics: ClientMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
 
-        let write_data_span = span!(Level::DEBUG, "write data");
-        let write_data_span_guard = write_data_span.enter();
-        self.stream.write_all(&serialized).await.unwrap();
-        drop(write_data_span_guard);
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let write_span_data_span = span!(Level::DEBUG, "write span data");
-        let write_span_data_guard = write_span_data_span.enter();
-        for v in span_data.iter() {
-            let writing_to_stream_span = span!(Level::DEBUG, "writing to stream");
-            let _writing_to_stream_guard = writing_to_stream_span.enter();
-            self.stream.write_all(v.as_slice()).await.unwrap();
+impl ClientMetrics {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            registry: registry.clone(),
+
+            connections: register_int_gauge_vec_with_registry!(
+                "storage_client_connections",
+                "open connections to storage",
+                &["endpoint"],
+                registry
+            ).unwrap(),
+            connects: register_int_counter_vec_with_registry!(
+                "storage_client_connects",
+                "successful connections to storage, including reconnects",
+                &["endpoint"],
+                registry
+            ).unwrap(),
+            connect_failures: register_int_counter_vec_with_registry!(
+                "storage_client_connect_failures",
+                "failed attempts to connect to storage",
+                &["endpoint"],
+                registry
+            ).unwrap(),
+            disconnects: register_int_counter_vec_with_registry!(
+                "storage_client_disconnects",
+                "connections to storage that failed and were replaced",
+                &["endpoint"],
+                registry
+            ).unwrap(),
+            request_timeouts: register_int_counter_vec_with_registry!(
+                "storage_client_request_timeouts",
+                "requests to storage that timed out",
+                &["endpoint"],
+                registry
+            ).unwrap(),
+            retries: register_int_counter_vec_with_registry!(
+                "storage_client_retries",
+                "requests to storage that were retried after reconnect",
+                &["endpoint"],
+                registry
+            ).unwrap(),
         }
-        drop(write_span_data_guard);
+    }
 
-        span!(Level::DEBUG, "dropping local span data").in_scope(|| drop(span_data));
+    pub fn unregister(&self) {
+        self.registry.unregister(Box::new(self.connections.clone())).unwrap();
+        self.registry.unregister(Box::new(self.connects.clone())).unwrap();
+        self.registry.unregister(Box::new(self.connect_failures.clone())).unwrap();
+        self.registry.unregister(Box::new(self.disconnects.clone())).unwrap();
+        self.registry.unregister(Box::new(self.request_timeouts.clone())).unwrap();
+        self.registry.unregister(Box::new(self.retries.clone())).unwrap();
     }
+}
+
+impl Connection {
+    async fn connect(addr: &str, config: &ClientConfig) -> Result<Self, String> {
+        let mut backoff = config.initial_backoff;
+        let mut attempts = 0;
+
+        let stream = loop {
+            let err = match timeout(config.connect_timeout, open_stream(addr)).await {
+                Ok(Ok(stream)) => break stream,
+                Ok(Err(err)) => err.to_string(),
+                Err(_) => "connection timed out".to_owned(),
+            };
 
-    async fn read_response(&mut self) -> StorageResponse {
-        let reading_response_header_span = span!(Level::DEBUG, "reading response header");
-        let reading_response_header_guard = reading_response_header_span.enter();
-        let mut res_len: [u8; 8] = [0u8; 8];
-        self.stream.read_exact(&mut res_len).await.unwrap();
-        let res_len = u64::from_be_bytes(res_len);
-        drop(reading_response_header_guard);
+            attempts += 1;
+            if let Some(metrics) = &config.metrics {
+                metrics.connect_failures.with_label_values(&[addr]).inc();
+            }
+            if attempts >= config.max_connect_attempts {
+                return Err(format!("failed to connect to storage at {} after {} attempts: {}", addr, attempts, err));
+            }
 
-        let reading_response_body_span = span!(Level::DEBUG, "reading response body");
-        let reading_response_body_guard = reading_response_body_span.enter();
-        let mut res = vec![0u8; res_len as usize];
-        self.stream.read_exact(&mut res).await.unwrap();
-        drop(reading_response_body_guard);
+            warn!("failed to connect to storage at {}, retrying in {:?}: {}", addr, backoff, err);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(config.max_backoff);
+        };
 
-        span!(Level::DEBUG, "deserialize").in_scope(|| bincode::deserialize(&res).unwrap())
+        if let Some(metrics) = &config.metrics {
+            metrics.connects.with_label_values(&[addr]).inc();
+            metrics.connections.with_label_values(&[addr]).inc();
+        }
+
+        let (reader, writer) = stream.into_split();
+        let pending = Arc::new(Mutex::new(PendingResponses {
+            senders: HashMap::new(),
+            is_closed: false,
+        }));
+
+        Ok(Self {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: pending.clone(),
+            reader: tokio::spawn(read_responses(reader, pending)),
+
+            addr: addr.to_owned(),
+            metrics: config.metrics.clone(),
+        })
     }
 
-    pub async fn close(&mut self) {
-        self.stream.shutdown().await.unwrap();
+    async fn request(&self, request: &StorageRequest, span_data: &[LocalSpanData], config: &ClientConfig) -> Result<StorageResponseBody, RequestError> {
+        let request_id = request.request_id;
+
+        // registered before writing the request, so that reader task does not receive response before that.
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.is_closed {
+                return Err(RequestError::ConnectionClosed);
+            }
+            pending.senders.insert(request_id, sender);
+        }
+
+        let write_result = timeout(config.write_timeout, self.write_request_with_external_span_data(request, span_data))
+            .instrument(span!(Level::DEBUG, "writing request", request_id))
+            .await;
+        // partially written request leaves connection in unknown state, so it is not used anymore.
+        match write_result {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => {
+                self.close();
+                return Err(RequestError::Write(err));
+            },
+            Err(_) => {
+                self.close();
+                return Err(RequestError::Timeout);
+            },
+        }
+
+        match timeout(config.read_timeout, receiver).instrument(span!(Level::DEBUG, "waiting for response", request_id)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(RequestError::ConnectionClosed),
+            Err(_) => {
+                // response may still arrive later, but there is no way to tell if server is alive.
+                self.close();
+                Err(RequestError::Timeout)
+            },
+        }
     }
 
-    fn next_request_id(&self) -> u64 {
-        self.request_id.fetch_add(1, Ordering::Relaxed)
+    async fn write_request_with_external_span_data(&self, request: &StorageRequest, span_data: &[LocalSpanData]) -> std::io::Result<()> {
+        let serialized = span!(Level::DEBUG, "serialize").in_scope(|| bincode::serialize(request).unwrap());
+
+        // requests are written as a whole, so that parts of concurrent requests are not interleaved.
+        let mut writer = self.writer.lock().instrument(span!(Level::DEBUG, "waiting for connection writer")).await;
+
+        writer.write_all(&(serialized.len() as u64).to_be_bytes()).instrument(span!(Level::DEBUG, "write header")).await?;
+        writer.write_all(&serialized).instrument(span!(Level::DEBUG, "write data")).await?;
+
+        async {
+            for v in span_data.iter() {
+                writer.write_all(v.as_slice()).instrument(span!(Level::DEBUG, "writing to stream")).await?;
+            }
+            Ok(())
+        }.instrument(span!(Level::DEBUG, "write span data")).await
+    }
+
+    // fails all requests waiting for response, so that they can be retried on a new connection.
+    fn close(&self) {
+        self.reader.abort();
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.is_closed = true;
+        pending.senders.clear();
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.close();
+
+        if let Some(metrics) = &self.metrics {
+            metrics.connections.with_label_values(&[&self.addr]).dec();
+        }
+    }
+}
+
+async fn open_stream(addr: &str) -> std::io::Result<TcpStream> {
+    let socket = TcpSocket::new_v4()?;
+    socket.set_recv_buffer_size(BUFFER_SIZE)?;
+    socket.set_send_buffer_size(BUFFER_SIZE)?;
+
+    let addr = addr.parse().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let stream = socket.connect(addr).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(stream)
+}
+
+fn is_retryable(request: &StorageRequestBody) -> bool {
+    match request {
+        // prepend could be applied twice if response to it was lost.
+        StorageRequestBody::SwapOut(swap_out) => !swap_out.prepend,
+        StorageRequestBody::Batch(reqs) => reqs.iter().all(is_retryable),
+        // swap ins are idempotent, because server keeps recently swapped in spans. Reads and removes are idempotent too.
+        StorageRequestBody::Auth { .. } | StorageRequestBody::SetRunId { .. } | StorageRequestBody::SwapIn { .. }
+            | StorageRequestBody::Read { .. } | StorageRequestBody::Remove { .. } => true,
+    }
+}
+
+async fn read_responses(mut reader: OwnedReadHalf, pending: Arc<Mutex<PendingResponses>>) {
+    loop {
+        let response = match read_response(&mut reader).instrument(span!(Level::DEBUG, "reading response")).await {
+            Ok(v) => v,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::UnexpectedEof {
+                    error!("failed to read response from storage: {:?}", err);
+                }
+                break;
+            }
+        };
+
+        let sender = pending.lock().unwrap().senders.remove(&response.request_id);
+        match sender {
+            // receiver may be dropped if request future was cancelled.
+            Some(sender) => { let _ = sender.send(response.body); },
+            None => error!("received response for unknown request: {}", response.request_id),
+        }
+    }
+
+    // dropping senders fails all requests that are still waiting for a response.
+    let mut pending = pending.lock().unwrap();
+    pending.is_closed = true;
+    pending.senders.clear();
+}
+
+async fn read_response(reader: &mut OwnedReadHalf) -> std::io::Result<StorageResponse> {
+    let mut res_len: [u8; 8] = [0u8; 8];
+    reader.read_exact(&mut res_len).instrument(span!(Level::DEBUG, "reading response header")).await?;
+    let res_len = u64::from_be_bytes(res_len);
+
+    let mut res = vec![0u8; res_len as usize];
+    reader.read_exact(&mut res).instrument(span!(Level::DEBUG, "reading response body")).await?;
+
+    let mut response: StorageResponse = span!(Level::DEBUG, "deserialize").in_scope(|| bincode::deserialize(&res))
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    // span data follows the response, so it has to be read before the next response.
+    response.body = read_external_span_data(response.body, reader).await?;
+
+    Ok(response)
+}
+
+#[async_recursion]
+async fn read_external_span_data(response: StorageResponseBody, reader: &mut OwnedReadHalf) -> std::io::Result<StorageResponseBody> {
+    Ok(match response {
+        StorageResponseBody::SwapIn { span_id, data: SpanData::External { len } } => {
+            let mut data = vec![0u8; len as usize];
+            reader.read_exact(&mut data).instrument(span!(Level::DEBUG, "reading span body", len)).await?;
+            StorageResponseBody::SwapIn { span_id, data: SpanData::Inline(data) }
+        },
+        StorageResponseBody::Batch(responses) => {
+            let mut result = Vec::new();
+            for response in responses {
+                result.push(read_external_span_data(response, reader).await?);
+            }
+            StorageResponseBody::Batch(result)
+        },
+        other => other,
+    })
+}
+
+fn span_data_into_vec(data: SpanData) -> Vec<u8> {
+    match data {
+        SpanData::Inline(data) => data,
+        SpanData::Concat { data } => data.concat(),
+        SpanData::Shared(data) => data.concat(),
+        SpanData::External { .. } => panic!("expected span data to be read by the reader task"),
     }
 }
 
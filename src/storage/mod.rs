@@ -1,26 +1,29 @@
 use {
-    std::collections::HashMap,
-    tracing::{info, error, span, Level},
+    std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}, io::ErrorKind, time::{Duration, Instant}},
+    tracing::{info, error, span, Level, Instrument},
     tokio::{net::{TcpSocket, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}},
     prometheus::{Registry, register_int_counter_vec_with_registry, IntCounterVec, IntGaugeVec, register_int_gauge_vec_with_registry},
     thiserror::Error,
     async_recursion::async_recursion,
-    self::protocol::{StorageRequest, StorageRequestBody, StorageResponse},
+    self::protocol::{StorageRequest, StorageRequestBody, StorageResponse, StorageResponseBody},
 };
 
 pub use self::{
     protocol::{SwapOutRequest, SpanData},
-    client::{Client, BatchSwapOutOperation, LocalSpanData},
+    client::{Client, ClientConfig, ClientMetrics, BatchSwapOutOperation, LocalSpanData},
 };
 
 const REQ_SIZE_LIMIT: u64 = 10 * 1024 * 1024 * 1024;
+const RUN_RETENTION: Duration = Duration::from_secs(60);
+const EXPIRED_RUNS_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const SWAP_IN_RETRY_BUFFER_BYTES: usize = 64 * 1024 * 1024;
 pub const BUFFER_SIZE: u32 = 32 * 1024 * 1024;
 
 mod client;
 mod protocol;
 
 #[derive(Error, Debug)]
-enum StorageServerError {
+pub(crate) enum StorageServerError {
     #[error("failed to create server socket")]
     FailedToCreateServerSocket,
     #[error("failed to read span data")]
@@ -31,7 +34,7 @@ pub fn run_storage_server(metrics: Registry, token: String, port: Option<u16>) {
     run_server(Some(metrics), "0.0.0.0".to_owned(), port, token, None, None).unwrap();
 }
 
-fn run_server(metrics: Option<Registry>, host: String, port: Option<u16>, token: String, connections_limit: Option<usize>, requests_limit: Option<usize>) -> Result<(), StorageServerError> {
+pub(crate) fn run_server(metrics: Option<Registry>, host: String, port: Option<u16>, token: String, connections_limit: Option<usize>, requests_limit: Option<usize>) -> Result<(), StorageServerError> {
     let rt = tokio::runtime::Runtime::new().unwrap();
 
     rt.block_on(async {
@@ -40,13 +43,13 @@ fn run_server(metrics: Option<Registry>, host: String, port: Option<u16>, token:
         let addr = format!("{}:{}", host, port);
 
         let socket = TcpSocket::new_v4().unwrap();
+        socket.set_reuseaddr(true).unwrap();
         if let Err(err) = socket.bind(addr.parse().unwrap()) {
             error!("failed to create server socket: {:?}", err);
             return Err(StorageServerError::FailedToCreateServerSocket);
         }
         socket.set_recv_buffer_size(BUFFER_SIZE).unwrap();
         socket.set_send_buffer_size(BUFFER_SIZE).unwrap();
-        socket.set_reuseaddr(true).unwrap();
 
         let listener = socket.listen(1024).unwrap();
 
@@ -54,153 +57,193 @@ fn run_server(metrics: Option<Registry>, host: String, port: Option<u16>, token:
 
         let metrics = metrics.map(|v| ServerMetrics::new(v));
 
+        let storage = Arc::new(Mutex::new(ServerStorage::new()));
+        let mut connection_handlers = Vec::new();
+
+        // runs are dropped even if no clients connect anymore.
+        let expired_runs_task = {
+            let (storage, metrics, addr) = (storage.clone(), metrics.clone(), format!("{}:{}", hostname, port));
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(EXPIRED_RUNS_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    drop_expired_runs(&storage, metrics.as_ref(), &addr);
+                }
+            })
+        };
+
         let mut connections = 0;
-        while let Ok ((mut stream, _add)) = listener.accept().await {
+        while let Ok ((stream, _add)) = listener.accept().await {
             connections += 1;
 
             stream.set_nodelay(true).unwrap();
-            let mut server = Server::new(metrics.clone(), format!("{}:{}", hostname, port), token.clone());
+            let server = Server::new(metrics.clone(), format!("{}:{}", hostname, port), token.clone(), storage.clone());
 
             info!("handling incoming connection");
-            let mut requests = 0;
-            loop {
-                let _req_loop_span = span!(Level::DEBUG, "request loop iteration").entered();
-
-                requests += 1;
-                if let Some(limit) = requests_limit {
-                    if requests > limit {
-                        break;
-                    }
-                }
+            // connections are handled concurrently, so that clients can use a pool of them.
+            connection_handlers.push(tokio::spawn(handle_connection(server, stream, requests_limit)));
 
-                let req_len = {
-                    let _req_len_span = span!(Level::DEBUG, "read request header").entered();
-
-                    let mut req_len: [u8; 8] = [0u8; 8];
-                    if let Err(err) = stream.read(&mut req_len).await {
-                        error!("unexpected error when reading request header: {:?}", err);
-                        break;
-                    }
-                    u64::from_be_bytes(req_len)
-                };
-
-                let req = {
-                    let _req_body_span = span!(Level::DEBUG, "read request body").entered();
-
-                    if req_len > REQ_SIZE_LIMIT {
-                        error!("request is too large!");
-                        break;
-                    }
+            if let Some(limit) = connections_limit {
+                if connections >= limit {
+                    break;
+                }
+            }
+        }
 
-                    let mut req = vec![0u8; req_len as usize];
-                    if let Err(err) = stream.read_exact(&mut req).await {
-                        error!("unexpected error when reading request body: {:?}", err);
-                        break;
-                    }
+        for handler in connection_handlers {
+            handler.await.unwrap();
+        }
+        expired_runs_task.abort();
 
-                    req
-                };
+        Ok(())
+    })?;
 
-                let mut req: StorageRequest = {
-                    let _req_deserialize_body = span!(Level::DEBUG, "deserialize request body").entered();
+    Ok(())
+}
 
-                    match bincode::deserialize(&req) {
-                        Ok(v) => v,
-                        Err(err) => {
-                            error!("unexpected error when reading request: {:?}", err);
-                            break;
-                        }
-                    }
-                };
+async fn handle_connection(mut server: Server, mut stream: TcpStream, requests_limit: Option<usize>) {
+    let mut requests = 0;
+    loop {
+        requests += 1;
+        if let Some(limit) = requests_limit {
+            if requests > limit {
+                break;
+            }
+        }
 
-                let request_id = req.request_id;
-                req.body = match inline_span_data_into_request(req.body, &mut stream).await {
-                    Ok(v) => v,
-                    Err(err) => {
-                        error!("failed to inline span data into request: {:?}", err);
-                        break;
-                    }
-                };
+        let req_len = {
+            let mut req_len: [u8; 8] = [0u8; 8];
+            match stream.read_exact(&mut req_len).instrument(span!(Level::DEBUG, "read request header")).await {
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                    info!("connection closed");
+                    break;
+                },
+                Err(err) => {
+                    error!("unexpected error when reading request header: {:?}", err);
+                    break;
+                }
+            }
+            u64::from_be_bytes(req_len)
+        };
 
-                let res = span!(Level::DEBUG, "handle request", request_id).in_scope(|| server.handle(req.body));
-                let (res, span_data) = match res {
-                    StorageResponse::SwapIn { span_id, data } => {
-                        let span_data = match data {
-                            SpanData::Inline(data) => vec![data],
-                            SpanData::Concat { data } => data,
-                            _ => panic!("didn't expect data to be external at this point"),
-                        };
+        let req = {
+            if req_len > REQ_SIZE_LIMIT {
+                error!("request is too large!");
+                break;
+            }
 
-                        (StorageResponse::SwapIn { span_id, data: SpanData::External { len: span_data.iter().map(|v| v.len() as u64).sum() } }, Some(span_data))
-                    },
-                    StorageResponse::Batch(responses) => {
-                        let mut span_data = None;
-
-                        let mut new_responses = Vec::new();
-                        for response in responses {
-                            let new_response = match response {
-                                StorageResponse::SwapIn { span_id, data } => {
-                                    span_data = Some(match data {
-                                        SpanData::Inline(data) => vec![data],
-                                        SpanData::Concat { data } => data,
-                                        _ => panic!("didn't expect data to be external at this point"),
-                                    });
-
-                                    StorageResponse::SwapIn { span_id, data: SpanData::External { len: span_data.as_ref().map(|data| data.iter().map(|v| v.len() as u64).sum()).unwrap() } }
-                                },
-                                other => other
-                            };
-                            new_responses.push(new_response);
-                        }
-
-                        (StorageResponse::Batch(new_responses), span_data)
-                    },
-                    other => (other, None),
-                };
+            let mut req = vec![0u8; req_len as usize];
+            if let Err(err) = stream.read_exact(&mut req).instrument(span!(Level::DEBUG, "read request body")).await {
+                error!("unexpected error when reading request body: {:?}", err);
+                break;
+            }
 
-                let res = span!(Level::DEBUG, "serialize response", request_id).in_scope(|| bincode::serialize(&res).unwrap());
+            req
+        };
 
-                let scope_write_response = span!(Level::DEBUG, "write response", request_id);
-                let _scope_write_response_guard = scope_write_response.enter();
+        let mut req: StorageRequest = match span!(Level::DEBUG, "deserialize request body").in_scope(|| bincode::deserialize(&req)) {
+            Ok(v) => v,
+            Err(err) => {
+                error!("unexpected error when reading request: {:?}", err);
+                break;
+            }
+        };
+
+        let request_id = req.request_id;
+        req.body = match inline_span_data_into_request(req.body, &mut stream).await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to inline span data into request: {:?}", err);
+                break;
+            }
+        };
 
-                stream.write_all(&(res.len() as u64).to_be_bytes()).await.unwrap();
-                stream.write_all(&res).await.unwrap();
+        let res = span!(Level::DEBUG, "handle request", request_id).in_scope(|| server.handle(req.body));
+        let (res, span_data) = match res {
+            StorageResponseBody::SwapIn { span_id, data } => {
+                let span_data = shared_span_data(data);
 
-                if let Some(span_data) = span_data {
-                    for chunk in span_data {
-                        stream.write_all(&chunk).await.unwrap();
-                    }
+                (StorageResponseBody::SwapIn { span_id, data: SpanData::External { len: span_data.iter().map(|v| v.len() as u64).sum() } }, Some(span_data))
+            },
+            StorageResponseBody::Batch(responses) => {
+                let mut span_data = None;
+
+                let mut new_responses = Vec::new();
+                for response in responses {
+                    let new_response = match response {
+                        StorageResponseBody::SwapIn { span_id, data } => {
+                            span_data = Some(shared_span_data(data));
+
+                            StorageResponseBody::SwapIn { span_id, data: SpanData::External { len: span_data.as_ref().map(|data| data.iter().map(|v| v.len() as u64).sum()).unwrap() } }
+                        },
+                        other => other
+                    };
+                    new_responses.push(new_response);
                 }
-            }
 
-            if let Some(metrics) = metrics.as_ref() {
-                metrics.reset();
-            }
-
-            if let Some(limit) = connections_limit {
-                if connections >= limit {
-                    break;
+                (StorageResponseBody::Batch(new_responses), span_data)
+            },
+            other => (other, None),
+        };
+
+        let res = StorageResponse {
+            request_id,
+            body: res,
+        };
+        let res = span!(Level::DEBUG, "serialize response", request_id).in_scope(|| bincode::serialize(&res).unwrap());
+
+        let write_result = async {
+            stream.write_all(&(res.len() as u64).to_be_bytes()).await?;
+            stream.write_all(&res).await?;
+
+            if let Some(span_data) = span_data {
+                for chunk in span_data.iter() {
+                    stream.write_all(chunk).await?;
                 }
             }
-        }
 
-        Ok(())
-    })?;
+            Ok::<(), std::io::Error>(())
+        }.instrument(span!(Level::DEBUG, "write response", request_id)).await;
 
-    Ok(())
+        if let Err(err) = write_result {
+            error!("unexpected error when writing response: {:?}", err);
+            break;
+        }
+    }
 }
 
 pub struct Server {
     auth: bool,
     token: String,
 
-    spans: HashMap<u64, Vec<Vec<u8>>>,
+    storage: Arc<Mutex<ServerStorage>>,
 
     metrics: Option<ServerMetrics>,
     addr: String,
     run_id: String,
 }
 
+/**
+ * Spans shared by all connections of the server. Spans are grouped by run id, so that a client can use multiple
+ * connections and spans of different runs do not clash. Spans of a run are kept for some time after its last
+ * connection is closed, so that client can reconnect and continue.
+ */
+pub struct ServerStorage {
+    runs: HashMap<String, RunStorage>,
+}
+
+struct RunStorage {
+    spans: HashMap<u64, Vec<Vec<u8>>>,
+    connections: usize,
+    disconnected_at: Option<Instant>,
+
+    // recently swapped in spans, so that swap in can be retried if response to it was lost.
+    swapped_in: VecDeque<u64>,
+    swapped_in_spans: HashMap<u64, Arc<Vec<Vec<u8>>>>,
+    swapped_in_bytes: usize,
+}
+
 #[derive(Clone)]
 pub struct ServerMetrics {
     total_spans: IntGaugeVec,
@@ -257,49 +300,60 @@ impl ServerMetrics {
         }
     }
 
-    pub fn reset(&self) {
-        self.total_spans.reset();
-        self.total_bytes.reset();
+    pub fn remove_run(&self, addr: &str, run_id: &str) {
+        let labels = [addr, run_id];
+
+        // label values may be missing if there were no requests for this run.
+        let _ = self.total_spans.remove_label_values(&labels);
+        let _ = self.total_bytes.remove_label_values(&labels);
 
-        self.swap_out_operations.reset();
-        self.swap_out_bytes.reset();
+        let _ = self.swap_out_operations.remove_label_values(&labels);
+        let _ = self.swap_out_bytes.remove_label_values(&labels);
 
-        self.swap_in_operations.reset();
-        self.swap_in_bytes.reset();
+        let _ = self.swap_in_operations.remove_label_values(&labels);
+        let _ = self.swap_in_bytes.remove_label_values(&labels);
     }
 }
 
 impl Server {
-    pub fn new(metrics: Option<ServerMetrics>, addr: String, token: String) -> Self {
+    pub fn new(metrics: Option<ServerMetrics>, addr: String, token: String, storage: Arc<Mutex<ServerStorage>>) -> Self {
+        let run_id = "unknown".to_owned();
+        storage.lock().unwrap().connect(&run_id);
+
         Self {
             auth: false,
             token,
 
-            spans: HashMap::new(),
+            storage,
 
             metrics,
             addr,
-            run_id: "unknown".to_owned(),
+            run_id,
         }
     }
 
-    pub fn handle(&mut self, req: StorageRequestBody) -> StorageResponse {
+    pub fn handle(&mut self, req: StorageRequestBody) -> StorageResponseBody {
         match req {
             StorageRequestBody::Auth { token } => {
                 self.auth = self.token == token;
                 if self.auth {
-                    StorageResponse::Ok
+                    StorageResponseBody::Ok
                 } else {
-                    StorageResponse::Forbidden
+                    StorageResponseBody::Forbidden
                 }
             },
             StorageRequestBody::SetRunId { run_id } => {
+                let mut storage = self.storage.lock().unwrap();
+                storage.connect(&run_id);
+                storage.disconnect(&self.run_id);
+                drop(storage);
+
                 self.run_id = run_id;
-                StorageResponse::Ok
+                StorageResponseBody::Ok
             }
             StorageRequestBody::SwapOut(swap_out_req) => span!(Level::DEBUG, "handling swap out request").in_scope(|| {
                 if !self.auth {
-                    return StorageResponse::Forbidden;
+                    return StorageResponseBody::Forbidden;
                 }
 
                 let data = match swap_out_req.data {
@@ -308,51 +362,191 @@ impl Server {
                 };
                 let bytes_swapped_out = data.len();
 
+                let mut storage = self.storage.lock().unwrap();
+                let run = storage.run(&self.run_id);
                 span!(Level::DEBUG, "inserting into spans").in_scope(|| if swap_out_req.prepend {
-                    self.spans.get_mut(&swap_out_req.span_id).unwrap().insert(0, data);
+                    run.spans.get_mut(&swap_out_req.span_id).unwrap().insert(0, data);
                 } else {
-                    self.spans.insert(swap_out_req.span_id, vec![data]);
+                    run.forget_swapped_in(swap_out_req.span_id);
+                    run.spans.insert(swap_out_req.span_id, vec![data]);
                 });
 
                 if let Some(metrics) = self.metrics.as_ref() {
-                    metrics.total_spans.with_label_values(&[&self.addr, &self.run_id]).set(self.spans.len() as i64);
-                    metrics.total_bytes.with_label_values(&[&self.addr, &self.run_id]).set(self.total_span_bytes() as i64);
+                    metrics.total_spans.with_label_values(&[&self.addr, &self.run_id]).set(run.spans.len() as i64);
+                    metrics.total_bytes.with_label_values(&[&self.addr, &self.run_id]).set(run.total_span_bytes() as i64);
 
                     metrics.swap_out_operations.with_label_values(&[&self.addr, &self.run_id]).inc();
                     metrics.swap_out_bytes.with_label_values(&[&self.addr, &self.run_id]).inc_by(bytes_swapped_out as u64);
                 }
 
-                StorageResponse::Ok
+                StorageResponseBody::Ok
             }),
             StorageRequestBody::SwapIn { span_id } => span!(Level::DEBUG, "handling swap in request").in_scope(|| {
                 if !self.auth {
-                    return StorageResponse::Forbidden;
+                    return StorageResponseBody::Forbidden;
                 }
 
-                let data = self.spans.remove(&span_id).unwrap();
+                let mut storage = self.storage.lock().unwrap();
+                let run = storage.run(&self.run_id);
+                let data = match run.spans.remove(&span_id) {
+                    Some(data) => {
+                        let data = Arc::new(data);
+                        run.remember_swapped_in(span_id, data.clone());
+                        data
+                    },
+                    // retried swap in, response to the previous one was lost.
+                    None => run.swapped_in_spans.get(&span_id).unwrap().clone(),
+                };
 
                 if let Some(metrics) = self.metrics.as_ref() {
-                    metrics.total_spans.with_label_values(&[&self.addr, &self.run_id]).set(self.spans.len() as i64);
-                    metrics.total_bytes.with_label_values(&[&self.addr, &self.run_id]).set(self.total_span_bytes() as i64);
+                    metrics.total_spans.with_label_values(&[&self.addr, &self.run_id]).set(run.spans.len() as i64);
+                    metrics.total_bytes.with_label_values(&[&self.addr, &self.run_id]).set(run.total_span_bytes() as i64);
 
                     metrics.swap_in_operations.with_label_values(&[&self.addr, &self.run_id]).inc();
                     metrics.swap_in_bytes.with_label_values(&[&self.addr, &self.run_id]).inc_by(data.len() as u64);
                 }
 
-                StorageResponse::SwapIn { span_id, data: SpanData::Concat { data } }
+                StorageResponseBody::SwapIn { span_id, data: SpanData::Shared(data) }
+            }),
+            StorageRequestBody::Read { span_id } => span!(Level::DEBUG, "handling read request").in_scope(|| {
+                if !self.auth {
+                    return StorageResponseBody::Forbidden;
+                }
+
+                let mut storage = self.storage.lock().unwrap();
+                let run = storage.run(&self.run_id);
+                let data = run.spans.get(&span_id).unwrap().clone();
+
+                StorageResponseBody::SwapIn { span_id, data: SpanData::Concat { data } }
+            }),
+            StorageRequestBody::Remove { span_id } => span!(Level::DEBUG, "handling remove request").in_scope(|| {
+                if !self.auth {
+                    return StorageResponseBody::Forbidden;
+                }
+
+                let mut storage = self.storage.lock().unwrap();
+                let run = storage.run(&self.run_id);
+                run.spans.remove(&span_id);
+                run.forget_swapped_in(span_id);
+
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.total_spans.with_label_values(&[&self.addr, &self.run_id]).set(run.spans.len() as i64);
+                    metrics.total_bytes.with_label_values(&[&self.addr, &self.run_id]).set(run.total_span_bytes() as i64);
+                }
+
+                StorageResponseBody::Ok
             }),
             StorageRequestBody::Batch(reqs) => span!(Level::DEBUG, "handling batch request").in_scope(|| {
                 let res = reqs.into_iter().map(|req| self.handle(req)).collect();
-                StorageResponse::Batch(res)
+                StorageResponseBody::Batch(res)
             }),
         }
     }
 
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.storage.lock().unwrap().disconnect(&self.run_id);
+    }
+}
+
+impl ServerStorage {
+    pub fn new() -> Self {
+        Self {
+            runs: HashMap::new(),
+        }
+    }
+
+    fn connect(&mut self, run_id: &str) {
+        let run = self.runs.entry(run_id.to_owned())
+            .or_insert_with(|| RunStorage {
+                spans: HashMap::new(),
+                connections: 0,
+                disconnected_at: None,
+
+                swapped_in: VecDeque::new(),
+                swapped_in_spans: HashMap::new(),
+                swapped_in_bytes: 0,
+            });
+        run.connections += 1;
+        run.disconnected_at = None;
+    }
+
+    fn disconnect(&mut self, run_id: &str) {
+        let run = self.runs.get_mut(run_id).unwrap();
+        run.connections -= 1;
+
+        if run.connections == 0 {
+            run.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    // returns ids of runs that had no connections for too long and were dropped.
+    fn drop_expired_runs(&mut self) -> Vec<String> {
+        let expired_runs: Vec<String> = self.runs.iter()
+            .filter(|(_, run)| run.disconnected_at.map(|v| v.elapsed() > RUN_RETENTION).unwrap_or(false))
+            .map(|(run_id, _)| run_id.clone())
+            .collect();
+
+        for run_id in &expired_runs {
+            self.runs.remove(run_id);
+        }
+
+        expired_runs
+    }
+
+    fn run(&mut self, run_id: &str) -> &mut RunStorage {
+        self.runs.get_mut(run_id).unwrap()
+    }
+}
+
+impl RunStorage {
+    fn remember_swapped_in(&mut self, span_id: u64, data: Arc<Vec<Vec<u8>>>) {
+        self.forget_swapped_in(span_id);
+
+        self.swapped_in_bytes += data.iter().map(|v| v.len()).sum::<usize>();
+        self.swapped_in.push_back(span_id);
+        self.swapped_in_spans.insert(span_id, data);
+
+        // the most recent span is always kept, even if it is larger than the buffer.
+        while self.swapped_in_bytes > SWAP_IN_RETRY_BUFFER_BYTES && self.swapped_in.len() > 1 {
+            let span_id = self.swapped_in.pop_front().unwrap();
+            let data = self.swapped_in_spans.remove(&span_id).unwrap();
+            self.swapped_in_bytes -= data.iter().map(|v| v.len()).sum::<usize>();
+        }
+    }
+
+    fn forget_swapped_in(&mut self, span_id: u64) {
+        if let Some(data) = self.swapped_in_spans.remove(&span_id) {
+            self.swapped_in_bytes -= data.iter().map(|v| v.len()).sum::<usize>();
+            self.swapped_in.retain(|v| *v != span_id);
+        }
+    }
+
     fn total_span_bytes(&self) -> usize {
         self.spans.iter().map(|v| v.1.iter().map(|t| t.len()).sum::<usize>()).sum()
     }
 }
 
+fn drop_expired_runs(storage: &Mutex<ServerStorage>, metrics: Option<&ServerMetrics>, addr: &str) {
+    let expired_runs = storage.lock().unwrap().drop_expired_runs();
+    if let Some(metrics) = metrics {
+        for run_id in expired_runs {
+            metrics.remove_run(addr, &run_id);
+        }
+    }
+}
+
+fn shared_span_data(data: SpanData) -> Arc<Vec<Vec<u8>>> {
+    match data {
+        SpanData::Inline(data) => Arc::new(vec![data]),
+        SpanData::Concat { data } => Arc::new(data),
+        SpanData::Shared(data) => data,
+        SpanData::External { .. } => panic!("didn't expect data to be external at this point"),
+    }
+}
+
 #[async_recursion]
 async fn inline_span_data_into_request(request: StorageRequestBody, stream: &mut TcpStream) -> Result<StorageRequestBody, StorageServerError> {
     Ok(match request {
@@ -360,6 +554,7 @@ async fn inline_span_data_into_request(request: StorageRequestBody, stream: &mut
             let data = match swap_out_request.data {
                 SpanData::Inline(data) => SpanData::Inline(data),
                 SpanData::Concat { data } => SpanData::Inline(data.concat()),
+                SpanData::Shared(data) => SpanData::Inline(data.concat()),
                 SpanData::External { len } => SpanData::Inline({
                     let mut data = vec![0; len as usize];
                     if let Err(_err) = stream.read_exact(&mut data).await {
@@ -389,6 +584,7 @@ async fn inline_span_data_into_request(request: StorageRequestBody, stream: &mut
 mod tests {
     use {
         std::thread,
+        futures::future::join_all,
         super::*,
     };
 
@@ -402,7 +598,7 @@ mod tests {
             Some(1),
             Some(3)
         ).unwrap());
-        let mut client = Client::new("127.0.0.1:14000").await;
+        let client = Client::new("127.0.0.1:14000").await;
 
         client.auth("some-token").await;
         client.swap_out(42, vec![10, 9, 8, 7, 6, 5, 4, 3, 2, 1], false).await;
@@ -423,7 +619,7 @@ mod tests {
             Some(1),
             Some(4)
         ).unwrap());
-        let mut client = Client::new("127.0.0.1:14001").await;
+        let client = Client::new("127.0.0.1:14001").await;
 
         client.auth("some-token").await;
 
@@ -436,4 +632,160 @@ mod tests {
 
         server_thread.join().unwrap();
     }
+
+    #[tokio::test]
+    async fn pipelined_requests() {
+        let server_thread = thread::spawn(|| run_server(
+            None,
+            "127.0.0.1".to_owned(),
+            Some(14002),
+            "some-token".to_owned(),
+            Some(1),
+            None
+        ).unwrap());
+        let client = Client::new("127.0.0.1:14002").await;
+
+        client.auth("some-token").await;
+
+        join_all((0..64u64).map(|i| client.swap_out(i, vec![i as u8; 1024 * (i as usize + 1)], false))).await;
+        let spans = join_all((0..64u64).map(|i| client.swap_in(i))).await;
+
+        for (i, span) in spans.into_iter().enumerate() {
+            assert_eq!(vec![i as u8; 1024 * (i + 1)], span);
+        }
+
+        client.close().await;
+        server_thread.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_share_spans_of_run() {
+        let server_thread = thread::spawn(|| run_server(
+            None,
+            "127.0.0.1".to_owned(),
+            Some(14003),
+            "some-token".to_owned(),
+            Some(3),
+            None
+        ).unwrap());
+
+        let mut clients = Vec::new();
+        for run_id in ["run-a", "run-a", "run-b"] {
+            let client = Client::new("127.0.0.1:14003").await;
+            client.auth("some-token").await;
+            client.set_run_id(run_id.to_owned()).await;
+            clients.push(client);
+        }
+
+        clients[0].swap_out(42, vec![1, 2, 3], false).await;
+        clients[2].swap_out(42, vec![4, 5, 6], false).await;
+        clients[1].swap_out(42, vec![0], true).await;
+
+        assert_eq!(vec![0, 1, 2, 3], clients[1].swap_in(42).await);
+        assert_eq!(vec![4, 5, 6], clients[2].swap_in(42).await);
+
+        for client in &clients {
+            client.close().await;
+        }
+        server_thread.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_restores_session() {
+        let server_thread = thread::spawn(|| run_server(
+            None,
+            "127.0.0.1".to_owned(),
+            Some(14004),
+            "some-token".to_owned(),
+            Some(2),
+            Some(3)
+        ).unwrap());
+
+        let registry = Registry::new();
+        let metrics = ClientMetrics::new(registry.clone());
+        let config = ClientConfig::new()
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(100))
+            .with_metrics(metrics.clone());
+        let client = Client::connect("127.0.0.1:14004", config).await;
+
+        client.auth("some-token").await;
+        client.set_run_id("some-run".to_owned()).await;
+        client.swap_out(42, vec![1, 2, 3], false).await;
+
+        // server closes the connection after three requests, so swap in is retried after reconnect.
+        assert_eq!(vec![1, 2, 3], client.swap_in(42).await);
+
+        let counter = |name: &str| registry.gather().into_iter()
+            .find(|v| v.get_name() == name)
+            .map(|v| v.get_metric()[0].get_counter().get_value())
+            .unwrap();
+        assert_eq!(2.0, counter("storage_client_connects"));
+        assert_eq!(1.0, counter("storage_client_disconnects"));
+        assert_eq!(1.0, counter("storage_client_retries"));
+
+        client.close().await;
+        server_thread.join().unwrap();
+
+        metrics.unregister();
+        assert!(registry.gather().is_empty());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "request timed out")]
+    async fn request_timeout() {
+        // accepts connections, but never responds.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut streams = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                streams.push(stream);
+            }
+        });
+
+        let config = ClientConfig::new()
+            .with_read_timeout(Duration::from_millis(100))
+            .with_max_retries(1);
+        let client = Client::connect(&addr, config).await;
+
+        client.swap_in(42).await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "storage at 127.0.0.1:14008 is unreachable")]
+    async fn give_up_when_storage_is_unreachable() {
+        // server handles auth and swap out, and then stops.
+        let server_thread = thread::spawn(|| run_server(
+            None,
+            "127.0.0.1".to_owned(),
+            Some(14008),
+            "some-token".to_owned(),
+            Some(1),
+            Some(2)
+        ).unwrap());
+
+        let config = ClientConfig::new()
+            .with_read_timeout(Duration::from_millis(100))
+            .with_max_connect_attempts(2)
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let client = Client::connect("127.0.0.1:14008", config).await;
+
+        client.auth("some-token").await;
+        client.swap_out(42, vec![1, 2, 3], false).await;
+        server_thread.join().unwrap();
+
+        client.swap_in(42).await;
+    }
+
+    #[test]
+    fn swap_in_is_idempotent() {
+        let mut server = Server::new(None, "localhost".to_owned(), "some-token".to_owned(), Arc::new(Mutex::new(ServerStorage::new())));
+
+        server.handle(StorageRequestBody::Auth { token: "some-token".to_owned() });
+        server.handle(StorageRequestBody::SwapOut(SwapOutRequest { span_id: 42, prepend: false, data: SpanData::Inline(vec![1, 2, 3]) }));
+
+        let expected = StorageResponseBody::SwapIn { span_id: 42, data: SpanData::Shared(Arc::new(vec![vec![1, 2, 3]])) };
+        assert_eq!(expected, server.handle(StorageRequestBody::SwapIn { span_id: 42 }));
+        assert_eq!(expected, server.handle(StorageRequestBody::SwapIn { span_id: 42 }));
+    }
 }
@@ -1,4 +1,7 @@
-use serde::{Serialize, Deserialize};
+use {
+    std::sync::Arc,
+    serde::{Serialize, Deserialize},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct StorageRequest {
@@ -18,18 +21,32 @@ pub enum StorageRequestBody {
     SwapIn {
         span_id: u64,
     },
+    // same as swap in, but keeps span in storage. Used when moving spans between servers.
+    Read {
+        span_id: u64,
+    },
+    Remove {
+        span_id: u64,
+    },
     Batch(Vec<StorageRequestBody>),
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StorageResponse {
+    // same as in the request, so that client can match responses to pipelined requests.
+    pub request_id: u64,
+    pub body: StorageResponseBody,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub enum StorageResponse {
+pub enum StorageResponseBody {
     Ok,
     Forbidden,
     SwapIn {
         span_id: u64,
         data: SpanData,
     },
-    Batch(Vec<StorageResponse>),
+    Batch(Vec<StorageResponseBody>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -50,6 +67,9 @@ pub enum SpanData {
     External {
         len: u64,
     },
+    // span data shared with retry buffer of the server. Only used before response is written, never serialized.
+    #[serde(skip)]
+    Shared(Arc<Vec<Vec<u8>>>),
 }
 
 pub enum InlineSpanData {